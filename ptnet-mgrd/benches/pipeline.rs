@@ -0,0 +1,145 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use criterion::measurement::{Measurement, ValueFormatter};
+use ptnet::Scanner;
+use ptnet_mgrd::bench;
+
+/// Counts allocations so regressions show up besides timing
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Measures allocations instead of time, criterion keeps baselines of them as of timings
+struct Allocations;
+
+impl Measurement for Allocations {
+    type Intermediate = u64;
+    type Value = u64;
+
+    fn start(&self) -> u64 {
+        ALLOCATIONS.load(Ordering::Relaxed)
+    }
+
+    fn end(&self, started: u64) -> u64 {
+        ALLOCATIONS.load(Ordering::Relaxed) - started
+    }
+
+    fn add(&self, v1: &u64, v2: &u64) -> u64 {
+        v1 + v2
+    }
+
+    fn zero(&self) -> u64 {
+        0
+    }
+
+    fn to_f64(&self, value: &u64) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &AllocationFormatter
+    }
+}
+
+struct AllocationFormatter;
+
+impl ValueFormatter for AllocationFormatter {
+    fn scale_values(&self, _typical_value: f64, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+
+    fn scale_throughputs(&self, _typical_value: f64, throughput: &Throughput, values: &mut [f64]) -> &'static str {
+        match throughput {
+            Throughput::Elements(elements) => {
+                for value in values {
+                    *value /= *elements as f64;
+                }
+                "allocs/elem"
+            },
+            _ => "allocs"
+        }
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+}
+
+/// IOBs in one synthetic message
+const IOBS: u32 = 16;
+/// Messages in one dispatched stream, 10k IOBs
+const MESSAGES: u32 = 625;
+/// Nodes sending dispatched stream
+const NODES: u32 = 50;
+
+fn scan(payload: &[u8]) -> usize {
+    Scanner::new(payload).into_iob_iter().take_while(|item| item.is_ok()).count()
+}
+
+fn scanner<M: Measurement>(c: &mut Criterion<M>, name: &str) {
+    let payload = bench::payload(IOBS);
+    assert_eq!(IOBS as usize, scan(&payload));
+
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Elements(u64::from(IOBS)));
+    group.bench_function("iob_iter", |b| b.iter(|| scan(criterion::black_box(&payload))));
+    group.bench_function("iob_iter_owned", |b| b.iter_batched(|| payload.clone(), |p| scan(&p), BatchSize::SmallInput));
+    group.finish();
+}
+
+fn dispatch<M: Measurement>(c: &mut Criterion<M>, name: &str) {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    let stream = bench::server_stream(MESSAGES, IOBS, NODES);
+    assert_eq!(u64::from(MESSAGES * IOBS), runtime.block_on(bench::dispatch(&stream)));
+
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Elements(u64::from(MESSAGES * IOBS)));
+    group.sample_size(20);
+    group.bench_function("dispatcher", |b| b.iter(|| runtime.block_on(bench::dispatch(&stream))));
+    group.finish();
+}
+
+fn persist<M: Measurement>(c: &mut Criterion<M>, name: &str) {
+    bench::with_persist(IOBS, |persist| {
+        let mut group = c.benchmark_group(name);
+        group.throughput(Throughput::Elements(u64::from(IOBS)));
+        group.bench_function("series", |b| b.iter(&mut *persist));
+        group.finish();
+    });
+}
+
+fn timing(c: &mut Criterion) {
+    scanner(c, "scanner");
+    dispatch(c, "dispatch");
+    persist(c, "persist");
+}
+
+fn allocations(c: &mut Criterion<Allocations>) {
+    scanner(c, "scanner_allocations");
+    dispatch(c, "dispatch_allocations");
+    persist(c, "persist_allocations");
+}
+
+criterion_group!(timings, timing);
+criterion_group! {
+    name = allocation_counts;
+    config = Criterion::default().with_measurement(Allocations);
+    targets = allocations
+}
+criterion_main!(timings, allocation_counts);
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::{Serialize, Deserialize};
+
+/// Roles of API clients, each role includes rights of lower ones
+#[derive(Debug,Clone,Copy,Serialize,Deserialize,PartialEq,Eq,PartialOrd,Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// read-only access
+    Viewer,
+    /// may scan nodes and send commands
+    Operator,
+    /// may approve and reject firmware updates
+    Admin
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub struct StaticToken {
+    /// shown in logs instead of token
+    pub name: String,
+    pub token: String,
+    pub role: Role
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub struct JwtConfig {
+    /// RS256, ES256 or HS256
+    pub algorithm: String,
+    /// PEM public key of identity provider, or shared secret for HS256
+    pub key: String,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    /// claim holding list of role names
+    #[serde(default = "JwtConfig::default_roles_claim")]
+    pub roles_claim: String
+}
+
+impl JwtConfig {
+    fn default_roles_claim() -> String {
+        "roles".to_string()
+    }
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq,Default)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub tokens: Vec<StaticToken>,
+    /// bearer tokens issued by OIDC provider, validated with configured key
+    pub jwt: Option<JwtConfig>
+}
+
+/// Authenticated API client
+#[derive(Debug,Clone,PartialEq)]
+pub struct Principal {
+    pub name: String,
+    pub role: Role
+}
+
+/// Compare without leaking length of matching prefix
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub struct Authenticator {
+    tokens: Vec<StaticToken>,
+    jwt: Option<(DecodingKey, Validation, String)>
+}
+
+impl Authenticator {
+    pub fn new(conf: &AuthConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let jwt = match &conf.jwt {
+            None => None,
+            Some(jwt) => {
+                let algorithm: Algorithm = jwt.algorithm.parse()?;
+                let key = match algorithm {
+                    Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => DecodingKey::from_secret(jwt.key.as_bytes()),
+                    Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(jwt.key.as_bytes())?,
+                    _ => DecodingKey::from_rsa_pem(jwt.key.as_bytes())?
+                };
+
+                let mut validation = Validation::new(algorithm);
+                if let Some(issuer) = &jwt.issuer {
+                    validation.set_issuer(&[issuer]);
+                }
+                match &jwt.audience {
+                    Some(audience) => validation.set_audience(&[audience]),
+                    None => validation.validate_aud = false
+                }
+
+                Some((key, validation, jwt.roles_claim.clone()))
+            }
+        };
+
+        Ok(Authenticator {
+            tokens: conf.tokens.clone(),
+            jwt: jwt
+        })
+    }
+
+    /// Identify client presenting bearer token
+    pub fn authenticate(&self, token: &str) -> Result<Principal, String> {
+        if let Some(known) = self.tokens.iter().find(|known| same_token(&known.token, token)) {
+            return Ok(Principal { name: known.name.clone(), role: known.role });
+        }
+
+        let (key, validation, roles_claim) = self.jwt.as_ref().ok_or_else(|| "Unknown token".to_string())?;
+        let claims = jsonwebtoken::decode::<HashMap<String, serde_json::Value>>(token, key, validation)
+            .map_err(|err| format!("Invalid token ({})", err))?
+            .claims;
+
+        // unknown role names are ignored, provider may issue roles of other applications
+        let role = claims.get(roles_claim)
+            .and_then(|roles| roles.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|role| serde_json::from_value::<Role>(role.clone()).ok())
+            .max()
+            .ok_or_else(|| "Token grants no role".to_string())?;
+
+        let name = claims.get("sub").and_then(|sub| sub.as_str()).unwrap_or("unknown").to_string();
+
+        Ok(Principal { name: name, role: role })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_and_jwt() {
+        let auth = Authenticator::new(&AuthConfig {
+            tokens: vec![StaticToken { name: "dashboard".to_string(), token: "secret-1".to_string(), role: Role::Viewer }],
+            jwt: Some(JwtConfig {
+                algorithm: "HS256".to_string(),
+                key: "shared".to_string(),
+                issuer: Some("idp".to_string()),
+                audience: None,
+                roles_claim: "roles".to_string()
+            })
+        }).unwrap();
+
+        assert_eq!(Role::Viewer, auth.authenticate("secret-1").unwrap().role);
+        assert!(auth.authenticate("secret-2").is_err());
+
+        let claims = serde_json::json!({ "sub": "alice", "iss": "idp", "exp": 4102444800u64, "roles": ["viewer", "admin", "other"] });
+        let token = jsonwebtoken::encode(&Default::default(), &claims, &jsonwebtoken::EncodingKey::from_secret(b"shared")).unwrap();
+        assert_eq!(Principal { name: "alice".to_string(), role: Role::Admin }, auth.authenticate(&token).unwrap());
+
+        let foreign = serde_json::json!({ "sub": "bob", "iss": "other-idp", "exp": 4102444800u64, "roles": ["admin"] });
+        let token = jsonwebtoken::encode(&Default::default(), &foreign, &jsonwebtoken::EncodingKey::from_secret(b"shared")).unwrap();
+        assert!(auth.authenticate(&token).is_err());
+
+        assert!(Role::Viewer < Role::Operator && Role::Operator < Role::Admin);
+    }
+}
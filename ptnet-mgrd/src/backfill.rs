@@ -0,0 +1,117 @@
+use std::{fs::File, io::{BufRead, BufReader}, path::Path, time::{Duration, Instant}};
+
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::{client_connection::{Message, MessageHeader, IOBMessage, IOBClass, decode_iobs}, database::{Database, NodeAddress}, dedup::{DedupConfig, DuplicateFilter}, ptnet_process::{PersistConfig, PersistSink}};
+
+#[derive(Debug,Deserialize)]
+struct CapturedHeader {
+    #[serde(rename = "C")]
+    control: u8,
+    address: NodeAddress
+}
+
+/// Message as dispatcher logs it
+#[derive(Debug,Deserialize)]
+struct CapturedMessage {
+    port: i32,
+    header: CapturedHeader,
+    payload: Vec<u8>
+}
+
+/// What was recovered from capture
+#[derive(Debug,Clone,Default,Serialize,PartialEq)]
+pub struct BackfillReport {
+    pub messages: usize,
+    /// messages delivered twice, decoded once
+    pub duplicates: usize,
+    pub iobs: usize,
+    /// samples added to measurement series
+    pub stored: usize,
+    /// lines without captured message, e.g. other log records
+    pub skipped: usize
+}
+
+/// Message of capture line with unix time of its reception in milliseconds, None if line has none.
+/// Line is JSON object with `ts` (RFC 3339) and `msg` as in JSON debug log of dispatcher.
+fn parse_line(line: &str) -> Option<(u64, Message)> {
+    let record: Value = serde_json::from_str(line).ok()?;
+    let at = chrono::DateTime::parse_from_rfc3339(record.get("ts")?.as_str()?).ok()?;
+    let msg: CapturedMessage = serde_json::from_value(record.get("msg")?.clone()).ok()?;
+
+    Some((
+        u64::try_from(at.timestamp_millis()).ok()?,
+        Message {
+            port: msg.port,
+            header: ptnet::Header { C: msg.header.control, address: msg.header.address },
+            payload: msg.payload
+        }
+    ))
+}
+
+/// Decode captured traffic and store its points like persist sink would have, for recovering
+/// history lost while persist wasn't running. Meant for offline copy of database.
+pub fn backfill(db: &Database, capture: &Path, persist: PersistConfig, dedup: &DedupConfig) -> Result<BackfillReport, Box<dyn std::error::Error>> {
+    let reader = BufReader::new(File::open(capture)?);
+    let sink = PersistSink::new(persist, db);
+    let mut duplicates = DuplicateFilter::new(dedup);
+    let mut report = BackfillReport::default();
+    // capture time is replayed on monotonic clock of duplicate filter
+    let base = Instant::now();
+    let mut first: Option<u64> = None;
+
+    for line in reader.lines() {
+        let (at, msg) = match parse_line(&line?) {
+            Some(captured) => captured,
+            None => {
+                report.skipped += 1;
+                continue;
+            }
+        };
+        report.messages += 1;
+
+        let since_first = at.saturating_sub(*first.get_or_insert(at));
+        if duplicates.is_duplicate(&msg.header.address, msg.header.C, &msg.payload, base + Duration::from_millis(since_first)) {
+            report.duplicates += 1;
+            continue;
+        }
+
+        for iob in decode_iobs(&msg) {
+            report.iobs += 1;
+
+            let iob_msg = IOBMessage {
+                message: MessageHeader::from(&msg),
+                class: IOBClass::of(&iob.asdh.cot),
+                trace: None,
+                seq: report.iobs as u64,
+                iob: iob
+            };
+            if sink.backfill(&iob_msg, at / 1000)? {
+                report.stored += 1;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_log_lines() {
+        let line = r#"{"ts":"2023-05-04T10:20:30.500+02:00","level":"DEBUG","target":"ptnet_mgrd::client_connection","msg":{"port":2,"header":{"C":68,"address":[254,237,222,175,190,239]},"payload":[62,3,1]}}"#;
+
+        let (at, msg) = parse_line(line).unwrap();
+        assert_eq!(1683188430500, at);
+        assert_eq!(2, msg.port);
+        assert_eq!(68, msg.header.C);
+        assert_eq!([0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0xEF], msg.header.address);
+        assert_eq!(vec![62, 3, 1], msg.payload);
+
+        assert!(parse_line(r#"{"ts":"2023-05-04T10:20:30.500+02:00","level":"INFO","msg":"Connected"}"#).is_none());
+        assert!(parse_line("not json").is_none());
+    }
+}
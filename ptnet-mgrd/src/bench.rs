@@ -0,0 +1,127 @@
+use std::fs;
+
+use ptnet::{PtNetPacket, ASDHConstruct, DUIConstruct, COT, FC, IE, MAGIC_SERVER_MESSAGE, helpers::any_as_u8_slice};
+use tokio::{io::AsyncWriteExt, net::{TcpListener, TcpStream}, sync::broadcast::{self, error::TryRecvError}};
+
+use crate::{client_connection::{ClientConnection, ClientConnectionDispatcher, Message, MessageHeader, IOBMessage, IOBClass, decode_iobs}, database::{Database, NodeAddress, storage::{Store, StorageBackend}}, dedup::DedupConfig, error::PtnetMgrError, ptnet_process::{PersistConfig, PersistSink, IobSink}};
+
+/// Type of synthetic points, 32-bit values
+const TI: u8 = 48;
+
+fn node_address(n: u32) -> NodeAddress {
+    let b = n.to_be_bytes();
+    [0x02, 0x50, b[0], b[1], b[2], b[3]]
+}
+
+/// Spontaneous message of node `n` carrying `iobs` points, values change with `seq`
+fn message(n: u32, iobs: u32, seq: u32) -> Message {
+    let mut buf = packet::buffer::Dynamic::new();
+    let mut builder = PtNetPacket::with_asdh(&ptnet::ASDH::with(0x3E, COT::SPONT, false), &mut buf).unwrap()
+        .begin_asdu(&ptnet::DUI::with_direct(TI, iobs as u8, false)).unwrap();
+
+    for i in 0..iobs {
+        builder = builder
+            .add_ioa(0x100 + i).unwrap()
+            .add_ie(&IE::from_bytes(TI, &seq.wrapping_add(i).to_le_bytes()).unwrap()).unwrap();
+    }
+    builder.end_asdu().unwrap();
+
+    Message {
+        port: 0,
+        header: ptnet::Header { C: ptnet::BIT_PRM as u8 | FC::PrmSendNoreply as u8, address: node_address(n) },
+        payload: buf.into()
+    }
+}
+
+/// Payload of one message of `iobs` points, as scanner gets it
+pub fn payload(iobs: u32) -> Vec<u8> {
+    message(0, iobs, 0).payload
+}
+
+/// `messages` messages of `iobs` points from `nodes` nodes, framed as ptlink server sends them
+pub fn server_stream(messages: u32, iobs: u32, nodes: u32) -> Vec<u8> {
+    let mut stream = Vec::new();
+
+    for seq in 0..messages {
+        let msg = message(seq % nodes.max(1), iobs, seq);
+        let raw_msg = ptnet::ServerMessage {
+            iPort: msg.port as _,
+            header: msg.header,
+            payloadLength: msg.payload.len() as _
+        };
+
+        unsafe {
+            stream.extend_from_slice(any_as_u8_slice(&MAGIC_SERVER_MESSAGE));
+            stream.extend_from_slice(any_as_u8_slice(&raw_msg));
+        }
+        stream.extend_from_slice(&msg.payload);
+    }
+
+    stream
+}
+
+/// Dispatch `stream` of ptlink server over loopback connection, returns number of IOBs dispatched
+pub async fn dispatch(stream: &[u8]) -> u64 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (mut client, _) = listener.accept().await.unwrap();
+
+    let (spontaneous, _) = broadcast::channel(128);
+    let conn = ClientConnection::new(spontaneous);
+    // one listener as processes always subscribe
+    let mut iobs = conn.subscribe_iob();
+
+    let (mut reader, _writer) = client.split();
+    let mut dispatcher = ClientConnectionDispatcher::new(&conn, &mut reader, &DedupConfig::default());
+
+    let write = async move {
+        let mut server = server;
+        server.write_all(stream).await.unwrap();
+        // closed connection ends dispatching
+    };
+    let (_, result) = tokio::join!(write, dispatcher.dispatch());
+    assert!(matches!(result, Err(PtnetMgrError::Link(_))), "{:?}", result);
+
+    let mut dispatched = 0;
+    loop {
+        match iobs.try_recv() {
+            Ok(_) => dispatched += 1,
+            Err(TryRecvError::Lagged(skipped)) => dispatched += skipped,
+            Err(_) => return dispatched
+        }
+    }
+}
+
+/// Run `f` with function passing message of `iobs` points to persist sink storing them as series,
+/// database is temporary file removed afterwards
+pub fn with_persist<R>(iobs: u32, f: impl FnOnce(&mut dyn FnMut()) -> R) -> R {
+    let path = std::env::temp_dir().join(format!("ptnet-mgrd-bench-{}.redb", std::process::id()));
+    fs::remove_file(&path).unwrap_or_default();
+
+    let result = {
+        let store = Store::open(StorageBackend::Redb, &path).unwrap();
+        let mut db = Database::new(&store);
+        db.init().unwrap();
+
+        let msg = message(0, iobs, 0);
+        let iob_msgs: Vec<IOBMessage> = decode_iobs(&msg).into_iter().enumerate().map(|(seq, iob)| IOBMessage {
+            message: MessageHeader::from(&msg),
+            class: IOBClass::of(&iob.asdh.cot),
+            trace: None,
+            seq: seq as u64,
+            iob: iob
+        }).collect();
+        assert_eq!(iobs as usize, iob_msgs.len());
+
+        let mut sink = PersistSink::new(PersistConfig { store_unknown: true, ..Default::default() }, &db);
+        let mut persist = || {
+            for iob_msg in &iob_msgs {
+                sink.accept(iob_msg).unwrap();
+            }
+        };
+        f(&mut persist)
+    };
+
+    fs::remove_file(&path).unwrap_or_default();
+    result
+}
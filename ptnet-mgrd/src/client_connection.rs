@@ -1,11 +1,106 @@
-use std::collections::HashMap;
-use serde::Serialize;
+use std::{collections::{HashMap, HashSet}, sync::Arc, time::Instant};
+use serde::{Serialize, Deserialize};
 use tokio::net::tcp::{ReadHalf, WriteHalf};
-use tokio::sync::{oneshot, broadcast, Mutex};
+use tokio::sync::{oneshot, broadcast, Mutex, OwnedMutexGuard};
 use tokio::io::{AsyncWriteExt, AsyncReadExt};
 use log::{warn, debug, as_serde};
 
-use ptnet::{self, MAGIC_RESULT, MAGIC_SERVER_MESSAGE, IOB, FC, HeaderBits, Scanner};
+use ptnet::{self, MAGIC_RESULT, MAGIC_SERVER_MESSAGE, IOB, FC, COT, HeaderBits, Scanner, MessageResultCode};
+
+use crate::{database::{NodeAddress, NodeAddr}, error::{PtnetMgrError, LinkError, ProtocolError}, dedup::{DedupConfig, DuplicateFilter}};
+
+/// Link address all nodes listen to
+pub const BROADCAST_ADDRESS: NodeAddress = [0xFF; 6];
+
+/// Payload length of message is single octet
+pub const MAX_PAYLOAD: usize = u8::MAX as usize;
+
+/// ptnet defines broadcast as its only group address, other group addresses come from `BroadcastConfig`
+pub fn is_group_address(address: &NodeAddress) -> bool {
+    *address == BROADCAST_ADDRESS
+}
+
+/// Link test or read request, neither changes anything on node; payload which doesn't parse isn't passive
+pub fn is_passive(msg: &Message) -> bool {
+    if !msg.header.prm() {
+        return false;
+    }
+
+    match msg.header.fc() {
+        Some(fc) if fc as u8 == ptnet::FC_PRM_LINK_TEST as u8 => true,
+        Some(FC::PrmSendNoreply) => {
+            let mut iobs = 0;
+            for item in Scanner::new(&msg.payload[..]).into_iob_iter() {
+                match item {
+                    Ok(iob) if matches!(iob.asdh.cot, COT::REQ) => iobs += 1,
+                    _ => return false
+                }
+            }
+            iobs > 0
+        },
+        _ => false
+    }
+}
+
+/// IOBs of PRM message carrying data, decoding stops at first IOB which doesn't parse
+pub fn decode_iobs(msg: &Message) -> Vec<IOB> {
+    if !msg.header.prm() || !matches!(msg.header.fc(), Some(FC::PrmSendConfirm) | Some(FC::PrmSendNoreply)) {
+        return Vec::new();
+    }
+
+    Scanner::new(&msg.payload[..]).into_iob_iter()
+        .map_while(|item| item.ok())
+        .collect()
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq,Default)]
+#[serde(default)]
+pub struct BroadcastConfig {
+    /// ports group-addressed messages may be sent to, PORT_AUTO sends to each of them;
+    /// ptlink server picks port itself if empty
+    pub ports: Vec<i32>,
+    /// group addresses nodes are configured to listen to, besides broadcast
+    pub groups: Vec<NodeAddress>
+}
+
+impl BroadcastConfig {
+    /// Broadcast or one of configured group addresses
+    pub fn is_group_address(&self, address: &NodeAddress) -> bool {
+        is_group_address(address) || self.groups.contains(address)
+    }
+}
+
+/// Send result of message, as reported by ptlink server
+#[derive(Debug,Clone,Copy,Serialize,PartialEq,Eq)]
+pub enum SendOutcome {
+    Delivered,
+    NotDelivered,
+    TimedOut,
+    LinkDown,
+    PortInvalid,
+    /// result code this version doesn't know, newer server may send it
+    Unknown(u16)
+}
+
+impl From<u16> for SendOutcome {
+    fn from(code: u16) -> Self {
+        match code {
+            c if c == MessageResultCode::Delivered as u16 => SendOutcome::Delivered,
+            c if c == MessageResultCode::NotDelivered as u16 => SendOutcome::NotDelivered,
+            c if c == MessageResultCode::TimedOut as u16 => SendOutcome::TimedOut,
+            c if c == MessageResultCode::LinkDown as u16 => SendOutcome::LinkDown,
+            c if c == MessageResultCode::PortInvalid as u16 => SendOutcome::PortInvalid,
+            c => SendOutcome::Unknown(c)
+        }
+    }
+}
+
+/// Send result of message with trace ID it was sent under
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct SendResult {
+    pub trace: u64,
+    pub outcome: SendOutcome
+}
 
 #[derive(Debug,Clone,Serialize)]
 pub struct Message {
@@ -14,16 +109,51 @@ pub struct Message {
     pub payload: Vec<u8>
 }
 
+/// Connection to ptlink server came up or went down
+#[derive(Debug,Clone,Serialize)]
+pub enum ConnectionEvent {
+    /// carries server address
+    Connected(String),
+    /// carries reason
+    Disconnected(String)
+}
+
 #[derive(Debug,Clone)]
 pub struct MessageHeader {
     pub port: i32,
     pub header: ptnet::Header
 }
 
+/// Traffic class of IOB, derived from its cause of transmission
+#[derive(Debug,Clone,Copy,Serialize,PartialEq,Eq)]
+pub enum IOBClass {
+    /// alarms and other changes node reports on its own
+    Spontaneous,
+    /// answers to reads and commands
+    Response,
+    /// periodic reports and everything else
+    Cyclic
+}
+
+impl IOBClass {
+    pub fn of(cot: &COT) -> Self {
+        match cot {
+            COT::SPONT => IOBClass::Spontaneous,
+            COT::REQ | COT::ACTCON | COT::ACTTERM => IOBClass::Response,
+            _ => IOBClass::Cyclic
+        }
+    }
+}
+
 #[derive(Debug,Clone)]
 pub struct IOBMessage {
     pub message: MessageHeader,
-    pub iob: IOB
+    pub iob: IOB,
+    pub class: IOBClass,
+    /// trace ID of last message sent to node, set on responses only
+    pub trace: Option<u64>,
+    /// number of IOB on connection, copy of spontaneous IOB on bulk channel has same one
+    pub seq: u64
 }
 
 impl From<&Message> for MessageHeader {
@@ -47,7 +177,13 @@ unsafe fn any_as_u8_slice_mut<T: Sized>(p: &mut T) -> &mut [u8] {
 
 pub struct SharedState {
     id_gen: u16,
-    request_map: HashMap<u16, oneshot::Sender<u16>>
+    /// message ids wrap, trace IDs keep increasing for life of connection
+    trace_gen: u64,
+    request_map: HashMap<u16, (u64, oneshot::Sender<SendResult>)>,
+    /// messages whose result nobody waits for
+    unconfirmed: HashSet<u16>,
+    /// trace ID of last message sent to node, responses of node are attributed to it
+    node_traces: HashMap<NodeAddress, u64>
 }
 
 pub struct ClientConnection {
@@ -56,17 +192,27 @@ pub struct ClientConnection {
     /// broadcasts server messages
     broadcast: broadcast::Sender<Message>,
     /// broadcasts parsed IOBs
-    iob_broadcast: broadcast::Sender<IOBMessage>
+    iob_broadcast: broadcast::Sender<IOBMessage>,
+    /// broadcasts spontaneous IOBs once more, they aren't held up by bulk traffic
+    spontaneous_broadcast: broadcast::Sender<IOBMessage>,
+    /// when anything was last read from server
+    last_activity: std::sync::Mutex<Instant>,
+    /// serializes exchanges with single node across processes
+    node_locks: std::sync::Mutex<HashMap<NodeAddress, Arc<Mutex<()>>>>
 }
 
 impl ClientConnection {
-    pub fn new() -> Self {
+    /// Spontaneous IOBs are sent to `spontaneous`, which outlives connection
+    pub fn new(spontaneous: broadcast::Sender<IOBMessage>) -> Self {
         let (msg_sender, _) = broadcast::channel::<Message>(128);
         let (iob_sender, _) = broadcast::channel::<IOBMessage>(128);
         ClientConnection {
-            lock: Mutex::new(SharedState { id_gen: 0, request_map: HashMap::new() }),
+            lock: Mutex::new(SharedState { id_gen: 0, trace_gen: 0, request_map: HashMap::new(), unconfirmed: HashSet::new(), node_traces: HashMap::new() }),
             broadcast: msg_sender,
-            iob_broadcast: iob_sender
+            iob_broadcast: iob_sender,
+            spontaneous_broadcast: spontaneous,
+            last_activity: std::sync::Mutex::new(Instant::now()),
+            node_locks: std::sync::Mutex::new(HashMap::new())
         }
     }
 
@@ -77,31 +223,134 @@ impl ClientConnection {
     pub fn subscribe_iob(&self) -> broadcast::Receiver<IOBMessage> {
         self.iob_broadcast.subscribe()
     }
+
+    /// Spontaneous IOBs only, they are in `subscribe_iob()` too
+    pub fn subscribe_spontaneous(&self) -> broadcast::Receiver<IOBMessage> {
+        self.spontaneous_broadcast.subscribe()
+    }
+
+    pub fn last_activity(&self) -> Instant {
+        *self.last_activity.lock().unwrap()
+    }
+
+    /// Wait until no other process talks to node, it's ours until guard is dropped
+    pub async fn lock_node(&self, address: &NodeAddress) -> OwnedMutexGuard<()> {
+        let node_lock = {
+            let mut node_locks = self.node_locks.lock().unwrap();
+            // forget locks nobody holds or waits for
+            node_locks.retain(|_, node_lock| Arc::strong_count(node_lock) > 1);
+            node_locks.entry(*address).or_default().clone()
+        };
+
+        node_lock.lock_owned().await
+    }
 }
 
 pub struct ClientConnectionSender<'a> {
     conn: &'a ClientConnection,
-    guarded_writer: &'a Mutex<WriteHalf<'a>>
+    guarded_writer: &'a Mutex<WriteHalf<'a>>,
+    broadcast: BroadcastConfig,
+    /// only passive messages (link tests and read requests) are sent
+    read_only: bool,
+    /// longer payloads are refused, never above MAX_PAYLOAD
+    max_payload: usize
 }
 
 impl<'a> ClientConnectionSender<'a> {
-    pub fn new(conn: &'a ClientConnection, guarded_writer: &'a Mutex<WriteHalf<'a>>) -> Self {
+    pub fn new(conn: &'a ClientConnection, guarded_writer: &'a Mutex<WriteHalf<'a>>, broadcast: BroadcastConfig, read_only: bool, max_payload: usize) -> Self {
         ClientConnectionSender {
             conn: conn,
-            guarded_writer: guarded_writer
+            guarded_writer: guarded_writer,
+            broadcast: broadcast,
+            read_only: read_only,
+            max_payload: max_payload.min(MAX_PAYLOAD)
         }
     }
 
-    pub async fn send_message(&self, msg: &Message) -> Result<oneshot::Receiver<u16>, Box<dyn std::error::Error>> {
+    pub fn conn(&self) -> &'a ClientConnection {
+        self.conn
+    }
+
+    /// Broadcast or one of configured group addresses, such messages go through `send_broadcast`
+    pub fn is_group_address(&self, address: &NodeAddress) -> bool {
+        self.broadcast.is_group_address(address)
+    }
+
+    /// Longest payload messages may carry, builders split requests to fit
+    pub fn max_payload(&self) -> usize {
+        self.max_payload
+    }
+
+    /// Send message to single node, receiver gets send result from ptlink server
+    pub async fn send_message(&self, msg: &Message) -> Result<oneshot::Receiver<SendResult>, LinkError> {
+        if self.broadcast.is_group_address(&msg.header.address) {
+            return Err(LinkError::Broadcast(format!("{} is group address, send it as broadcast", NodeAddr(msg.header.address))));
+        }
+
+        let mut ss = self.conn.lock.lock().await;
+        let (sender, receiver) = oneshot::channel::<SendResult>();
+
+        let (id, trace) = self.write_message(&mut ss, msg).await?;
+        ss.request_map.insert(id, (trace, sender));
+
+        Ok(receiver)
+    }
+
+    /// Send no-reply message to group address on all ports allowing broadcasts, nobody confirms it
+    pub async fn send_broadcast(&self, msg: &Message) -> Result<(), LinkError> {
+        if !self.broadcast.is_group_address(&msg.header.address) {
+            return Err(LinkError::Broadcast(format!("{} is not group address", NodeAddr(msg.header.address))));
+        }
+
+        if !msg.header.prm() || !matches!(msg.header.fc(), Some(FC::PrmSendNoreply)) {
+            return Err(LinkError::Broadcast("only no-reply messages may be broadcast".to_string()));
+        }
+
+        let ports = match (msg.port, self.broadcast.ports.is_empty()) {
+            (_, true) => vec![msg.port],
+            (ptnet::PORT_AUTO, false) => self.broadcast.ports.clone(),
+            (port, false) if self.broadcast.ports.contains(&port) => vec![port],
+            (port, false) => return Err(LinkError::Broadcast(format!("port {} doesn't allow broadcasts", port)))
+        };
+
         let mut ss = self.conn.lock.lock().await;
 
+        for port in ports {
+            let (id, _) = self.write_message(&mut ss, &Message { port: port, ..msg.clone() }).await?;
+            ss.unconfirmed.insert(id);
+        }
+
+        Ok(())
+    }
+
+    /// Write message to server, returns its id and trace ID
+    async fn write_message(&self, ss: &mut SharedState, msg: &Message) -> Result<(u16, u64), LinkError> {
+        // every transmit path ends here, nothing gets around the check
+        if self.read_only && !is_passive(msg) {
+            let node = NodeAddr(msg.header.address).to_string();
+            debug!(node = node.as_str(); "Refusing message to {} in read-only mode", node);
+            return Err(LinkError::ReadOnly(format!("message to {} isn't link test or read request", node)));
+        }
+
+        // length field would wrap, server would read garbage as next message
+        let payload_length = match u8::try_from(msg.payload.len()) {
+            Ok(length) if usize::from(length) <= self.max_payload => length,
+            _ => return Err(LinkError::PayloadTooLarge(msg.payload.len(), self.max_payload))
+        };
+
         let raw_msg = ptnet::Message {
             id: ss.id_gen,
             iPort: msg.port,
             header: msg.header,
-            payloadLength: msg.payload.len() as u8,
+            payloadLength: payload_length,
         };
-        ss.id_gen += 1;
+        ss.id_gen = ss.id_gen.wrapping_add(1);
+        ss.trace_gen += 1;
+        let trace = ss.trace_gen;
+        ss.node_traces.insert(msg.header.address, trace);
+
+        let node = NodeAddr(msg.header.address).to_string();
+        debug!(trace = trace, msg_id = raw_msg.id, node = node.as_str(); "Sending msgId {} to {} port {}", raw_msg.id, node, msg.port);
 
         let magic_slice: &[u8];
         let msg_slice: &[u8];
@@ -111,8 +360,6 @@ impl<'a> ClientConnectionSender<'a> {
             msg_slice = any_as_u8_slice(&raw_msg);
         }
 
-        let (sender, receiver) = oneshot::channel::<u16>();
-
         {
             let mut writer = self.guarded_writer.lock().await;
 
@@ -121,12 +368,10 @@ impl<'a> ClientConnectionSender<'a> {
             writer.write_all(&msg.payload).await?;
         }
 
-        ss.request_map.insert(raw_msg.id, sender);
-
-        Ok(receiver)
+        Ok((raw_msg.id, trace))
     }
 
-    pub async fn send_prm(&self, fc: FC, address: &[u8; 6], buf: &[u8]) -> Result<oneshot::Receiver<u16>, Box<dyn std::error::Error>> {
+    pub async fn send_prm(&self, fc: FC, address: &[u8; 6], buf: &[u8]) -> Result<oneshot::Receiver<SendResult>, LinkError> {
         let msg = Message {
             port: ptnet::PORT_AUTO,
             header: ptnet::Header {
@@ -142,18 +387,24 @@ impl<'a> ClientConnectionSender<'a> {
 
 pub struct ClientConnectionDispatcher<'a> {
     conn: &'a ClientConnection,
-    reader: &'a mut ReadHalf<'a>
+    reader: &'a mut ReadHalf<'a>,
+    /// frames delivered twice are dispatched once
+    duplicates: DuplicateFilter,
+    /// sequence number of next IOB
+    iob_seq: u64
 }
 
 impl<'a> ClientConnectionDispatcher<'a> {
-    pub fn new(conn: &'a ClientConnection, reader: &'a mut ReadHalf<'a>) -> Self {
+    pub fn new(conn: &'a ClientConnection, reader: &'a mut ReadHalf<'a>, dedup: &DedupConfig) -> Self {
         ClientConnectionDispatcher {
             conn: conn,
-            reader: reader
+            reader: reader,
+            duplicates: DuplicateFilter::new(dedup),
+            iob_seq: 0
         }
     }
 
-    pub async fn dispatch(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn dispatch(&mut self) -> Result<(), PtnetMgrError> {
         loop {
             let mut magic: ptnet::magic_t = 0;
             let mut magic_slice: &mut [u8];
@@ -162,20 +413,18 @@ impl<'a> ClientConnectionDispatcher<'a> {
                 magic_slice = any_as_u8_slice_mut(&mut magic);
             }
 
-            self.reader.read_exact(&mut magic_slice).await?;
+            self.reader.read_exact(&mut magic_slice).await.map_err(LinkError::from)?;
+            *self.conn.last_activity.lock().unwrap() = Instant::now();
 
             match magic {
-                MAGIC_RESULT => self.dispatch_result().await,
-                MAGIC_SERVER_MESSAGE => self.dispatch_server_message().await,
-                x => Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!("Unsupported magic {:#04x}", x)
-                ).into())
-            }?;
+                MAGIC_RESULT => self.dispatch_result().await?,
+                MAGIC_SERVER_MESSAGE => self.dispatch_server_message().await?,
+                x => return Err(ProtocolError::UnsupportedMagic(x as u32).into())
+            };
         }
     }
 
-    async fn dispatch_result(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    async fn dispatch_result(&mut self) -> Result<(), LinkError> {
         let mut result = ptnet::MessageResult { msgId: 0, result: 0 };
         let mut result_slice: &mut [u8];
 
@@ -185,19 +434,29 @@ impl<'a> ClientConnectionDispatcher<'a> {
 
         self.reader.read_exact(&mut result_slice).await?;
 
+        let outcome = SendOutcome::from(result.result);
+        if let SendOutcome::Unknown(code) = outcome {
+            warn!(msg_id = result.msgId; "Unknown result code {} of msgId {}", code, result.msgId);
+        }
+
         {
             let mut ss = self.conn.lock.lock().await;
 
             match ss.request_map.remove(&result.msgId) {
-                Some(sender) => sender.send(result.result).unwrap(),
-                None => warn!("No request_map entry for msgId {}", result.msgId)
+                Some((trace, sender)) => {
+                    debug!(trace = trace, msg_id = result.msgId; "Result of msgId {} is {:?}", result.msgId, outcome);
+                    // requester may have stopped waiting
+                    sender.send(SendResult { trace: trace, outcome: outcome }).unwrap_or_default()
+                },
+                None if ss.unconfirmed.remove(&result.msgId) => (),
+                None => warn!(msg_id = result.msgId; "No request_map entry for msgId {}", result.msgId)
             };
         }
 
         Ok(())
     }
 
-    async fn dispatch_server_message(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    async fn dispatch_server_message(&mut self) -> Result<(), LinkError> {
         let mut raw_msg = ptnet::ServerMessage {
             iPort: 0,
             header: ptnet::Header { C: 0, address: [0; 6] },
@@ -222,27 +481,36 @@ impl<'a> ClientConnectionDispatcher<'a> {
             payload: pay
         };
 
+        if self.duplicates.is_duplicate(&msg.header.address, msg.header.C, &msg.payload, Instant::now()) {
+            debug!(msg = as_serde!(msg); "Dropping duplicate message");
+            return Ok(());
+        }
+
         debug!(msg = as_serde!(msg); "Dispatching message");
 
+        let trace = self.conn.lock.lock().await.node_traces.get(&msg.header.address).copied();
+
         // parse and dispatch IOBs from PRM messages
-        if msg.header.prm() {
-            if let Some(fc) = msg.header.fc() {
-                match fc {
-                    FC::PrmSendConfirm | FC::PrmSendNoreply => {
-                        for item in Scanner::new(&msg.payload[..]).into_iob_iter() {
-                            if let Ok(iob) = item {
-                                self.conn.iob_broadcast.send(IOBMessage {
-                                    message: MessageHeader::from(&msg),
-                                    iob: iob
-                                }).unwrap_or(0); // ignore no-one listening error
-                            } else {
-                                break;
-                            }
-                        }
-                    },
-                    _ => {}
-                }
+        for iob in decode_iobs(&msg) {
+            let class = IOBClass::of(&iob.asdh.cot);
+            let iob_msg = IOBMessage {
+                message: MessageHeader::from(&msg),
+                class: class,
+                trace: trace.filter(|_| class == IOBClass::Response),
+                seq: self.iob_seq,
+                iob: iob
+            };
+            self.iob_seq += 1;
+
+            if let Some(trace) = iob_msg.trace {
+                debug!(trace = trace, ioa = iob_msg.iob.ioa; "Response to IOA {}", iob_msg.iob.ioa);
             }
+
+            // ignore no-one listening error
+            if iob_msg.class == IOBClass::Spontaneous {
+                self.conn.spontaneous_broadcast.send(iob_msg.clone()).unwrap_or(0);
+            }
+            self.conn.iob_broadcast.send(iob_msg).unwrap_or(0);
         }
 
         // ignore no-one listening error
@@ -251,3 +519,50 @@ impl<'a> ClientConnectionDispatcher<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ptnet::{PtNetPacket, ASDHConstruct, DUIConstruct};
+
+    use super::*;
+
+    #[test]
+    fn send_outcome_of_code() {
+        assert_eq!(SendOutcome::Delivered, SendOutcome::from(MessageResultCode::Delivered as u16));
+        assert_eq!(SendOutcome::LinkDown, SendOutcome::from(MessageResultCode::LinkDown as u16));
+        assert_eq!(SendOutcome::Unknown(0xFFFF), SendOutcome::from(0xFFFF));
+    }
+
+    #[test]
+    fn group_addresses() {
+        let conf = BroadcastConfig { groups: vec![[0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x01]], ..Default::default() };
+
+        assert!(conf.is_group_address(&BROADCAST_ADDRESS));
+        assert!(conf.is_group_address(&[0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x01]));
+        assert!(!conf.is_group_address(&[0x01, 0x02, 0x03, 0x0A, 0x0B, 0xFF]), "odd first octet is unicast");
+        assert!(!is_group_address(&[0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x01]), "configured groups need configuration");
+    }
+
+    #[test]
+    fn passive_messages() {
+        let message = |fc: u8, cot: COT| {
+            let mut buf = packet::buffer::Dynamic::new();
+            PtNetPacket::with_asdh(&ptnet::ASDH::with(0x3E, cot, false), &mut buf).unwrap()
+                .begin_asdu(&ptnet::DUI::with_direct(ptnet::TC_C_RD, 1, false)).unwrap()
+                .add_ioa(0).unwrap()
+                .end_asdu().unwrap();
+
+            Message {
+                port: ptnet::PORT_AUTO,
+                header: ptnet::Header { C: ptnet::BIT_PRM as u8 | fc, address: [0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0xEF] },
+                payload: buf.into()
+            }
+        };
+
+        assert!(is_passive(&Message { payload: Vec::new(), ..message(ptnet::FC_PRM_LINK_TEST as u8, COT::REQ) }));
+        assert!(is_passive(&message(FC::PrmSendNoreply as u8, COT::REQ)));
+        assert!(!is_passive(&message(FC::PrmSendNoreply as u8, COT::ACT)));
+        assert!(!is_passive(&message(FC::PrmSendConfirm as u8, COT::REQ)));
+        assert!(!is_passive(&Message { payload: Vec::new(), ..message(FC::PrmSendNoreply as u8, COT::REQ) }));
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+
+use serde::{Serialize, Deserialize};
+
+use crate::database::node_table::NodeRecord;
+
+/// Common address of all profiles unless configured otherwise
+pub const DEFAULT_CA: u8 = 0x3E;
+
+/// Common addresses of device profiles node implements
+#[derive(Debug,Clone,Copy,Serialize,Deserialize,PartialEq)]
+pub struct ProfileAddresses {
+    /// system information: device status, descriptor, firmware update
+    pub system: u8,
+    /// commands, setpoints, parameters and group membership
+    pub control: u8,
+    /// energy metering
+    pub metering: u8
+}
+
+/// Addresses of node type differing from global ones
+#[derive(Debug,Clone,Default,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct ProfileOverrides {
+    pub system: Option<u8>,
+    pub control: Option<u8>,
+    pub metering: Option<u8>
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct CommonAddressConfig {
+    pub system: u8,
+    pub control: u8,
+    pub metering: u8,
+    /// by node type of SOL model, e.g. product line using other addresses
+    pub node_types: HashMap<String, ProfileOverrides>
+}
+
+impl Default for CommonAddressConfig {
+    fn default() -> Self {
+        Self {
+            system: DEFAULT_CA,
+            control: DEFAULT_CA,
+            metering: DEFAULT_CA,
+            node_types: HashMap::new()
+        }
+    }
+}
+
+impl CommonAddressConfig {
+    /// Addresses of node type, global ones if type is unknown
+    pub fn of_type(&self, node_type: Option<&str>) -> ProfileAddresses {
+        let overrides = node_type.and_then(|node_type| self.node_types.get(node_type));
+
+        ProfileAddresses {
+            system: overrides.and_then(|o| o.system).unwrap_or(self.system),
+            control: overrides.and_then(|o| o.control).unwrap_or(self.control),
+            metering: overrides.and_then(|o| o.metering).unwrap_or(self.metering)
+        }
+    }
+
+    pub fn of_node(&self, node: Option<&NodeRecord>) -> ProfileAddresses {
+        self.of_type(node.and_then(|node| node.model.as_ref()).map(|model| model.type_id.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_type_overrides() {
+        let mut conf = CommonAddressConfig { metering: 0x40, ..Default::default() };
+        conf.node_types.insert("sensor".to_string(), ProfileOverrides { system: Some(0x10), ..Default::default() });
+
+        assert_eq!(ProfileAddresses { system: 0x3E, control: 0x3E, metering: 0x40 }, conf.of_type(None));
+        assert_eq!(ProfileAddresses { system: 0x3E, control: 0x3E, metering: 0x40 }, conf.of_type(Some("ballast")));
+        assert_eq!(ProfileAddresses { system: 0x10, control: 0x3E, metering: 0x40 }, conf.of_type(Some("sensor")));
+    }
+}
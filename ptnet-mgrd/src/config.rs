@@ -0,0 +1,454 @@
+use std::{fmt, fs, net::SocketAddr, path::Path, str::FromStr};
+
+use crate::{Configuration, NodeModelSource, ptnet_process::ProcessRegistry, client_connection::MAX_PAYLOAD, profile::{self, Profile}, database::storage::StorageBackend};
+
+/// Format of configuration file, chosen by its extension
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml
+}
+
+impl ConfigFormat {
+    /// JSON unless file ends with `.toml`, `.yaml` or `.yml`
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()).map(str::to_lowercase).as_deref() {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Json
+        }
+    }
+}
+
+/// Problems found in configuration, one actionable message each
+#[derive(Debug,Clone,PartialEq)]
+pub struct ConfigErrors(pub Vec<String>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.join("; "))
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+/// Parsed configuration with keys the daemon doesn't know
+pub struct LoadedConfig {
+    pub conf: Configuration,
+    /// paths of ignored keys, e.g. `watchdog.dead_afer`
+    pub unknown_keys: Vec<String>
+}
+
+/// Prefix of environment variables overriding configuration keys
+pub const ENV_PREFIX: &str = "PTNET_MGR_";
+
+/// Variables with prefix which aren't configuration keys
+const ENV_IGNORED: &[&str] = &[
+    // bearer token of ptnet-mgr-ctl
+    "PTNET_MGR_TOKEN"
+];
+
+/// Configuration layers above file, lowest first; kept by reloader so reloaded file doesn't drop them
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct Overrides(Vec<serde_json::Value>);
+
+/// Object with value at nested key path
+fn nested(path: &[&str], value: serde_json::Value) -> serde_json::Value {
+    path.iter().rev().fold(value, |value, key| {
+        let mut object = serde_json::Map::new();
+        object.insert(key.to_string(), value);
+        serde_json::Value::Object(object)
+    })
+}
+
+/// Prefix of override value given as JSON, e.g. `json:[1, 2]`
+const JSON_PREFIX: &str = "json:";
+
+/// Value given in variable or flag, string unless it has JSON prefix; strings are coerced by `coerce` once
+/// type of target key is known
+fn override_value(raw: &str) -> Result<serde_json::Value, String> {
+    match raw.strip_prefix(JSON_PREFIX) {
+        Some(json) => serde_json::from_str(json).map_err(|err| format!("'{}' is not JSON ({})", json, err)),
+        None => Ok(serde_json::Value::String(raw.to_string()))
+    }
+}
+
+/// Parse string leaves of `value` as JSON where the same key of `template` isn't a string, so
+/// `t_reconnect=10` is a number while `mqtt.password=12345` stays a string. Keys missing in template
+/// or null there, e.g. unset options, keep strings.
+pub fn coerce(value: serde_json::Value, template: &serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(object) => serde_json::Value::Object(object.into_iter().map(|(key, value)| {
+            let value = match template.get(&key) {
+                Some(template) => coerce(value, template),
+                None => value
+            };
+            (key, value)
+        }).collect()),
+        serde_json::Value::String(raw) => match template {
+            serde_json::Value::String(_) | serde_json::Value::Null => serde_json::Value::String(raw),
+            _ => serde_json::from_str(&raw).unwrap_or(serde_json::Value::String(raw))
+        },
+        value => value
+    }
+}
+
+/// Deserialize process section, string overrides are coerced by types of process defaults
+pub fn params<T: serde::Serialize + serde::de::DeserializeOwned + Default>(params: serde_json::Value) -> Result<T, serde_json::Error> {
+    let template = serde_json::to_value(T::default())?;
+    serde_json::from_value(coerce(params, &template))
+}
+
+impl Overrides {
+    /// Collect overrides from environment variables and `--set` flags. Variable `PTNET_MGR_SERVER_ADDRESS`
+    /// sets `server_address`, `__` separates nested keys, e.g. `PTNET_MGR_PROCESSES__NODESCAN__PERIOD`.
+    /// Flag `--set processes.nodescan.period=30` uses dotted path. Flags override variables. Values are
+    /// strings converted to type of the key, prefix `json:` gives JSON value, e.g. `json:[1, 2]`.
+    pub fn collect(vars: impl Iterator<Item = (String, String)>, sets: &[String]) -> Result<Self, ConfigErrors> {
+        let mut errors = Vec::new();
+        let mut env = serde_json::json!({});
+        let mut cli = serde_json::json!({});
+
+        let mut vars: Vec<(String, String)> = vars.filter(|(name, _)| name.starts_with(ENV_PREFIX) && !ENV_IGNORED.contains(&name.as_str())).collect();
+        vars.sort();
+        for (name, raw) in vars {
+            let key = name[ENV_PREFIX.len()..].to_lowercase();
+            let path: Vec<&str> = key.split("__").collect();
+            match path.iter().any(|key| key.is_empty()) {
+                true => errors.push(format!("{}: not a configuration key, separate nested keys by '__'", name)),
+                false => match override_value(&raw) {
+                    Ok(value) => profile::merge(&mut env, nested(&path, value)),
+                    Err(err) => errors.push(format!("{}: {}", name, err))
+                }
+            }
+        }
+
+        for set in sets {
+            let (key, raw) = match set.split_once('=') {
+                Some(pair) => pair,
+                None => {
+                    errors.push(format!("--set {}: use key=value, e.g. processes.nodescan.period=30", set));
+                    continue;
+                }
+            };
+            let path: Vec<&str> = key.trim().split('.').collect();
+            match path.iter().any(|key| key.is_empty()) {
+                true => errors.push(format!("--set {}: '{}' is not a configuration key", set, key)),
+                false => match override_value(raw) {
+                    Ok(value) => profile::merge(&mut cli, nested(&path, value)),
+                    Err(err) => errors.push(format!("--set {}: {}", set, err))
+                }
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(Overrides(vec![env, cli].into_iter().filter(|layer| layer.as_object().map_or(false, |obj| !obj.is_empty())).collect())),
+            false => Err(ConfigErrors(errors))
+        }
+    }
+}
+
+/// Layer configuration sources: defaults < profile < file < environment variables < `--set` flags.
+/// Keys of process sections are passed to processes as is.
+pub fn load(path: Option<&Path>, overrides: &Overrides) -> Result<LoadedConfig, Box<dyn std::error::Error>> {
+    let text = match path {
+        Some(path) => Some(fs::read_to_string(path)?),
+        None => None
+    };
+    let mut unknown_keys = Vec::new();
+    let unknown = |key: serde_ignored::Path| unknown_keys.push(key.to_string());
+
+    let mut value: serde_json::Value = match (path, &text) {
+        (Some(path), Some(text)) => match ConfigFormat::of(path) {
+            ConfigFormat::Json => serde_json::from_str(text)?,
+            ConfigFormat::Toml => toml::from_str(text)?,
+            ConfigFormat::Yaml => serde_yaml::from_str(text)?
+        },
+        _ => serde_json::json!({})
+    };
+    // string overrides take types of keys in defaults and file
+    let mut template = serde_json::to_value(Configuration::default())?;
+    profile::merge(&mut template, value.clone());
+    for layer in overrides.0.iter() {
+        profile::merge(&mut value, coerce(layer.clone(), &template));
+    }
+
+    let conf = match (value.get("profile"), path, &text) {
+        (Some(profile), _, _) => {
+            let profile: Profile = serde_json::from_value(profile.clone()).map_err(|err| format!("profile: {}", err))?;
+            let mut merged = profile.defaults();
+            profile::merge(&mut merged, value);
+            serde_ignored::deserialize(merged, unknown)?
+        },
+        // merged keys lose positions, plain file is parsed directly so errors point at lines
+        (None, Some(path), Some(text)) if overrides.0.is_empty() => match ConfigFormat::of(path) {
+            ConfigFormat::Json => serde_ignored::deserialize(&mut serde_json::Deserializer::from_str(text), unknown)?,
+            ConfigFormat::Toml => serde_ignored::deserialize(toml::Deserializer::new(text), unknown)?,
+            ConfigFormat::Yaml => serde_ignored::deserialize(serde_yaml::Deserializer::from_str(text), unknown)?
+        },
+        _ => serde_ignored::deserialize(value, unknown)?
+    };
+
+    Ok(LoadedConfig {
+        conf: conf,
+        unknown_keys: unknown_keys
+    })
+}
+
+fn check_address(errors: &mut Vec<String>, key: &str, address: &str) {
+    if SocketAddr::from_str(address).is_err() {
+        errors.push(format!("{}: '{}' is not an address, use IP and port, e.g. 127.0.0.1:9885", key, address));
+    }
+}
+
+fn check_range(errors: &mut Vec<String>, key: &str, value: u64, min: u64, max: u64, unit: &str) {
+    if value < min || value > max {
+        errors.push(format!("{}: {} {} is out of range, use {} to {} {}", key, value, unit, min, max, unit));
+    }
+}
+
+/// Site IDs are part of MQTT topics
+fn check_site(errors: &mut Vec<String>, key: &str, site: &str) {
+    if site.is_empty() || site.contains(|c| c == '/' || c == '+' || c == '#') {
+        errors.push(format!("{}: '{}' can't be part of MQTT topic, use non-empty ID without '/', '+' and '#'", key, site));
+    }
+}
+
+fn check_level(errors: &mut Vec<String>, key: &str, level: &str) {
+    if log::LevelFilter::from_str(level).is_err() {
+        errors.push(format!("{}: unknown log level '{}', use off, error, warn, info, debug or trace", key, level));
+    }
+}
+
+/// Check values serde can't, all problems are reported at once
+pub fn validate(conf: &Configuration) -> Result<(), ConfigErrors> {
+    let mut errors = Vec::new();
+    let registry = ProcessRegistry::builtin();
+
+    check_address(&mut errors, "server_address", &conf.server_address);
+    check_range(&mut errors, "t_reconnect", conf.t_reconnect, 1, 3600, "s");
+
+    check_level(&mut errors, "log.level", &conf.log.level);
+    for (module, level) in &conf.log.modules {
+        check_level(&mut errors, &format!("log.modules.{}", module), level);
+    }
+
+    check_range(&mut errors, "max_payload", conf.max_payload as u64, 16, MAX_PAYLOAD as u64, "bytes");
+
+    if cfg!(not(feature = "sqlite")) && conf.storage == StorageBackend::Sqlite {
+        errors.push("storage: sqlite needs daemon built with feature sqlite, use redb".to_string());
+    }
+
+    check_range(&mut errors, "command_timeouts.confirm", conf.command_timeouts.confirm, 100, 600_000, "ms");
+    check_range(&mut errors, "command_timeouts.terminate", conf.command_timeouts.terminate, 0, 3_600_000, "ms");
+
+    check_range(&mut errors, "restart.initial_backoff", conf.restart.initial_backoff, 1, 3_600_000, "ms");
+    check_range(&mut errors, "restart.max_backoff", conf.restart.max_backoff, conf.restart.initial_backoff, 3_600_000, "ms");
+    check_range(&mut errors, "restart.stable_after", conf.restart.stable_after, 1, 86400, "s");
+
+    check_range(&mut errors, "watchdog.check", conf.watchdog.check, 1, 3600, "s");
+    check_range(&mut errors, "watchdog.probe_after", conf.watchdog.probe_after, conf.watchdog.check, 86400, "s");
+    check_range(&mut errors, "watchdog.dead_after", conf.watchdog.dead_after, conf.watchdog.probe_after, 86400, "s");
+
+    check_range(&mut errors, "dedup.window", conf.dedup.window, 0, 60_000, "ms");
+    if conf.dedup.window > 0 && conf.dedup.capacity == 0 {
+        errors.push("dedup.capacity: 0 would remember no frame, use at least 1 or set dedup.window to 0".to_string());
+    }
+
+    if conf.fwu_limits.max_concurrent == 0 {
+        errors.push("fwu_limits.max_concurrent: 0 would never start firmware update, use at least 1".to_string());
+    }
+    if conf.fwu_limits.max_concurrent_per_port == 0 {
+        errors.push("fwu_limits.max_concurrent_per_port: 0 would never start firmware update, use at least 1".to_string());
+    }
+
+    check_range(&mut errors, "bandwidth.window", conf.bandwidth.window, 1, 7 * 86400, "s");
+    let mut budgets: Vec<(&String, &u64)> = conf.bandwidth.budgets.iter().collect();
+    budgets.sort();
+    for (name, budget) in budgets {
+        if !registry.names().any(|n| n == name) {
+            errors.push(format!("bandwidth.budgets.{}: unknown process, known are {}", name, registry.names().collect::<Vec<_>>().join(", ")));
+        } else if *budget == 0 {
+            errors.push(format!("bandwidth.budgets.{}: 0 would stop process from sending, pause process instead", name));
+        }
+    }
+
+    if !(0.0..=100.0).contains(&conf.orphan_limit) {
+        errors.push(format!("orphan_limit: {} is out of range, use 0 to 100 %", conf.orphan_limit));
+    }
+
+    check_range(&mut errors, "maintenance.check_interval", conf.maintenance.check_interval, 60, 86400, "s");
+    if !(0.0..=100.0).contains(&conf.maintenance.fragmentation) {
+        errors.push(format!("maintenance.fragmentation: {} is out of range, use 0 to 100 %", conf.maintenance.fragmentation));
+    }
+
+    if let Some(repo) = &conf.firmware_repository {
+        if conf.firmware_path.is_none() {
+            errors.push("firmware_repository: needs firmware_path to cache images in".to_string());
+        }
+        if !repo.url.starts_with("https://") {
+            errors.push(format!("firmware_repository.url: '{}' is not HTTPS URL", repo.url));
+        }
+        check_range(&mut errors, "firmware_repository.period", repo.period, 60, 7 * 86400, "s");
+        check_range(&mut errors, "firmware_repository.timeout", repo.timeout, 1, 3600, "s");
+    }
+
+    if let Some(state) = &conf.sol_state {
+        if state.file.is_none() && conf.node_model_source == NodeModelSource::None {
+            errors.push("sol_state.file: needs to be set when node_model_source is None".to_string());
+        }
+        check_range(&mut errors, "sol_state.min_interval", state.min_interval, 1, 3600, "s");
+    }
+
+    if let Some(http) = &conf.http {
+        check_address(&mut errors, "http.bind", &http.bind);
+        check_range(&mut errors, "http.request_timeout", http.request_timeout, 1, 3600, "s");
+    }
+
+    if let Some(mqtt) = &conf.mqtt {
+        if mqtt.port == 0 {
+            errors.push("mqtt.port: 0 is not a port, broker usually listens on 1883".to_string());
+        }
+        check_range(&mut errors, "mqtt.keep_alive", mqtt.keep_alive, 5, 3600, "s");
+        check_range(&mut errors, "mqtt.t_reconnect", mqtt.t_reconnect, 1, 3600, "s");
+        check_range(&mut errors, "mqtt.command_timeout", mqtt.command_timeout, 1, 3600, "s");
+        if let Some(site) = &mqtt.site {
+            check_site(&mut errors, "mqtt.site", site);
+        }
+    }
+
+    check_site(&mut errors, "site.id", &conf.site.id);
+    if conf.site.labels.keys().any(|key| key.is_empty() || key.contains(',') || key.contains('=')) {
+        errors.push("site.labels: label keys must be non-empty and contain no ',' or '='".to_string());
+    }
+    if let Some(location) = &conf.site.location {
+        if !(-90.0..=90.0).contains(&location.latitude) || !(-180.0..=180.0).contains(&location.longitude) {
+            errors.push(format!("site.location: {}, {} is not on Earth, use latitude -90 to 90 and longitude -180 to 180 degrees", location.latitude, location.longitude));
+        }
+    }
+
+    check_range(&mut errors, "journal.max_entries", conf.journal.max_entries as u64, 100, 10_000_000, "entries");
+
+    if let Some(control) = &conf.control {
+        check_range(&mut errors, "control.request_timeout", control.request_timeout, 1, 3600, "s");
+        if control.mode > 0o777 {
+            errors.push(format!("control.mode: {} is not file permission, use at most 511 (0o777), e.g. 432 for 0o660", control.mode));
+        }
+    }
+
+    for (i, webhook) in conf.webhooks.iter().enumerate() {
+        if conf.webhooks[..i].iter().any(|other| other.name == webhook.name) {
+            errors.push(format!("webhooks.{}: name is used by other webhook, their journal positions would mix", webhook.name));
+        }
+        if !webhook.url.starts_with("https://") && !webhook.url.starts_with("http://") {
+            errors.push(format!("webhooks.{}.url: '{}' is not HTTP URL", webhook.name, webhook.url));
+        }
+        if webhook.events.is_empty() {
+            errors.push(format!("webhooks.{}.events: no events, webhook would never be notified", webhook.name));
+        }
+        check_range(&mut errors, &format!("webhooks.{}.retries", webhook.name), webhook.retries as u64, 0, 100, "retries");
+        check_range(&mut errors, &format!("webhooks.{}.backoff", webhook.name), webhook.backoff, 1, 3600, "s");
+        check_range(&mut errors, &format!("webhooks.{}.timeout", webhook.name), webhook.timeout, 1, 3600, "s");
+    }
+
+    let mut names: Vec<&String> = conf.processes.keys().filter(|name| !registry.knows(name)).collect();
+    names.sort();
+    for name in names {
+        errors.push(format!("processes.{}: unknown process, known are {}", name, registry.sections().collect::<Vec<_>>().join(", ")));
+    }
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(ConfigErrors(errors))
+    }
+}
+
+/// Load and validate configuration, reporting unknown keys as errors too
+pub fn check(path: Option<&Path>, overrides: &Overrides) -> Result<(), ConfigErrors> {
+    let source = path.map_or("overrides".to_string(), |path| path.display().to_string());
+    let loaded = load(path, overrides).map_err(|err| ConfigErrors(vec![format!("{}: {}", source, err)]))?;
+
+    let mut errors: Vec<String> = loaded.unknown_keys.iter()
+        .map(|key| format!("{}: unknown key, check spelling and nesting", key))
+        .collect();
+
+    if let Err(ConfigErrors(invalid)) = validate(&loaded.conf) {
+        errors.extend(invalid);
+    }
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(ConfigErrors(errors))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ptnet_process::NodeScanConfig;
+
+    #[test]
+    fn formats_and_validation() {
+        let dir = std::env::temp_dir().join(format!("ptnet-mgrd-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let toml_path = dir.join("ptnet-mgrd.toml");
+        fs::write(&toml_path, "server_address = \"10.0.0.1:9885\"\nt_reconect = 5\n[watchdog]\ncheck = 5\n").unwrap();
+        let loaded = load(Some(toml_path.as_path()), &Overrides::default()).unwrap();
+        assert_eq!("10.0.0.1:9885", loaded.conf.server_address);
+        assert_eq!(5, loaded.conf.watchdog.check);
+        assert_eq!(vec!["t_reconect".to_string()], loaded.unknown_keys);
+
+        let yaml_path = dir.join("ptnet-mgrd.yml");
+        fs::write(&yaml_path, "server_address: nowhere\nt_reconnect: 0\nprocesses:\n  nodescn: {}\n").unwrap();
+        let errors = check(Some(yaml_path.as_path()), &Overrides::default()).unwrap_err().0;
+        assert_eq!(3, errors.len());
+        assert!(errors[0].starts_with("server_address:"));
+        assert!(errors[1].starts_with("t_reconnect:"));
+        assert!(errors[2].starts_with("processes.nodescn:"));
+
+        let profile_path = dir.join("ptnet-mgrd-profile.toml");
+        fs::write(&profile_path, "profile = \"street-lighting\"\n[fwu_limits]\nmax_concurrent = 3\n").unwrap();
+        let loaded = load(Some(profile_path.as_path()), &Overrides::default()).unwrap();
+        assert_eq!(Some(Profile::StreetLighting), loaded.conf.profile);
+        assert_eq!((3, 1), (loaded.conf.fwu_limits.max_concurrent, loaded.conf.fwu_limits.max_concurrent_per_port), "explicit key overrides profile");
+        assert!(loaded.unknown_keys.is_empty());
+        assert!(validate(&loaded.conf).is_ok());
+
+        fs::write(&profile_path, "profile = \"stadium\"\n").unwrap();
+        assert!(load(Some(profile_path.as_path()), &Overrides::default()).is_err());
+
+        let env = vec![
+            ("PTNET_MGR_SERVER_ADDRESS".to_string(), "10.0.0.2:9885".to_string()),
+            ("PTNET_MGR_PROCESSES__NODESCAN__PERIOD".to_string(), "20".to_string()),
+            ("PTNET_MGR_FWU_LIMITS__MAX_CONCURRENT".to_string(), "5".to_string()),
+            ("PTNET_MGR_MQTT__PASSWORD".to_string(), "12345".to_string()),
+            ("PTNET_MGR_SITE__ID".to_string(), "1234".to_string()),
+            ("PTNET_MGR_TOKEN".to_string(), "secret".to_string()),
+            ("HOME".to_string(), "/root".to_string())
+        ];
+        let overrides = Overrides::collect(env.into_iter(), &["fwu_limits.max_concurrent=6".to_string()]).unwrap();
+        fs::write(&profile_path, "profile = \"street-lighting\"\nserver_address = \"10.0.0.1:9885\"\n[fwu_limits]\nmax_concurrent = 3\n").unwrap();
+        let loaded = load(Some(profile_path.as_path()), &overrides).unwrap();
+        assert_eq!("10.0.0.2:9885", loaded.conf.server_address, "variable overrides file");
+        assert_eq!(6, loaded.conf.fwu_limits.max_concurrent, "flag overrides variable");
+        assert_eq!(1, loaded.conf.fwu_limits.max_concurrent_per_port, "profile fills rest");
+        assert_eq!(Some("12345"), loaded.conf.mqtt.as_ref().and_then(|mqtt| mqtt.password.as_deref()), "string key keeps number-like value");
+        assert_eq!("1234", loaded.conf.site.id);
+        assert_eq!(Some(&serde_json::json!("20")), loaded.conf.processes["nodescan"].params.get("period"));
+        let nodescan: NodeScanConfig = params(serde_json::Value::Object(loaded.conf.processes["nodescan"].params.clone())).unwrap();
+        assert_eq!(20, nodescan.period, "process takes type from its defaults");
+        assert!(loaded.unknown_keys.is_empty(), "token of ptnet-mgr-ctl isn't a key");
+
+        let loaded = load(None, &overrides).unwrap();
+        assert_eq!("10.0.0.2:9885", loaded.conf.server_address, "overrides apply without file");
+        assert!(Overrides::collect(std::iter::empty(), &["t_reconnect".to_string()]).is_err());
+        assert!(Overrides::collect(std::iter::empty(), &["t_reconnect=json:ten".to_string()]).is_err());
+        let overrides = Overrides::collect(std::iter::empty(), &["mqtt.port=json:1884".to_string()]).unwrap();
+        assert_eq!(Some(1884), load(None, &overrides).unwrap().conf.mqtt.map(|mqtt| mqtt.port), "unset section takes explicit JSON");
+
+        assert_eq!(ConfigFormat::Json, ConfigFormat::of(Path::new("/etc/ptnet-mgrd.json")));
+        fs::remove_dir_all(&dir).unwrap_or_default();
+    }
+}
@@ -0,0 +1,644 @@
+use std::{fs, io, os::unix::fs::PermissionsExt, path::Path, sync::Arc, time::Duration};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use log::{debug, info, warn};
+use ptnet::image_header::{FWVersion, HWVersion};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::{UnixListener, UnixStream}, sync::mpsc};
+
+use crate::{logging, error::{self, DbError}, fw_index::FirmwareIndex, database::{Database, NodeAddress, NodeAddr, group_table::GroupId, schedule_table::ScheduleId, em_test_table::{FUNCTION_TEST_DAYS, DURATION_TEST_DAYS}}, management::{Management, ScheduleSpec}, site::{Labels, LabelFilter, Tags}, sol::{self, sync::SyncSettings}, ptnet_process::{ApiRequest, Reply, ReadTarget, SubmitError, submit}};
+
+/// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+const NOT_FOUND: i64 = -32001;
+const CONNECTION_ERROR: i64 = -32002;
+const CONFLICT: i64 = -32003;
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct ControlConfig {
+    /// path of unix socket
+    pub path: String,
+    /// socket file permissions, only users allowed to write the socket can connect
+    pub mode: u32,
+    /// how long to wait for requests executed on ptlink connection (seconds)
+    pub request_timeout: u64
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            path: "/run/ptnet-mgrd.sock".to_string(),
+            mode: 0o660,
+            request_timeout: 60
+        }
+    }
+}
+
+#[derive(Debug,Deserialize)]
+struct RpcRequest {
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value
+}
+
+#[derive(Debug,Serialize)]
+struct RpcError {
+    code: i64,
+    message: String
+}
+
+#[derive(Debug,Serialize)]
+struct RpcResponse {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError { code: code, message: message.into() }
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for RpcError {
+    fn from(err: Box<dyn std::error::Error>) -> Self {
+        let code = match error::kind_of(&*err) {
+            Some(io::ErrorKind::NotFound) => NOT_FOUND,
+            Some(io::ErrorKind::InvalidInput) | Some(io::ErrorKind::InvalidData) => INVALID_PARAMS,
+            Some(io::ErrorKind::AlreadyExists) => CONFLICT,
+            _ => SERVER_ERROR
+        };
+
+        RpcError::new(code, err.to_string())
+    }
+}
+
+impl From<io::Error> for RpcError {
+    fn from(err: io::Error) -> Self {
+        RpcError::from(Box::new(err) as Box<dyn std::error::Error>)
+    }
+}
+
+impl From<DbError> for RpcError {
+    fn from(err: DbError) -> Self {
+        RpcError::from(Box::new(err) as Box<dyn std::error::Error>)
+    }
+}
+
+impl From<SubmitError> for RpcError {
+    fn from(err: SubmitError) -> Self {
+        RpcError::new(CONNECTION_ERROR, err.to_string())
+    }
+}
+
+#[derive(Debug,Deserialize)]
+struct AddressParams {
+    address: String
+}
+
+#[derive(Debug,Deserialize)]
+struct ReplaceParams {
+    address: String,
+    new: String,
+    #[serde(default)]
+    push_parameters: bool
+}
+
+#[derive(Debug,Deserialize)]
+struct ApproveParams {
+    address: String,
+    version: FWVersion
+}
+
+#[derive(Debug,Deserialize)]
+struct UploadParams {
+    /// base64 firmware image
+    image: String
+}
+
+#[derive(Debug,Deserialize)]
+struct FirmwareParams {
+    hw_version: HWVersion,
+    fw_version: FWVersion
+}
+
+#[derive(Debug,Deserialize)]
+struct LogLevelParams {
+    /// module path prefix, default level if not set
+    module: Option<String>,
+    level: String
+}
+
+#[derive(Debug,Deserialize)]
+struct CommandParams {
+    address: String,
+    ioa: u32,
+    ti: u8,
+    value: u32,
+    /// select with this value before executing
+    select: Option<u32>
+}
+
+#[derive(Debug,Deserialize)]
+struct BulkCommandParams {
+    /// nodes to command, or
+    addresses: Option<Vec<String>>,
+    /// nodes with these labels, `key=value[,key=value...]`
+    label: Option<String>,
+    ioa: u32,
+    ti: u8,
+    value: u32,
+    select: Option<u32>
+}
+
+#[derive(Debug,Deserialize)]
+struct ParameterParams {
+    address: String,
+    ioa: u32,
+    /// not needed for removal
+    ti: Option<u8>,
+    value: Option<u32>
+}
+
+#[derive(Debug,Deserialize,Default)]
+struct ListNodesParams {
+    /// `key=value[,key=value...]`, only nodes with all these labels
+    label: Option<String>
+}
+
+#[derive(Debug,Deserialize)]
+struct LabelsParams {
+    address: String,
+    labels: Labels
+}
+
+#[derive(Debug,Deserialize)]
+struct TagsParams {
+    address: String,
+    tags: Tags
+}
+
+#[derive(Debug,Deserialize)]
+struct TagParams {
+    tag: String,
+    /// nodes to tag, or
+    addresses: Option<Vec<String>>,
+    /// nodes with these labels, `key=value[,key=value...]`, not needed for removal
+    label: Option<String>
+}
+
+#[derive(Debug,Deserialize)]
+struct KeyParams {
+    key: String
+}
+
+#[derive(Debug,Deserialize)]
+struct ProcessParams {
+    name: String,
+    /// only for pausing
+    reason: Option<String>
+}
+
+#[derive(Debug,Deserialize,Default)]
+struct EmReportParams {
+    /// emergency ballasts, never tested ones included, all tested nodes if not given
+    label: Option<String>,
+    function_days: Option<u64>,
+    duration_days: Option<u64>
+}
+
+#[derive(Debug,Deserialize,Default)]
+struct ReportParams {
+    /// `json` (default) or `csv`, CSV is returned as string
+    format: Option<String>
+}
+
+#[derive(Debug,Deserialize)]
+struct ScheduleParams {
+    id: ScheduleId
+}
+
+#[derive(Debug,Deserialize)]
+struct UpdateScheduleParams {
+    id: ScheduleId,
+    #[serde(flatten)]
+    spec: ScheduleSpec
+}
+
+#[derive(Debug,Deserialize)]
+struct GroupMemberParams {
+    group: GroupId,
+    address: String
+}
+
+#[derive(Debug,Deserialize)]
+struct ReadParams {
+    /// node to read, or
+    address: Option<String>,
+    /// all members of group
+    group: Option<GroupId>,
+    #[serde(flatten)]
+    target: ReadTarget
+}
+
+fn params<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))
+}
+
+fn parse_address(address: &str) -> Result<NodeAddress, RpcError> {
+    address.parse::<NodeAddr>()
+        .map(|addr| addr.0)
+        .map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))
+}
+
+fn to_value<T: Serialize>(value: T) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|err| RpcError::new(SERVER_ERROR, err.to_string()))
+}
+
+/// Control interface on unix socket, same operations as HTTP API
+pub struct ControlServer {
+    conf: ControlConfig,
+    db: &'static Database<'static>,
+    fw_index: Option<&'static FirmwareIndex>,
+    sync_settings: Option<SyncSettings>,
+    requests: mpsc::Sender<ApiRequest>
+}
+
+impl ControlServer {
+    pub fn new(conf: ControlConfig, db: &'static Database<'static>, fw_index: Option<&'static FirmwareIndex>, sync_settings: Option<SyncSettings>, requests: mpsc::Sender<ApiRequest>) -> Self {
+        ControlServer {
+            conf: conf,
+            db: db,
+            fw_index: fw_index,
+            sync_settings: sync_settings,
+            requests: requests
+        }
+    }
+
+    fn fw_index(&self) -> Result<&'static FirmwareIndex, RpcError> {
+        self.fw_index.ok_or_else(|| RpcError::new(NOT_FOUND, "Firmware updates are disabled"))
+    }
+
+    fn sync_settings(&self) -> Result<&SyncSettings, RpcError> {
+        self.sync_settings.as_ref().ok_or_else(|| RpcError::new(NOT_FOUND, "No SOL model configured"))
+    }
+
+    async fn submit<T, F>(&self, make_request: F) -> Result<T, RpcError>
+    where
+        F: FnOnce(Reply<T>) -> ApiRequest
+    {
+        self.submit_within(Duration::from_secs(self.conf.request_timeout), make_request).await
+    }
+
+    async fn submit_within<T, F>(&self, wait: Duration, make_request: F) -> Result<T, RpcError>
+    where
+        F: FnOnce(Reply<T>) -> ApiRequest
+    {
+        Ok(submit(&self.requests, wait, make_request).await?)
+    }
+
+    async fn call(&self, method: &str, p: Value) -> Result<Value, RpcError> {
+        match method {
+            "list_nodes" => {
+                let p: ListNodesParams = match p {
+                    Value::Null => Default::default(),
+                    p => params(p)?
+                };
+                let labels = match &p.label {
+                    Some(label) => label.parse::<LabelFilter>().map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))?,
+                    None => LabelFilter::default()
+                };
+                to_value(self.db.query_nodes(&labels)?)
+            },
+            "set_labels" => {
+                let p: LabelsParams = params(p)?;
+                Management::new(self.db).set_labels(&parse_address(&p.address)?, p.labels)?;
+                Ok(Value::Null)
+            },
+            "set_tags" => {
+                let p: TagsParams = params(p)?;
+                Management::new(self.db).set_tags(&parse_address(&p.address)?, p.tags)?;
+                Ok(Value::Null)
+            },
+            "list_tags" => to_value(Management::new(self.db).tags()?),
+            "add_tag" => {
+                let p: TagParams = params(p)?;
+                let addresses = match &p.addresses {
+                    Some(addresses) => Some(addresses.iter().map(|address| parse_address(address)).collect::<Result<Vec<_>, _>>()?),
+                    None => None
+                };
+                let filter = match &p.label {
+                    Some(label) => Some(label.parse::<LabelFilter>().map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))?),
+                    None => None
+                };
+                let management = Management::new(self.db);
+                let addresses = management.select_nodes(addresses, filter.as_ref())?;
+                to_value(serde_json::json!({ "nodes": management.add_tag(&p.tag, &addresses)? }))
+            },
+            "remove_tag" => {
+                let p: TagParams = params(p)?;
+                to_value(serde_json::json!({ "nodes": Management::new(self.db).remove_tag(&p.tag)? }))
+            },
+            "get_node" => {
+                let p: AddressParams = params(p)?;
+                to_value(self.db.query_node(&parse_address(&p.address)?)?)
+            },
+            "get_fwu" => {
+                let p: AddressParams = params(p)?;
+                let address = parse_address(&p.address)?;
+                to_value(serde_json::json!({
+                    "state": self.db.fwu_state.get(&address)?,
+                    "history": self.db.fwu_history.get(&address)?
+                }))
+            },
+            "list_approvals" => to_value(Management::new(self.db).approvals()?),
+            "list_pending" => to_value(Management::new(self.db).pending_nodes()?),
+            "adopt_node" => {
+                let p: AddressParams = params(p)?;
+                Management::new(self.db).adopt_node(&parse_address(&p.address)?)?;
+                info!("Node {} adopted", p.address);
+                Ok(Value::Null)
+            },
+            "approve" => {
+                let p: ApproveParams = params(p)?;
+                Management::new(self.db).approve(&parse_address(&p.address)?, &p.version)?;
+                Ok(Value::Null)
+            },
+            "reject" => {
+                let p: AddressParams = params(p)?;
+                Management::new(self.db).reject(&parse_address(&p.address)?)?;
+                Ok(Value::Null)
+            },
+            "purge_node" => {
+                let p: AddressParams = params(p)?;
+                let address = parse_address(&p.address)?;
+                Management::new(self.db).purge_node(&address)?;
+                info!("Node {} purged", p.address);
+                Ok(Value::Null)
+            },
+            "replace_node" => {
+                let p: ReplaceParams = params(p)?;
+                let old = parse_address(&p.address)?;
+                let new = parse_address(&p.new)?;
+                let replacement = Management::new(self.db).replace_node(&old, &new, p.push_parameters)?;
+                info!("Node {} replaced by {}", p.address, p.new);
+                to_value(replacement)
+            },
+            "scan" => {
+                let p: AddressParams = params(p)?;
+                let address = parse_address(&p.address)?;
+                self.submit(|reply| ApiRequest::Scan(address, reply)).await?;
+                Ok(Value::Null)
+            },
+            "list_groups" => to_value(Management::new(self.db).groups()?),
+            "add_to_group" => {
+                let p: GroupMemberParams = params(p)?;
+                Management::new(self.db).add_to_group(p.group, &parse_address(&p.address)?)?;
+                Ok(Value::Null)
+            },
+            "remove_from_group" => {
+                let p: GroupMemberParams = params(p)?;
+                Management::new(self.db).remove_from_group(p.group, &parse_address(&p.address)?)?;
+                Ok(Value::Null)
+            },
+            "read" => {
+                let p: ReadParams = params(p)?;
+                let addresses = match (&p.address, p.group) {
+                    (Some(address), None) => vec![parse_address(address)?],
+                    (None, Some(group)) => Management::new(self.db).group_members(group)?,
+                    _ => return Err(RpcError::new(INVALID_PARAMS, "Either address or group is required"))
+                };
+                to_value(self.submit(|reply| ApiRequest::Read { addresses: addresses, target: p.target, reply: reply }).await?)
+            },
+            "get_parameters" => {
+                let p: AddressParams = params(p)?;
+                to_value(Management::new(self.db).parameters(&parse_address(&p.address)?)?)
+            },
+            "set_parameter" => {
+                let p: ParameterParams = params(p)?;
+                let (ti, value) = p.ti.zip(p.value).ok_or_else(|| RpcError::new(INVALID_PARAMS, "ti and value are required"))?;
+                Management::new(self.db).set_parameter(&parse_address(&p.address)?, p.ioa, ti, value)?;
+                info!("Parameter IOA {} of node {} set to {}", p.ioa, p.address, value);
+                Ok(Value::Null)
+            },
+            "remove_parameter" => {
+                let p: ParameterParams = params(p)?;
+                Management::new(self.db).remove_parameter(&parse_address(&p.address)?, p.ioa)?;
+                Ok(Value::Null)
+            },
+            "command" => {
+                let p: CommandParams = params(p)?;
+                let address = parse_address(&p.address)?;
+                self.submit(|reply| ApiRequest::Command {
+                    address: address,
+                    ioa: p.ioa,
+                    ti: p.ti,
+                    value: p.value,
+                    select: p.select,
+                    reply: reply
+                }).await?;
+                Ok(Value::Null)
+            },
+            "bulk_command" => {
+                let p: BulkCommandParams = params(p)?;
+                let addresses = match &p.addresses {
+                    Some(addresses) => Some(addresses.iter().map(|address| parse_address(address)).collect::<Result<Vec<_>, _>>()?),
+                    None => None
+                };
+                let filter = match &p.label {
+                    Some(label) => Some(label.parse::<LabelFilter>().map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))?),
+                    None => None
+                };
+                let addresses = Management::new(self.db).select_nodes(addresses, filter.as_ref())?;
+
+                // each node may take as long as single command
+                let wait = Duration::from_secs(self.conf.request_timeout) * (addresses.len() as u32).max(1);
+                to_value(self.submit_within(wait, |reply| ApiRequest::BulkCommand {
+                    addresses: addresses,
+                    ioa: p.ioa,
+                    ti: p.ti,
+                    value: p.value,
+                    select: p.select,
+                    reply: reply
+                }).await?)
+            },
+            "sync_report" => to_value(sol::sync::report(self.db, self.sync_settings()?)?),
+            "list_firmware" => to_value(self.fw_index()?.list()),
+            "fw_compliance" => {
+                let p: ReportParams = match p {
+                    Value::Null => Default::default(),
+                    p => params(p)?
+                };
+                let report = Management::new(self.db).fw_compliance(self.fw_index()?)?;
+                match p.format.as_deref() {
+                    None | Some("json") => to_value(report),
+                    Some("csv") => Ok(Value::String(report.to_csv())),
+                    Some(format) => Err(RpcError::new(INVALID_PARAMS, format!("Unknown report format '{}'", format)))
+                }
+            },
+            "fwu_plan" => to_value(Management::new(self.db).fwu_plan(self.fw_index()?)?),
+            "add_firmware" => {
+                let p: UploadParams = params(p)?;
+                let image = BASE64.decode(&p.image).map_err(|err| RpcError::new(INVALID_PARAMS, format!("Invalid base64 image ({})", err)))?;
+                let (hw_version, fw_version) = self.fw_index()?.add(&image)?;
+                info!("Firmware {} uploaded", fw_version);
+                to_value(serde_json::json!({ "hw_version": hw_version, "fw_version": fw_version }))
+            },
+            "delete_firmware" => {
+                let p: FirmwareParams = params(p)?;
+                Management::new(self.db).delete_firmware(self.fw_index()?, &p.hw_version, &p.fw_version)?;
+                info!("Firmware {} deleted", p.fw_version);
+                Ok(Value::Null)
+            },
+            "health" => to_value(Management::new(self.db).health_summary()?),
+            "list_ports" => to_value(Management::new(self.db).ports()?),
+            "list_alarms" => to_value(Management::new(self.db).active_alarms()?),
+            "list_processes" => to_value(Management::new(self.db).processes()?),
+            "pause_process" => {
+                let p: ProcessParams = params(p)?;
+                Management::new(self.db).pause_process(&p.name, p.reason)?;
+                Ok(Value::Null)
+            },
+            "resume_process" => {
+                let p: ProcessParams = params(p)?;
+                Management::new(self.db).resume_process(&p.name)?;
+                Ok(Value::Null)
+            },
+            "runtime_state" => to_value(Management::new(self.db).runtime_state()?),
+            "reset_runtime_state" => {
+                let p: KeyParams = params(p)?;
+                Management::new(self.db).reset_runtime_state(&p.key)?;
+                info!("Runtime state {} reset", p.key);
+                Ok(Value::Null)
+            },
+            "storage_stats" => to_value(Management::new(self.db).storage_stats()?),
+            "get_em_tests" => {
+                let p: AddressParams = params(p)?;
+                to_value(Management::new(self.db).em_tests(&parse_address(&p.address)?)?)
+            },
+            "get_raw_frames" => {
+                let p: AddressParams = params(p)?;
+                to_value(Management::new(self.db).raw_frames(&parse_address(&p.address)?)?)
+            },
+            "clear_raw_frames" => {
+                let p: AddressParams = params(p)?;
+                Management::new(self.db).clear_raw_frames(&parse_address(&p.address)?)?;
+                Ok(Value::Null)
+            },
+            "em_report" => {
+                let p: EmReportParams = match p {
+                    Value::Null => Default::default(),
+                    p => params(p)?
+                };
+                let filter = match &p.label {
+                    Some(label) => Some(label.parse::<LabelFilter>().map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))?),
+                    None => None
+                };
+                to_value(Management::new(self.db).em_report(
+                    filter.as_ref(),
+                    p.function_days.unwrap_or(FUNCTION_TEST_DAYS),
+                    p.duration_days.unwrap_or(DURATION_TEST_DAYS)
+                )?)
+            },
+            "list_schedules" => to_value(Management::new(self.db).schedules()?),
+            "get_schedule" => {
+                let p: ScheduleParams = params(p)?;
+                to_value(Management::new(self.db).schedule(p.id)?)
+            },
+            "create_schedule" => {
+                let spec: ScheduleSpec = params(p)?;
+                let management = Management::new(self.db);
+                let id = management.create_schedule(spec)?;
+                info!("Schedule {} created", id);
+                to_value(management.schedule(id)?)
+            },
+            "update_schedule" => {
+                let p: UpdateScheduleParams = params(p)?;
+                Management::new(self.db).update_schedule(p.id, p.spec)?;
+                Ok(Value::Null)
+            },
+            "remove_schedule" => {
+                let p: ScheduleParams = params(p)?;
+                Management::new(self.db).remove_schedule(p.id)?;
+                Ok(Value::Null)
+            },
+            "get_log_levels" => to_value(logging::levels()),
+            "set_log_level" => {
+                let p: LogLevelParams = params(p)?;
+                logging::set_level(p.module.as_deref(), &p.level).map_err(|err| RpcError::new(INVALID_PARAMS, err))?;
+                info!("Log level of {} set to {}", p.module.as_deref().unwrap_or("all modules"), p.level);
+                Ok(Value::Null)
+            },
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("Unknown method '{}'", method)))
+        }
+    }
+
+    async fn handle_line(&self, line: &str) -> RpcResponse {
+        let (id, result) = match serde_json::from_str::<RpcRequest>(line) {
+            Err(err) => (Value::Null, Err(RpcError::new(PARSE_ERROR, err.to_string()))),
+            Ok(request) => {
+                debug!("Control request {}", request.method);
+                (request.id.unwrap_or(Value::Null), self.call(&request.method, request.params).await)
+            }
+        };
+
+        match result {
+            Ok(value) => RpcResponse { jsonrpc: "2.0", id: id, result: Some(value), error: None },
+            Err(err) => RpcResponse { jsonrpc: "2.0", id: id, result: None, error: Some(err) }
+        }
+    }
+
+    /// Serve one client, one request per line
+    async fn handle_client(&self, stream: UnixStream) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (rd, mut wr) = stream.into_split();
+        let mut lines = BufReader::new(rd).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let mut response = serde_json::to_vec(&self.handle_line(&line).await)?;
+            response.push(b'\n');
+            wr.write_all(&response).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Serve control socket until error
+    pub async fn serve(self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let path = Path::new(&self.conf.path);
+
+        // socket left behind by previous run
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(self.conf.mode))?;
+        info!("Control socket listening on {}", self.conf.path);
+
+        let server = Arc::new(self);
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let server = server.clone();
+
+            tokio::spawn(async move {
+                if let Err(err) = server.handle_client(stream).await {
+                    warn!("Control client error! ({})", err);
+                }
+            });
+        }
+    }
+}
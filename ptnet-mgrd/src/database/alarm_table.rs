@@ -0,0 +1,166 @@
+use std::{sync::Arc, collections::BTreeMap};
+
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::error::DbError;
+
+use super::{NodeAddress, RawValue, storage::{Store, TableDefinition, ReadableTable}};
+
+pub(super) const ALARM_TABLE: TableDefinition<&NodeAddress, &RawValue> = TableDefinition::new("alarms");
+
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub struct AlarmRecord {
+    /// name of rule which raised alarm
+    pub rule: String,
+    pub series: String,
+    pub active: bool,
+    /// value which raised or cleared alarm
+    pub value: f64,
+    /// unix time alarm was raised
+    pub raised_at: u64,
+    /// unix time alarm was cleared, None while active
+    pub cleared_at: Option<u64>
+}
+
+/// Alarms of node, by rule name, cleared alarms are kept until raised again
+#[derive(Debug,Serialize,Deserialize,Clone,Default,PartialEq)]
+pub struct AlarmsRecord {
+    pub alarms: BTreeMap<String, AlarmRecord>
+}
+
+#[derive(Clone)]
+pub enum Event {
+    /// alarm was raised or cleared
+    AlarmChanged(NodeAddress, Arc<AlarmRecord>)
+}
+
+pub struct AlarmTable<'a> {
+    db: &'a Store,
+    pub events: broadcast::Sender<Event>
+}
+
+impl<'a> AlarmTable<'a> {
+    pub fn new(db: &'a Store) -> Self {
+        let (evt_sender, _) = broadcast::channel::<Event>(128);
+
+        Self {
+            db: db,
+            events: evt_sender
+        }
+    }
+
+    pub fn get(&self, address: &NodeAddress) -> Result<AlarmsRecord, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(ALARM_TABLE)?;
+
+        Ok(match table.get(address)? {
+            None => Default::default(),
+            Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+        })
+    }
+
+    /// Active alarms of all nodes
+    pub fn list_active(&self) -> Result<Vec<(NodeAddress, AlarmRecord)>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(ALARM_TABLE)?;
+        let mut results = Vec::new();
+
+        for entry in table.iter()? {
+            let (address, cbor) = entry?;
+            let rec: AlarmsRecord = serde_cbor::from_slice(cbor.value()).unwrap();
+            results.extend(rec.alarms.into_values().filter(|alarm| alarm.active).map(|alarm| (*address.value(), alarm)));
+        }
+
+        Ok(results)
+    }
+
+    /// Raise alarm of rule, nothing happens if it's active already
+    pub fn raise(&self, address: &NodeAddress, rule: &str, series: &str, value: f64, now: u64) -> Result<(), DbError> {
+        self.transition(address, rule, true, |_| AlarmRecord {
+            rule: rule.to_string(),
+            series: series.to_string(),
+            active: true,
+            value: value,
+            raised_at: now,
+            cleared_at: None
+        })
+    }
+
+    /// Clear alarm of rule, nothing happens if it isn't active
+    pub fn clear(&self, address: &NodeAddress, rule: &str, value: f64, now: u64) -> Result<(), DbError> {
+        self.transition(address, rule, false, |org_rec| AlarmRecord {
+            active: false,
+            value: value,
+            cleared_at: Some(now),
+            ..org_rec.unwrap()
+        })
+    }
+
+    /// Written and announced only if alarm changes state
+    fn transition<T>(&self, address: &NodeAddress, rule: &str, active: bool, cb: T) -> Result<(), DbError>
+    where
+        T: FnOnce(Option<AlarmRecord>) -> AlarmRecord
+    {
+        let alarm: AlarmRecord;
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(ALARM_TABLE)?;
+            let mut rec: AlarmsRecord = match table.get(address)? {
+                None => Default::default(),
+                Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+            };
+
+            let org_alarm = rec.alarms.get(rule).cloned();
+            if org_alarm.as_ref().map_or(false, |a| a.active) == active {
+                return Ok(());
+            }
+
+            alarm = cb(org_alarm);
+            rec.alarms.insert(rule.to_string(), alarm.clone());
+
+            table.insert(address, serde_cbor::to_vec(&rec)?.as_slice())?;
+        }
+        txn.commit()?;
+
+        self.events.send(Event::AlarmChanged(*address, Arc::new(alarm))).unwrap_or_default();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::testing::{make_redb, make_db};
+
+    use super::*;
+
+    #[test]
+    fn transitions() {
+        let rdb = make_redb("alarm-db.redb");
+        let db = make_db(&rdb);
+        let mut rcvr = db.alarms.events.subscribe();
+        let a: NodeAddress = [0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0xEF];
+
+        db.alarms.clear(&a, "lamp", 0.0, 5).unwrap();
+        db.alarms.raise(&a, "lamp", "current", 0.1, 10).unwrap();
+        db.alarms.raise(&a, "lamp", "current", 0.2, 20).unwrap();
+        assert_eq!(1, db.alarms.list_active().unwrap().len());
+
+        db.alarms.clear(&a, "lamp", 1.5, 30).unwrap();
+
+        assert!(matches!(rcvr.try_recv().unwrap(), Event::AlarmChanged(_, rec) if rec.active));
+        assert!(matches!(rcvr.try_recv().unwrap(), Event::AlarmChanged(_, rec) if !rec.active));
+        assert!(rcvr.is_empty(), "Repeated state shall not generate event");
+
+        assert_eq!(AlarmRecord {
+            rule: "lamp".to_string(),
+            series: "current".to_string(),
+            active: false,
+            value: 1.5,
+            raised_at: 10,
+            cleared_at: Some(30)
+        }, db.alarms.get(&a).unwrap().alarms["lamp"]);
+        assert!(db.alarms.list_active().unwrap().is_empty());
+    }
+}
@@ -1,14 +1,16 @@
-use std::{sync::Arc, io};
+use std::sync::Arc;
 
-use redb::ReadableTable;
+use redb::RedbValue;
 use serde::Serialize;
 
-use super::{UpdateMode, node_table::{NodeRecord, NodeTable, self, NODE_TABLE}, NodeAddress, RawValue};
+use crate::error::DbError;
+
+use super::{UpdateMode, node_table::{NodeRecord, NodeTable, self, NODE_TABLE}, NodeAddress, RawValue, storage::{Store, StorageKey, TableDefinition, ReadableTable}};
 
 pub trait TableKey<K> {
     fn table_key(&self) -> &K
     where
-        K: redb::RedbKey;
+        K: StorageKey;
 }
 
 impl TableKey<NodeAddress> for NodeRecord {
@@ -22,22 +24,22 @@ pub trait DatabaseTable<T> {
     type Record: Clone;
     type Event;
 
-    fn redb(&self) -> &redb::Database;
+    fn store(&self) -> &Store;
     fn table_definition(&self) -> T;
     fn send_event(&self, evt: Self::Event);
     fn make_record_added_event(&self, rec: Self::Record) -> Self::Event;
     fn make_record_modified_event(&self, rec: Self::Record) -> Self::Event;
 }
 
-impl<'a> DatabaseTable<redb::TableDefinition<'static, &'static NodeAddress, &'static RawValue>> for NodeTable<'a> {
+impl<'a> DatabaseTable<TableDefinition<&'static NodeAddress, &'static RawValue>> for NodeTable<'a> {
     type Record = NodeRecord;
     type Event = node_table::Event;
 
-    fn redb(&self) -> &redb::Database {
+    fn store(&self) -> &Store {
         self.db
     }
 
-    fn table_definition(&self) -> redb::TableDefinition<'static,&'static NodeAddress, &'static RawValue>
+    fn table_definition(&self) -> TableDefinition<&'static NodeAddress, &'static RawValue>
     {
         NODE_TABLE
     }
@@ -56,7 +58,7 @@ impl<'a> DatabaseTable<redb::TableDefinition<'static, &'static NodeAddress, &'st
 }
 
 pub trait TableOps<'a,Key,Value,Record: Clone> {
-    fn x_update_many<'t,T>(&self, it: T, mode: UpdateMode) -> Result<(), Box<dyn std::error::Error>>
+    fn x_update_many<'t,T>(&self, it: T, mode: UpdateMode) -> Result<(), DbError>
     where
         T: Iterator<Item = &'t Record> + Clone,
         Record: 't;
@@ -65,19 +67,19 @@ pub trait TableOps<'a,Key,Value,Record: Clone> {
 
 impl<'a,T,Key,Value,Record> TableOps<'a,Key,Value,Record> for T
 where
-    for<'t> T: DatabaseTable<redb::TableDefinition<'a, &'t Key, &'t Value>,Record=Record>,
-    for<'t> &'t Key: redb::RedbKey,
-    for<'t> &'t Value: redb::RedbValue,
+    for<'t> T: DatabaseTable<TableDefinition<&'t Key, &'t Value>,Record=Record>,
+    for<'t> &'t Key: StorageKey,
+    for<'t> &'t Value: RedbValue,
     Record: Serialize + Clone,
     for<'t> &'t Record: TableKey<Key>,
-    Key: redb::RedbKey + 'static,
+    Key: StorageKey + 'static,
     Key: Copy,
-    for<'t> &'t Key: std::borrow::Borrow<<&'t Key as redb::RedbValue>::SelfType<'t>>,
+    for<'t> &'t Key: std::borrow::Borrow<<&'t Key as RedbValue>::SelfType<'t>>,
     Value: 'static,
-    for<'t> Key: std::borrow::Borrow<<&'t Key as redb::RedbValue>::SelfType<'t>>,
-    for<'t> &'t [u8]: std::borrow::Borrow<<&'t Value as redb::RedbValue>::SelfType<'t>>
+    for<'t> Key: std::borrow::Borrow<<&'t Key as RedbValue>::SelfType<'t>>,
+    for<'t> &'t [u8]: std::borrow::Borrow<<&'t Value as RedbValue>::SelfType<'t>>
 {
-    fn x_update_many<'t,IT>(&self, it: IT, mode: UpdateMode) -> Result<(), Box<dyn std::error::Error>>
+    fn x_update_many<'t,IT>(&self, it: IT, mode: UpdateMode) -> Result<(), DbError>
     where
         IT: Iterator<Item = &'t Record> + Clone,
         Record: 't
@@ -85,7 +87,7 @@ where
         let mut events: Vec<T::Event> = Vec::new();
         // let prev_rec_exists;
 
-        let txn = self.redb().begin_write()?;
+        let txn = self.store().begin_write()?;
         {
             let mut table = txn.open_table(self.table_definition())?;
             // let mut table = self.open_table(&txn)?;
@@ -95,18 +97,12 @@ where
                 match mode {
                     UpdateMode::MustCreate => {
                         if table.get(&rec_key)?.is_some() {
-                            return Err(Box::new(io::Error::new(
-                                io::ErrorKind::AlreadyExists,
-                                "Record already exists"
-                            )));
+                            return Err(DbError::AlreadyExists("Record already exists".to_string()));
                         }
                     },
                     UpdateMode::MustExist => {
                         if table.get(&rec_key)?.is_none() {
-                            return Err(Box::new(io::Error::new(
-                                io::ErrorKind::NotFound,
-                                "Record does not exist"
-                            )));
+                            return Err(DbError::NotFound("Record does not exist".to_string()));
                         }
                     },
                     UpdateMode::UpdateOrCreate => {}
@@ -0,0 +1,208 @@
+use std::sync::Arc;
+
+use ptnet::image_header::{FWVersion, HWVersion};
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::error::DbError;
+
+use super::{NodeAddress, RawValue, unix_now, storage::{Store, TableDefinition, ReadableTable}};
+
+pub(super) const CAMPAIGN_TABLE: TableDefinition<u64, &RawValue> = TableDefinition::new("campaigns");
+
+pub type CampaignId = u64;
+
+/// Nodes targeted by campaign
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub enum Selector {
+    /// all nodes with given hardware version
+    HWVersion(HWVersion),
+    /// explicit list of nodes
+    Nodes(Vec<NodeAddress>),
+    /// nodes matching label filter, e.g. `tag:corridor,floor=3`
+    Filter(String)
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq,Default)]
+pub enum CampaignState {
+    #[default]
+    Running,
+    /// paused by operator or because of too many failures
+    Paused,
+    /// all nodes are done or failed, or time window is over
+    Finished
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq)]
+pub enum NodeState {
+    /// waiting for free update slot
+    Pending,
+    /// goal was set, update in progress since given unix time
+    Updating(u64),
+    /// node runs target version
+    Done,
+    Failed
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub struct CampaignRecord {
+    pub id: CampaignId,
+    pub selector: Selector,
+    /// firmware version to update to
+    pub target: FWVersion,
+    /// max. nodes updating at once
+    pub max_concurrent: usize,
+    /// unix time range in which updates may be started, unlimited if not set
+    pub window: Option<(u64, u64)>,
+    /// pause campaign when failed nodes exceed this percentage of finished ones
+    pub max_failure_percent: u8,
+    /// mark node failed if its update takes longer than this (seconds)
+    pub node_timeout: u64,
+    pub state: CampaignState,
+    /// per-node state, filled as nodes get selected
+    pub nodes: Vec<(NodeAddress, NodeState)>,
+    /// unix time of creation
+    pub created_at: u64
+}
+
+impl CampaignRecord {
+    pub fn new(selector: Selector, target: FWVersion, max_concurrent: usize) -> Self {
+        Self {
+            id: 0,
+            selector: selector,
+            target: target,
+            max_concurrent: max_concurrent,
+            window: None,
+            max_failure_percent: 10,
+            node_timeout: 3600,
+            state: CampaignState::Running,
+            nodes: Vec::new(),
+            created_at: unix_now()
+        }
+    }
+
+    pub fn count(&self, pred: impl Fn(&NodeState) -> bool) -> usize {
+        self.nodes.iter().filter(|(_, st)| pred(st)).count()
+    }
+
+    /// Failed nodes exceed allowed percentage of finished ones
+    pub fn failure_rate_exceeded(&self) -> bool {
+        let failed = self.count(|st| *st == NodeState::Failed);
+        let finished = failed + self.count(|st| *st == NodeState::Done);
+
+        finished > 0 && failed * 100 > finished * self.max_failure_percent as usize
+    }
+}
+
+#[derive(Clone)]
+pub enum Event {
+    CampaignAdded(Arc<CampaignRecord>),
+    CampaignModified(Arc<CampaignRecord>),
+    CampaignRemoved(CampaignId)
+}
+
+pub struct CampaignTable<'a> {
+    db: &'a Store,
+    pub events: broadcast::Sender<Event>
+}
+
+impl<'a> CampaignTable<'a> {
+    pub fn new(db: &'a Store) -> Self {
+        let (evt_sender, _) = broadcast::channel::<Event>(128);
+
+        Self {
+            db: db,
+            events: evt_sender
+        }
+    }
+
+    /// Store new campaign, id is assigned automatically
+    pub fn create(&self, mut rec: CampaignRecord) -> Result<CampaignId, DbError> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(CAMPAIGN_TABLE)?;
+
+            rec.id = match table.iter()?.next_back() {
+                None => 1,
+                Some(entry) => entry?.0.value() + 1
+            };
+
+            table.insert(rec.id, serde_cbor::to_vec(&rec)?.as_slice())?;
+        }
+        txn.commit()?;
+
+        let id = rec.id;
+        self.events.send(Event::CampaignAdded(Arc::new(rec))).unwrap_or_default();
+
+        Ok(id)
+    }
+
+    pub fn get(&self, id: CampaignId) -> Result<Option<CampaignRecord>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(CAMPAIGN_TABLE)?;
+
+        Ok(match table.get(id)? {
+            None => None,
+            Some(cbor) => Some(serde_cbor::from_slice(cbor.value()).unwrap())
+        })
+    }
+
+    pub fn list(&self) -> Result<Vec<CampaignRecord>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(CAMPAIGN_TABLE)?;
+        let mut results: Vec<CampaignRecord> = Vec::new();
+
+        for entry in table.iter()? {
+            let (_, cbor) = entry?;
+            results.push(serde_cbor::from_slice(cbor.value()).unwrap());
+        }
+
+        Ok(results)
+    }
+
+    /// Modify campaign in callback
+    pub fn modify<T>(&self, id: CampaignId, cb: T) -> Result<(), DbError>
+    where
+        T: FnOnce(CampaignRecord) -> Option<CampaignRecord>
+    {
+        let rec: CampaignRecord;
+        let txn = self.db.begin_write()?;
+
+        {
+            let mut table = txn.open_table(CAMPAIGN_TABLE)?;
+            let org_rec: CampaignRecord = match table.get(id)? {
+                None => return Err(DbError::NotFound(format!("Campaign {} does not exist", id))),
+                Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+            };
+
+            match cb(org_rec) {
+                None => return Ok(()),
+                Some(new_rec) => rec = new_rec
+            };
+
+            table.insert(id, serde_cbor::to_vec(&rec)?.as_slice())?;
+        }
+
+        txn.commit()?;
+
+        self.events.send(Event::CampaignModified(Arc::new(rec))).unwrap_or_default();
+
+        Ok(())
+    }
+
+    pub fn remove(&self, id: CampaignId) -> Result<(), DbError> {
+        let txn = self.db.begin_write()?;
+        let removed = {
+            let mut table = txn.open_table(CAMPAIGN_TABLE)?;
+            let removed = table.remove(id)?.is_some();
+            removed
+        };
+        txn.commit()?;
+
+        if removed {
+            self.events.send(Event::CampaignRemoved(id)).unwrap_or_default();
+        }
+
+        Ok(())
+    }
+}
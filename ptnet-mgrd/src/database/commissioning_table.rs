@@ -0,0 +1,83 @@
+use std::sync::Arc;
+
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::error::DbError;
+
+use super::{NodeAddress, RawValue, storage::{Store, TableDefinition, ReadableTable}};
+
+pub(super) const COMMISSIONING_TABLE: TableDefinition<&NodeAddress, &RawValue> = TableDefinition::new("commissioning");
+
+/// Configuration assigned to node at commissioning
+#[derive(Debug,Serialize,Deserialize,Clone,Default,PartialEq)]
+pub struct NodeSetup {
+    pub ca: Option<u8>,
+    /// spontaneous reporting interval (seconds)
+    pub report_interval: Option<u32>,
+    pub groups: Vec<u8>
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub struct CommissioningRecord {
+    /// configuration node was commissioned with
+    pub applied: NodeSetup,
+    /// unix time of commissioning
+    pub commissioned_at: u64
+}
+
+#[derive(Clone)]
+pub enum Event {
+    Commissioned(NodeAddress, Arc<CommissioningRecord>)
+}
+
+pub struct CommissioningTable<'a> {
+    db: &'a Store,
+    pub events: broadcast::Sender<Event>
+}
+
+impl<'a> CommissioningTable<'a> {
+    pub fn new(db: &'a Store) -> Self {
+        let (evt_sender, _) = broadcast::channel::<Event>(128);
+
+        Self {
+            db: db,
+            events: evt_sender
+        }
+    }
+
+    pub fn get(&self, address: &NodeAddress) -> Result<Option<CommissioningRecord>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(COMMISSIONING_TABLE)?;
+
+        Ok(match table.get(address)? {
+            None => None,
+            Some(cbor) => Some(serde_cbor::from_slice(cbor.value()).unwrap())
+        })
+    }
+
+    pub fn set(&self, address: &NodeAddress, rec: CommissioningRecord) -> Result<(), DbError> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(COMMISSIONING_TABLE)?;
+            table.insert(address, serde_cbor::to_vec(&rec)?.as_slice())?;
+        }
+        txn.commit()?;
+
+        self.events.send(Event::Commissioned(*address, Arc::new(rec))).unwrap_or_default();
+
+        Ok(())
+    }
+
+    /// Forget commissioning, node gets commissioned again when seen
+    pub fn remove(&self, address: &NodeAddress) -> Result<(), DbError> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(COMMISSIONING_TABLE)?;
+            table.remove(address)?;
+        }
+        txn.commit()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use serde::Serialize;
+
+use crate::error::DbError;
+
+use super::{Database, unix_now, ADDRESS_TABLES, NAME_TABLES, ID_TABLES, PORT_TABLE, GROUP_TABLE, JOURNAL_ACK_TABLE, storage::{Store, StorageBackend, ReadableTable}};
+
+/// Space taken by database file
+#[derive(Debug,Clone,Default,Serialize,PartialEq)]
+pub struct StorageStats {
+    /// size of database file (bytes)
+    pub file_size: u64,
+    /// bytes of records
+    pub stored: u64,
+    /// free space inside file, given back only by compaction (bytes)
+    pub fragmented: u64,
+    /// unix time stats were taken
+    pub at: u64
+}
+
+impl StorageStats {
+    /// Share of file which is free space (percent)
+    pub fn fragmentation(&self) -> f64 {
+        match self.file_size {
+            0 => 0.0,
+            size => self.fragmented as f64 * 100.0 / size as f64
+        }
+    }
+}
+
+#[derive(Debug,Clone,Serialize,PartialEq)]
+pub struct CompactionReport {
+    pub size_before: u64,
+    pub size_after: u64
+}
+
+/// Stats of database stored in `path`, blocks writers for a moment
+pub fn measure(db: &Store, path: &Path) -> Result<StorageStats, DbError> {
+    let (stored, fragmented) = db.usage()?;
+
+    Ok(StorageStats {
+        file_size: fs::metadata(path)?.len(),
+        stored: stored,
+        fragmented: fragmented,
+        at: unix_now()
+    })
+}
+
+/// Copy of database being written by compaction
+fn compact_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".compact");
+    PathBuf::from(name)
+}
+
+/// Copy records of all tables in one transaction, records with keys already in `dst` are overwritten
+pub fn copy_tables(src: &Store, dst: &Store) -> Result<(), DbError> {
+    let read = src.begin_read()?;
+    let write = dst.begin_write()?;
+
+    {
+        for (_, definition) in ADDRESS_TABLES {
+            let mut table = write.open_table(definition)?;
+            for entry in read.open_table(definition)?.iter()? {
+                let (key, value) = entry?;
+                table.insert(key.value(), value.value())?;
+            }
+        }
+
+        for (_, definition) in NAME_TABLES {
+            let mut table = write.open_table(definition)?;
+            for entry in read.open_table(definition)?.iter()? {
+                let (key, value) = entry?;
+                table.insert(key.value(), value.value())?;
+            }
+        }
+
+        for (_, definition) in ID_TABLES {
+            let mut table = write.open_table(definition)?;
+            for entry in read.open_table(definition)?.iter()? {
+                let (key, value) = entry?;
+                table.insert(key.value(), value.value())?;
+            }
+        }
+
+        let mut table = write.open_table(PORT_TABLE)?;
+        for entry in read.open_table(PORT_TABLE)?.iter()? {
+            let (key, value) = entry?;
+            table.insert(key.value(), value.value())?;
+        }
+
+        let mut table = write.open_table(GROUP_TABLE)?;
+        for entry in read.open_table(GROUP_TABLE)?.iter()? {
+            let (key, value) = entry?;
+            table.insert(key.value(), value.value())?;
+        }
+
+        let mut table = write.open_table(JOURNAL_ACK_TABLE)?;
+        for entry in read.open_table(JOURNAL_ACK_TABLE)?.iter()? {
+            let (key, value) = entry?;
+            table.insert(key.value(), value.value())?;
+        }
+    }
+
+    write.commit()?;
+    Ok(())
+}
+
+/// Rewrite database into new file and rename it over the old one, nothing may have database open.
+/// Copy is synced before rename, so crash leaves either the old file or the complete copy.
+/// SQLite rebuilds file itself (VACUUM), which is atomic as well.
+pub fn compact(backend: StorageBackend, path: &Path) -> Result<CompactionReport, DbError> {
+    if backend == StorageBackend::Sqlite {
+        return vacuum(path);
+    }
+
+    let tmp = compact_path(path);
+    // leftover of interrupted compaction, original is intact
+    fs::remove_file(&tmp).unwrap_or_default();
+    let size_before = fs::metadata(path)?.len();
+
+    {
+        let src = Store::open(StorageBackend::Redb, path)?;
+        Database::new(&src).init()?;
+        let dst = Store::open(StorageBackend::Redb, &tmp)?;
+        Database::new(&dst).init()?;
+        copy_tables(&src, &dst)?;
+    }
+
+    fs::File::open(&tmp)?.sync_all()?;
+    fs::rename(&tmp, path)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new(".")
+    };
+    fs::File::open(dir)?.sync_all()?;
+
+    Ok(CompactionReport {
+        size_before: size_before,
+        size_after: fs::metadata(path)?.len()
+    })
+}
+
+/// SQLite file rebuilt in place
+fn vacuum(path: &Path) -> Result<CompactionReport, DbError> {
+    let size_before = fs::metadata(path)?.len();
+    Store::open(StorageBackend::Sqlite, path)?.vacuum()?;
+
+    Ok(CompactionReport {
+        size_before: size_before,
+        size_after: fs::metadata(path)?.len()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::{UpdateMode, node_table::NodeRecord, testing::{make_db, temp_path}};
+
+    use super::*;
+
+    #[test]
+    fn compaction() {
+        let pth = temp_path("compaction-db.redb");
+        let addresses: Vec<[u8; 6]> = (0..200u8).map(|i| [0, 0, 0, 0, 1, i]).collect();
+
+        {
+            let rdb = Store::open(StorageBackend::Redb, &pth).unwrap();
+            let db = make_db(&rdb);
+            for address in addresses.iter() {
+                db.nodes.update(address, &NodeRecord { address: *address, ..Default::default() }, UpdateMode::MustCreate).unwrap();
+            }
+            db.nodes.remove_many(addresses[1..].iter()).unwrap();
+            assert!(measure(&rdb, &pth).unwrap().fragmented > 0);
+        }
+
+        let report = compact(StorageBackend::Redb, &pth).unwrap();
+        assert!(report.size_after <= report.size_before);
+        assert!(!compact_path(&pth).exists());
+
+        let rdb = Store::open(StorageBackend::Redb, &pth).unwrap();
+        let db = Database::new(&rdb);
+        assert_eq!(vec![addresses[0]], db.nodes.list().unwrap());
+    }
+}
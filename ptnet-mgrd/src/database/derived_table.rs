@@ -0,0 +1,96 @@
+use std::sync::Arc;
+
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::error::DbError;
+
+use super::{RawValue, storage::{Store, TableDefinition, ReadableTable}};
+
+pub(super) const DERIVED_TABLE: TableDefinition<&str, &RawValue> = TableDefinition::new("derived");
+
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub struct DerivedSample {
+    /// unix time value was computed
+    pub at: u64,
+    pub value: f64
+}
+
+/// Computed values of derived point, oldest first
+#[derive(Debug,Serialize,Deserialize,Clone,Default,PartialEq)]
+pub struct DerivedRecord {
+    pub name: String,
+    pub samples: Vec<DerivedSample>
+}
+
+#[derive(Clone)]
+pub enum Event {
+    DerivedUpdated(Arc<String>, Arc<DerivedSample>)
+}
+
+pub struct DerivedTable<'a> {
+    db: &'a Store,
+    pub events: broadcast::Sender<Event>
+}
+
+impl<'a> DerivedTable<'a> {
+    pub fn new(db: &'a Store) -> Self {
+        let (evt_sender, _) = broadcast::channel::<Event>(128);
+
+        Self {
+            db: db,
+            events: evt_sender
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<DerivedRecord>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(DERIVED_TABLE)?;
+
+        Ok(match table.get(name)? {
+            None => None,
+            Some(cbor) => Some(serde_cbor::from_slice(cbor.value()).unwrap())
+        })
+    }
+
+    pub fn list(&self) -> Result<Vec<DerivedRecord>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(DERIVED_TABLE)?;
+        let mut results: Vec<DerivedRecord> = Vec::new();
+
+        for entry in table.iter()? {
+            let (_, cbor) = entry?;
+            results.push(serde_cbor::from_slice(cbor.value()).unwrap());
+        }
+
+        Ok(results)
+    }
+
+    /// Append value of derived point, keeping at most `max_samples` newest, unchanged value isn't recorded
+    pub fn record(&self, name: &str, sample: DerivedSample, max_samples: usize) -> Result<(), DbError> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(DERIVED_TABLE)?;
+            let mut rec: DerivedRecord = match table.get(name)? {
+                None => DerivedRecord { name: name.to_string(), samples: Vec::new() },
+                Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+            };
+
+            if rec.samples.last().map(|s| s.value) == Some(sample.value) {
+                return Ok(());
+            }
+
+            rec.samples.push(sample.clone());
+            if rec.samples.len() > max_samples.max(1) {
+                rec.samples.drain(..rec.samples.len() - max_samples.max(1));
+            }
+
+            table.insert(name, serde_cbor::to_vec(&rec)?.as_slice())?;
+        }
+        txn.commit()?;
+
+        self.events.send(Event::DerivedUpdated(Arc::new(name.to_string()), Arc::new(sample))).unwrap_or_default();
+
+        Ok(())
+    }
+}
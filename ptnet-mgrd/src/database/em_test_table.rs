@@ -0,0 +1,214 @@
+use std::sync::Arc;
+
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::error::DbError;
+
+use super::{NodeAddress, RawValue, storage::{Store, TableDefinition, ReadableTable}};
+
+pub(super) const EM_TEST_TABLE: TableDefinition<&NodeAddress, &RawValue> = TableDefinition::new("em_tests");
+
+/// Test results kept per node, five years of monthly tests
+pub const MAX_RESULTS: usize = 64;
+/// Longest age of passed function test (days), monthly by EN 50172
+pub const FUNCTION_TEST_DAYS: u64 = 30;
+/// Longest age of passed duration test (days), yearly by EN 50172
+pub const DURATION_TEST_DAYS: u64 = 365;
+
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TestKind {
+    /// short switch to battery operation
+    Function,
+    /// full rated duration on battery, covers function test too
+    Duration
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub struct TestResult {
+    pub kind: TestKind,
+    /// unix times
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub passed: bool,
+    /// result reported by ballast, None if it reported none in time
+    pub code: Option<u32>
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq)]
+pub struct PendingTest {
+    pub kind: TestKind,
+    /// unix time of start command
+    pub started_at: u64
+}
+
+/// Emergency lighting tests of node
+#[derive(Debug,Serialize,Deserialize,Clone,Default,PartialEq)]
+pub struct EmTestsRecord {
+    /// test in progress
+    pub pending: Option<PendingTest>,
+    /// oldest first
+    pub results: Vec<TestResult>
+}
+
+/// Whether node has recent passed tests as required
+#[derive(Debug,Serialize,Clone,PartialEq)]
+pub struct Compliance {
+    pub address: NodeAddress,
+    /// latest function or duration test
+    pub last_function: Option<TestResult>,
+    pub last_duration: Option<TestResult>,
+    /// both latest tests passed and aren't older than required
+    pub compliant: bool
+}
+
+impl EmTestsRecord {
+    /// Latest result counting as test of given kind
+    pub fn last(&self, kind: TestKind) -> Option<&TestResult> {
+        self.results.iter().rev().find(|result| kind == TestKind::Function || result.kind == kind)
+    }
+
+    pub fn compliance(&self, address: &NodeAddress, now: u64, function_days: u64, duration_days: u64) -> Compliance {
+        let valid = |result: Option<&TestResult>, days: u64| result.map_or(false, |result| result.passed && now.saturating_sub(result.finished_at) <= days * 86400);
+
+        Compliance {
+            address: *address,
+            last_function: self.last(TestKind::Function).cloned(),
+            last_duration: self.last(TestKind::Duration).cloned(),
+            compliant: valid(self.last(TestKind::Function), function_days) && valid(self.last(TestKind::Duration), duration_days)
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Event {
+    TestStarted(NodeAddress, PendingTest),
+    TestFinished(NodeAddress, Arc<TestResult>)
+}
+
+pub struct EmTestTable<'a> {
+    db: &'a Store,
+    pub events: broadcast::Sender<Event>
+}
+
+impl<'a> EmTestTable<'a> {
+    pub fn new(db: &'a Store) -> Self {
+        let (evt_sender, _) = broadcast::channel::<Event>(128);
+
+        Self {
+            db: db,
+            events: evt_sender
+        }
+    }
+
+    pub fn get(&self, address: &NodeAddress) -> Result<EmTestsRecord, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(EM_TEST_TABLE)?;
+
+        Ok(match table.get(address)? {
+            None => Default::default(),
+            Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+        })
+    }
+
+    pub fn list(&self) -> Result<Vec<(NodeAddress, EmTestsRecord)>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(EM_TEST_TABLE)?;
+        let mut results = Vec::new();
+
+        for entry in table.iter()? {
+            let (address, cbor) = entry?;
+            results.push((*address.value(), serde_cbor::from_slice(cbor.value()).unwrap()));
+        }
+
+        Ok(results)
+    }
+
+    /// Record started test, returns false if another one is in progress
+    pub fn start(&self, address: &NodeAddress, kind: TestKind, at: u64) -> Result<bool, DbError> {
+        let test = PendingTest { kind: kind, started_at: at };
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(EM_TEST_TABLE)?;
+            let mut rec: EmTestsRecord = match table.get(address)? {
+                None => Default::default(),
+                Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+            };
+
+            if rec.pending.is_some() {
+                return Ok(false);
+            }
+
+            rec.pending = Some(test);
+            table.insert(address, serde_cbor::to_vec(&rec)?.as_slice())?;
+        }
+        txn.commit()?;
+
+        self.events.send(Event::TestStarted(*address, test)).unwrap_or_default();
+
+        Ok(true)
+    }
+
+    /// Record result of test in progress, returns None if there is none
+    pub fn finish(&self, address: &NodeAddress, passed: bool, code: Option<u32>, at: u64) -> Result<Option<TestResult>, DbError> {
+        let txn = self.db.begin_write()?;
+        let result = {
+            let mut table = txn.open_table(EM_TEST_TABLE)?;
+            let mut rec: EmTestsRecord = match table.get(address)? {
+                None => return Ok(None),
+                Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+            };
+
+            let test = match rec.pending.take() {
+                None => return Ok(None),
+                Some(test) => test
+            };
+
+            let result = TestResult { kind: test.kind, started_at: test.started_at, finished_at: at, passed: passed, code: code };
+            rec.results.push(result.clone());
+            let excess = rec.results.len().saturating_sub(MAX_RESULTS);
+            rec.results.drain(..excess);
+
+            table.insert(address, serde_cbor::to_vec(&rec)?.as_slice())?;
+            result
+        };
+        txn.commit()?;
+
+        self.events.send(Event::TestFinished(*address, Arc::new(result.clone()))).unwrap_or_default();
+
+        Ok(Some(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::testing::{make_redb, make_db};
+
+    use super::*;
+
+    const DAY: u64 = 86400;
+
+    #[test]
+    fn test_compliance() {
+        let rdb = make_redb("em-test-db.redb");
+        let db = make_db(&rdb);
+        let address: NodeAddress = [0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0xEF];
+
+        assert!(db.em_tests.start(&address, TestKind::Duration, 0).unwrap());
+        assert!(!db.em_tests.start(&address, TestKind::Function, 10).unwrap(), "Test is in progress");
+        assert_eq!(TestKind::Duration, db.em_tests.finish(&address, true, Some(0), 3 * 3600).unwrap().unwrap().kind);
+        assert_eq!(None, db.em_tests.finish(&address, true, Some(0), 3 * 3600).unwrap());
+
+        let rec = db.em_tests.get(&address).unwrap();
+        assert!(rec.compliance(&address, 20 * DAY, 30, 365).compliant, "Duration test counts as function test");
+        assert!(!rec.compliance(&address, 40 * DAY, 30, 365).compliant);
+
+        db.em_tests.start(&address, TestKind::Function, 40 * DAY).unwrap();
+        db.em_tests.finish(&address, false, None, 40 * DAY + 600).unwrap();
+        let compliance = db.em_tests.get(&address).unwrap().compliance(&address, 40 * DAY + 600, 30, 365);
+        assert!(!compliance.compliant, "Latest function test failed");
+        assert_eq!(Some(false), compliance.last_function.map(|result| result.passed));
+        assert_eq!(Some(true), compliance.last_duration.map(|result| result.passed));
+    }
+}
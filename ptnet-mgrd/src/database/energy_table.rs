@@ -0,0 +1,155 @@
+use std::{sync::Arc, collections::BTreeMap};
+
+use chrono::NaiveDate;
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::error::DbError;
+
+use super::{NodeAddress, RawValue, storage::{Store, TableDefinition, ReadableTable}};
+
+pub(super) const ENERGY_TABLE: TableDefinition<&NodeAddress, &RawValue> = TableDefinition::new("energy");
+
+/// Daily aggregates kept per meter, oldest are dropped
+const MAX_DAYS: usize = 400;
+
+/// Consumption accumulated from a counter of node
+#[derive(Debug,Serialize,Deserialize,Clone,Default,PartialEq)]
+pub struct Meter {
+    /// last raw counter reading
+    pub last_raw: Option<u32>,
+    /// unix time of last reading
+    pub last_at: u64,
+    /// consumption since first reading
+    pub total: u64,
+    /// consumption by local day
+    pub daily: BTreeMap<NaiveDate, u64>
+}
+
+impl Meter {
+    /// Increase since `last` of counter `bits` wide, wrapped counter is expected to wrap at most once
+    pub fn delta(last: u32, raw: u32, bits: u8) -> u64 {
+        if raw >= last {
+            return u64::from(raw - last);
+        }
+
+        let modulus = 1u64 << bits.clamp(1, 32);
+        let wrapped = modulus.saturating_sub(u64::from(last)) + u64::from(raw);
+
+        // counter went back by more than half of its range, device was reset
+        match wrapped > modulus / 2 {
+            true => u64::from(raw),
+            false => wrapped
+        }
+    }
+
+    fn add_reading(&mut self, raw: u32, bits: u8, at: u64, day: NaiveDate) -> u64 {
+        let delta = match self.last_raw {
+            Some(last) => Meter::delta(last, raw, bits),
+            None => 0
+        };
+
+        self.last_raw = Some(raw);
+        self.last_at = at;
+        self.total += delta;
+        *self.daily.entry(day).or_default() += delta;
+
+        while self.daily.len() > MAX_DAYS {
+            let first = *self.daily.keys().next().unwrap();
+            self.daily.remove(&first);
+        }
+
+        delta
+    }
+}
+
+/// Meters of node by series name
+#[derive(Debug,Serialize,Deserialize,Clone,Default,PartialEq)]
+pub struct EnergyRecord {
+    pub meters: BTreeMap<String, Meter>
+}
+
+#[derive(Clone)]
+pub enum Event {
+    /// consumption increased by given amount
+    Consumed(NodeAddress, Arc<String>, u64)
+}
+
+pub struct EnergyTable<'a> {
+    db: &'a Store,
+    pub events: broadcast::Sender<Event>
+}
+
+impl<'a> EnergyTable<'a> {
+    pub fn new(db: &'a Store) -> Self {
+        let (evt_sender, _) = broadcast::channel::<Event>(128);
+
+        Self {
+            db: db,
+            events: evt_sender
+        }
+    }
+
+    pub fn get(&self, address: &NodeAddress) -> Result<EnergyRecord, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(ENERGY_TABLE)?;
+
+        Ok(match table.get(address)? {
+            None => Default::default(),
+            Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+        })
+    }
+
+    /// Account counter reading taken at `at` on local `day`, returns consumption since previous reading
+    pub fn add_reading(&self, address: &NodeAddress, series: &str, raw: u32, bits: u8, at: u64, day: NaiveDate) -> Result<u64, DbError> {
+        let delta: u64;
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(ENERGY_TABLE)?;
+            let mut rec: EnergyRecord = match table.get(address)? {
+                None => Default::default(),
+                Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+            };
+
+            delta = rec.meters.entry(series.to_string()).or_default().add_reading(raw, bits, at, day);
+
+            table.insert(address, serde_cbor::to_vec(&rec)?.as_slice())?;
+        }
+        txn.commit()?;
+
+        if delta > 0 {
+            self.events.send(Event::Consumed(*address, Arc::new(series.to_string()), delta)).unwrap_or_default();
+        }
+
+        Ok(delta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollover() {
+        assert_eq!(5, Meter::delta(10, 15, 32));
+        assert_eq!(6, Meter::delta(0xFFFF_FFFD, 3, 32));
+        assert_eq!(6, Meter::delta(0xFFFD, 3, 16));
+        // reset, not a wrap
+        assert_eq!(3, Meter::delta(1000, 3, 32));
+    }
+
+    #[test]
+    fn daily() {
+        let day1 = NaiveDate::from_ymd_opt(2023, 5, 1).unwrap();
+        let day2 = NaiveDate::from_ymd_opt(2023, 5, 2).unwrap();
+        let mut meter = Meter::default();
+
+        assert_eq!(0, meter.add_reading(100, 32, 10, day1));
+        assert_eq!(50, meter.add_reading(150, 32, 20, day1));
+        assert_eq!(25, meter.add_reading(175, 32, 30, day2));
+
+        assert_eq!(75, meter.total);
+        assert_eq!(Some(&50), meter.daily.get(&day1));
+        assert_eq!(Some(&25), meter.daily.get(&day2));
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::Arc;
+
+use ptnet::image_header::FWVersion;
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::error::DbError;
+
+use super::{NodeAddress, RawValue, storage::{Store, TableDefinition, ReadableTable}};
+
+pub(super) const FWU_HISTORY_TABLE: TableDefinition<&NodeAddress, &RawValue> = TableDefinition::new("fwu_history");
+
+/// Entries kept per node, oldest are dropped
+const MAX_ENTRIES: usize = 32;
+
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub enum Outcome {
+    /// node runs target version
+    Succeeded,
+    /// node left download before new image was in place
+    Aborted,
+    /// node came back running given (old) version
+    RolledBack(FWVersion),
+    /// node didn't finish update in time
+    NoResponse
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub struct HistoryEntry {
+    /// version running before update
+    pub from: FWVersion,
+    pub to: FWVersion,
+    /// unix time of update start
+    pub started_at: u64,
+    /// unix time when outcome was determined
+    pub finished_at: u64,
+    pub outcome: Outcome
+}
+
+#[derive(Clone)]
+pub enum Event {
+    HistoryAdded(NodeAddress, Arc<HistoryEntry>)
+}
+
+pub struct FWUHistoryTable<'a> {
+    db: &'a Store,
+    pub events: broadcast::Sender<Event>
+}
+
+impl<'a> FWUHistoryTable<'a> {
+    pub fn new(db: &'a Store) -> Self {
+        let (evt_sender, _) = broadcast::channel::<Event>(128);
+
+        Self {
+            db: db,
+            events: evt_sender
+        }
+    }
+
+    /// Update attempts of node, oldest first
+    pub fn get(&self, address: &NodeAddress) -> Result<Vec<HistoryEntry>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(FWU_HISTORY_TABLE)?;
+
+        Ok(match table.get(address)? {
+            None => Vec::new(),
+            Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+        })
+    }
+
+    pub fn append(&self, address: &NodeAddress, entry: HistoryEntry) -> Result<(), DbError> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(FWU_HISTORY_TABLE)?;
+            let mut entries: Vec<HistoryEntry> = match table.get(address)? {
+                None => Vec::new(),
+                Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+            };
+
+            entries.push(entry.clone());
+            if entries.len() > MAX_ENTRIES {
+                entries.drain(..entries.len() - MAX_ENTRIES);
+            }
+
+            table.insert(address, serde_cbor::to_vec(&entries)?.as_slice())?;
+        }
+        txn.commit()?;
+
+        self.events.send(Event::HistoryAdded(*address, Arc::new(entry))).unwrap_or_default();
+
+        Ok(())
+    }
+}
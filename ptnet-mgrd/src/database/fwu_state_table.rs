@@ -1,13 +1,14 @@
 use std::sync::Arc;
 
-use ptnet::image_header::FWVersion;
-use redb::ReadableTable;
+use ptnet::{image_header::FWVersion, FW_State_A};
 use serde::{Serialize, Deserialize};
 use tokio::sync::broadcast;
 
-use super::{NodeAddress, RawValue};
+use crate::error::DbError;
 
-pub(super) const FWU_STATE_TABLE: redb::TableDefinition<&NodeAddress, &RawValue> = redb::TableDefinition::new("fwu_state");
+use super::{NodeAddress, RawValue, unix_now, storage::{Store, TableDefinition, ReadableTable}};
+
+pub(super) const FWU_STATE_TABLE: TableDefinition<&NodeAddress, &RawValue> = TableDefinition::new("fwu_state");
 
 #[derive(Debug,Serialize,Deserialize,Clone,PartialEq,Default)]
 pub enum Goal {
@@ -21,24 +22,111 @@ pub enum Goal {
     UpdateTo(FWVersion)
 }
 
+/// Phase of firmware update, as reported by node
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq,Default)]
+pub enum Phase {
+    #[default]
+    Idle,
+    /// image is being downloaded to node
+    Download,
+    /// node is writing image to flash
+    Flashing,
+    /// node has new image in place
+    Updated
+}
+
+/// Firmware state in device status of node
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum FwState {
+    Idle,
+    Download,
+    Flashing,
+    Updated,
+    /// state of newer firmware unknown to this version, node isn't updated while in it
+    Unknown(u8)
+}
+
+impl From<u8> for FwState {
+    fn from(value: u8) -> Self {
+        match FW_State_A::try_from(value) {
+            Ok(FW_State_A::Idle) => FwState::Idle,
+            Ok(FW_State_A::Download) => FwState::Download,
+            Ok(FW_State_A::Flashing) => FwState::Flashing,
+            Ok(FW_State_A::Updated) => FwState::Updated,
+            Err(_) => FwState::Unknown(value)
+        }
+    }
+}
+
+impl FwState {
+    /// Update phase of state, None if state is unknown
+    pub fn phase(&self) -> Option<Phase> {
+        match self {
+            FwState::Idle => Some(Phase::Idle),
+            FwState::Download => Some(Phase::Download),
+            FwState::Flashing => Some(Phase::Flashing),
+            FwState::Updated => Some(Phase::Updated),
+            FwState::Unknown(_) => None
+        }
+    }
+}
+
+/// Progress of update as far as daemon sees it, node downloads image on its own
+#[derive(Debug,Serialize,Deserialize,Clone,Default,PartialEq)]
+pub struct Progress {
+    pub phase: Phase,
+    /// unix time when update started
+    pub started_at: u64,
+    /// unix time of last progress change
+    pub updated_at: u64
+}
+
+/// Update started by FWU process, awaiting verification
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub struct Attempt {
+    /// version running before update
+    pub from: FWVersion,
+    pub to: FWVersion,
+    /// unix time of update start
+    pub started_at: u64,
+    /// unix time node reported new image in place
+    pub updated_at: Option<u64>
+}
+
 #[derive(Debug,Serialize,Deserialize,Clone,Default,PartialEq)]
 pub struct FWUStateRecord {
-    pub goal: Goal
+    pub goal: Goal,
+    /// progress of running (or last) update
+    #[serde(default)]
+    pub progress: Option<Progress>,
+    /// running update attempt
+    #[serde(default)]
+    pub attempt: Option<Attempt>,
+    /// consecutive failed update attempts
+    #[serde(default)]
+    pub failures: u32,
+    /// unix time before which update is not retried
+    #[serde(default)]
+    pub retry_after: u64,
+    /// update offer rejected by user, newer versions are offered again
+    #[serde(default)]
+    pub rejected: Option<FWVersion>
 }
 
 #[derive(Clone)]
 pub enum Event {
-    FWUStateAdded(Arc<FWUStateRecord>),
-    FWUStateModified(Arc<FWUStateRecord>)
+    FWUStateAdded(NodeAddress, Arc<FWUStateRecord>),
+    FWUStateModified(NodeAddress, Arc<FWUStateRecord>),
+    FWUProgress(NodeAddress, Arc<Progress>)
 }
 
 pub struct FWUStateTable<'a> {
-    db: &'a redb::Database,
+    db: &'a Store,
     pub events: broadcast::Sender<Event>
 }
 
 impl<'a> FWUStateTable<'a> {
-    pub fn new(db: &'a redb::Database) -> Self {
+    pub fn new(db: &'a Store) -> Self {
         let (evt_sender, _) = broadcast::channel::<Event>(128);
 
         Self {
@@ -47,7 +135,30 @@ impl<'a> FWUStateTable<'a> {
         }
     }
 
-    pub fn get_or_create_for(&self, address: &NodeAddress) -> Result<FWUStateRecord, Box<dyn std::error::Error>> {
+    pub fn get(&self, address: &NodeAddress) -> Result<Option<FWUStateRecord>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(FWU_STATE_TABLE)?;
+
+        Ok(match table.get(address)? {
+            None => None,
+            Some(cbor) => Some(serde_cbor::from_slice(cbor.value()).unwrap())
+        })
+    }
+
+    pub fn list(&self) -> Result<Vec<(NodeAddress, FWUStateRecord)>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(FWU_STATE_TABLE)?;
+        let mut results: Vec<(NodeAddress, FWUStateRecord)> = Vec::new();
+
+        for entry in table.iter()? {
+            let (address, cbor) = entry?;
+            results.push((address.value().clone(), serde_cbor::from_slice(cbor.value()).unwrap()));
+        }
+
+        Ok(results)
+    }
+
+    pub fn get_or_create_for(&self, address: &NodeAddress) -> Result<FWUStateRecord, DbError> {
         let txn = self.db.begin_write()?;
 
         let mut table = txn.open_table(FWU_STATE_TABLE)?;
@@ -66,8 +177,17 @@ impl<'a> FWUStateTable<'a> {
         Ok(def_rec)
     }
 
+    /// Remove state record, returns whether it existed
+    pub fn remove(&self, address: &NodeAddress) -> Result<bool, DbError> {
+        let txn = self.db.begin_write()?;
+        let existed = txn.open_table(FWU_STATE_TABLE)?.remove(address)?.is_some();
+        txn.commit()?;
+
+        Ok(existed)
+    }
+
     /// Modify state record in callback
-    pub fn modify<T>(&self, address: &NodeAddress, cb: T) -> Result<(), Box<dyn std::error::Error>>
+    pub fn modify<T>(&self, address: &NodeAddress, cb: T) -> Result<(), DbError>
     where
         T: FnOnce(Option<FWUStateRecord>) -> Option<FWUStateRecord>
     {
@@ -85,8 +205,8 @@ impl<'a> FWUStateTable<'a> {
                 None => return Ok(()),
                 Some(rec) => {
                     match table.insert(address, serde_cbor::to_vec(&rec)?.as_slice())? {
-                        None => event = Some(Event::FWUStateAdded(Arc::new(rec))),
-                        Some(_) => event = Some(Event::FWUStateModified(Arc::new(rec)))
+                        None => event = Some(Event::FWUStateAdded(*address, Arc::new(rec))),
+                        Some(_) => event = Some(Event::FWUStateModified(*address, Arc::new(rec)))
                     };
                 }
             }
@@ -101,4 +221,103 @@ impl<'a> FWUStateTable<'a> {
         Ok(())
     }
 
+    /// Modify update progress in callback
+    ///
+    /// Unlike `modify`, no FWUStateModified event is generated. FWUProgress event
+    /// is sent only when phase changes.
+    pub fn update_progress<T>(&self, address: &NodeAddress, cb: T) -> Result<(), DbError>
+    where
+        T: FnOnce(&mut Progress)
+    {
+        let event: Option<Event>;
+        let txn = self.db.begin_write()?;
+
+        {
+            let mut table = txn.open_table(FWU_STATE_TABLE)?;
+            let mut rec: FWUStateRecord = match table.get(address)? {
+                None => FWUStateRecord::default(),
+                Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+            };
+
+            let prev = rec.progress.clone().unwrap_or_default();
+            let mut progress = prev.clone();
+            cb(&mut progress);
+
+            if progress == prev {
+                return Ok(());
+            }
+
+            progress.updated_at = unix_now();
+
+            event = match progress.phase != prev.phase {
+                true => Some(Event::FWUProgress(*address, Arc::new(progress.clone()))),
+                false => None
+            };
+
+            rec.progress = Some(progress);
+            table.insert(address, serde_cbor::to_vec(&rec)?.as_slice())?;
+        }
+
+        txn.commit()?;
+
+        if let Some(evt) = event {
+            self.events.send(evt).unwrap_or_default();
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use crate::database::testing::{make_redb, make_db};
+
+    use super::*;
+
+    #[test]
+    fn progress_events() {
+        let rdb = make_redb("fwu-db.redb");
+        let db = make_db(&rdb);
+        let mut rcvr = db.fwu_state.events.subscribe();
+        let address: NodeAddress = [0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0xEF];
+
+        db.fwu_state.update_progress(&address, |p| {
+            p.phase = Phase::Download;
+            p.started_at = 100;
+        }).unwrap();
+
+        let evt = rcvr.recv().now_or_never().expect("Event shall arrive").unwrap();
+        if let Event::FWUProgress(addr, progress) = evt {
+            assert_eq!(address, addr);
+            assert_eq!(Phase::Download, progress.phase);
+        } else {
+            assert!(false, "FWUProgress event not generated");
+        }
+
+        db.fwu_state.update_progress(&address, |p| p.started_at = 200).unwrap();
+        assert!(rcvr.is_empty(), "No event expected without phase change");
+
+        db.fwu_state.update_progress(&address, |p| p.phase = Phase::Flashing).unwrap();
+        let evt = rcvr.recv().now_or_never().expect("Event shall arrive").unwrap();
+        if let Event::FWUProgress(_, progress) = evt {
+            assert_eq!(Phase::Flashing, progress.phase);
+        } else {
+            assert!(false, "FWUProgress event not generated");
+        }
+
+        assert_eq!(200, db.fwu_state.get(&address).unwrap().unwrap().progress.unwrap().started_at);
+    }
+
+    #[test]
+    fn fw_state_of_code() {
+        assert_eq!(FwState::Idle, FwState::from(FW_State_A::Idle as u8));
+        assert_eq!(FwState::Updated, FwState::from(FW_State_A::Updated as u8));
+        assert_eq!(Some(Phase::Flashing), FwState::from(FW_State_A::Flashing as u8).phase());
+
+        // state added by newer firmware
+        assert_eq!(FwState::Unknown(0xEE), FwState::from(0xEE));
+        assert_eq!(None, FwState::Unknown(0xEE).phase());
+    }
 }
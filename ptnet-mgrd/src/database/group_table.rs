@@ -0,0 +1,124 @@
+use std::sync::Arc;
+
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::error::DbError;
+
+use super::{NodeAddress, RawValue, storage::{Store, TableDefinition, ReadableTable}};
+
+pub(super) const GROUP_TABLE: TableDefinition<u8, &RawValue> = TableDefinition::new("groups");
+
+pub type GroupId = u8;
+
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub struct Member {
+    pub address: NodeAddress,
+    /// device confirmed membership, None if not checked yet
+    pub verified: Option<bool>,
+    /// unix time of last check
+    pub checked_at: Option<u64>
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,Default,PartialEq)]
+pub struct GroupRecord {
+    pub id: GroupId,
+    pub members: Vec<Member>
+}
+
+impl GroupRecord {
+    pub fn is_member(&self, address: &NodeAddress) -> bool {
+        self.members.iter().any(|m| m.address == *address)
+    }
+}
+
+#[derive(Clone)]
+pub enum Event {
+    GroupModified(Arc<GroupRecord>),
+    GroupRemoved(GroupId)
+}
+
+pub struct GroupTable<'a> {
+    db: &'a Store,
+    pub events: broadcast::Sender<Event>
+}
+
+impl<'a> GroupTable<'a> {
+    pub fn new(db: &'a Store) -> Self {
+        let (evt_sender, _) = broadcast::channel::<Event>(128);
+
+        Self {
+            db: db,
+            events: evt_sender
+        }
+    }
+
+    pub fn get(&self, id: GroupId) -> Result<Option<GroupRecord>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(GROUP_TABLE)?;
+
+        Ok(match table.get(id)? {
+            None => None,
+            Some(cbor) => Some(serde_cbor::from_slice(cbor.value()).unwrap())
+        })
+    }
+
+    pub fn list(&self) -> Result<Vec<GroupRecord>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(GROUP_TABLE)?;
+        let mut results: Vec<GroupRecord> = Vec::new();
+
+        for entry in table.iter()? {
+            let (_, cbor) = entry?;
+            results.push(serde_cbor::from_slice(cbor.value()).unwrap());
+        }
+
+        Ok(results)
+    }
+
+    /// Modify group in callback, group is created if it doesn't exist
+    pub fn modify<T>(&self, id: GroupId, cb: T) -> Result<(), DbError>
+    where
+        T: FnOnce(GroupRecord) -> Option<GroupRecord>
+    {
+        let rec: GroupRecord;
+        let txn = self.db.begin_write()?;
+
+        {
+            let mut table = txn.open_table(GROUP_TABLE)?;
+            let org_rec: GroupRecord = match table.get(id)? {
+                None => GroupRecord { id: id, ..Default::default() },
+                Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+            };
+
+            match cb(org_rec) {
+                None => return Ok(()),
+                Some(new_rec) => rec = new_rec
+            };
+
+            table.insert(id, serde_cbor::to_vec(&rec)?.as_slice())?;
+        }
+
+        txn.commit()?;
+
+        self.events.send(Event::GroupModified(Arc::new(rec))).unwrap_or_default();
+
+        Ok(())
+    }
+
+    pub fn remove(&self, id: GroupId) -> Result<(), DbError> {
+        let txn = self.db.begin_write()?;
+        let removed = {
+            let mut table = txn.open_table(GROUP_TABLE)?;
+            let removed = table.remove(id)?.is_some();
+            removed
+        };
+        txn.commit()?;
+
+        if removed {
+            self.events.send(Event::GroupRemoved(id)).unwrap_or_default();
+        }
+
+        Ok(())
+    }
+}
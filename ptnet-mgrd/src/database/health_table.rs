@@ -0,0 +1,149 @@
+use std::sync::Arc;
+
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::error::DbError;
+
+use super::{NodeAddress, RawValue, storage::{Store, TableDefinition, ReadableTable}};
+
+pub(super) const HEALTH_TABLE: TableDefinition<&NodeAddress, &RawValue> = TableDefinition::new("health");
+
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq)]
+pub enum Health {
+    Online,
+    /// node answers only sometimes
+    Degraded,
+    Offline
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub struct HealthRecord {
+    pub health: Health,
+    /// unix time of last transition
+    pub since: u64
+}
+
+/// Number of nodes in each health state
+#[derive(Debug,Serialize,Clone,Default,PartialEq)]
+pub struct HealthSummary {
+    pub online: usize,
+    pub degraded: usize,
+    pub offline: usize
+}
+
+#[derive(Clone)]
+pub enum Event {
+    /// node changed health, carries previous health if known
+    HealthChanged(NodeAddress, Option<Health>, Arc<HealthRecord>)
+}
+
+pub struct HealthTable<'a> {
+    db: &'a Store,
+    pub events: broadcast::Sender<Event>
+}
+
+impl<'a> HealthTable<'a> {
+    pub fn new(db: &'a Store) -> Self {
+        let (evt_sender, _) = broadcast::channel::<Event>(128);
+
+        Self {
+            db: db,
+            events: evt_sender
+        }
+    }
+
+    pub fn get(&self, address: &NodeAddress) -> Result<Option<HealthRecord>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(HEALTH_TABLE)?;
+
+        Ok(match table.get(address)? {
+            None => None,
+            Some(cbor) => Some(serde_cbor::from_slice(cbor.value()).unwrap())
+        })
+    }
+
+    pub fn summary(&self) -> Result<HealthSummary, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(HEALTH_TABLE)?;
+        let mut summary = HealthSummary::default();
+
+        for entry in table.iter()? {
+            let (_, cbor) = entry?;
+            let rec: HealthRecord = serde_cbor::from_slice(cbor.value()).unwrap();
+            match rec.health {
+                Health::Online => summary.online += 1,
+                Health::Degraded => summary.degraded += 1,
+                Health::Offline => summary.offline += 1
+            }
+        }
+
+        Ok(summary)
+    }
+
+    /// Forget health of node which is no longer managed
+    pub fn remove(&self, address: &NodeAddress) -> Result<(), DbError> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(HEALTH_TABLE)?;
+            table.remove(address)?;
+        }
+        txn.commit()?;
+
+        Ok(())
+    }
+
+    /// Store health of node, written and announced only on transition
+    pub fn set(&self, address: &NodeAddress, health: Health, now: u64) -> Result<(), DbError> {
+        let txn = self.db.begin_write()?;
+        let previous: Option<Health>;
+        let rec = HealthRecord { health: health, since: now };
+        {
+            let mut table = txn.open_table(HEALTH_TABLE)?;
+            previous = match table.get(address)? {
+                None => None,
+                Some(cbor) => Some(serde_cbor::from_slice::<HealthRecord>(cbor.value()).unwrap().health)
+            };
+
+            if previous == Some(health) {
+                return Ok(());
+            }
+
+            table.insert(address, serde_cbor::to_vec(&rec)?.as_slice())?;
+        }
+        txn.commit()?;
+
+        self.events.send(Event::HealthChanged(*address, previous, Arc::new(rec))).unwrap_or_default();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::testing::{make_redb, make_db};
+
+    use super::*;
+
+    #[test]
+    fn transitions() {
+        let rdb = make_redb("health-db.redb");
+        let db = make_db(&rdb);
+        let mut rcvr = db.health.events.subscribe();
+        let a: NodeAddress = [0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0xEF];
+        let b: NodeAddress = [0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0xF0];
+
+        db.health.set(&a, Health::Online, 10).unwrap();
+        db.health.set(&a, Health::Online, 20).unwrap();
+        db.health.set(&a, Health::Offline, 30).unwrap();
+        db.health.set(&b, Health::Degraded, 30).unwrap();
+
+        assert!(matches!(rcvr.try_recv().unwrap(), Event::HealthChanged(_, None, _)));
+        assert!(matches!(rcvr.try_recv().unwrap(), Event::HealthChanged(_, Some(Health::Online), _)));
+        assert!(matches!(rcvr.try_recv().unwrap(), Event::HealthChanged(_, None, _)));
+        assert!(rcvr.is_empty(), "Repeated health shall not generate event");
+
+        assert_eq!(HealthRecord { health: Health::Offline, since: 30 }, db.health.get(&a).unwrap().unwrap());
+        assert_eq!(HealthSummary { online: 0, degraded: 1, offline: 1 }, db.health.summary().unwrap());
+    }
+}
@@ -0,0 +1,154 @@
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::error::DbError;
+
+use super::{NodeAddress, RawValue, unix_now, node_table::NodeRecord, fwu_state_table::FWUStateRecord, alarm_table::AlarmRecord, storage::{Store, TableDefinition, ReadableTable}};
+
+pub(super) const JOURNAL_TABLE: TableDefinition<u64, &RawValue> = TableDefinition::new("journal");
+/// last offset acknowledged by each consumer
+pub(super) const JOURNAL_ACK_TABLE: TableDefinition<&str, u64> = TableDefinition::new("journal_acks");
+
+/// Event kept for consumers which may have been down when it happened
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub enum JournalEvent {
+    NodeAdded(NodeRecord),
+    NodeModified(NodeRecord),
+    NodeRemoved(NodeAddress),
+    FWUState(NodeAddress, FWUStateRecord),
+    AlarmChanged(NodeAddress, AlarmRecord)
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub struct JournalEntry {
+    /// position in journal, increasing
+    pub offset: u64,
+    /// unix time of appending
+    pub at: u64,
+    pub event: JournalEvent
+}
+
+#[derive(Clone)]
+pub enum Event {
+    /// carries offset of appended entry
+    Appended(u64)
+}
+
+pub struct JournalTable<'a> {
+    db: &'a Store,
+    pub events: broadcast::Sender<Event>
+}
+
+impl<'a> JournalTable<'a> {
+    pub fn new(db: &'a Store) -> Self {
+        let (evt_sender, _) = broadcast::channel::<Event>(128);
+
+        Self {
+            db: db,
+            events: evt_sender
+        }
+    }
+
+    /// Append event, oldest entries beyond `max_entries` are dropped, returns offset of event
+    pub fn append(&self, event: JournalEvent, max_entries: usize) -> Result<u64, DbError> {
+        let txn = self.db.begin_write()?;
+        let offset = {
+            let mut table = txn.open_table(JOURNAL_TABLE)?;
+
+            let offset = match table.iter()?.next_back() {
+                None => 1,
+                Some(entry) => entry?.0.value() + 1
+            };
+
+            let entry = JournalEntry { offset: offset, at: unix_now(), event: event };
+            table.insert(offset, serde_cbor::to_vec(&entry)?.as_slice())?;
+
+            let mut stale: Vec<u64> = Vec::new();
+            for entry in table.iter()? {
+                let old = entry?.0.value();
+                if old + max_entries as u64 > offset {
+                    break;
+                }
+                stale.push(old);
+            }
+            for old in stale {
+                table.remove(old)?;
+            }
+
+            offset
+        };
+        txn.commit()?;
+
+        self.events.send(Event::Appended(offset)).unwrap_or_default();
+
+        Ok(offset)
+    }
+
+    /// Up to `limit` entries following `offset`, oldest first
+    pub fn read_after(&self, offset: u64, limit: usize) -> Result<Vec<JournalEntry>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(JOURNAL_TABLE)?;
+        let mut results: Vec<JournalEntry> = Vec::new();
+
+        for entry in table.range(offset + 1..)?.take(limit) {
+            let (_, cbor) = entry?;
+            results.push(serde_cbor::from_slice(cbor.value()).unwrap());
+        }
+
+        Ok(results)
+    }
+
+    /// Last offset consumer acknowledged, 0 if it never did
+    pub fn acked(&self, consumer: &str) -> Result<u64, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(JOURNAL_ACK_TABLE)?;
+
+        Ok(table.get(consumer)?.map_or(0, |offset| offset.value()))
+    }
+
+    /// Consumer processed everything up to `offset`, acknowledgement never moves back
+    pub fn ack(&self, consumer: &str, offset: u64) -> Result<(), DbError> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(JOURNAL_ACK_TABLE)?;
+            let acked = table.get(consumer)?.map_or(0, |offset| offset.value());
+
+            if offset <= acked {
+                return Ok(());
+            }
+
+            table.insert(consumer, offset)?;
+        }
+        txn.commit()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::testing::{make_redb, make_db};
+
+    use super::*;
+
+    #[test]
+    fn append_trim_ack() {
+        let rdb = make_redb("journal-db.redb");
+        let db = make_db(&rdb);
+        let address: NodeAddress = [0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0xEF];
+
+        for _ in 0..5 {
+            db.journal.append(JournalEvent::NodeRemoved(address), 3).unwrap();
+        }
+
+        let entries = db.journal.read_after(0, 10).unwrap();
+        assert_eq!(vec![3, 4, 5], entries.iter().map(|entry| entry.offset).collect::<Vec<_>>());
+        assert_eq!(JournalEvent::NodeRemoved(address), entries[0].event);
+        assert_eq!(1, db.journal.read_after(4, 10).unwrap().len());
+
+        assert_eq!(0, db.journal.acked("test").unwrap());
+        db.journal.ack("test", 4).unwrap();
+        db.journal.ack("test", 2).unwrap();
+        assert_eq!(4, db.journal.acked("test").unwrap());
+    }
+}
@@ -0,0 +1,171 @@
+use std::sync::Arc;
+
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::error::DbError;
+
+use super::{NodeAddress, RawValue, unix_now, storage::{Store, TableDefinition, ReadableTable}};
+
+pub(super) const LINK_QUALITY_TABLE: TableDefinition<&NodeAddress, &RawValue> = TableDefinition::new("link_quality");
+
+/// Response times kept per node
+pub const RESPONSE_SAMPLES: usize = 32;
+
+/// Scan statistics of node
+#[derive(Debug,Serialize,Deserialize,Clone,Default,PartialEq)]
+pub struct LinkQualityRecord {
+    /// scans sent, failed transmits included
+    pub scans: u64,
+    /// scans node answered
+    pub answered: u64,
+    /// retransmissions of scan requests
+    pub retries: u64,
+    /// response times of recent scans answered without retransmission (milliseconds), oldest first
+    pub response_times: Vec<u32>,
+    /// unix time of last scan
+    pub updated_at: u64,
+    /// unix time of last answered scan
+    #[serde(default)]
+    pub answered_at: Option<u64>
+}
+
+/// Link quality as shown to users
+#[derive(Debug,Serialize,Clone,PartialEq)]
+pub struct LinkQuality {
+    pub scans: u64,
+    /// answered scans in percent, None before first scan
+    pub success_rate: Option<f64>,
+    /// median of recent response times (milliseconds)
+    pub median_response: Option<u32>,
+    pub retries: u64,
+    pub updated_at: u64
+}
+
+impl LinkQualityRecord {
+    pub fn success_rate(&self) -> Option<f64> {
+        match self.scans {
+            0 => None,
+            scans => Some(self.answered as f64 * 100.0 / scans as f64)
+        }
+    }
+
+    /// Recent response time below which `pct` percent of them are (milliseconds)
+    pub fn percentile(&self, pct: u8) -> Option<u32> {
+        if self.response_times.is_empty() {
+            return None;
+        }
+
+        let mut times = self.response_times.clone();
+        times.sort_unstable();
+        let idx = (times.len() - 1) * pct.min(100) as usize / 100;
+        Some(times[idx])
+    }
+
+    pub fn summary(&self) -> LinkQuality {
+        LinkQuality {
+            scans: self.scans,
+            success_rate: self.success_rate(),
+            median_response: self.percentile(50),
+            retries: self.retries,
+            updated_at: self.updated_at
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Event {
+    LinkQualityUpdated(NodeAddress, Arc<LinkQualityRecord>)
+}
+
+pub struct LinkQualityTable<'a> {
+    db: &'a Store,
+    pub events: broadcast::Sender<Event>
+}
+
+impl<'a> LinkQualityTable<'a> {
+    pub fn new(db: &'a Store) -> Self {
+        let (evt_sender, _) = broadcast::channel::<Event>(128);
+
+        Self {
+            db: db,
+            events: evt_sender
+        }
+    }
+
+    pub fn get(&self, address: &NodeAddress) -> Result<Option<LinkQualityRecord>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(LINK_QUALITY_TABLE)?;
+
+        Ok(match table.get(address)? {
+            None => None,
+            Some(cbor) => Some(serde_cbor::from_slice(cbor.value()).unwrap())
+        })
+    }
+
+    /// Account scan of node, `response_time` (milliseconds) is recorded only for scans answered without retransmission
+    pub fn record_scan(&self, address: &NodeAddress, answered: bool, retries: u32, response_time: Option<u32>) -> Result<(), DbError> {
+        let txn = self.db.begin_write()?;
+        let rec = {
+            let mut table = txn.open_table(LINK_QUALITY_TABLE)?;
+            let mut rec: LinkQualityRecord = match table.get(address)? {
+                None => Default::default(),
+                Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+            };
+
+            rec.scans += 1;
+            rec.retries += retries as u64;
+            if answered {
+                rec.answered += 1;
+                rec.answered_at = Some(unix_now());
+            }
+            if let Some(response_time) = response_time.filter(|_| answered && retries == 0) {
+                rec.response_times.push(response_time);
+                let excess = rec.response_times.len().saturating_sub(RESPONSE_SAMPLES);
+                rec.response_times.drain(..excess);
+            }
+            rec.updated_at = unix_now();
+
+            table.insert(address, serde_cbor::to_vec(&rec)?.as_slice())?;
+            rec
+        };
+        txn.commit()?;
+
+        self.events.send(Event::LinkQualityUpdated(*address, Arc::new(rec))).unwrap_or_default();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::testing::{make_redb, make_db};
+
+    use super::*;
+
+    #[test]
+    fn scan_statistics() {
+        let rdb = make_redb("link-quality-db.redb");
+        let db = make_db(&rdb);
+        let address: NodeAddress = [0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0xEF];
+
+        for time in [100, 300, 200] {
+            db.link_quality.record_scan(&address, true, 0, Some(time)).unwrap();
+        }
+        // retransmitted scan doesn't tell response time
+        db.link_quality.record_scan(&address, true, 2, Some(5000)).unwrap();
+        db.link_quality.record_scan(&address, false, 2, None).unwrap();
+
+        let rec = db.link_quality.get(&address).unwrap().unwrap();
+        assert_eq!(5, rec.scans);
+        assert_eq!(4, rec.retries);
+        assert_eq!(Some(80.0), rec.success_rate());
+        assert_eq!(Some(200), rec.percentile(50));
+        assert_eq!(Some(300), rec.percentile(100));
+
+        for _ in 0..RESPONSE_SAMPLES {
+            db.link_quality.record_scan(&address, true, 0, Some(50)).unwrap();
+        }
+        assert_eq!(RESPONSE_SAMPLES, db.link_quality.get(&address).unwrap().unwrap().response_times.len());
+    }
+}
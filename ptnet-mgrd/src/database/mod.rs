@@ -1,17 +1,164 @@
-use self::{node_table::{NodeTable, NODE_TABLE}, fwu_state_table::{FWU_STATE_TABLE, FWUStateTable}};
+use self::{node_table::{NodeTable, NodeRecord, NODE_TABLE}, fwu_state_table::{FWU_STATE_TABLE, FWUStateTable}, campaign_table::{CAMPAIGN_TABLE, CampaignTable}, fwu_history_table::{FWU_HISTORY_TABLE, FWUHistoryTable}, point_table::{POINT_TABLE, PointTable}, health_table::{HEALTH_TABLE, HealthTable}, commissioning_table::{COMMISSIONING_TABLE, CommissioningTable}, group_table::{GROUP_TABLE, GroupTable}, energy_table::{ENERGY_TABLE, EnergyTable}, port_table::{PORT_TABLE, PortTable}, route_table::{ROUTE_TABLE, RouteTable}, alarm_table::{ALARM_TABLE, AlarmTable}, derived_table::{DERIVED_TABLE, DerivedTable}, parameter_table::{PARAMETER_TABLE, ParameterTable}, journal_table::{JOURNAL_TABLE, JOURNAL_ACK_TABLE, JournalTable}, process_table::{PROCESS_TABLE, ProcessTable}, link_quality_table::{LINK_QUALITY_TABLE, LinkQualityTable}, schedule_table::{SCHEDULE_TABLE, ScheduleTable}, em_test_table::{EM_TEST_TABLE, EmTestTable}, raw_frame_table::{RAW_FRAME_TABLE, RawFrameTable}, runtime_state_table::{RUNTIME_STATE_TABLE, RuntimeStateTable}, compaction::StorageStats, storage::{Store, TableDefinition}};
+
+use std::{fmt, str::FromStr, sync::{RwLock, atomic::{AtomicBool, Ordering}}};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{fw_policy::FirmwarePolicy, time_window::UpdateWindows, ptnet_process::{UpdateLimits, BandwidthMeter}, site::SiteConfig, common_address::{CommonAddressConfig, ProfileAddresses}, error::{DbError, AddressError}};
+
 
 pub mod node_table;
 pub mod fwu_state_table;
+pub mod campaign_table;
+pub mod fwu_history_table;
+pub mod point_table;
+pub mod health_table;
+pub mod commissioning_table;
+pub mod group_table;
+pub mod energy_table;
+pub mod port_table;
+pub mod route_table;
+pub mod alarm_table;
+pub mod derived_table;
+pub mod parameter_table;
+pub mod journal_table;
+pub mod process_table;
+pub mod link_quality_table;
+pub mod schedule_table;
+pub mod em_test_table;
+pub mod raw_frame_table;
+pub mod runtime_state_table;
 pub mod algo;
+pub mod query;
+pub mod replace;
+pub mod compaction;
+pub mod storage;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_store;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 
 pub type NodeAddress = [u8; 6];
 type RawValue = [u8];
 
-pub fn node_address_to_string(a: &NodeAddress) -> String {
-    format!("{:#02X}:{:#02X}:{:#02X}:{:#02X}:{:#02X}:{:#02X}",
-        a.get(0).unwrap(), a.get(1).unwrap(), a.get(2).unwrap(),
-        a.get(3).unwrap(), a.get(4).unwrap(), a.get(5).unwrap()
-    )
+/// Tables keyed by node address, with names for tooling copying whole database
+const ADDRESS_TABLES: [(&str, TableDefinition<&NodeAddress, &RawValue>); 13] = [
+    ("nodes", NODE_TABLE), ("fwu_state", FWU_STATE_TABLE), ("fwu_history", FWU_HISTORY_TABLE), ("points", POINT_TABLE),
+    ("health", HEALTH_TABLE), ("commissioning", COMMISSIONING_TABLE), ("energy", ENERGY_TABLE), ("routes", ROUTE_TABLE),
+    ("alarms", ALARM_TABLE), ("parameters", PARAMETER_TABLE), ("link_quality", LINK_QUALITY_TABLE), ("em_tests", EM_TEST_TABLE),
+    ("raw_frames", RAW_FRAME_TABLE)
+];
+
+/// Tables keyed by name
+const NAME_TABLES: [(&str, TableDefinition<&str, &RawValue>); 3] = [
+    ("derived", DERIVED_TABLE), ("processes", PROCESS_TABLE), ("runtime_state", RUNTIME_STATE_TABLE)
+];
+
+/// Tables keyed by sequence number or ID
+const ID_TABLES: [(&str, TableDefinition<u64, &RawValue>); 3] = [
+    ("campaigns", CAMPAIGN_TABLE), ("journal", JOURNAL_TABLE), ("schedules", SCHEDULE_TABLE)
+];
+
+/// Current unix time in seconds, used for record timestamps
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// How node addresses are written in logs, APIs and exports
+#[derive(Debug,Clone,Copy,Serialize,Deserialize,PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFormat {
+    /// all six bytes, e.g. `0xFE:0xED:0xDE:0xAF:0xBE:0xEF`
+    Full,
+    /// four bytes as in SOL model, e.g. `de:af:be:ef`, full form for addresses not fitting it
+    Short
+}
+
+impl Default for AddressFormat {
+    fn default() -> Self { AddressFormat::Full }
+}
+
+/// Addresses are formatted all over the daemon, format is process-wide like log levels
+static SHORT_ADDRESSES: AtomicBool = AtomicBool::new(false);
+
+pub fn set_address_format(format: AddressFormat) {
+    SHORT_ADDRESSES.store(format == AddressFormat::Short, Ordering::Relaxed);
+}
+
+fn address_format() -> AddressFormat {
+    match SHORT_ADDRESSES.load(Ordering::Relaxed) {
+        true => AddressFormat::Short,
+        false => AddressFormat::Full
+    }
+}
+
+/// Node address written in configured format and parsed from either format
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct NodeAddr(pub NodeAddress);
+
+impl NodeAddr {
+    pub fn to_string_as(&self, format: AddressFormat) -> String {
+        let a = &self.0;
+
+        match format {
+            AddressFormat::Short if a[..2] == [0, 0] => format!("{:02x}:{:02x}:{:02x}:{:02x}", a[2], a[3], a[4], a[5]),
+            _ => format!("{:#02X}:{:#02X}:{:#02X}:{:#02X}:{:#02X}:{:#02X}", a[0], a[1], a[2], a[3], a[4], a[5])
+        }
+    }
+}
+
+impl fmt::Display for NodeAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_string_as(address_format()))
+    }
+}
+
+/// Hex byte of address, `0x` prefix and leading zeros are accepted
+fn parse_address_byte(s: &str) -> Option<u8> {
+    let digits = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+
+    match !digits.is_empty() && digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => u8::from_str_radix(digits, 16).ok(),
+        false => None
+    }
+}
+
+/// Only parser of node addresses coming from outside (APIs, CLI, SOL model, MQTT topics), so differently
+/// written addresses end up as the same node. Six or four (short form, upper bytes zero) hex bytes
+/// separated by colons or dashes, or 12 or 8 hex digits without separators; case doesn't matter.
+impl FromStr for NodeAddr {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let text = s.trim();
+
+        if text.is_empty() {
+            return Err(AddressError::Empty);
+        }
+
+        let parts: Vec<&str> = match text.contains(|c| c == ':' || c == '-') {
+            true => text.split(|c| c == ':' || c == '-').collect(),
+            false if text.len() % 2 == 0 && text.is_ascii() => (0..text.len() / 2).map(|i| &text[2 * i..2 * i + 2]).collect(),
+            false => vec![text]
+        };
+
+        let mut bytes: Vec<u8> = Vec::with_capacity(parts.len());
+        for (idx, part) in parts.iter().enumerate() {
+            match parse_address_byte(part) {
+                Some(byte) => bytes.push(byte),
+                None => return Err(AddressError::InvalidByte(text.to_string(), idx + 1, part.to_string()))
+            }
+        }
+
+        match bytes.len() {
+            6 => Ok(NodeAddr(bytes.try_into().unwrap())),
+            4 => Ok(NodeAddr([0, 0, bytes[0], bytes[1], bytes[2], bytes[3]])),
+            len => Err(AddressError::Length(text.to_string(), len))
+        }
+    }
 }
 
 pub enum UpdateMode {
@@ -25,28 +172,250 @@ impl Default for UpdateMode {
 }
 
 pub struct Database<'a> {
-    pub(crate) inner_db: &'a redb::Database,
+    pub(crate) inner_db: &'a Store,
     pub nodes: NodeTable<'a>,
-    pub fwu_state: FWUStateTable<'a>
+    pub fwu_state: FWUStateTable<'a>,
+    pub campaigns: CampaignTable<'a>,
+    pub fwu_history: FWUHistoryTable<'a>,
+    pub points: PointTable<'a>,
+    pub health: HealthTable<'a>,
+    pub commissioning: CommissioningTable<'a>,
+    pub groups: GroupTable<'a>,
+    pub energy: EnergyTable<'a>,
+    pub ports: PortTable<'a>,
+    pub routes: RouteTable<'a>,
+    pub alarms: AlarmTable<'a>,
+    pub derived: DerivedTable<'a>,
+    pub parameters: ParameterTable<'a>,
+    pub journal: JournalTable<'a>,
+    pub processes: ProcessTable<'a>,
+    pub link_quality: LinkQualityTable<'a>,
+    pub schedules: ScheduleTable<'a>,
+    pub em_tests: EmTestTable<'a>,
+    pub raw_frames: RawFrameTable<'a>,
+    pub runtime_state: RuntimeStateTable<'a>,
+    /// bytes transmitted by processes, kept across reconnects
+    pub bandwidth: BandwidthMeter,
+    /// queries flag nodes violating it, processes get it from their context
+    fw_policy: RwLock<FirmwarePolicy>,
+    /// queries label nodes with it
+    site: RwLock<SiteConfig>,
+    /// processes address profiles of nodes with it
+    common_addresses: RwLock<CommonAddressConfig>,
+    auto_adopt: RwLock<bool>,
+    /// update plans schedule with them
+    fwu_windows: RwLock<UpdateWindows>,
+    fwu_limits: RwLock<UpdateLimits>,
+    /// last measured by maintenance task
+    storage: RwLock<Option<StorageStats>>
 }
 
 impl<'a> Database<'a> {
-    pub fn new(re_db: &'a redb::Database) -> Self {
+    pub fn new(store: &'a Store) -> Self {
         Self {
-            inner_db: re_db,
-            nodes: NodeTable::new(&re_db),
-            fwu_state: FWUStateTable::new(&re_db)
+            inner_db: store,
+            nodes: NodeTable::new(&store),
+            fwu_state: FWUStateTable::new(&store),
+            campaigns: CampaignTable::new(&store),
+            fwu_history: FWUHistoryTable::new(&store),
+            points: PointTable::new(&store),
+            health: HealthTable::new(&store),
+            commissioning: CommissioningTable::new(&store),
+            groups: GroupTable::new(&store),
+            energy: EnergyTable::new(&store),
+            ports: PortTable::new(&store),
+            routes: RouteTable::new(&store),
+            alarms: AlarmTable::new(&store),
+            derived: DerivedTable::new(&store),
+            parameters: ParameterTable::new(&store),
+            journal: JournalTable::new(&store),
+            processes: ProcessTable::new(&store),
+            link_quality: LinkQualityTable::new(&store),
+            schedules: ScheduleTable::new(&store),
+            em_tests: EmTestTable::new(&store),
+            raw_frames: RawFrameTable::new(&store),
+            runtime_state: RuntimeStateTable::new(&store),
+            bandwidth: BandwidthMeter::new(Default::default()),
+            fw_policy: RwLock::new(Default::default()),
+            site: RwLock::new(Default::default()),
+            common_addresses: RwLock::new(Default::default()),
+            auto_adopt: RwLock::new(true),
+            fwu_windows: RwLock::new(Default::default()),
+            fwu_limits: RwLock::new(Default::default()),
+            storage: RwLock::new(None)
         }
     }
 
-    pub fn init(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn fw_policy(&self) -> FirmwarePolicy {
+        self.fw_policy.read().unwrap().clone()
+    }
+
+    pub fn set_fw_policy(&self, policy: FirmwarePolicy) {
+        *self.fw_policy.write().unwrap() = policy;
+    }
+
+    pub fn site(&self) -> SiteConfig {
+        self.site.read().unwrap().clone()
+    }
+
+    pub fn set_site(&self, site: SiteConfig) {
+        *self.site.write().unwrap() = site;
+    }
+
+    /// Common addresses of node, by its type in SOL model
+    pub fn common_addresses(&self, address: &NodeAddress) -> ProfileAddresses {
+        let node = self.nodes.load_many(std::iter::once(address)).ok().and_then(|mut nodes| nodes.pop());
+        self.common_addresses.read().unwrap().of_node(node.as_ref())
+    }
+
+    pub fn set_common_addresses(&self, conf: CommonAddressConfig) {
+        *self.common_addresses.write().unwrap() = conf;
+    }
+
+    pub fn set_auto_adopt(&self, auto_adopt: bool) {
+        *self.auto_adopt.write().unwrap() = auto_adopt;
+    }
+
+    pub fn fwu_schedule(&self) -> (UpdateWindows, UpdateLimits) {
+        (self.fwu_windows.read().unwrap().clone(), self.fwu_limits.read().unwrap().clone())
+    }
+
+    pub fn set_fwu_schedule(&self, windows: UpdateWindows, limits: UpdateLimits) {
+        *self.fwu_windows.write().unwrap() = windows;
+        *self.fwu_limits.write().unwrap() = limits;
+    }
+
+    pub fn storage_stats(&self) -> Option<StorageStats> {
+        self.storage.read().unwrap().clone()
+    }
+
+    pub fn set_storage_stats(&self, stats: StorageStats) {
+        *self.storage.write().unwrap() = Some(stats);
+    }
+
+    /// Record of node new to database, pending adoption unless nodes are adopted automatically
+    pub fn discovered_node(&self, address: &NodeAddress) -> NodeRecord {
+        NodeRecord {
+            address: *address,
+            pending: !*self.auto_adopt.read().unwrap(),
+            ..Default::default()
+        }
+    }
+
+    pub fn init(&mut self) -> Result<(), DbError> {
         let txn = self.inner_db.begin_write()?;
         {
             let _node_table = txn.open_table(NODE_TABLE)?;
             let _fwu_state_table = txn.open_table(FWU_STATE_TABLE)?;
+            let _campaign_table = txn.open_table(CAMPAIGN_TABLE)?;
+            let _fwu_history_table = txn.open_table(FWU_HISTORY_TABLE)?;
+            let _point_table = txn.open_table(POINT_TABLE)?;
+            let _health_table = txn.open_table(HEALTH_TABLE)?;
+            let _commissioning_table = txn.open_table(COMMISSIONING_TABLE)?;
+            let _group_table = txn.open_table(GROUP_TABLE)?;
+            let _energy_table = txn.open_table(ENERGY_TABLE)?;
+            let _port_table = txn.open_table(PORT_TABLE)?;
+            let _route_table = txn.open_table(ROUTE_TABLE)?;
+            let _alarm_table = txn.open_table(ALARM_TABLE)?;
+            let _derived_table = txn.open_table(DERIVED_TABLE)?;
+            let _parameter_table = txn.open_table(PARAMETER_TABLE)?;
+            let _journal_table = txn.open_table(JOURNAL_TABLE)?;
+            let _journal_ack_table = txn.open_table(JOURNAL_ACK_TABLE)?;
+            let _process_table = txn.open_table(PROCESS_TABLE)?;
+            let _link_quality_table = txn.open_table(LINK_QUALITY_TABLE)?;
+            let _schedule_table = txn.open_table(SCHEDULE_TABLE)?;
+            let _em_test_table = txn.open_table(EM_TEST_TABLE)?;
+            let _raw_frame_table = txn.open_table(RAW_FRAME_TABLE)?;
+            let _runtime_state_table = txn.open_table(RUNTIME_STATE_TABLE)?;
+        }
+        txn.commit()?;
+
+        Ok(())
+    }
+
+    /// Remove node with everything recorded about it, group membership excepted
+    pub fn purge_node(&self, address: &NodeAddress) -> Result<(), DbError> {
+        let txn = self.inner_db.begin_write()?;
+        {
+            for table in [NODE_TABLE, FWU_STATE_TABLE, FWU_HISTORY_TABLE, POINT_TABLE, HEALTH_TABLE, COMMISSIONING_TABLE, ENERGY_TABLE, ROUTE_TABLE, ALARM_TABLE, PARAMETER_TABLE, LINK_QUALITY_TABLE, EM_TEST_TABLE, RAW_FRAME_TABLE] {
+                txn.open_table(table)?.remove(address)?;
+            }
         }
         txn.commit()?;
 
+        self.nodes.events.send(node_table::Event::NodeRemoved(*address)).unwrap_or_default();
+
         Ok(())
     }
 }
+
+/// Fixtures of database tests
+#[cfg(test)]
+pub mod testing {
+    use std::{fs, path::PathBuf};
+
+    use super::{Database, storage::{Store, StorageBackend}};
+
+    /// Path of file `name` in temporary directory of test run, removed if it exists
+    pub fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ptnet-mgrd-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(name);
+        fs::remove_file(&path).unwrap_or_default();
+        path
+    }
+
+    /// Empty redb database, `name` must be unique as tests run in parallel
+    pub fn make_redb(name: &str) -> Store {
+        Store::open(StorageBackend::Redb, &temp_path(name)).unwrap()
+    }
+
+    /// Empty SQLite database, `name` must be unique as tests run in parallel
+    #[cfg(feature = "sqlite")]
+    pub fn make_sqlite(name: &str) -> Store {
+        Store::open(StorageBackend::Sqlite, &temp_path(name)).unwrap()
+    }
+
+    pub fn make_db<'a>(store: &'a Store) -> Database<'a> {
+        let mut db = Database::new(store);
+        db.init().unwrap();
+        db
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_formats() {
+        let sol = NodeAddr([0, 0, 0xDE, 0xAD, 0xBE, 0xEF]);
+        let other = NodeAddr([0xFE, 0xED, 0xDE, 0xAD, 0xBE, 0xEF]);
+
+        assert_eq!("0x0:0x0:0xDE:0xAD:0xBE:0xEF", sol.to_string_as(AddressFormat::Full));
+        assert_eq!("de:ad:be:ef", sol.to_string_as(AddressFormat::Short));
+        assert_eq!("0xFE:0xED:0xDE:0xAD:0xBE:0xEF", other.to_string_as(AddressFormat::Short), "doesn't fit short form");
+
+        for format in [AddressFormat::Full, AddressFormat::Short] {
+            assert_eq!(Ok(sol), sol.to_string_as(format).parse());
+            assert_eq!(Ok(other), other.to_string_as(format).parse());
+        }
+    }
+
+    #[test]
+    fn address_normalization() {
+        let sol = NodeAddr([0, 0, 0x0E, 0xAD, 0xBE, 0xEF]);
+
+        for text in ["e:ad:be:ef", " 0E:AD:Be:eF ", "0x0e:0xAD:0xbe:0xef", "0e-ad-be-ef", "0EADBEEF", "00:00:0e:ad:be:ef", "00000eadbeef", "0x00:0x0:0x00e:ad:be:ef"] {
+            assert_eq!(Ok(sol), text.parse(), "{}", text);
+        }
+
+        assert_eq!(Err(AddressError::Empty), "  ".parse::<NodeAddr>());
+        assert_eq!(Err(AddressError::Length("de:ad:be".to_string(), 3)), "de:ad:be".parse::<NodeAddr>());
+        assert_eq!(Err(AddressError::InvalidByte("de:ad:be:xx".to_string(), 4, "xx".to_string())), "de:ad:be:xx".parse::<NodeAddr>());
+        assert_eq!(Err(AddressError::InvalidByte("de::be:ef".to_string(), 2, "".to_string())), "de::be:ef".parse::<NodeAddr>());
+        assert_eq!(Err(AddressError::InvalidByte("de:+d:be:ef".to_string(), 2, "+d".to_string())), "de:+d:be:ef".parse::<NodeAddr>());
+        assert_eq!(Err(AddressError::InvalidByte("de:1ad:be:ef".to_string(), 2, "1ad".to_string())), "de:1ad:be:ef".parse::<NodeAddr>());
+    }
+}
@@ -1,24 +1,65 @@
-use std::{sync::Arc, io};
+use std::sync::Arc;
 
-use ptnet;
-use redb::ReadableTable;
+use ptnet::{self, image_header::FWVersion};
 use serde::{Serialize, Deserialize};
 use tokio::sync::broadcast;
 
-use super::{NodeAddress, RawValue, node_address_to_string, UpdateMode};
+use crate::{error::DbError, site::{Labels, Tags}};
 
-pub(super) const NODE_TABLE: redb::TableDefinition<&NodeAddress, &RawValue> = redb::TableDefinition::new("nodes");
+use super::{NodeAddress, RawValue, NodeAddr, UpdateMode, storage::{Store, TableDefinition, ReadableTable}};
+
+pub(super) const NODE_TABLE: TableDefinition<&NodeAddress, &RawValue> = TableDefinition::new("nodes");
 
 #[derive(Debug,Serialize,Deserialize,Clone,Default,PartialEq)]
 pub struct NodeRecord {
     pub address: NodeAddress,
     pub device_status: Option<ptnet::M_DEV_ST>,
-    pub device_descriptor: Option<ptnet::M_DEV_DC>
+    pub device_descriptor: Option<ptnet::M_DEV_DC>,
+    /// firmware node ran when descriptor was read, descriptor is read again when it changes
+    #[serde(default)]
+    pub descriptor_fw_version: Option<FWVersion>,
+    /// ptlink port node was last heard on
+    #[serde(default)]
+    pub port: Option<i32>,
+    /// unix time of last frame received from node, lags by up to link test period
+    #[serde(default)]
+    pub last_seen: Option<u64>,
+    /// node responded to last link test or sent something recently
+    #[serde(default)]
+    pub online: Option<bool>,
+    /// consecutive scans without response
+    #[serde(default)]
+    pub missed_scans: u32,
+    /// identity of node in node model, kept in sync by model reconciliation
+    #[serde(default)]
+    pub model: Option<ModelInfo>,
+    /// unix time node disappeared from node model, purged after retention period
+    #[serde(default)]
+    pub orphaned_at: Option<u64>,
+    /// free-form labels assigned by operator, override labels of site
+    #[serde(default)]
+    pub labels: Labels,
+    /// free-form tags assigned by operator, matched by `tag:<tag>` in label filters
+    #[serde(default)]
+    pub tags: Tags,
+    /// discovered but not adopted by operator yet, node isn't scanned, tested nor updated
+    #[serde(default)]
+    pub pending: bool
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,Default,PartialEq)]
+pub struct ModelInfo {
+    pub name: String,
+    #[serde(rename="type")]
+    pub type_id: String,
+    /// lighting groups node is member of
+    #[serde(default)]
+    pub groups: Vec<u8>
 }
 
 impl NodeRecord {
     pub fn mac(&self) -> String {
-        node_address_to_string(&self.address)
+        NodeAddr(self.address).to_string()
     }
 }
 
@@ -26,15 +67,16 @@ impl NodeRecord {
 pub enum Event {
     NodeAdded(Arc<NodeRecord>),
     NodeModified(Arc<NodeRecord>),
+    NodeRemoved(NodeAddress),
 }
 
 pub struct NodeTable<'a> {
-    pub(crate) db: &'a redb::Database,
+    pub(crate) db: &'a Store,
     pub events: broadcast::Sender<Event>
 }
 
 impl<'a> NodeTable<'a> {
-    pub fn new(db: &'a redb::Database) -> Self {
+    pub fn new(db: &'a Store) -> Self {
         let (evt_sender, _) = broadcast::channel::<Event>(128);
 
         Self {
@@ -43,13 +85,13 @@ impl<'a> NodeTable<'a> {
         }
     }
 
-    pub fn len(&self) -> Result<usize, Box<dyn std::error::Error>> {
+    pub fn len(&self) -> Result<usize, DbError> {
         let txn = self.db.begin_read()?;
         let table = txn.open_table(NODE_TABLE)?;
         Ok(table.len()? as usize)
     }
 
-    pub fn list(&self) -> Result<Vec<NodeAddress>, Box<dyn std::error::Error>> {
+    pub fn list(&self) -> Result<Vec<NodeAddress>, DbError> {
         let txn = self.db.begin_read()?;
         let table = txn.open_table(NODE_TABLE)?;
         let mut results: Vec<NodeAddress> = Vec::new();
@@ -61,8 +103,8 @@ impl<'a> NodeTable<'a> {
         Ok(results)
     }
 
-    pub fn load_many<'call, T: Iterator<Item = &'call NodeAddress>>(&self, iter: T) -> Result<Vec<NodeRecord>, Box<dyn std::error::Error>> {
-        // pub fn remove_nodes<'call, T: Iterator<Item = &'call NodeAddress>>(&self, iter: T) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn load_many<'call, T: Iterator<Item = &'call NodeAddress>>(&self, iter: T) -> Result<Vec<NodeRecord>, DbError> {
+        // pub fn remove_nodes<'call, T: Iterator<Item = &'call NodeAddress>>(&self, iter: T) -> Result<(), DbError> {
         let txn = self.db.begin_read()?;
         let table = txn.open_table(NODE_TABLE)?;
         let mut results: Vec<NodeRecord> = Vec::new();
@@ -74,10 +116,7 @@ impl<'a> NodeTable<'a> {
                     results.push(rec);
                 },
                 None => {
-                    return Err(Box::new(io::Error::new(
-                        io::ErrorKind::NotFound,
-                        format!("Node {} does not exist", node_address_to_string(address))
-                    )));
+                    return Err(DbError::NotFound(format!("Node {} does not exist", NodeAddr(*address))));
                 }
             }
         }
@@ -86,7 +125,7 @@ impl<'a> NodeTable<'a> {
     }
 
     /// Modify node in callback
-    pub fn modify<T>(&self, address: &NodeAddress, cb: T) -> Result<(), Box<dyn std::error::Error>>
+    pub fn modify<T>(&self, address: &NodeAddress, cb: T) -> Result<(), DbError>
     where
         T: FnOnce(Option<NodeRecord>) -> Option<NodeRecord>
     {
@@ -95,13 +134,15 @@ impl<'a> NodeTable<'a> {
 
         {
             let mut table = txn.open_table(NODE_TABLE)?;
-            let rec: Option<NodeRecord> = match table.get(address)? {
+            let org_rec: Option<NodeRecord> = match table.get(address)? {
                 None => None,
                 Some(cbor) => Some(serde_cbor::from_slice(cbor.value()).unwrap())
             };
 
-            match cb(rec) {
+            match cb(org_rec.clone()) {
                 None => return Ok(()),
+                // cyclic reports mostly repeat stored values, they shouldn't wake event subscribers
+                Some(rec) if Some(&rec) == org_rec.as_ref() => return Ok(()),
                 Some(rec) => {
                     match table.insert(address, serde_cbor::to_vec(&rec)?.as_slice())? {
                         None => event = Some(Event::NodeAdded(Arc::new(rec))),
@@ -121,7 +162,7 @@ impl<'a> NodeTable<'a> {
     }
 
     /// update or create node
-    pub fn update(&self, address: &NodeAddress, rec: &NodeRecord, mode: UpdateMode) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn update(&self, address: &NodeAddress, rec: &NodeRecord, mode: UpdateMode) -> Result<(), DbError> {
         let prev_rec_exists;
 
         let txn = self.db.begin_write()?;
@@ -131,18 +172,12 @@ impl<'a> NodeTable<'a> {
             match mode {
                 UpdateMode::MustCreate => {
                     if table.get(address)?.is_some() {
-                        return Err(Box::new(io::Error::new(
-                            io::ErrorKind::AlreadyExists,
-                            format!("Node {} already exists", rec.mac())
-                        )));
+                        return Err(DbError::AlreadyExists(format!("Node {} already exists", rec.mac())));
                     }
                 },
                 UpdateMode::MustExist => {
                     if table.get(address)?.is_none() {
-                        return Err(Box::new(io::Error::new(
-                            io::ErrorKind::NotFound,
-                            format!("Node {} does not exist", rec.mac())
-                        )));
+                        return Err(DbError::NotFound(format!("Node {} does not exist", rec.mac())));
                     }
                 },
                 UpdateMode::UpdateOrCreate => {}
@@ -164,19 +199,28 @@ impl<'a> NodeTable<'a> {
         Ok(())
     }
 
-    pub fn remove_many<'call, T: Iterator<Item = &'call NodeAddress>>(&self, iter: T) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn remove_many<'call, T: Iterator<Item = &'call NodeAddress>>(&self, iter: T) -> Result<(), DbError> {
+        let mut events: Vec<Event> = Vec::new();
+
         let txn = self.db.begin_write()?;
         {
             let mut table = txn.open_table(NODE_TABLE)?;
             for address in iter {
-                table.remove(address)?;
+                if table.remove(address)?.is_some() {
+                    events.push(Event::NodeRemoved(*address));
+                }
             }
         }
         txn.commit()?;
+
+        for evt in events {
+            self.events.send(evt).unwrap_or_default();
+        }
+
         Ok(())
     }
 
-    pub fn update_many<'b,T>(&mut self, it: T, mode: UpdateMode) -> Result<(), Box<dyn std::error::Error>>
+    pub fn update_many<'b,T>(&self, it: T, mode: UpdateMode) -> Result<(), DbError>
     where
         T: Iterator<Item = &'b NodeRecord> + Clone,
     {
@@ -191,18 +235,12 @@ impl<'a> NodeTable<'a> {
                 match mode {
                     UpdateMode::MustCreate => {
                         if table.get(&rec.address)?.is_some() {
-                            return Err(Box::new(io::Error::new(
-                                io::ErrorKind::AlreadyExists,
-                                format!("Node {} already exists", rec.mac())
-                            )));
+                            return Err(DbError::AlreadyExists(format!("Node {} already exists", rec.mac())));
                         }
                     },
                     UpdateMode::MustExist => {
                         if table.get(&rec.address)?.is_none() {
-                            return Err(Box::new(io::Error::new(
-                                io::ErrorKind::NotFound,
-                                format!("Node {} does not exist", rec.mac())
-                            )));
+                            return Err(DbError::NotFound(format!("Node {} does not exist", rec.mac())));
                         }
                     },
                     UpdateMode::UpdateOrCreate => {}
@@ -284,7 +322,7 @@ pub trait DatabaseTable<T> {
     fn make_record_modified_event(&self, rec: Self::Record) -> Event;
 }
 
-impl<'a> DatabaseTable<redb::TableDefinition<'static, &'static NodeAddress, &'static RawValue>> for NodeTable<'a> {
+impl<'a> DatabaseTable<TableDefinition<'static, &'static NodeAddress, &'static RawValue>> for NodeTable<'a> {
     // type Key = &'static NodeAddress;
     type Record = NodeRecord;
     type Event = Event;
@@ -293,13 +331,13 @@ impl<'a> DatabaseTable<redb::TableDefinition<'static, &'static NodeAddress, &'st
         self.db
     }
 
-    fn table_definition(&self) -> redb::TableDefinition<'static,&'static NodeAddress, &'static RawValue>
+    fn table_definition(&self) -> TableDefinition<'static,&'static NodeAddress, &'static RawValue>
     {
         NODE_TABLE
     }
 
     /*
-    fn table_definition(&self) -> redb::TableDefinition<Self::Key,&RawValue>
+    fn table_definition(&self) -> TableDefinition<Self::Key,&RawValue>
     where
         Self::Key: redb::RedbKey
     {
@@ -342,7 +380,7 @@ pub trait TableOps<'a,Key,Value,Record: Clone> {
 
 pub fn x_update_many<'t,T,IT,Key,Record>(dt: T, it: IT, mode: UpdateMode) -> Result<(), Box<dyn std::error::Error>>
 where
-    T: Borrow<DatabaseTable<redb::TableDefinition<'t, &'t Key, &'t RawValue>>,
+    T: Borrow<DatabaseTable<TableDefinition<'t, &'t Key, &'t RawValue>>,
     IT: Iterator<Item = &'t Record> + Clone,
     Record: TableKey<Key> + Serialize + 't,
     Key: redb::RedbKey + 'static,
@@ -403,7 +441,7 @@ where
 #[cfg(kokot)]
 impl<'a,T,Key,Value,Record> TableOps<'a,Key,Value,Record> for T
 where
-    for<'t> T: DatabaseTable<redb::TableDefinition<'a, &'t Key, &'t Value>,Record=Record>,
+    for<'t> T: DatabaseTable<TableDefinition<'a, &'t Key, &'t Value>,Record=Record>,
     //for<'t> Key: redb::RedbKey + std::borrow::Borrow<Key::SelfType<'t>> + 't,
     for<'t> &'t Key: redb::RedbKey,
     for<'t> &'t Value: redb::RedbValue,
@@ -487,18 +525,16 @@ where
 
 #[cfg(test)]
 mod tests {
-    use std::{fs, path::PathBuf, str::FromStr};
-
     use futures::FutureExt;
     use ptnet::{M_DEV_ST, FW_Version_A, HW_Version_A, M_DEV_DC};
 
-    use crate::database::Database;
+    use crate::database::testing::{make_redb, make_db};
 
     use super::*;
 
     #[test]
     fn node_events() {
-        let rdb = make_redb();
+        let rdb = make_redb("db.redb");
         let db = make_db(&rdb);
         let mut rcvr = db.nodes.events.subscribe();
 
@@ -517,7 +553,8 @@ mod tests {
                     rev: 0x11,
                 },
             }),
-            device_descriptor: None
+            device_descriptor: None,
+            ..Default::default()
         };
 
         db.nodes.update(&rec.address, &rec, UpdateMode::MustCreate).expect("update_node shall succeeed");
@@ -545,17 +582,35 @@ mod tests {
         }
 
         assert!(rcvr.is_empty(), "Exactly one event should have been generated");
-    }
 
-    fn make_redb() -> redb::Database {
-        let pth = PathBuf::from_str("test-db.redb").unwrap();
-        fs::remove_file(&pth).unwrap_or_default();
-        redb::Database::create(&pth).unwrap()
+        let same_rec = rec.clone();
+        db.nodes.modify(&rec.address, |_| Some(same_rec)).unwrap();
+        assert!(rcvr.is_empty(), "Unchanged record shall not generate event");
+
+        // unknown address is skipped silently
+        db.nodes.remove_many([rec.address, [0; 6]].iter()).unwrap();
+
+        let evt = rcvr.recv().now_or_never().expect("Event shall arrive").unwrap();
+        if let Event::NodeRemoved(address) = evt {
+            assert_eq!(rec.address, address);
+        } else {
+            assert!(false, "NodeRemoved event not generated");
+        }
+
+        assert!(rcvr.is_empty(), "Exactly one event should have been generated");
     }
 
-    fn make_db<'a>(redb_db: &'a redb::Database) -> Database<'a> {
-        let mut db = Database::new(redb_db);
-        db.init().unwrap();
-        db
+    #[test]
+    fn discovered_nodes_wait_for_adoption() {
+        let rdb = make_redb("adopt-db.redb");
+        let db = make_db(&rdb);
+        let address: NodeAddress = [0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0xEF];
+
+        assert!(!db.discovered_node(&address).pending, "Nodes are adopted automatically by default");
+
+        db.set_auto_adopt(false);
+        let rec = db.discovered_node(&address);
+        assert_eq!(address, rec.address);
+        assert!(rec.pending);
     }
 }
@@ -0,0 +1,136 @@
+use std::{sync::Arc, collections::BTreeMap};
+
+use ptnet::IE;
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::error::DbError;
+
+use super::{NodeAddress, RawValue, storage::{Store, TableDefinition, ReadableTable}};
+
+pub(super) const PARAMETER_TABLE: TableDefinition<&NodeAddress, &RawValue> = TableDefinition::new("parameters");
+
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq)]
+pub enum ParameterState {
+    /// intended value not written yet
+    Pending,
+    /// written, not read back yet
+    Written,
+    /// device has intended value
+    InSync,
+    /// device value differs from intended one
+    Drifted,
+    /// device didn't accept value
+    Failed
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub struct Parameter {
+    /// information element type of point
+    pub ti: u8,
+    /// value device should have
+    pub intended: u32,
+    pub state: ParameterState,
+    /// value read from device at last check
+    pub actual: Option<IE>,
+    /// unix time of last successful write
+    pub written_at: Option<u64>,
+    /// unix time of last read back
+    pub checked_at: Option<u64>
+}
+
+impl Parameter {
+    pub fn new(ti: u8, intended: u32) -> Self {
+        Self {
+            ti: ti,
+            intended: intended,
+            state: ParameterState::Pending,
+            actual: None,
+            written_at: None,
+            checked_at: None
+        }
+    }
+}
+
+/// Intended device parameters of node, by IOA
+#[derive(Debug,Serialize,Deserialize,Clone,Default,PartialEq)]
+pub struct ParametersRecord {
+    pub params: BTreeMap<u32, Parameter>
+}
+
+#[derive(Clone)]
+pub enum Event {
+    ParametersModified(NodeAddress, Arc<ParametersRecord>)
+}
+
+pub struct ParameterTable<'a> {
+    db: &'a Store,
+    pub events: broadcast::Sender<Event>
+}
+
+impl<'a> ParameterTable<'a> {
+    pub fn new(db: &'a Store) -> Self {
+        let (evt_sender, _) = broadcast::channel::<Event>(128);
+
+        Self {
+            db: db,
+            events: evt_sender
+        }
+    }
+
+    pub fn get(&self, address: &NodeAddress) -> Result<ParametersRecord, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(PARAMETER_TABLE)?;
+
+        Ok(match table.get(address)? {
+            None => Default::default(),
+            Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+        })
+    }
+
+    pub fn list(&self) -> Result<Vec<(NodeAddress, ParametersRecord)>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(PARAMETER_TABLE)?;
+        let mut results = Vec::new();
+
+        for entry in table.iter()? {
+            let (address, cbor) = entry?;
+            results.push((*address.value(), serde_cbor::from_slice(cbor.value()).unwrap()));
+        }
+
+        Ok(results)
+    }
+
+    /// Modify parameters of node in callback, record without parameters is removed
+    pub fn modify<T>(&self, address: &NodeAddress, cb: T) -> Result<(), DbError>
+    where
+        T: FnOnce(ParametersRecord) -> Option<ParametersRecord>
+    {
+        let rec: ParametersRecord;
+        let txn = self.db.begin_write()?;
+
+        {
+            let mut table = txn.open_table(PARAMETER_TABLE)?;
+            let org_rec: ParametersRecord = match table.get(address)? {
+                None => Default::default(),
+                Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+            };
+
+            match cb(org_rec) {
+                None => return Ok(()),
+                Some(new_rec) => rec = new_rec
+            };
+
+            match rec.params.is_empty() {
+                true => { table.remove(address)?; },
+                false => { table.insert(address, serde_cbor::to_vec(&rec)?.as_slice())?; }
+            };
+        }
+
+        txn.commit()?;
+
+        self.events.send(Event::ParametersModified(*address, Arc::new(rec))).unwrap_or_default();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+use std::{sync::Arc, collections::BTreeMap};
+
+use ptnet::IE;
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::error::DbError;
+
+use super::{NodeAddress, RawValue, storage::{Store, TableDefinition, ReadableTable}};
+
+pub(super) const POINT_TABLE: TableDefinition<&NodeAddress, &RawValue> = TableDefinition::new("points");
+
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub struct Sample {
+    /// unix time sample was received
+    pub at: u64,
+    pub value: IE
+}
+
+/// Measurement series of node, by series name, oldest samples first
+#[derive(Debug,Serialize,Deserialize,Clone,Default,PartialEq)]
+pub struct PointsRecord {
+    pub series: BTreeMap<String, Vec<Sample>>
+}
+
+impl Sample {
+    /// First number found in JSON form of value, device values don't have common numeric accessor
+    pub fn number(&self) -> Option<f64> {
+        first_number(&serde_json::to_value(&self.value).ok()?)
+    }
+}
+
+fn first_number(value: &serde_json::Value) -> Option<f64> {
+    match value {
+        serde_json::Value::Number(n) => n.as_f64(),
+        serde_json::Value::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+        serde_json::Value::Object(fields) => fields.values().find_map(first_number),
+        serde_json::Value::Array(items) => items.iter().find_map(first_number),
+        _ => None
+    }
+}
+
+#[derive(Clone)]
+pub enum Event {
+    SampleAdded(NodeAddress, Arc<String>, Arc<Sample>)
+}
+
+pub struct PointTable<'a> {
+    db: &'a Store,
+    pub events: broadcast::Sender<Event>
+}
+
+impl<'a> PointTable<'a> {
+    pub fn new(db: &'a Store) -> Self {
+        let (evt_sender, _) = broadcast::channel::<Event>(128);
+
+        Self {
+            db: db,
+            events: evt_sender
+        }
+    }
+
+    pub fn get(&self, address: &NodeAddress) -> Result<PointsRecord, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(POINT_TABLE)?;
+
+        Ok(match table.get(address)? {
+            None => Default::default(),
+            Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+        })
+    }
+
+    /// Add sample to series of node in order of time, keeping at most `max_samples` newest samples
+    pub fn record(&self, address: &NodeAddress, series: &str, sample: Sample, max_samples: usize) -> Result<(), DbError> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(POINT_TABLE)?;
+            let mut rec: PointsRecord = match table.get(address)? {
+                None => Default::default(),
+                Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+            };
+
+            let samples = rec.series.entry(series.to_string()).or_default();
+            // backfilled samples are older than ones received live
+            let pos = samples.partition_point(|s| s.at <= sample.at);
+            samples.insert(pos, sample.clone());
+            if samples.len() > max_samples.max(1) {
+                samples.drain(..samples.len() - max_samples.max(1));
+            }
+
+            table.insert(address, serde_cbor::to_vec(&rec)?.as_slice())?;
+        }
+        txn.commit()?;
+
+        self.events.send(Event::SampleAdded(*address, Arc::new(series.to_string()), Arc::new(sample))).unwrap_or_default();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,104 @@
+use std::sync::Arc;
+
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::error::DbError;
+
+use super::{RawValue, storage::{Store, TableDefinition, ReadableTable}};
+
+pub(super) const PORT_TABLE: TableDefinition<i32, &RawValue> = TableDefinition::new("ports");
+
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq)]
+pub enum PortStatus {
+    /// traffic arrives on port
+    Up,
+    /// nothing heard on port recently
+    Silent
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub struct PortRecord {
+    pub port: i32,
+    pub status: PortStatus,
+    /// unix time port was discovered
+    pub first_seen: u64,
+    /// unix time of last frame received on port
+    pub last_heard: u64,
+    /// nodes last heard on this port
+    pub nodes: usize
+}
+
+#[derive(Clone)]
+pub enum Event {
+    PortModified(Arc<PortRecord>)
+}
+
+pub struct PortTable<'a> {
+    db: &'a Store,
+    pub events: broadcast::Sender<Event>
+}
+
+impl<'a> PortTable<'a> {
+    pub fn new(db: &'a Store) -> Self {
+        let (evt_sender, _) = broadcast::channel::<Event>(128);
+
+        Self {
+            db: db,
+            events: evt_sender
+        }
+    }
+
+    pub fn get(&self, port: i32) -> Result<Option<PortRecord>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(PORT_TABLE)?;
+
+        Ok(match table.get(port)? {
+            None => None,
+            Some(cbor) => Some(serde_cbor::from_slice(cbor.value()).unwrap())
+        })
+    }
+
+    pub fn list(&self) -> Result<Vec<PortRecord>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(PORT_TABLE)?;
+        let mut results: Vec<PortRecord> = Vec::new();
+
+        for entry in table.iter()? {
+            let (_, cbor) = entry?;
+            results.push(serde_cbor::from_slice(cbor.value()).unwrap());
+        }
+
+        Ok(results)
+    }
+
+    /// Modify port in callback, callback gets None for port not seen before
+    pub fn modify<T>(&self, port: i32, cb: T) -> Result<(), DbError>
+    where
+        T: FnOnce(Option<PortRecord>) -> Option<PortRecord>
+    {
+        let rec: PortRecord;
+        let txn = self.db.begin_write()?;
+
+        {
+            let mut table = txn.open_table(PORT_TABLE)?;
+            let org_rec: Option<PortRecord> = match table.get(port)? {
+                None => None,
+                Some(cbor) => Some(serde_cbor::from_slice(cbor.value()).unwrap())
+            };
+
+            match cb(org_rec) {
+                None => return Ok(()),
+                Some(new_rec) => rec = new_rec
+            };
+
+            table.insert(port, serde_cbor::to_vec(&rec)?.as_slice())?;
+        }
+
+        txn.commit()?;
+
+        self.events.send(Event::PortModified(Arc::new(rec))).unwrap_or_default();
+
+        Ok(())
+    }
+}
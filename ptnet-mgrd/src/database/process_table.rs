@@ -0,0 +1,124 @@
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::error::DbError;
+
+use super::{RawValue, unix_now, storage::{Store, TableDefinition, ReadableTable}};
+
+/// paused processes by name, process without record runs
+pub(super) const PROCESS_TABLE: TableDefinition<&str, &RawValue> = TableDefinition::new("processes");
+
+/// Process paused by administrator, kept paused across restarts until resumed
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub struct PausedRecord {
+    /// unix time of pausing
+    pub paused_at: u64,
+    /// why it was paused, e.g. RF site survey
+    pub reason: Option<String>
+}
+
+#[derive(Clone)]
+pub enum Event {
+    Paused(String),
+    Resumed(String)
+}
+
+pub struct ProcessTable<'a> {
+    db: &'a Store,
+    pub events: broadcast::Sender<Event>
+}
+
+impl<'a> ProcessTable<'a> {
+    pub fn new(db: &'a Store) -> Self {
+        let (evt_sender, _) = broadcast::channel::<Event>(16);
+
+        Self {
+            db: db,
+            events: evt_sender
+        }
+    }
+
+    /// Pause record of process, None if it runs
+    pub fn get(&self, name: &str) -> Result<Option<PausedRecord>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(PROCESS_TABLE)?;
+
+        Ok(match table.get(name)? {
+            None => None,
+            Some(cbor) => Some(serde_cbor::from_slice(cbor.value()).unwrap())
+        })
+    }
+
+    pub fn list(&self) -> Result<Vec<(String, PausedRecord)>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(PROCESS_TABLE)?;
+        let mut results: Vec<(String, PausedRecord)> = Vec::new();
+
+        for entry in table.iter()? {
+            let (name, cbor) = entry?;
+            results.push((name.value().to_string(), serde_cbor::from_slice(cbor.value()).unwrap()));
+        }
+
+        Ok(results)
+    }
+
+    /// Pause process, returns false if it already was paused
+    pub fn pause(&self, name: &str, reason: Option<String>) -> Result<bool, DbError> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(PROCESS_TABLE)?;
+
+            if table.get(name)?.is_some() {
+                return Ok(false);
+            }
+
+            let rec = PausedRecord { paused_at: unix_now(), reason: reason };
+            table.insert(name, serde_cbor::to_vec(&rec)?.as_slice())?;
+        }
+        txn.commit()?;
+
+        self.events.send(Event::Paused(name.to_string())).unwrap_or_default();
+
+        Ok(true)
+    }
+
+    /// Resume process, returns false if it wasn't paused
+    pub fn resume(&self, name: &str) -> Result<bool, DbError> {
+        let txn = self.db.begin_write()?;
+        let existed = txn.open_table(PROCESS_TABLE)?.remove(name)?.is_some();
+        txn.commit()?;
+
+        if existed {
+            self.events.send(Event::Resumed(name.to_string())).unwrap_or_default();
+        }
+
+        Ok(existed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use crate::database::testing::{make_redb, make_db};
+
+    use super::*;
+
+    #[test]
+    fn pause_resume() {
+        let rdb = make_redb("process-db.redb");
+        let db = make_db(&rdb);
+        let mut rcvr = db.processes.events.subscribe();
+
+        assert!(db.processes.pause("nodescan", Some("site survey".to_string())).unwrap());
+        assert!(!db.processes.pause("nodescan", None).unwrap());
+        assert_eq!(Some("site survey".to_string()), db.processes.get("nodescan").unwrap().unwrap().reason);
+        assert!(matches!(rcvr.recv().now_or_never(), Some(Ok(Event::Paused(name))) if name == "nodescan"));
+        assert!(rcvr.is_empty(), "Pausing paused process is no event");
+
+        assert!(db.processes.resume("nodescan").unwrap());
+        assert!(!db.processes.resume("nodescan").unwrap());
+        assert_eq!(None, db.processes.get("nodescan").unwrap());
+        assert!(matches!(rcvr.recv().now_or_never(), Some(Ok(Event::Resumed(name))) if name == "nodescan"));
+    }
+}
@@ -0,0 +1,51 @@
+use std::iter;
+
+use serde::Serialize;
+
+use crate::{fw_policy::Violation, site::{Labels, LabelFilter}, error::DbError};
+
+use super::{Database, NodeAddress, node_table::NodeRecord, fwu_state_table::FWUStateRecord, link_quality_table::LinkQuality};
+
+/// Everything known about a node, as returned by node queries
+#[derive(Debug,Serialize,Clone,PartialEq)]
+pub struct NodeInfo {
+    pub node: NodeRecord,
+    /// firmware update goal and progress
+    pub fwu_state: Option<FWUStateRecord>,
+    /// running firmware violates firmware policy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub policy_violation: Option<Violation>,
+    /// site node belongs to
+    pub site: String,
+    /// labels of site merged with labels of node
+    pub labels: Labels,
+    /// scan statistics, None until node is scanned
+    pub link_quality: Option<LinkQuality>
+}
+
+impl<'a> Database<'a> {
+    pub fn query_node(&self, address: &NodeAddress) -> Result<NodeInfo, DbError> {
+        let node = self.nodes.load_many(iter::once(address))?.remove(0);
+        let fwu_state = self.fwu_state.get(address)?;
+        let policy_violation = node.device_status.and_then(|st| self.fw_policy().violation(&st.fw_version.into()));
+        let site = self.site();
+        let labels = site.labels_of(&node.labels);
+        let link_quality = self.link_quality.get(address)?.map(|rec| rec.summary());
+
+        Ok(NodeInfo { node, fwu_state, policy_violation, site: site.id, labels, link_quality })
+    }
+
+    /// Nodes whose labels match filter, empty filter matches all
+    pub fn query_nodes(&self, filter: &LabelFilter) -> Result<Vec<NodeInfo>, DbError> {
+        let mut results = Vec::new();
+
+        for address in self.nodes.list()?.iter() {
+            let info = self.query_node(address)?;
+            if filter.matches(&info.labels, &info.node.tags) {
+                results.push(info);
+            }
+        }
+
+        Ok(results)
+    }
+}
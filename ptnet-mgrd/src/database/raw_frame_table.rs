@@ -0,0 +1,127 @@
+use std::sync::Arc;
+
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::error::DbError;
+
+use super::{NodeAddress, RawValue, storage::{Store, TableDefinition, ReadableTable}};
+
+pub(super) const RAW_FRAME_TABLE: TableDefinition<&NodeAddress, &RawValue> = TableDefinition::new("raw_frames");
+
+/// Frame of node which didn't decode
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub struct RawFrame {
+    /// unix time of reception
+    pub at: u64,
+    pub port: i32,
+    /// control field of link header
+    pub control: u8,
+    pub payload: Vec<u8>,
+    /// decoder error, e.g. unknown TI
+    pub reason: String
+}
+
+/// Undecodable frames of node
+#[derive(Debug,Serialize,Deserialize,Clone,Default,PartialEq)]
+pub struct RawFramesRecord {
+    /// oldest first
+    pub frames: Vec<RawFrame>
+}
+
+#[derive(Clone)]
+pub enum Event {
+    FrameCaptured(NodeAddress, Arc<RawFrame>),
+    FramesCleared(NodeAddress)
+}
+
+pub struct RawFrameTable<'a> {
+    db: &'a Store,
+    pub events: broadcast::Sender<Event>
+}
+
+impl<'a> RawFrameTable<'a> {
+    pub fn new(db: &'a Store) -> Self {
+        let (evt_sender, _) = broadcast::channel::<Event>(128);
+
+        Self {
+            db: db,
+            events: evt_sender
+        }
+    }
+
+    pub fn get(&self, address: &NodeAddress) -> Result<RawFramesRecord, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(RAW_FRAME_TABLE)?;
+
+        Ok(match table.get(address)? {
+            None => Default::default(),
+            Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+        })
+    }
+
+    /// Store frame, only last `max_frames` frames of node are kept
+    pub fn record(&self, address: &NodeAddress, frame: RawFrame, max_frames: usize) -> Result<(), DbError> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(RAW_FRAME_TABLE)?;
+            let mut rec: RawFramesRecord = match table.get(address)? {
+                None => Default::default(),
+                Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+            };
+
+            rec.frames.push(frame.clone());
+            let excess = rec.frames.len().saturating_sub(max_frames);
+            rec.frames.drain(..excess);
+
+            table.insert(address, serde_cbor::to_vec(&rec)?.as_slice())?;
+        }
+        txn.commit()?;
+
+        self.events.send(Event::FrameCaptured(*address, Arc::new(frame))).unwrap_or_default();
+
+        Ok(())
+    }
+
+    /// Forget frames of node, returns false if there were none
+    pub fn clear(&self, address: &NodeAddress) -> Result<bool, DbError> {
+        let txn = self.db.begin_write()?;
+        let removed = txn.open_table(RAW_FRAME_TABLE)?.remove(address)?.is_some();
+        txn.commit()?;
+
+        if removed {
+            self.events.send(Event::FramesCleared(*address)).unwrap_or_default();
+        }
+
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::testing::{make_redb, make_db};
+
+    use super::*;
+
+    fn frame(at: u64) -> RawFrame {
+        RawFrame { at: at, port: 1, control: 0x44, payload: vec![0x3E, 0x03, 0xFA], reason: "unknown TI 250".to_string() }
+    }
+
+    #[test]
+    fn keeps_last_frames() {
+        let rdb = make_redb("raw-frame-db.redb");
+        let db = make_db(&rdb);
+        let address: NodeAddress = [0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0xEF];
+
+        for at in 0..5 {
+            db.raw_frames.record(&address, frame(at), 3).unwrap();
+        }
+
+        let frames = db.raw_frames.get(&address).unwrap().frames;
+        assert_eq!(vec![2, 3, 4], frames.iter().map(|frame| frame.at).collect::<Vec<_>>());
+
+        assert!(db.raw_frames.clear(&address).unwrap());
+        assert!(!db.raw_frames.clear(&address).unwrap());
+        assert!(db.raw_frames.get(&address).unwrap().frames.is_empty());
+    }
+}
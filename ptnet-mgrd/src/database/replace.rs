@@ -0,0 +1,198 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::error::DbError;
+
+use super::{Database, NodeAddress, NodeAddr, node_table::{self, NodeRecord, NODE_TABLE}, fwu_state_table::{self, FWUStateRecord, FWU_STATE_TABLE}, fwu_history_table::FWU_HISTORY_TABLE, point_table::POINT_TABLE, health_table::HEALTH_TABLE, commissioning_table::COMMISSIONING_TABLE, energy_table::ENERGY_TABLE, route_table::ROUTE_TABLE, alarm_table::ALARM_TABLE, parameter_table::{self, ParametersRecord, ParameterState, PARAMETER_TABLE}, link_quality_table::LINK_QUALITY_TABLE, em_test_table::EM_TEST_TABLE, raw_frame_table::RAW_FRAME_TABLE, group_table::{self, GroupRecord, GROUP_TABLE}, schedule_table::{self, ScheduleRecord, SCHEDULE_TABLE}, storage::ReadableTable};
+
+/// What replacement of node took over from failed one
+#[derive(Debug,Serialize,Clone,PartialEq)]
+pub struct Replacement {
+    pub node: NodeRecord,
+    /// firmware update goal was transferred
+    pub fwu_goal: bool,
+    /// stored parameters, pushed to replacement if requested
+    pub parameters: usize,
+    /// groups and schedules now addressing replacement
+    pub groups: usize,
+    pub schedules: usize
+}
+
+impl<'a> Database<'a> {
+    /// Move identity, history and configuration of failed node to its replacement in one transaction.
+    /// Link state of replacement is kept, link state of failed node is dropped and its history
+    /// overrides history replacement gathered so far. SOL model has to be changed to new address too,
+    /// otherwise reconciliation orphans replacement.
+    pub fn replace_node(&self, old: &NodeAddress, new: &NodeAddress, push_parameters: bool) -> Result<Replacement, DbError> {
+        let txn = self.inner_db.begin_write()?;
+        let (rec, added, fwu_state, params, groups, schedules) = {
+            let mut nodes = txn.open_table(NODE_TABLE)?;
+            let old_rec: NodeRecord = match nodes.remove(old)? {
+                None => return Err(DbError::NotFound(format!("Node {} does not exist", NodeAddr(*old)))),
+                Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+            };
+            let new_rec: Option<NodeRecord> = nodes.get(new)?.map(|cbor| serde_cbor::from_slice(cbor.value()).unwrap());
+            let added = new_rec.is_none();
+            let new_rec = new_rec.unwrap_or_else(|| NodeRecord { address: *new, ..Default::default() });
+
+            let mut labels = old_rec.labels;
+            labels.extend(new_rec.labels.clone());
+            // model already naming replacement is newer than identity of failed node,
+            // replacing by operator adopts replacement
+            let rec = NodeRecord { model: new_rec.model.clone().or(old_rec.model), labels: labels, orphaned_at: None, pending: false, ..new_rec };
+            nodes.insert(new, serde_cbor::to_vec(&rec)?.as_slice())?;
+
+            for definition in [FWU_HISTORY_TABLE, POINT_TABLE, COMMISSIONING_TABLE, ENERGY_TABLE, EM_TEST_TABLE] {
+                let mut table = txn.open_table(definition)?;
+                let value = table.remove(old)?.map(|cbor| cbor.value().to_vec());
+                if let Some(value) = value {
+                    table.insert(new, value.as_slice())?;
+                }
+            }
+
+            // describe failed device, not its replacement
+            for definition in [HEALTH_TABLE, ROUTE_TABLE, ALARM_TABLE, LINK_QUALITY_TABLE, RAW_FRAME_TABLE] {
+                txn.open_table(definition)?.remove(old)?;
+            }
+
+            let mut fwu_table = txn.open_table(FWU_STATE_TABLE)?;
+            let old_state: Option<FWUStateRecord> = fwu_table.remove(old)?.map(|cbor| serde_cbor::from_slice(cbor.value()).unwrap());
+            let fwu_state = match old_state {
+                None => None,
+                Some(old_state) => {
+                    let mut state: FWUStateRecord = match fwu_table.get(new)? {
+                        None => Default::default(),
+                        Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+                    };
+                    state.goal = old_state.goal;
+                    state.rejected = old_state.rejected;
+                    fwu_table.insert(new, serde_cbor::to_vec(&state)?.as_slice())?;
+                    Some(state)
+                }
+            };
+
+            let mut param_table = txn.open_table(PARAMETER_TABLE)?;
+            let old_params: Option<ParametersRecord> = param_table.remove(old)?.map(|cbor| serde_cbor::from_slice(cbor.value()).unwrap());
+            let params = match old_params {
+                None => None,
+                Some(mut params) => {
+                    for param in params.params.values_mut() {
+                        // written ones are only read back, telling whether replacement has them already
+                        param.state = match push_parameters {
+                            true => ParameterState::Pending,
+                            false => ParameterState::Written
+                        };
+                        param.actual = None;
+                        param.written_at = None;
+                        param.checked_at = None;
+                    }
+                    param_table.insert(new, serde_cbor::to_vec(&params)?.as_slice())?;
+                    Some(params)
+                }
+            };
+
+            let mut group_table = txn.open_table(GROUP_TABLE)?;
+            let mut groups: Vec<GroupRecord> = Vec::new();
+            for entry in group_table.iter()? {
+                let group: GroupRecord = serde_cbor::from_slice(entry?.1.value()).unwrap();
+                if group.is_member(old) {
+                    groups.push(group);
+                }
+            }
+            for group in groups.iter_mut() {
+                let has_new = group.is_member(new);
+                group.members.retain(|member| !has_new || member.address != *old);
+                for member in group.members.iter_mut().filter(|member| member.address == *old) {
+                    // membership has to be set up on replacement
+                    member.address = *new;
+                    member.verified = None;
+                    member.checked_at = None;
+                }
+                group_table.insert(group.id, serde_cbor::to_vec(group)?.as_slice())?;
+            }
+
+            let mut schedule_table = txn.open_table(SCHEDULE_TABLE)?;
+            let mut schedules: Vec<ScheduleRecord> = Vec::new();
+            for entry in schedule_table.iter()? {
+                let schedule: ScheduleRecord = serde_cbor::from_slice(entry?.1.value()).unwrap();
+                if schedule.steps.iter().any(|step| step.addresses.contains(old)) {
+                    schedules.push(schedule);
+                }
+            }
+            for schedule in schedules.iter_mut() {
+                for address in schedule.steps.iter_mut().flat_map(|step| step.addresses.iter_mut()).filter(|address| **address == *old) {
+                    *address = *new;
+                }
+                schedule_table.insert(schedule.id, serde_cbor::to_vec(schedule)?.as_slice())?;
+            }
+
+            (rec, added, fwu_state, params, groups, schedules)
+        };
+        txn.commit()?;
+
+        self.nodes.events.send(node_table::Event::NodeRemoved(*old)).unwrap_or_default();
+        self.nodes.events.send(match added {
+            true => node_table::Event::NodeAdded(Arc::new(rec.clone())),
+            false => node_table::Event::NodeModified(Arc::new(rec.clone()))
+        }).unwrap_or_default();
+        if let Some(state) = &fwu_state {
+            self.fwu_state.events.send(fwu_state_table::Event::FWUStateModified(*new, Arc::new(state.clone()))).unwrap_or_default();
+        }
+        if let Some(params) = &params {
+            self.parameters.events.send(parameter_table::Event::ParametersModified(*new, Arc::new(params.clone()))).unwrap_or_default();
+        }
+        for group in &groups {
+            self.groups.events.send(group_table::Event::GroupModified(Arc::new(group.clone()))).unwrap_or_default();
+        }
+        for schedule in &schedules {
+            self.schedules.events.send(schedule_table::Event::ScheduleModified(Arc::new(schedule.clone()))).unwrap_or_default();
+        }
+
+        Ok(Replacement {
+            node: rec,
+            fwu_goal: fwu_state.is_some(),
+            parameters: params.map_or(0, |params| params.params.len()),
+            groups: groups.len(),
+            schedules: schedules.len()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::{testing::{make_redb, make_db}, UpdateMode, node_table::ModelInfo, fwu_state_table::Goal, parameter_table::Parameter, group_table::Member, em_test_table::TestKind};
+
+    use super::*;
+
+    const OLD: NodeAddress = [0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0xEF];
+    const NEW: NodeAddress = [0xFE, 0xED, 0xDE, 0xAF, 0x00, 0x01];
+
+    #[test]
+    fn transfers_to_replacement() {
+        let rdb = make_redb("replace-db.redb");
+        let db = make_db(&rdb);
+
+        let model = ModelInfo { name: "hall-1".to_string(), type_id: "ballast".to_string(), groups: vec![1] };
+        db.nodes.update(&OLD, &NodeRecord { address: OLD, model: Some(model.clone()), online: Some(false), ..Default::default() }, UpdateMode::MustCreate).unwrap();
+        db.nodes.update(&NEW, &NodeRecord { address: NEW, online: Some(true), ..Default::default() }, UpdateMode::MustCreate).unwrap();
+        db.fwu_state.modify(&OLD, |rec| Some(FWUStateRecord { goal: Goal::KeepCurrent, ..rec.unwrap_or_default() })).unwrap();
+        db.parameters.modify(&OLD, |mut rec| { rec.params.insert(0x200, Parameter { state: ParameterState::InSync, ..Parameter::new(48, 10) }); Some(rec) }).unwrap();
+        db.groups.modify(1, |mut rec| { rec.members.push(Member { address: OLD, verified: Some(true), checked_at: Some(0) }); Some(rec) }).unwrap();
+        db.em_tests.start(&OLD, TestKind::Function, 0).unwrap();
+
+        let replacement = db.replace_node(&OLD, &NEW, true).unwrap();
+        assert_eq!(Some(model), replacement.node.model);
+        assert_eq!(Some(true), replacement.node.online, "Link state of replacement is kept");
+        assert!(replacement.fwu_goal);
+        assert_eq!((1, 1, 0), (replacement.parameters, replacement.groups, replacement.schedules));
+
+        assert!(db.nodes.load_many([OLD].iter()).is_err());
+        assert_eq!(Goal::KeepCurrent, db.fwu_state.get(&NEW).unwrap().unwrap().goal);
+        assert_eq!(ParameterState::Pending, db.parameters.get(&NEW).unwrap().params[&0x200].state);
+        assert_eq!(vec![Member { address: NEW, verified: None, checked_at: None }], db.groups.get(1).unwrap().unwrap().members);
+        assert!(db.em_tests.get(&NEW).unwrap().pending.is_some());
+
+        assert!(db.replace_node(&OLD, &NEW, false).is_err(), "Failed node is gone");
+    }
+}
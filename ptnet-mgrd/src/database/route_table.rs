@@ -0,0 +1,103 @@
+use serde::{Serialize, Deserialize};
+
+use crate::error::DbError;
+
+use super::{NodeAddress, RawValue, storage::{Store, TableDefinition, ReadableTable}};
+
+pub(super) const ROUTE_TABLE: TableDefinition<&NodeAddress, &RawValue> = TableDefinition::new("routes");
+
+/// Port messages to node are sent to
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub struct RouteRecord {
+    /// last known good port
+    pub port: i32,
+    /// consecutive failed sends on `port`
+    pub failures: u32,
+    /// port node was pinned to before failing over, failback tries it again
+    pub home: Option<i32>,
+    /// unix time of fail over or of last failed failback
+    pub failed_over_at: Option<u64>
+}
+
+impl RouteRecord {
+    pub fn pinned(port: i32) -> Self {
+        Self {
+            port: port,
+            failures: 0,
+            home: None,
+            failed_over_at: None
+        }
+    }
+}
+
+pub struct RouteTable<'a> {
+    db: &'a Store
+}
+
+impl<'a> RouteTable<'a> {
+    pub fn new(db: &'a Store) -> Self {
+        Self {
+            db: db
+        }
+    }
+
+    pub fn get(&self, address: &NodeAddress) -> Result<Option<RouteRecord>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(ROUTE_TABLE)?;
+
+        Ok(match table.get(address)? {
+            None => None,
+            Some(cbor) => Some(serde_cbor::from_slice(cbor.value()).unwrap())
+        })
+    }
+
+    pub fn list(&self) -> Result<Vec<(NodeAddress, RouteRecord)>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(ROUTE_TABLE)?;
+        let mut results: Vec<(NodeAddress, RouteRecord)> = Vec::new();
+
+        for entry in table.iter()? {
+            let (address, cbor) = entry?;
+            results.push((address.value().clone(), serde_cbor::from_slice(cbor.value()).unwrap()));
+        }
+
+        Ok(results)
+    }
+
+    pub fn remove(&self, address: &NodeAddress) -> Result<(), DbError> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(ROUTE_TABLE)?;
+            table.remove(address)?;
+        }
+        txn.commit()?;
+
+        Ok(())
+    }
+
+    /// Modify route in callback, nothing is changed if callback returns None
+    pub fn modify<T>(&self, address: &NodeAddress, cb: T) -> Result<(), DbError>
+    where
+        T: FnOnce(Option<RouteRecord>) -> Option<RouteRecord>
+    {
+        let txn = self.db.begin_write()?;
+
+        {
+            let mut table = txn.open_table(ROUTE_TABLE)?;
+            let org_rec: Option<RouteRecord> = match table.get(address)? {
+                None => None,
+                Some(cbor) => Some(serde_cbor::from_slice(cbor.value()).unwrap())
+            };
+
+            match cb(org_rec.clone()) {
+                Some(rec) if Some(&rec) == org_rec.as_ref() => return Ok(()),
+                Some(rec) => { table.insert(address, serde_cbor::to_vec(&rec)?.as_slice())?; },
+                None => return Ok(())
+            };
+        }
+
+        txn.commit()?;
+
+        Ok(())
+    }
+}
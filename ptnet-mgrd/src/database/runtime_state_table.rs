@@ -0,0 +1,108 @@
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+
+use crate::error::DbError;
+
+use super::{RawValue, unix_now, storage::{Store, TableDefinition, ReadableTable}};
+
+/// cursors of processes by key, e.g. `nodescan.cursor`, so restarted daemon resumes where it stopped
+pub(super) const RUNTIME_STATE_TABLE: TableDefinition<&str, &RawValue> = TableDefinition::new("runtime_state");
+
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+struct StateRecord {
+    /// unix time of last update
+    updated_at: u64,
+    value: serde_cbor::Value
+}
+
+/// Runtime state as exposed by APIs
+#[derive(Debug,Serialize,Clone,PartialEq)]
+pub struct RuntimeState {
+    pub key: String,
+    pub updated_at: u64,
+    pub value: serde_cbor::Value
+}
+
+pub struct RuntimeStateTable<'a> {
+    db: &'a Store
+}
+
+impl<'a> RuntimeStateTable<'a> {
+    pub fn new(db: &'a Store) -> Self {
+        Self {
+            db: db
+        }
+    }
+
+    /// Stored state, None if there is none or it no longer deserializes as `T`
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(RUNTIME_STATE_TABLE)?;
+
+        Ok(match table.get(key)? {
+            None => None,
+            Some(cbor) => {
+                let rec: StateRecord = serde_cbor::from_slice(cbor.value()).unwrap();
+                // state of older version is dropped rather than failing process
+                serde_cbor::value::from_value(rec.value).ok()
+            }
+        })
+    }
+
+    pub fn set<T: Serialize>(&self, key: &str, value: &T) -> Result<(), DbError> {
+        let rec = StateRecord { updated_at: unix_now(), value: serde_cbor::value::to_value(value)? };
+
+        let txn = self.db.begin_write()?;
+        txn.open_table(RUNTIME_STATE_TABLE)?.insert(key, serde_cbor::to_vec(&rec)?.as_slice())?;
+        txn.commit()?;
+
+        Ok(())
+    }
+
+    pub fn list(&self) -> Result<Vec<RuntimeState>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(RUNTIME_STATE_TABLE)?;
+        let mut results: Vec<RuntimeState> = Vec::new();
+
+        for entry in table.iter()? {
+            let (key, cbor) = entry?;
+            let rec: StateRecord = serde_cbor::from_slice(cbor.value()).unwrap();
+            results.push(RuntimeState { key: key.value().to_string(), updated_at: rec.updated_at, value: rec.value });
+        }
+
+        Ok(results)
+    }
+
+    /// Forget state, e.g. to restart scan from first node; returns false if there was none
+    pub fn remove(&self, key: &str) -> Result<bool, DbError> {
+        let txn = self.db.begin_write()?;
+        let existed = txn.open_table(RUNTIME_STATE_TABLE)?.remove(key)?.is_some();
+        txn.commit()?;
+
+        Ok(existed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::{testing::{make_redb, make_db}, NodeAddress};
+
+    use super::*;
+
+    #[test]
+    fn cursors() {
+        let rdb = make_redb("runtime-state-db.redb");
+        let db = make_db(&rdb);
+        let cursor: NodeAddress = [0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0xEF];
+
+        assert_eq!(None, db.runtime_state.get::<NodeAddress>("nodescan.cursor").unwrap());
+        db.runtime_state.set("nodescan.cursor", &cursor).unwrap();
+        assert_eq!(Some(cursor), db.runtime_state.get::<NodeAddress>("nodescan.cursor").unwrap());
+        assert_eq!(None, db.runtime_state.get::<String>("nodescan.cursor").unwrap(), "Incompatible state is ignored");
+
+        let states = db.runtime_state.list().unwrap();
+        assert_eq!(vec!["nodescan.cursor"], states.iter().map(|state| state.key.as_str()).collect::<Vec<_>>());
+
+        assert!(db.runtime_state.remove("nodescan.cursor").unwrap());
+        assert!(!db.runtime_state.remove("nodescan.cursor").unwrap());
+    }
+}
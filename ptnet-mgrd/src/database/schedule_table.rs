@@ -0,0 +1,270 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Datelike, Duration, NaiveTime, TimeZone, Weekday};
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::{error::DbError, sun::{self, Location, SunEvent}};
+
+use super::{NodeAddress, RawValue, unix_now, storage::{Store, TableDefinition, ReadableTable}};
+
+pub(super) const SCHEDULE_TABLE: TableDefinition<u64, &RawValue> = TableDefinition::new("schedules");
+
+pub type ScheduleId = u64;
+
+/// When schedule runs on days it's active
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    /// at local time
+    At(NaiveTime),
+    /// at sun event at site location, shifted by offset (minutes)
+    Sun {
+        event: SunEvent,
+        #[serde(default)]
+        offset: i32
+    }
+}
+
+/// Command sent to nodes by schedule
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub struct ScheduleStep {
+    /// nodes to command, or
+    pub addresses: Vec<NodeAddress>,
+    /// nodes with these labels, `key=value[,key=value...]`, resolved on each run
+    pub label: Option<String>,
+    pub ioa: u32,
+    pub ti: u8,
+    pub value: u32,
+    /// select with given value first
+    pub select: Option<u32>
+}
+
+/// Outcome of last run of schedule
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub struct ScheduleRun {
+    /// unix time of run
+    pub at: u64,
+    /// node commands of all steps
+    pub succeeded: usize,
+    pub failed: usize
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub struct ScheduleRecord {
+    pub id: ScheduleId,
+    pub name: String,
+    pub trigger: Trigger,
+    /// days on which schedule runs, every day if empty
+    pub days: Vec<Weekday>,
+    /// executed in order
+    pub steps: Vec<ScheduleStep>,
+    pub enabled: bool,
+    pub last_run: Option<ScheduleRun>,
+    /// unix time of creation
+    pub created_at: u64
+}
+
+impl ScheduleRecord {
+    pub fn new(name: String, trigger: Trigger, steps: Vec<ScheduleStep>) -> Self {
+        Self {
+            id: 0,
+            name: name,
+            trigger: trigger,
+            days: Vec::new(),
+            steps: steps,
+            enabled: true,
+            last_run: None,
+            created_at: unix_now()
+        }
+    }
+
+    /// First run after given time, None if there is none within a week, e.g. sun event without location or in polar day
+    pub fn next_run<Tz: TimeZone>(&self, after: &DateTime<Tz>, location: Option<&Location>) -> Option<DateTime<Tz>> {
+        let tz = after.timezone();
+        let today = after.date_naive();
+
+        (0..8).map(|days| today + Duration::days(days))
+            .filter(|date| self.days.is_empty() || self.days.contains(&date.weekday()))
+            .filter_map(|date| match self.trigger {
+                Trigger::At(time) => {
+                    let local = date.and_time(time);
+                    // time skipped by DST change runs an hour later
+                    tz.from_local_datetime(&local).earliest()
+                        .or_else(|| tz.from_local_datetime(&(local + Duration::hours(1))).earliest())
+                },
+                Trigger::Sun { event, offset } => location
+                    .and_then(|location| sun::event_time(date, location, event))
+                    .map(|time| time.with_timezone(&tz) + Duration::minutes(offset as i64))
+            })
+            .find(|time| time > after)
+    }
+}
+
+#[derive(Clone)]
+pub enum Event {
+    ScheduleAdded(Arc<ScheduleRecord>),
+    ScheduleModified(Arc<ScheduleRecord>),
+    ScheduleRemoved(ScheduleId)
+}
+
+pub struct ScheduleTable<'a> {
+    db: &'a Store,
+    pub events: broadcast::Sender<Event>
+}
+
+impl<'a> ScheduleTable<'a> {
+    pub fn new(db: &'a Store) -> Self {
+        let (evt_sender, _) = broadcast::channel::<Event>(128);
+
+        Self {
+            db: db,
+            events: evt_sender
+        }
+    }
+
+    /// Store new schedule, id is assigned automatically
+    pub fn create(&self, mut rec: ScheduleRecord) -> Result<ScheduleId, DbError> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(SCHEDULE_TABLE)?;
+
+            rec.id = match table.iter()?.next_back() {
+                None => 1,
+                Some(entry) => entry?.0.value() + 1
+            };
+
+            table.insert(rec.id, serde_cbor::to_vec(&rec)?.as_slice())?;
+        }
+        txn.commit()?;
+
+        let id = rec.id;
+        self.events.send(Event::ScheduleAdded(Arc::new(rec))).unwrap_or_default();
+
+        Ok(id)
+    }
+
+    pub fn get(&self, id: ScheduleId) -> Result<Option<ScheduleRecord>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(SCHEDULE_TABLE)?;
+
+        Ok(match table.get(id)? {
+            None => None,
+            Some(cbor) => Some(serde_cbor::from_slice(cbor.value()).unwrap())
+        })
+    }
+
+    pub fn list(&self) -> Result<Vec<ScheduleRecord>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(SCHEDULE_TABLE)?;
+        let mut results: Vec<ScheduleRecord> = Vec::new();
+
+        for entry in table.iter()? {
+            let (_, cbor) = entry?;
+            results.push(serde_cbor::from_slice(cbor.value()).unwrap());
+        }
+
+        Ok(results)
+    }
+
+    /// Modify schedule in callback
+    pub fn modify<T>(&self, id: ScheduleId, cb: T) -> Result<(), DbError>
+    where
+        T: FnOnce(ScheduleRecord) -> Option<ScheduleRecord>
+    {
+        let rec: ScheduleRecord;
+        let txn = self.db.begin_write()?;
+
+        {
+            let mut table = txn.open_table(SCHEDULE_TABLE)?;
+            let org_rec: ScheduleRecord = match table.get(id)? {
+                None => return Err(DbError::NotFound(format!("Schedule {} does not exist", id))),
+                Some(cbor) => serde_cbor::from_slice(cbor.value()).unwrap()
+            };
+
+            match cb(org_rec) {
+                None => return Ok(()),
+                Some(new_rec) => rec = new_rec
+            };
+
+            table.insert(id, serde_cbor::to_vec(&rec)?.as_slice())?;
+        }
+
+        txn.commit()?;
+
+        self.events.send(Event::ScheduleModified(Arc::new(rec))).unwrap_or_default();
+
+        Ok(())
+    }
+
+    /// Remove schedule, returns false if it didn't exist
+    pub fn remove(&self, id: ScheduleId) -> Result<bool, DbError> {
+        let txn = self.db.begin_write()?;
+        let removed = txn.open_table(SCHEDULE_TABLE)?.remove(id)?.is_some();
+        txn.commit()?;
+
+        if removed {
+            self.events.send(Event::ScheduleRemoved(id)).unwrap_or_default();
+        }
+
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{FixedOffset, NaiveDate};
+
+    use crate::database::testing::{make_redb, make_db};
+
+    use super::*;
+
+    fn at(day: u32, h: u32, m: u32) -> DateTime<FixedOffset> {
+        // 2023-05-01 is monday
+        FixedOffset::east_opt(2 * 3600).unwrap()
+            .from_local_datetime(&NaiveDate::from_ymd_opt(2023, 5, day).unwrap().and_hms_opt(h, m, 0).unwrap())
+            .unwrap()
+    }
+
+    fn step() -> ScheduleStep {
+        ScheduleStep { addresses: Vec::new(), label: Some("zone=hall".to_string()), ioa: 1, ti: 45, value: 1, select: None }
+    }
+
+    #[test]
+    fn next_run() {
+        let mut rec = ScheduleRecord::new("lights off".to_string(), Trigger::At(NaiveTime::from_hms_opt(22, 0, 0).unwrap()), vec![step()]);
+
+        assert_eq!(Some(at(1, 22, 0)), rec.next_run(&at(1, 12, 0), None));
+        assert_eq!(Some(at(2, 22, 0)), rec.next_run(&at(1, 22, 0), None), "Run at given time is over");
+
+        rec.days = vec![Weekday::Sat, Weekday::Sun];
+        assert_eq!(Some(at(6, 22, 0)), rec.next_run(&at(1, 12, 0), None));
+
+        let prague = Location { latitude: 50.08, longitude: 14.42 };
+        rec.days = Vec::new();
+        rec.trigger = Trigger::Sun { event: SunEvent::Sunset, offset: -30 };
+        assert_eq!(None, rec.next_run(&at(1, 12, 0), None), "Sun events need location");
+
+        let run = rec.next_run(&at(1, 12, 0), Some(&prague)).unwrap();
+        // sunset in Prague is about 20:20 CEST in early May
+        assert!(run > at(1, 19, 40) && run < at(1, 20, 0), "unexpected run at {}", run);
+        assert!(rec.next_run(&at(1, 20, 0), Some(&prague)).unwrap() > at(2, 19, 40));
+    }
+
+    #[test]
+    fn create_modify_remove() {
+        let rdb = make_redb("schedule-db.redb");
+        let db = make_db(&rdb);
+
+        let id = db.schedules.create(ScheduleRecord::new("dusk".to_string(), Trigger::Sun { event: SunEvent::Dusk, offset: 0 }, vec![step()])).unwrap();
+        assert_eq!(id + 1, db.schedules.create(ScheduleRecord::new("dawn".to_string(), Trigger::Sun { event: SunEvent::Dawn, offset: 0 }, vec![step()])).unwrap());
+
+        db.schedules.modify(id, |mut rec| { rec.enabled = false; Some(rec) }).unwrap();
+        assert!(!db.schedules.get(id).unwrap().unwrap().enabled);
+        assert!(db.schedules.modify(100, Some).is_err());
+
+        assert!(db.schedules.remove(id).unwrap());
+        assert!(!db.schedules.remove(id).unwrap());
+        assert_eq!(1, db.schedules.list().unwrap().len());
+    }
+}
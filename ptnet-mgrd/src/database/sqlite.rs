@@ -0,0 +1,241 @@
+use std::path::Path;
+
+use rusqlite::{Connection, OptionalExtension, types::Value};
+use serde::Serialize;
+
+use crate::error::DbError;
+
+use super::{NodeAddress, NodeAddr, AddressFormat, ADDRESS_TABLES, NAME_TABLES, ID_TABLES, PORT_TABLE, GROUP_TABLE, JOURNAL_ACK_TABLE, storage::{Store, ReadableTable}};
+
+/// Rows copied per table
+#[derive(Debug,Clone,Serialize,PartialEq)]
+pub struct TableCopy {
+    pub table: &'static str,
+    pub rows: usize
+}
+
+/// Record as JSON for querying by SQLite JSON functions, None if it has no JSON form (e.g. map with integer keys)
+fn record_json(cbor: &[u8]) -> Option<String> {
+    let value: serde_cbor::Value = serde_cbor::from_slice(cbor).ok()?;
+    serde_json::to_string(&value).ok()
+}
+
+fn create_table(conn: &Connection, table: &str, key_type: &str) -> rusqlite::Result<()> {
+    conn.execute_batch(&format!(
+        "DROP TABLE IF EXISTS \"{0}\"; CREATE TABLE \"{0}\" (key {1} PRIMARY KEY, record BLOB NOT NULL, json TEXT);",
+        table, key_type
+    ))
+}
+
+fn insert_row(conn: &Connection, table: &str, key: Value, cbor: &[u8]) -> rusqlite::Result<()> {
+    conn.execute(
+        &format!("INSERT INTO \"{}\" (key, record, json) VALUES (?1, ?2, ?3)", table),
+        (key, cbor, record_json(cbor))
+    )?;
+
+    Ok(())
+}
+
+fn table_exists(conn: &Connection, table: &str) -> rusqlite::Result<bool> {
+    Ok(conn.query_row("SELECT name FROM sqlite_master WHERE type = 'table' AND name = ?1", [table], |row| row.get::<_, String>(0))
+        .optional()?
+        .is_some())
+}
+
+/// Rows of exported table, empty if file has no such table
+fn read_rows(conn: &Connection, table: &str) -> rusqlite::Result<Vec<(Value, Vec<u8>)>> {
+    if !table_exists(conn, table)? {
+        return Ok(Vec::new());
+    }
+
+    let mut stmt = conn.prepare(&format!("SELECT key, record FROM \"{}\"", table))?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, Value>(0)?, row.get::<_, Vec<u8>>(1)?)))?;
+    rows.collect()
+}
+
+fn integer_key(table: &str, key: Value) -> Result<i64, DbError> {
+    match key {
+        Value::Integer(id) => Ok(id),
+        other => Err(DbError::InvalidData(format!("{}: key {:?} isn't integer", table, other)))
+    }
+}
+
+fn text_key(table: &str, key: Value) -> Result<String, DbError> {
+    match key {
+        Value::Text(text) => Ok(text),
+        other => Err(DbError::InvalidData(format!("{}: key {:?} isn't text", table, other)))
+    }
+}
+
+/// Copy all tables into SQLite file, replacing tables already there. Each table has columns `key`
+/// (node addresses in full form), `record` (CBOR as stored) and `json` (record as JSON).
+/// Journal acknowledgements are stored as integer `record` without JSON.
+pub fn export(db: &Store, path: &Path) -> Result<Vec<TableCopy>, DbError> {
+    let mut conn = Connection::open(path)?;
+    let sql = conn.transaction()?;
+    let txn = db.begin_read()?;
+    let mut copies = Vec::new();
+
+    for (name, definition) in ADDRESS_TABLES {
+        create_table(&sql, name, "TEXT")?;
+        let mut rows = 0;
+        for entry in txn.open_table(definition)?.iter()? {
+            let (address, cbor) = entry?;
+            insert_row(&sql, name, Value::Text(NodeAddr(*address.value()).to_string_as(AddressFormat::Full)), cbor.value())?;
+            rows += 1;
+        }
+        copies.push(TableCopy { table: name, rows: rows });
+    }
+
+    for (name, definition) in NAME_TABLES {
+        create_table(&sql, name, "TEXT")?;
+        let mut rows = 0;
+        for entry in txn.open_table(definition)?.iter()? {
+            let (key, cbor) = entry?;
+            insert_row(&sql, name, Value::Text(key.value().to_string()), cbor.value())?;
+            rows += 1;
+        }
+        copies.push(TableCopy { table: name, rows: rows });
+    }
+
+    for (name, definition) in ID_TABLES {
+        create_table(&sql, name, "INTEGER")?;
+        let mut rows = 0;
+        for entry in txn.open_table(definition)?.iter()? {
+            let (id, cbor) = entry?;
+            insert_row(&sql, name, Value::Integer(id.value() as i64), cbor.value())?;
+            rows += 1;
+        }
+        copies.push(TableCopy { table: name, rows: rows });
+    }
+
+    create_table(&sql, "ports", "INTEGER")?;
+    let mut rows = 0;
+    for entry in txn.open_table(PORT_TABLE)?.iter()? {
+        let (port, cbor) = entry?;
+        insert_row(&sql, "ports", Value::Integer(port.value() as i64), cbor.value())?;
+        rows += 1;
+    }
+    copies.push(TableCopy { table: "ports", rows: rows });
+
+    create_table(&sql, "groups", "INTEGER")?;
+    let mut rows = 0;
+    for entry in txn.open_table(GROUP_TABLE)?.iter()? {
+        let (id, cbor) = entry?;
+        insert_row(&sql, "groups", Value::Integer(id.value() as i64), cbor.value())?;
+        rows += 1;
+    }
+    copies.push(TableCopy { table: "groups", rows: rows });
+
+    sql.execute_batch("DROP TABLE IF EXISTS journal_acks; CREATE TABLE journal_acks (key TEXT PRIMARY KEY, record INTEGER NOT NULL, json TEXT);")?;
+    let mut rows = 0;
+    for entry in txn.open_table(JOURNAL_ACK_TABLE)?.iter()? {
+        let (consumer, seq) = entry?;
+        sql.execute("INSERT INTO journal_acks (key, record) VALUES (?1, ?2)", (consumer.value(), seq.value() as i64))?;
+        rows += 1;
+    }
+    copies.push(TableCopy { table: "journal_acks", rows: rows });
+
+    sql.commit()?;
+    Ok(copies)
+}
+
+/// Copy tables of SQLite file made by `export()` into database in one transaction, records with
+/// keys already in database are overwritten. Daemon must not run on database being imported into.
+pub fn import(db: &Store, path: &Path) -> Result<Vec<TableCopy>, DbError> {
+    let conn = Connection::open(path)?;
+    let txn = db.begin_write()?;
+    let mut copies = Vec::new();
+
+    {
+        for (name, definition) in ADDRESS_TABLES {
+            let rows = read_rows(&conn, name)?;
+            let mut table = txn.open_table(definition)?;
+            for (key, cbor) in rows.iter() {
+                let address = text_key(name, key.clone())?.parse::<NodeAddr>()
+                    .map_err(|err| DbError::InvalidData(format!("{}: {}", name, err)))?;
+                table.insert(&address.0, cbor.as_slice())?;
+            }
+            copies.push(TableCopy { table: name, rows: rows.len() });
+        }
+
+        for (name, definition) in NAME_TABLES {
+            let rows = read_rows(&conn, name)?;
+            let mut table = txn.open_table(definition)?;
+            for (key, cbor) in rows.iter() {
+                table.insert(text_key(name, key.clone())?.as_str(), cbor.as_slice())?;
+            }
+            copies.push(TableCopy { table: name, rows: rows.len() });
+        }
+
+        for (name, definition) in ID_TABLES {
+            let rows = read_rows(&conn, name)?;
+            let mut table = txn.open_table(definition)?;
+            for (key, cbor) in rows.iter() {
+                table.insert(integer_key(name, key.clone())? as u64, cbor.as_slice())?;
+            }
+            copies.push(TableCopy { table: name, rows: rows.len() });
+        }
+
+        let rows = read_rows(&conn, "ports")?;
+        let mut table = txn.open_table(PORT_TABLE)?;
+        for (key, cbor) in rows.iter() {
+            table.insert(integer_key("ports", key.clone())? as i32, cbor.as_slice())?;
+        }
+        copies.push(TableCopy { table: "ports", rows: rows.len() });
+
+        let rows = read_rows(&conn, "groups")?;
+        let mut table = txn.open_table(GROUP_TABLE)?;
+        for (key, cbor) in rows.iter() {
+            table.insert(integer_key("groups", key.clone())? as u8, cbor.as_slice())?;
+        }
+        copies.push(TableCopy { table: "groups", rows: rows.len() });
+
+        let acks: Vec<(String, i64)> = match table_exists(&conn, "journal_acks")? {
+            true => conn.prepare("SELECT key, record FROM journal_acks")?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<_>>()?,
+            false => Vec::new()
+        };
+        let mut table = txn.open_table(JOURNAL_ACK_TABLE)?;
+        for (consumer, seq) in acks.iter() {
+            table.insert(consumer.as_str(), *seq as u64)?;
+        }
+        copies.push(TableCopy { table: "journal_acks", rows: acks.len() });
+    }
+
+    txn.commit()?;
+    Ok(copies)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::{UpdateMode, node_table::NodeRecord, testing::{make_redb, make_db, temp_path}};
+
+    use super::*;
+
+    #[test]
+    fn export_import() {
+        let src = make_redb("sqlite-src.redb");
+        let dst = make_redb("sqlite-dst.redb");
+        let path = temp_path("sqlite-export.sqlite");
+        let address: NodeAddress = [0, 0, 0xDE, 0xAD, 0xBE, 0xEF];
+
+        let src_db = make_db(&src);
+        src_db.nodes.update(&address, &NodeRecord { address: address, ..Default::default() }, UpdateMode::MustCreate).unwrap();
+        src_db.runtime_state.set("nodescan.cursor", &address).unwrap();
+
+        let copies = export(&src, &path).unwrap();
+        assert!(copies.contains(&TableCopy { table: "nodes", rows: 1 }));
+        assert!(copies.contains(&TableCopy { table: "runtime_state", rows: 1 }));
+
+        let conn = Connection::open(&path).unwrap();
+        let key: String = conn.query_row("SELECT key FROM nodes WHERE json_extract(json, '$.pending') = 0", [], |row| row.get(0)).unwrap();
+        assert_eq!("0x0:0x0:0xDE:0xAD:0xBE:0xEF", key);
+
+        import(&dst, &path).unwrap();
+        let dst_db = make_db(&dst);
+        assert_eq!(src_db.nodes.load_many([address].iter()).unwrap(), dst_db.nodes.load_many([address].iter()).unwrap());
+        assert_eq!(Some(address), dst_db.runtime_state.get::<NodeAddress>("nodescan.cursor").unwrap());
+    }
+}
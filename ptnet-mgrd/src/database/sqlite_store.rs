@@ -0,0 +1,190 @@
+use std::{ops::Bound, path::{Path, PathBuf}, sync::{Mutex, MutexGuard}, time::Duration};
+
+use rusqlite::{Connection, OptionalExtension};
+
+/// Database in SQLite file, one table per table of database with keys and values as blobs.
+/// Readers get own connection, so they see last commit while writer works (WAL mode).
+pub struct SqliteStore {
+    path: PathBuf,
+    /// connections of finished transactions
+    idle: Mutex<Vec<Connection>>,
+    /// writers queue here, as they do in redb
+    writer: Mutex<()>
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let conn = Self::connect(path)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            idle: Mutex::new(vec![conn]),
+            writer: Mutex::new(())
+        })
+    }
+
+    fn connect(path: &Path) -> rusqlite::Result<Connection> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = FULL;")?;
+        conn.busy_timeout(Duration::from_secs(10))?;
+        Ok(conn)
+    }
+
+    fn connection(&self) -> rusqlite::Result<Connection> {
+        match self.idle.lock().unwrap().pop() {
+            Some(conn) => Ok(conn),
+            None => Self::connect(&self.path)
+        }
+    }
+
+    pub fn begin_read(&self) -> rusqlite::Result<SqliteTxn<'_>> {
+        let conn = self.connection()?;
+        conn.execute_batch("BEGIN")?;
+
+        Ok(SqliteTxn {
+            store: self,
+            conn: Some(conn),
+            _writer: None,
+            finished: false
+        })
+    }
+
+    pub fn begin_write(&self) -> rusqlite::Result<SqliteTxn<'_>> {
+        let writer = self.writer.lock().unwrap();
+        let conn = self.connection()?;
+        conn.execute_batch("BEGIN IMMEDIATE")?;
+
+        Ok(SqliteTxn {
+            store: self,
+            conn: Some(conn),
+            _writer: Some(writer),
+            finished: false
+        })
+    }
+
+    /// Bytes of pages in use and of free pages
+    pub fn usage(&self) -> rusqlite::Result<(u64, u64)> {
+        let conn = self.connection()?;
+        let pragma = |name: &str| conn.query_row(&format!("PRAGMA {}", name), [], |row| row.get::<_, i64>(0));
+        let (page_size, pages, free) = (pragma("page_size")?, pragma("page_count")?, pragma("freelist_count")?);
+        self.idle.lock().unwrap().push(conn);
+
+        Ok(((page_size * (pages - free)) as u64, (page_size * free) as u64))
+    }
+
+    /// Rebuild file without free pages, waits for writers
+    pub fn vacuum(&self) -> rusqlite::Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        let conn = self.connection()?;
+        conn.execute_batch("VACUUM; PRAGMA wal_checkpoint(TRUNCATE);")?;
+        self.idle.lock().unwrap().push(conn);
+        Ok(())
+    }
+}
+
+/// Transaction on connection of store, rolled back unless committed
+pub struct SqliteTxn<'db> {
+    store: &'db SqliteStore,
+    conn: Option<Connection>,
+    _writer: Option<MutexGuard<'db, ()>>,
+    finished: bool
+}
+
+impl<'db> SqliteTxn<'db> {
+    fn conn(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+
+    pub fn open_table(&self, name: &'static str) -> rusqlite::Result<SqliteTable<'_>> {
+        Ok(SqliteTable {
+            conn: self.conn(),
+            name: name
+        })
+    }
+
+    pub fn create_table(&self, name: &'static str) -> rusqlite::Result<SqliteTable<'_>> {
+        self.conn().execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS \"{}\" (key BLOB PRIMARY KEY NOT NULL, value BLOB NOT NULL) WITHOUT ROWID",
+            name
+        ))?;
+        self.open_table(name)
+    }
+
+    pub fn commit(mut self) -> rusqlite::Result<()> {
+        self.conn().execute_batch("COMMIT")?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl<'db> Drop for SqliteTxn<'db> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            if !self.finished {
+                conn.execute_batch("ROLLBACK").unwrap_or_default();
+            }
+            self.store.idle.lock().unwrap().push(conn);
+        }
+    }
+}
+
+/// Table of transaction, keys are compared as bytes
+pub struct SqliteTable<'txn> {
+    conn: &'txn Connection,
+    name: &'static str
+}
+
+impl<'txn> SqliteTable<'txn> {
+    pub fn get(&self, key: &[u8]) -> rusqlite::Result<Option<Vec<u8>>> {
+        self.conn.prepare_cached(&format!("SELECT value FROM \"{}\" WHERE key = ?1", self.name))?
+            .query_row([key], |row| row.get(0))
+            .optional()
+    }
+
+    /// Returns previous value
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> rusqlite::Result<Option<Vec<u8>>> {
+        let old = self.get(key)?;
+        self.conn.prepare_cached(&format!("INSERT OR REPLACE INTO \"{}\" (key, value) VALUES (?1, ?2)", self.name))?
+            .execute([key, value])?;
+        Ok(old)
+    }
+
+    /// Returns removed value
+    pub fn remove(&mut self, key: &[u8]) -> rusqlite::Result<Option<Vec<u8>>> {
+        let old = self.get(key)?;
+        if old.is_some() {
+            self.conn.prepare_cached(&format!("DELETE FROM \"{}\" WHERE key = ?1", self.name))?
+                .execute([key])?;
+        }
+        Ok(old)
+    }
+
+    /// Entries with keys between bounds, in key order
+    pub fn range(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> rusqlite::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut conditions: Vec<String> = Vec::new();
+        let mut keys: Vec<Vec<u8>> = Vec::new();
+
+        for (bound, included, excluded) in [(start, ">=", ">"), (end, "<=", "<")] {
+            match bound {
+                Bound::Included(key) => { keys.push(key); conditions.push(format!("key {} ?{}", included, keys.len())); },
+                Bound::Excluded(key) => { keys.push(key); conditions.push(format!("key {} ?{}", excluded, keys.len())); },
+                Bound::Unbounded => {}
+            }
+        }
+
+        let filter = match conditions.is_empty() {
+            true => String::new(),
+            false => format!(" WHERE {}", conditions.join(" AND "))
+        };
+
+        let mut stmt = self.conn.prepare_cached(&format!("SELECT key, value FROM \"{}\"{} ORDER BY key", self.name, filter))?;
+        let rows = stmt.query_map(rusqlite::params_from_iter(keys.iter()), |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+
+    pub fn len(&self) -> rusqlite::Result<u64> {
+        self.conn.prepare_cached(&format!("SELECT COUNT(*) FROM \"{}\"", self.name))?
+            .query_row([], |row| row.get::<_, i64>(0))
+            .map(|count| count as u64)
+    }
+}
@@ -0,0 +1,535 @@
+use std::{borrow::Borrow, fmt, marker::PhantomData, ops::{Bound, RangeBounds}, path::Path, str::FromStr};
+
+use redb::{RedbKey, RedbValue, ReadableTable as _};
+use serde::{Serialize, Deserialize};
+
+use crate::error::DbError;
+
+#[cfg(feature = "sqlite")]
+use super::sqlite_store::{SqliteStore, SqliteTxn, SqliteTable};
+
+use super::NodeAddress;
+
+/// Where database file keeps records, tables work the same on both
+#[derive(Debug,Clone,Copy,Serialize,Deserialize,PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    Redb,
+    /// needs daemon built with feature `sqlite`
+    Sqlite
+}
+
+impl Default for StorageBackend {
+    fn default() -> Self { StorageBackend::Redb }
+}
+
+impl fmt::Display for StorageBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StorageBackend::Redb => "redb",
+            StorageBackend::Sqlite => "sqlite"
+        })
+    }
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "redb" => Ok(StorageBackend::Redb),
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            other => Err(format!("Unknown storage backend {}, use redb or sqlite", other))
+        }
+    }
+}
+
+/// Key of table. SQLite compares keys as bytes, so they are stored in encoding keeping order of redb.
+pub trait StorageKey: RedbKey {
+    fn ordered_bytes(value: &Self::SelfType<'_>) -> Vec<u8>;
+
+    fn from_ordered_bytes<'a>(bytes: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a;
+}
+
+impl StorageKey for &NodeAddress {
+    fn ordered_bytes(value: &Self::SelfType<'_>) -> Vec<u8> {
+        value.to_vec()
+    }
+
+    fn from_ordered_bytes<'a>(bytes: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a
+    {
+        <Self as RedbValue>::from_bytes(bytes)
+    }
+}
+
+impl StorageKey for &str {
+    fn ordered_bytes(value: &Self::SelfType<'_>) -> Vec<u8> {
+        value.as_bytes().to_vec()
+    }
+
+    fn from_ordered_bytes<'a>(bytes: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a
+    {
+        <Self as RedbValue>::from_bytes(bytes)
+    }
+}
+
+impl StorageKey for u64 {
+    fn ordered_bytes(value: &Self::SelfType<'_>) -> Vec<u8> {
+        value.to_be_bytes().to_vec()
+    }
+
+    fn from_ordered_bytes<'a>(bytes: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a
+    {
+        u64::from_be_bytes(bytes.try_into().unwrap())
+    }
+}
+
+impl StorageKey for u8 {
+    fn ordered_bytes(value: &Self::SelfType<'_>) -> Vec<u8> {
+        vec![*value]
+    }
+
+    fn from_ordered_bytes<'a>(bytes: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a
+    {
+        bytes[0]
+    }
+}
+
+impl StorageKey for i32 {
+    /// sign bit flipped, negative ports sort before positive ones
+    fn ordered_bytes(value: &Self::SelfType<'_>) -> Vec<u8> {
+        ((*value as u32) ^ 0x8000_0000).to_be_bytes().to_vec()
+    }
+
+    fn from_ordered_bytes<'a>(bytes: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a
+    {
+        (u32::from_be_bytes(bytes.try_into().unwrap()) ^ 0x8000_0000) as i32
+    }
+}
+
+/// Table of either backend, named as redb table
+pub struct TableDefinition<K, V> {
+    name: &'static str,
+    types: PhantomData<(K, V)>
+}
+
+impl<K, V> TableDefinition<K, V> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name: name,
+            types: PhantomData
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl<K: StorageKey + 'static, V: RedbValue + 'static> TableDefinition<K, V> {
+    fn redb(&self) -> redb::TableDefinition<'static, K, V> {
+        redb::TableDefinition::new(self.name)
+    }
+}
+
+impl<K, V> Clone for TableDefinition<K, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K, V> Copy for TableDefinition<K, V> {}
+
+/// Key or value read from table, borrowed from redb page or copied out of SQLite row
+pub enum AccessGuard<'a, V: RedbValue + 'static> {
+    Redb(redb::AccessGuard<'a, V>),
+    /// redb encoding of key or value
+    #[cfg(feature = "sqlite")]
+    Owned(Vec<u8>)
+}
+
+impl<'a, V: RedbValue + 'static> AccessGuard<'a, V> {
+    pub fn value(&self) -> V::SelfType<'_> {
+        match self {
+            AccessGuard::Redb(guard) => guard.value(),
+            #[cfg(feature = "sqlite")]
+            AccessGuard::Owned(bytes) => V::from_bytes(bytes)
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn owned_key<'a, K: StorageKey + 'static>(ordered: &[u8]) -> AccessGuard<'a, K> {
+    AccessGuard::Owned(K::as_bytes(&K::from_ordered_bytes(ordered)).as_ref().to_vec())
+}
+
+#[cfg(feature = "sqlite")]
+fn ordered_bound<'a, K: StorageKey + 'static, KR: Borrow<K::SelfType<'a>>>(bound: Bound<&KR>) -> Bound<Vec<u8>>
+where
+    K: 'a
+{
+    match bound {
+        Bound::Included(key) => Bound::Included(K::ordered_bytes(key.borrow())),
+        Bound::Excluded(key) => Bound::Excluded(K::ordered_bytes(key.borrow())),
+        Bound::Unbounded => Bound::Unbounded
+    }
+}
+
+/// Entries of table in key order, from both ends
+pub enum RangeIter<'a, K: StorageKey + 'static, V: RedbValue + 'static> {
+    Redb(redb::RangeIter<'a, K, V>),
+    /// ordered key and redb encoding of value
+    #[cfg(feature = "sqlite")]
+    Rows(std::vec::IntoIter<(Vec<u8>, Vec<u8>)>)
+}
+
+impl<'a, K: StorageKey + 'static, V: RedbValue + 'static> Iterator for RangeIter<'a, K, V> {
+    type Item = Result<(AccessGuard<'a, K>, AccessGuard<'a, V>), DbError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            RangeIter::Redb(iter) => iter.next().map(|entry| match entry {
+                Ok((key, value)) => Ok((AccessGuard::Redb(key), AccessGuard::Redb(value))),
+                Err(err) => Err(err.into())
+            }),
+            #[cfg(feature = "sqlite")]
+            RangeIter::Rows(rows) => rows.next().map(|(key, value)| Ok((owned_key::<K>(&key), AccessGuard::Owned(value))))
+        }
+    }
+}
+
+impl<'a, K: StorageKey + 'static, V: RedbValue + 'static> DoubleEndedIterator for RangeIter<'a, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        match self {
+            RangeIter::Redb(iter) => iter.next_back().map(|entry| match entry {
+                Ok((key, value)) => Ok((AccessGuard::Redb(key), AccessGuard::Redb(value))),
+                Err(err) => Err(err.into())
+            }),
+            #[cfg(feature = "sqlite")]
+            RangeIter::Rows(rows) => rows.next_back().map(|(key, value)| Ok((owned_key::<K>(&key), AccessGuard::Owned(value))))
+        }
+    }
+}
+
+/// Reading part of table API, same for tables of read and write transactions
+pub trait ReadableTable<K: StorageKey + 'static, V: RedbValue + 'static> {
+    fn get<'a>(&self, key: impl Borrow<K::SelfType<'a>>) -> Result<Option<AccessGuard<V>>, DbError>
+    where
+        K: 'a;
+
+    fn range<'a, KR>(&self, range: impl RangeBounds<KR> + 'a) -> Result<RangeIter<K, V>, DbError>
+    where
+        K: 'a,
+        KR: Borrow<K::SelfType<'a>> + 'a;
+
+    fn len(&self) -> Result<u64, DbError>;
+
+    fn is_empty(&self) -> Result<bool, DbError> {
+        Ok(self.len()? == 0)
+    }
+
+    fn iter(&self) -> Result<RangeIter<K, V>, DbError> {
+        self.range::<K::SelfType<'_>>(..)
+    }
+}
+
+/// Table opened in write transaction
+pub enum Table<'db, 'txn, K: StorageKey + 'static, V: RedbValue + 'static> {
+    Redb(redb::Table<'db, 'txn, K, V>),
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqliteTable<'txn>, PhantomData<(K, V)>)
+}
+
+impl<'db, 'txn, K: StorageKey + 'static, V: RedbValue + 'static> Table<'db, 'txn, K, V> {
+    /// Returns previous value of key
+    pub fn insert<'a>(&mut self, key: impl Borrow<K::SelfType<'a>>, value: impl Borrow<V::SelfType<'a>>) -> Result<Option<AccessGuard<V>>, DbError>
+    where
+        K: 'a,
+        V: 'a
+    {
+        match self {
+            Table::Redb(table) => Ok(table.insert(key, value)?.map(AccessGuard::Redb)),
+            #[cfg(feature = "sqlite")]
+            Table::Sqlite(table, _) => Ok(table.insert(&K::ordered_bytes(key.borrow()), V::as_bytes(value.borrow()).as_ref())?.map(AccessGuard::Owned))
+        }
+    }
+
+    /// Returns removed value, None if there was no such key
+    pub fn remove<'a>(&mut self, key: impl Borrow<K::SelfType<'a>>) -> Result<Option<AccessGuard<V>>, DbError>
+    where
+        K: 'a
+    {
+        match self {
+            Table::Redb(table) => Ok(table.remove(key)?.map(AccessGuard::Redb)),
+            #[cfg(feature = "sqlite")]
+            Table::Sqlite(table, _) => Ok(table.remove(&K::ordered_bytes(key.borrow()))?.map(AccessGuard::Owned))
+        }
+    }
+}
+
+impl<'db, 'txn, K: StorageKey + 'static, V: RedbValue + 'static> ReadableTable<K, V> for Table<'db, 'txn, K, V> {
+    fn get<'a>(&self, key: impl Borrow<K::SelfType<'a>>) -> Result<Option<AccessGuard<V>>, DbError>
+    where
+        K: 'a
+    {
+        match self {
+            Table::Redb(table) => Ok(table.get(key)?.map(AccessGuard::Redb)),
+            #[cfg(feature = "sqlite")]
+            Table::Sqlite(table, _) => Ok(table.get(&K::ordered_bytes(key.borrow()))?.map(AccessGuard::Owned))
+        }
+    }
+
+    fn range<'a, KR>(&self, range: impl RangeBounds<KR> + 'a) -> Result<RangeIter<K, V>, DbError>
+    where
+        K: 'a,
+        KR: Borrow<K::SelfType<'a>> + 'a
+    {
+        match self {
+            Table::Redb(table) => Ok(RangeIter::Redb(table.range(range)?)),
+            #[cfg(feature = "sqlite")]
+            Table::Sqlite(table, _) => Ok(RangeIter::Rows(table.range(ordered_bound::<K, KR>(range.start_bound()), ordered_bound::<K, KR>(range.end_bound()))?.into_iter()))
+        }
+    }
+
+    fn len(&self) -> Result<u64, DbError> {
+        match self {
+            Table::Redb(table) => Ok(table.len()? as u64),
+            #[cfg(feature = "sqlite")]
+            Table::Sqlite(table, _) => Ok(table.len()?)
+        }
+    }
+}
+
+/// Table opened in read transaction
+pub enum ReadOnlyTable<'txn, K: StorageKey + 'static, V: RedbValue + 'static> {
+    Redb(redb::ReadOnlyTable<'txn, K, V>),
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqliteTable<'txn>, PhantomData<(K, V)>)
+}
+
+impl<'txn, K: StorageKey + 'static, V: RedbValue + 'static> ReadableTable<K, V> for ReadOnlyTable<'txn, K, V> {
+    fn get<'a>(&self, key: impl Borrow<K::SelfType<'a>>) -> Result<Option<AccessGuard<V>>, DbError>
+    where
+        K: 'a
+    {
+        match self {
+            ReadOnlyTable::Redb(table) => Ok(table.get(key)?.map(AccessGuard::Redb)),
+            #[cfg(feature = "sqlite")]
+            ReadOnlyTable::Sqlite(table, _) => Ok(table.get(&K::ordered_bytes(key.borrow()))?.map(AccessGuard::Owned))
+        }
+    }
+
+    fn range<'a, KR>(&self, range: impl RangeBounds<KR> + 'a) -> Result<RangeIter<K, V>, DbError>
+    where
+        K: 'a,
+        KR: Borrow<K::SelfType<'a>> + 'a
+    {
+        match self {
+            ReadOnlyTable::Redb(table) => Ok(RangeIter::Redb(table.range(range)?)),
+            #[cfg(feature = "sqlite")]
+            ReadOnlyTable::Sqlite(table, _) => Ok(RangeIter::Rows(table.range(ordered_bound::<K, KR>(range.start_bound()), ordered_bound::<K, KR>(range.end_bound()))?.into_iter()))
+        }
+    }
+
+    fn len(&self) -> Result<u64, DbError> {
+        match self {
+            ReadOnlyTable::Redb(table) => Ok(table.len()? as u64),
+            #[cfg(feature = "sqlite")]
+            ReadOnlyTable::Sqlite(table, _) => Ok(table.len()?)
+        }
+    }
+}
+
+pub enum ReadTransaction<'db> {
+    Redb(redb::ReadTransaction<'db>),
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqliteTxn<'db>)
+}
+
+impl<'db> ReadTransaction<'db> {
+    pub fn open_table<K: StorageKey + 'static, V: RedbValue + 'static>(&self, definition: TableDefinition<K, V>) -> Result<ReadOnlyTable<K, V>, DbError> {
+        match self {
+            ReadTransaction::Redb(txn) => Ok(ReadOnlyTable::Redb(txn.open_table(definition.redb())?)),
+            #[cfg(feature = "sqlite")]
+            ReadTransaction::Sqlite(txn) => Ok(ReadOnlyTable::Sqlite(txn.open_table(definition.name())?, PhantomData))
+        }
+    }
+}
+
+/// Changes are dropped unless transaction is committed
+pub enum WriteTransaction<'db> {
+    Redb(redb::WriteTransaction<'db>),
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqliteTxn<'db>)
+}
+
+impl<'db> WriteTransaction<'db> {
+    /// Table is created if it doesn't exist
+    pub fn open_table<'txn, K: StorageKey + 'static, V: RedbValue + 'static>(&'txn self, definition: TableDefinition<K, V>) -> Result<Table<'db, 'txn, K, V>, DbError> {
+        match self {
+            WriteTransaction::Redb(txn) => Ok(Table::Redb(txn.open_table(definition.redb())?)),
+            #[cfg(feature = "sqlite")]
+            WriteTransaction::Sqlite(txn) => Ok(Table::Sqlite(txn.create_table(definition.name())?, PhantomData))
+        }
+    }
+
+    pub fn commit(self) -> Result<(), DbError> {
+        match self {
+            WriteTransaction::Redb(txn) => Ok(txn.commit()?),
+            #[cfg(feature = "sqlite")]
+            WriteTransaction::Sqlite(txn) => Ok(txn.commit()?)
+        }
+    }
+
+    pub fn abort(self) -> Result<(), DbError> {
+        match self {
+            WriteTransaction::Redb(txn) => Ok(txn.abort()?),
+            #[cfg(feature = "sqlite")]
+            WriteTransaction::Sqlite(_) => Ok(())
+        }
+    }
+}
+
+/// Database file of configured backend
+pub enum Store {
+    Redb(redb::Database),
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqliteStore)
+}
+
+impl Store {
+    /// Open database file, created if it doesn't exist
+    pub fn open(backend: StorageBackend, path: &Path) -> Result<Self, DbError> {
+        match backend {
+            StorageBackend::Redb => Ok(Store::Redb(redb::Database::create(path)?)),
+            #[cfg(feature = "sqlite")]
+            StorageBackend::Sqlite => Ok(Store::Sqlite(SqliteStore::open(path)?)),
+            #[cfg(not(feature = "sqlite"))]
+            StorageBackend::Sqlite => Err(DbError::Unsupported("SQLite storage needs daemon built with feature sqlite".to_string()))
+        }
+    }
+
+    pub fn backend(&self) -> StorageBackend {
+        match self {
+            Store::Redb(_) => StorageBackend::Redb,
+            #[cfg(feature = "sqlite")]
+            Store::Sqlite(_) => StorageBackend::Sqlite
+        }
+    }
+
+    pub fn begin_read(&self) -> Result<ReadTransaction<'_>, DbError> {
+        match self {
+            Store::Redb(db) => Ok(ReadTransaction::Redb(db.begin_read()?)),
+            #[cfg(feature = "sqlite")]
+            Store::Sqlite(db) => Ok(ReadTransaction::Sqlite(db.begin_read()?))
+        }
+    }
+
+    /// Blocks while other write transaction is open
+    pub fn begin_write(&self) -> Result<WriteTransaction<'_>, DbError> {
+        match self {
+            Store::Redb(db) => Ok(WriteTransaction::Redb(db.begin_write()?)),
+            #[cfg(feature = "sqlite")]
+            Store::Sqlite(db) => Ok(WriteTransaction::Sqlite(db.begin_write()?))
+        }
+    }
+
+    /// Bytes of records and free space inside file, blocks writers for a moment
+    pub fn usage(&self) -> Result<(u64, u64), DbError> {
+        match self {
+            Store::Redb(db) => {
+                let txn = db.begin_write()?;
+                let stats = txn.stats()?;
+                txn.abort()?;
+                Ok((stats.stored_bytes(), stats.fragmented_bytes()))
+            },
+            #[cfg(feature = "sqlite")]
+            Store::Sqlite(db) => Ok(db.usage()?)
+        }
+    }
+
+    /// Rebuild SQLite file without free pages, redb file is compacted by copying it
+    pub fn vacuum(&self) -> Result<(), DbError> {
+        match self {
+            Store::Redb(_) => Err(DbError::Unsupported("redb database is compacted by copying".to_string())),
+            #[cfg(feature = "sqlite")]
+            Store::Sqlite(db) => Ok(db.vacuum()?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::testing::make_redb;
+    #[cfg(feature = "sqlite")]
+    use crate::database::testing::make_sqlite;
+
+    use super::*;
+
+    const IDS: TableDefinition<u64, &[u8]> = TableDefinition::new("ids");
+    const PORTS: TableDefinition<i32, &[u8]> = TableDefinition::new("ports");
+    const NAMES: TableDefinition<&str, u64> = TableDefinition::new("names");
+
+    fn table_ops(store: &Store) {
+        let txn = store.begin_write().unwrap();
+        {
+            let mut ids = txn.open_table(IDS).unwrap();
+            for id in [3u64, 1, 256, 2] {
+                assert!(ids.insert(id, [id as u8].as_slice()).unwrap().is_none());
+            }
+            assert_eq!(Some(vec![1u8]), ids.insert(1, [10u8].as_slice()).unwrap().map(|old| old.value().to_vec()));
+            assert!(ids.remove(2).unwrap().is_some());
+            assert!(ids.remove(2).unwrap().is_none());
+
+            let mut ports = txn.open_table(PORTS).unwrap();
+            for port in [1, -1, 0, i32::MIN] {
+                ports.insert(port, [].as_slice()).unwrap();
+            }
+
+            txn.open_table(NAMES).unwrap().insert("a", 7).unwrap();
+        }
+        txn.commit().unwrap();
+
+        let aborted = store.begin_write().unwrap();
+        aborted.open_table(NAMES).unwrap().insert("b", 8).unwrap();
+        drop(aborted);
+
+        let txn = store.begin_read().unwrap();
+        let ids = txn.open_table(IDS).unwrap();
+        assert_eq!(3, ids.len().unwrap());
+        assert_eq!(Some(vec![10u8]), ids.get(1).unwrap().map(|value| value.value().to_vec()));
+        assert_eq!(vec![1, 3, 256], ids.iter().unwrap().map(|entry| entry.unwrap().0.value()).collect::<Vec<u64>>());
+        assert_eq!(vec![3, 256], ids.range(2..).unwrap().map(|entry| entry.unwrap().0.value()).collect::<Vec<u64>>());
+        assert_eq!(256, ids.iter().unwrap().next_back().unwrap().unwrap().0.value());
+
+        let ports = txn.open_table(PORTS).unwrap();
+        assert_eq!(vec![i32::MIN, -1, 0, 1], ports.iter().unwrap().map(|entry| entry.unwrap().0.value()).collect::<Vec<i32>>());
+
+        let names = txn.open_table(NAMES).unwrap();
+        assert_eq!(Some(7), names.get("a").unwrap().map(|value| value.value()));
+        assert!(names.get("b").unwrap().is_none(), "Dropped transaction shall not be committed");
+    }
+
+    #[test]
+    fn redb_tables() {
+        table_ops(&make_redb("storage-redb.redb"));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_tables() {
+        table_ops(&make_sqlite("storage-sqlite.sqlite"));
+    }
+}
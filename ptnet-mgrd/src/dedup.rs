@@ -0,0 +1,103 @@
+use std::{collections::{HashMap, VecDeque, hash_map::DefaultHasher}, hash::{Hash, Hasher}, time::{Duration, Instant}};
+
+use serde::{Serialize, Deserialize};
+
+use crate::database::NodeAddress;
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct DedupConfig {
+    /// same frame from same node arriving again within this time is dropped (milliseconds), disabled if 0
+    pub window: u64,
+    /// max. frames remembered, oldest are forgotten first
+    pub capacity: usize
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            window: 500,
+            capacity: 1024
+        }
+    }
+}
+
+type FrameKey = (NodeAddress, u64);
+
+/// Drops frames radios delivered twice
+pub struct DuplicateFilter {
+    window: Duration,
+    capacity: usize,
+    /// when frame was last seen
+    seen: HashMap<FrameKey, Instant>,
+    /// frames in order of arrival, for expiry
+    order: VecDeque<(FrameKey, Instant)>
+}
+
+impl DuplicateFilter {
+    pub fn new(conf: &DedupConfig) -> Self {
+        Self {
+            window: Duration::from_millis(conf.window),
+            capacity: conf.capacity,
+            seen: HashMap::new(),
+            order: VecDeque::new()
+        }
+    }
+
+    /// Remember frame, true if the same one was seen within window
+    pub fn is_duplicate(&mut self, address: &NodeAddress, control: u8, payload: &[u8], now: Instant) -> bool {
+        if self.window.is_zero() {
+            return false;
+        }
+
+        while let Some((key, at)) = self.order.front() {
+            if now.duration_since(*at) < self.window && self.order.len() < self.capacity.max(1) {
+                break;
+            }
+
+            // entry may have been refreshed by later arrival
+            if self.seen.get(key) == Some(at) {
+                self.seen.remove(key);
+            }
+            self.order.pop_front();
+        }
+
+        let mut hasher = DefaultHasher::new();
+        control.hash(&mut hasher);
+        payload.hash(&mut hasher);
+        let key: FrameKey = (*address, hasher.finish());
+
+        // window counts from first arrival, repeated copies don't extend it
+        if self.seen.get(&key).map_or(false, |at| now.duration_since(*at) < self.window) {
+            return true;
+        }
+
+        self.seen.insert(key, now);
+        self.order.push_back((key, now));
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window() {
+        let mut filter = DuplicateFilter::new(&DedupConfig { window: 100, capacity: 16 });
+        let a: NodeAddress = [0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0xEF];
+        let b: NodeAddress = [0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0xF0];
+        let t = Instant::now();
+
+        assert!(!filter.is_duplicate(&a, 0x44, &[1, 2, 3], t));
+        assert!(filter.is_duplicate(&a, 0x44, &[1, 2, 3], t + Duration::from_millis(50)));
+        assert!(!filter.is_duplicate(&b, 0x44, &[1, 2, 3], t + Duration::from_millis(50)), "other node");
+        assert!(!filter.is_duplicate(&a, 0x44, &[1, 2, 4], t + Duration::from_millis(50)), "other payload");
+        assert!(!filter.is_duplicate(&a, 0x44, &[1, 2, 3], t + Duration::from_millis(200)), "window passed");
+
+        let mut disabled = DuplicateFilter::new(&DedupConfig { window: 0, ..Default::default() });
+        assert!(!disabled.is_duplicate(&a, 0x44, &[1], t));
+        assert!(!disabled.is_duplicate(&a, 0x44, &[1], t));
+    }
+}
@@ -0,0 +1,170 @@
+use std::{io, fmt};
+
+use thiserror::Error;
+use tokio::sync::broadcast;
+
+use crate::{ptnet_process::{SendError, CommandError}, site::LabelFilterError};
+
+/// Failure of link dispatcher or process, matchable by where it happened
+#[derive(Debug,Error)]
+pub enum PtnetMgrError {
+    #[error(transparent)]
+    Db(#[from] DbError),
+    #[error(transparent)]
+    Link(#[from] LinkError),
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    #[error(transparent)]
+    Fwu(#[from] FwuError),
+    /// message not delivered to node
+    #[error(transparent)]
+    Send(#[from] SendError),
+    #[error(transparent)]
+    Command(#[from] CommandError),
+    /// process fell behind its event channel or the channel closed
+    #[error("Event channel failure ({0})")]
+    Channel(#[from] broadcast::error::RecvError),
+    /// label filter selecting nodes of campaign or schedule step
+    #[error(transparent)]
+    Filter(#[from] LabelFilterError),
+    /// nodes didn't answer request
+    #[error("No response from nodes")]
+    NoResponse,
+    /// SOL model can't be read
+    #[error("Model can't be loaded ({0})")]
+    Model(io::Error)
+}
+
+#[derive(Debug,Error)]
+pub enum DbError {
+    #[error("Database failure ({0})")]
+    Storage(#[from] redb::Error),
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite database failure ({0})")]
+    Sqlite(#[from] rusqlite::Error),
+    /// database file or SQLite export can't be read or written
+    #[error("Database file failure ({0})")]
+    Io(#[from] io::Error),
+    /// storage backend not built into daemon
+    #[error("{0}")]
+    Unsupported(String),
+    /// imported row doesn't fit table
+    #[error("{0}")]
+    InvalidData(String),
+    #[error("Record can't be encoded ({0})")]
+    Encoding(#[from] serde_cbor::Error),
+    /// node, campaign or group
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    AlreadyExists(String)
+}
+
+/// Connection to ptlink server
+#[derive(Debug,Error)]
+pub enum LinkError {
+    #[error("Connection to ptlink server failed ({0})")]
+    Io(#[from] io::Error),
+    /// group-addressed message expecting confirmation or sent to port not allowing broadcasts
+    #[error("Broadcast refused ({0})")]
+    Broadcast(String),
+    /// message would change something on node while daemon is read-only
+    #[error("Transmit refused in read-only mode ({0})")]
+    ReadOnly(String),
+    /// payload length and max. payload length
+    #[error("Payload of {0} bytes exceeds max. payload of {1} bytes")]
+    PayloadTooLarge(usize, usize)
+}
+
+/// ptlink server or node sent something it shouldn't have
+#[derive(Debug,Error)]
+pub enum ProtocolError {
+    #[error("Unsupported magic {0:#04x}")]
+    UnsupportedMagic(u32),
+    /// packet builder refused request, e.g. IE doesn't fit point type
+    #[error("Packet can't be built ({0})")]
+    Encoding(String)
+}
+
+/// Failure of firmware update of node
+#[derive(Debug,Error)]
+pub enum FwuError {
+    /// node reported firmware state unknown to firmware update, it isn't updated until state is known
+    #[error("Invalid firmware state {0} of node {1}")]
+    InvalidFwState(u8, String),
+    #[error(transparent)]
+    Db(#[from] DbError),
+    /// firmware image update command not delivered
+    #[error(transparent)]
+    Send(#[from] SendError),
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError)
+}
+
+/// Node address given by user or model can't be parsed
+#[derive(Debug,Error,PartialEq)]
+pub enum AddressError {
+    #[error("Node address is empty")]
+    Empty,
+    /// address, position of byte (from 1) and the byte
+    #[error("Invalid node address '{0}', byte {1} '{2}' is not hex byte")]
+    InvalidByte(String, usize, String),
+    /// address and number of bytes
+    #[error("Invalid node address '{0}' of {1} bytes, use six bytes or four of short form")]
+    Length(String, usize)
+}
+
+impl ProtocolError {
+    /// Failure of packet builder
+    pub fn encoding<E: fmt::Display>(err: E) -> Self {
+        ProtocolError::Encoding(err.to_string())
+    }
+}
+
+impl DbError {
+    pub fn kind(&self) -> Option<io::ErrorKind> {
+        match self {
+            DbError::NotFound(_) => Some(io::ErrorKind::NotFound),
+            DbError::AlreadyExists(_) => Some(io::ErrorKind::AlreadyExists),
+            DbError::Unsupported(_) => Some(io::ErrorKind::Unsupported),
+            DbError::InvalidData(_) => Some(io::ErrorKind::InvalidData),
+            DbError::Io(err) => Some(err.kind()),
+            _ => None
+        }
+    }
+}
+
+/// Kind of failure boxed error stands for, for mapping to API status codes
+pub fn kind_of(err: &(dyn std::error::Error + 'static)) -> Option<io::ErrorKind> {
+    if let Some(err) = err.downcast_ref::<io::Error>() {
+        Some(err.kind())
+    } else if let Some(err) = err.downcast_ref::<DbError>() {
+        err.kind()
+    } else if let Some(PtnetMgrError::Db(err) | PtnetMgrError::Fwu(FwuError::Db(err))) = err.downcast_ref::<PtnetMgrError>() {
+        err.kind()
+    } else if let Some(FwuError::Db(err)) = err.downcast_ref::<FwuError>() {
+        err.kind()
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kinds() {
+        let missing: Box<dyn std::error::Error> = Box::new(DbError::NotFound("Node 1 does not exist".to_string()));
+        assert_eq!(Some(io::ErrorKind::NotFound), kind_of(&*missing));
+
+        let wrapped: Box<dyn std::error::Error> = Box::new(PtnetMgrError::from(DbError::AlreadyExists("Node 1 already exists".to_string())));
+        assert_eq!(Some(io::ErrorKind::AlreadyExists), kind_of(&*wrapped));
+
+        let fwu: Box<dyn std::error::Error> = Box::new(FwuError::from(DbError::NotFound("Node 1 does not exist".to_string())));
+        assert_eq!(Some(io::ErrorKind::NotFound), kind_of(&*fwu));
+
+        let link: Box<dyn std::error::Error> = Box::new(PtnetMgrError::from(ProtocolError::UnsupportedMagic(7)));
+        assert_eq!(None, kind_of(&*link));
+    }
+}
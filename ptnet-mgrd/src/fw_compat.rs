@@ -0,0 +1,99 @@
+use std::{fs, io, path::Path};
+
+use ptnet::image_header::{FWVersion, HWVersion};
+use serde::{Serialize, Deserialize};
+
+/// Manifest in firmware directory, images are matched by exact hardware version without it
+pub const MANIFEST_FILE: &str = "compatibility.json";
+
+#[derive(Debug,Clone,Copy,Serialize,Deserialize,PartialEq)]
+pub struct RevRange {
+    pub from: u32,
+    /// inclusive
+    pub to: u32
+}
+
+/// Lets images built for one revision serve other revisions of same vid:pid
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub struct CompatRule {
+    pub vid: u32,
+    pub pid: u32,
+    /// firmware versions rule applies to, all if empty
+    #[serde(default)]
+    pub versions: Vec<FWVersion>,
+    /// node revisions images serve, any revision if not set
+    #[serde(default)]
+    pub revs: Option<RevRange>,
+    /// node revisions never served, even if in range
+    #[serde(default)]
+    pub exclude: Vec<u32>
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq,Default)]
+#[serde(default)]
+pub struct Compatibility {
+    pub rules: Vec<CompatRule>
+}
+
+/// vid, pid and rev
+type HWKey = (u32, u32, u32);
+
+fn key(hw: &HWVersion) -> HWKey {
+    (u32::from(hw.vid), u32::from(hw.pid), u32::from(hw.rev))
+}
+
+impl CompatRule {
+    fn matches(&self, node: HWKey, image: HWKey, fw: &FWVersion) -> bool {
+        (node.0, node.1) == (self.vid, self.pid)
+            && (image.0, image.1) == (self.vid, self.pid)
+            && (self.versions.is_empty() || self.versions.contains(fw))
+            && self.revs.map_or(true, |revs| revs.from <= node.2 && node.2 <= revs.to)
+            && !self.exclude.contains(&node.2)
+    }
+}
+
+impl Compatibility {
+    /// Manifest of firmware directory, empty if there is none
+    pub fn load_from(dir: &Path) -> Result<Self, io::Error> {
+        match fs::read(dir.join(MANIFEST_FILE)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Default::default()),
+            Err(err) => Err(err),
+            Ok(raw) => serde_json::from_slice(&raw).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", MANIFEST_FILE, err)))
+        }
+    }
+
+    /// Image built for `image` hardware may be installed on `node` hardware
+    pub fn serves(&self, node: &HWVersion, image: &HWVersion, fw: &FWVersion) -> bool {
+        node == image || self.serves_key(key(node), key(image), fw)
+    }
+
+    fn serves_key(&self, node: HWKey, image: HWKey, fw: &FWVersion) -> bool {
+        self.rules.iter().any(|rule| rule.matches(node, image, fw))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn rev_ranges_and_exclusions() {
+        let v1 = FWVersion::from_str("1.0.0").unwrap();
+        let v2 = FWVersion::from_str("2.0.0").unwrap();
+
+        let compat: Compatibility = serde_json::from_value(serde_json::json!({ "rules": [
+            { "vid": 128, "pid": 134, "revs": { "from": 16, "to": 31 }, "exclude": [21] },
+            { "vid": 128, "pid": 135, "versions": [serde_json::to_value(v1).unwrap()] }
+        ]})).unwrap();
+
+        assert!(compat.serves_key((128, 134, 17), (128, 134, 16), &v1));
+        assert!(!compat.serves_key((128, 134, 21), (128, 134, 16), &v1));
+        assert!(!compat.serves_key((128, 134, 32), (128, 134, 16), &v1));
+        // other product never matches
+        assert!(!compat.serves_key((128, 136, 17), (128, 134, 16), &v1));
+        // wildcard revision, limited to listed versions
+        assert!(compat.serves_key((128, 135, 99), (128, 135, 1), &v1));
+        assert!(!compat.serves_key((128, 135, 99), (128, 135, 1), &v2));
+    }
+}
@@ -1,11 +1,18 @@
-use std::{collections::{HashMap, BTreeMap}, path::PathBuf, fs, ops::Range};
+use std::{collections::{HashMap, BTreeMap}, path::{Path, PathBuf}, fs, io, ops::Range, sync::{Arc, RwLock}};
 
-use log::error;
+use log::{error, info};
 
 use memmap2::Mmap;
-use ptnet::image_header::{self, HWVersion};
+use ptnet::image_header::{self, HWVersion, FWVersion};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::broadcast;
+
+use crate::fw_compat::{self, Compatibility};
 
 pub struct Firmware {
+    /// image file in firmware directory
+    pub path: PathBuf,
     mmap: Mmap,
     pub header: image_header::Header,
     payload_range: Range<usize>
@@ -17,20 +24,117 @@ impl Firmware {
     }
 }
 
-pub type FirmwareMap = BTreeMap<image_header::FWVersion, Box<Firmware>>;
+pub type FirmwareMap = BTreeMap<image_header::FWVersion, Arc<Firmware>>;
+
+type HWMap = HashMap<image_header::HWVersion, Arc<FirmwareMap>>;
+
+#[derive(Debug,Clone)]
+pub enum Event {
+    FirmwareAdded(HWVersion, FWVersion),
+    FirmwareRemoved(HWVersion, FWVersion)
+}
+
+/// Firmware versions available for hardware version
+#[derive(Debug,Clone,Serialize)]
+pub struct FirmwareList {
+    pub hw_version: HWVersion,
+    pub versions: Vec<FWVersion>
+}
 
 pub struct FirmwareIndex {
-    map: HashMap<image_header::HWVersion, FirmwareMap>
+    path: PathBuf,
+    map: RwLock<HWMap>,
+    /// images serving other hardware revisions than they were built for
+    compat: RwLock<Compatibility>,
+    pub events: broadcast::Sender<Event>
 }
 
 impl FirmwareIndex {
     pub fn load_from(path: &PathBuf) -> Result<Self, std::io::Error> {
-        let mut index = FirmwareIndex {
-            map: HashMap::new()
+        let (evt_sender, _) = broadcast::channel::<Event>(32);
+
+        Ok(FirmwareIndex {
+            path: path.clone(),
+            map: RwLock::new(Self::scan(path)?),
+            compat: RwLock::new(Compatibility::load_from(path)?),
+            events: evt_sender
+        })
+    }
+
+    /// Rescan directory, firmwares already handed out stay mapped until dropped
+    pub fn reload(&self) -> Result<(), std::io::Error> {
+        let map = Self::scan(&self.path)?;
+        *self.compat.write().unwrap() = Compatibility::load_from(&self.path)?;
+        info!("Firmware index reloaded, {} hardware versions", map.len());
+
+        let old = std::mem::replace(&mut *self.map.write().unwrap(), map.clone());
+
+        let versions = |map: &HWMap| -> Vec<(HWVersion, FWVersion)> {
+            map.iter().flat_map(|(hw, fws)| fws.keys().map(move |fw| (*hw, *fw))).collect()
         };
+        let (old, new) = (versions(&old), versions(&map));
+
+        for (hw, fw) in new.iter().filter(|v| !old.contains(v)) {
+            self.events.send(Event::FirmwareAdded(*hw, *fw)).unwrap_or_default();
+        }
+        for (hw, fw) in old.iter().filter(|v| !new.contains(v)) {
+            self.events.send(Event::FirmwareRemoved(*hw, *fw)).unwrap_or_default();
+        }
+
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<FirmwareList> {
+        self.map.read().unwrap().iter()
+            .map(|(hw, fws)| FirmwareList { hw_version: *hw, versions: fws.keys().copied().collect() })
+            .collect()
+    }
+
+    /// Verify image and store it in firmware directory, returns its versions
+    pub fn add(&self, image: &[u8]) -> Result<(HWVersion, FWVersion), io::Error> {
+        let (cont, _) = image_header::Container::parse_from(image)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid firmware image ({})", err)))?;
+        let hw_version = unsafe { cont.header.fields }.v0.hw_version;
+        let fw_version = unsafe { cont.header.fields }.v0.fw_version;
+
+        if self.get_firmwares_for(&hw_version).map_or(false, |fws| fws.contains_key(&fw_version)) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("Firmware {} already in index", fw_version)));
+        }
+
+        // content addressed name, same image uploaded twice can't clash
+        let digest: String = Sha256::digest(image).iter().take(8).map(|b| format!("{:02x}", b)).collect();
+        let path = self.path.join(format!("fw-{}.img", digest));
+        let part = self.path.join(format!(".fw-{}.img.part", digest));
+
+        fs::write(&part, image)?;
+        fs::rename(&part, &path)?;
+
+        self.reload()?;
+        Ok((hw_version, fw_version))
+    }
+
+    /// Delete image file from firmware directory
+    pub fn remove(&self, hw: &HWVersion, fw: &FWVersion) -> Result<(), io::Error> {
+        let path = self.map.read().unwrap().get(hw).cloned()
+            .and_then(|fws| fws.get(fw).map(|firmware| firmware.path.clone()))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Firmware {} not in index", fw)))?;
+
+        fs::remove_file(&path)?;
+
+        self.reload()
+    }
+
+    fn scan(path: &Path) -> Result<HWMap, std::io::Error> {
+        let mut index: HashMap<image_header::HWVersion, FirmwareMap> = HashMap::new();
 
         for entry in fs::read_dir(path)? {
             let pth = entry?.path();
+
+            // dot-files are bookkeeping and partial downloads of firmware repository
+            if pth.file_name().and_then(|name| name.to_str()).map_or(true, |name| name.starts_with('.') || name == fw_compat::MANIFEST_FILE) {
+                continue;
+            }
+
             match fs::File::open(&pth) {
                 Ok(file) => {
                     let mmap_result = unsafe { Mmap::map(&file) };
@@ -41,6 +145,7 @@ impl FirmwareIndex {
                     }
 
                     let mut fw = Box::new(Firmware {
+                        path: pth.clone(),
                         mmap: mmap_result.unwrap(),
                         header: image_header::Header { raw: [0; 116] },
                         payload_range: 0..0
@@ -54,14 +159,14 @@ impl FirmwareIndex {
                             fw.header = cont.header;
                             fw.payload_range = pay_rng;
 
-                            match index.map.get_mut(hw_version) {
+                            match index.get_mut(hw_version) {
                                 Some(fwmap) => {
-                                    fwmap.insert(*fw_version, fw);
+                                    fwmap.insert(*fw_version, Arc::from(fw));
                                 },
                                 None => {
                                     let mut fwmap = BTreeMap::new();
-                                    fwmap.insert(*fw_version, fw);
-                                    index.map.insert(*hw_version, fwmap);
+                                    fwmap.insert(*fw_version, Arc::from(fw));
+                                    index.insert(*hw_version, fwmap);
                                 }
                             };
                         },
@@ -76,10 +181,36 @@ impl FirmwareIndex {
             }
         }
 
-        Ok(index)
+        Ok(index.into_iter().map(|(hw, fwmap)| (hw, Arc::new(fwmap))).collect())
     }
 
-    pub fn get_firmwares_for(&self, hw: &HWVersion) -> Option<&FirmwareMap> {
-        self.map.get_key_value(hw).and_then(|x| Some(x.1))
+    /// Firmwares installable on hardware, image built for exact hardware version wins over compatible one
+    pub fn get_firmwares_for(&self, hw: &HWVersion) -> Option<Arc<FirmwareMap>> {
+        let map = self.map.read().unwrap();
+        let compat = self.compat.read().unwrap();
+
+        if compat.rules.is_empty() {
+            return map.get(hw).cloned();
+        }
+
+        let mut fws: FirmwareMap = BTreeMap::new();
+        for (image_hw, images) in map.iter().filter(|(image_hw, _)| *image_hw != hw) {
+            for (fw, firmware) in images.iter().filter(|(fw, _)| compat.serves(hw, image_hw, fw)) {
+                fws.insert(*fw, firmware.clone());
+            }
+        }
+        if let Some(exact) = map.get(hw) {
+            fws.extend(exact.iter().map(|(fw, firmware)| (*fw, firmware.clone())));
+        }
+
+        match fws.is_empty() {
+            true => None,
+            false => Some(Arc::new(fws))
+        }
     }
-}
\ No newline at end of file
+
+    /// Image built for `image` hardware may be installed on `node` hardware
+    pub fn serves(&self, node: &HWVersion, image: &HWVersion, fw: &FWVersion) -> bool {
+        self.compat.read().unwrap().serves(node, image, fw)
+    }
+}
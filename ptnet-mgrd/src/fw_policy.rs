@@ -0,0 +1,56 @@
+use ptnet::image_header::FWVersion;
+use serde::{Serialize, Deserialize};
+
+/// Fleet-wide rules on firmware nodes may run
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq,Default)]
+#[serde(default)]
+pub struct FirmwarePolicy {
+    /// nodes running older firmware violate policy
+    pub minimum: Option<FWVersion>,
+    /// versions which must never run, they are neither offered nor installed
+    pub blacklist: Vec<FWVersion>,
+    /// update violating nodes to latest allowed firmware without waiting for approval
+    pub enforce: bool
+}
+
+#[derive(Debug,Clone,Copy,Serialize,Deserialize,PartialEq)]
+pub enum Violation {
+    Blacklisted,
+    BelowMinimum
+}
+
+impl FirmwarePolicy {
+    pub fn violation(&self, fw: &FWVersion) -> Option<Violation> {
+        if self.blacklist.contains(fw) {
+            Some(Violation::Blacklisted)
+        } else if self.minimum.as_ref().map_or(false, |minimum| fw < minimum) {
+            Some(Violation::BelowMinimum)
+        } else {
+            None
+        }
+    }
+
+    pub fn allows(&self, fw: &FWVersion) -> bool {
+        self.violation(fw).is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn violations() {
+        let policy = FirmwarePolicy {
+            minimum: Some(FWVersion::from_str("1.2.0").unwrap()),
+            blacklist: vec![FWVersion::from_str("1.3.0").unwrap()],
+            enforce: false
+        };
+
+        assert_eq!(Some(Violation::BelowMinimum), policy.violation(&FWVersion::from_str("1.1.9").unwrap()));
+        assert_eq!(Some(Violation::Blacklisted), policy.violation(&FWVersion::from_str("1.3.0").unwrap()));
+        assert!(policy.allows(&FWVersion::from_str("1.2.0").unwrap()));
+        assert!(FirmwarePolicy::default().allows(&FWVersion::from_str("0.0.1").unwrap()));
+    }
+}
@@ -0,0 +1,192 @@
+use ptnet::image_header::{FWVersion, HWVersion};
+use serde::Serialize;
+
+use crate::{database::{node_table::NodeRecord, fwu_state_table::{FWUStateRecord, Goal}}, fw_index::FirmwareIndex, fw_policy::{FirmwarePolicy, Violation}};
+
+/// Where node stands with respect to firmware it should run
+#[derive(Debug,Clone,Copy,Serialize,PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FwStatus {
+    /// runs latest firmware allowed by policy
+    UpToDate,
+    /// newer firmware is available but no update is planned, e.g. offer was rejected
+    Outdated,
+    PendingApproval,
+    Updating,
+    /// last update attempts failed, update is retried after backoff
+    Failed,
+    /// hardware not reported yet or no firmware in index for it
+    UnknownHardware
+}
+
+impl FwStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FwStatus::UpToDate => "up_to_date",
+            FwStatus::Outdated => "outdated",
+            FwStatus::PendingApproval => "pending_approval",
+            FwStatus::Updating => "updating",
+            FwStatus::Failed => "failed",
+            FwStatus::UnknownHardware => "unknown_hardware"
+        }
+    }
+}
+
+#[derive(Debug,Clone,Serialize,PartialEq)]
+pub struct NodeFwCompliance {
+    pub address: String,
+    /// name of node in model
+    pub name: Option<String>,
+    pub hw_version: Option<HWVersion>,
+    pub running: Option<FWVersion>,
+    /// latest firmware for hardware allowed by policy
+    pub latest: Option<FWVersion>,
+    pub violation: Option<Violation>,
+    pub status: FwStatus,
+    /// consecutive failed update attempts
+    pub failures: u32
+}
+
+/// Number of nodes in each status
+#[derive(Debug,Clone,Default,Serialize,PartialEq)]
+pub struct FwSummary {
+    pub up_to_date: usize,
+    pub outdated: usize,
+    pub pending_approval: usize,
+    pub updating: usize,
+    pub failed: usize,
+    pub unknown_hardware: usize,
+    /// nodes running firmware policy doesn't allow, whatever their status
+    pub violating: usize
+}
+
+#[derive(Debug,Clone,Default,Serialize,PartialEq)]
+pub struct FwComplianceReport {
+    pub summary: FwSummary,
+    pub nodes: Vec<NodeFwCompliance>
+}
+
+/// Compliance of node, `fwu_state` is None if FWU process didn't see node yet
+pub fn node_compliance(node: &NodeRecord, fwu_state: Option<&FWUStateRecord>, fw_index: &FirmwareIndex, policy: &FirmwarePolicy) -> NodeFwCompliance {
+    let hw_version: Option<HWVersion> = node.device_status.map(|st| st.hw_version.into());
+    let running: Option<FWVersion> = node.device_status.map(|st| st.fw_version.into());
+    let firmwares = hw_version.as_ref().and_then(|hw| fw_index.get_firmwares_for(hw));
+    let latest = firmwares.as_ref().and_then(|fws| fws.keys().rev().find(|ver| policy.allows(ver)).cloned());
+    let failures = fwu_state.map_or(0, |state| state.failures);
+
+    let status = match (fwu_state.map(|state| &state.goal), &running) {
+        (_, None) => FwStatus::UnknownHardware,
+        (Some(Goal::UpdateTo(_)), _) if fwu_state.map_or(false, |state| state.attempt.is_none() && state.failures > 0) => FwStatus::Failed,
+        (Some(Goal::UpdateTo(_)), _) => FwStatus::Updating,
+        (Some(Goal::ApproveUpdateTo(_)), _) => FwStatus::PendingApproval,
+        _ if firmwares.is_none() => FwStatus::UnknownHardware,
+        (_, Some(running)) => match &latest {
+            Some(latest) if latest > running || !policy.allows(running) => FwStatus::Outdated,
+            // nothing allowed to update to, violator stays outdated
+            None if !policy.allows(running) => FwStatus::Outdated,
+            _ => FwStatus::UpToDate
+        }
+    };
+
+    NodeFwCompliance {
+        address: node.mac(),
+        name: node.model.as_ref().map(|model| model.name.clone()),
+        hw_version: hw_version,
+        running: running,
+        latest: latest,
+        violation: running.as_ref().and_then(|running| policy.violation(running)),
+        status: status,
+        failures: failures
+    }
+}
+
+impl FwComplianceReport {
+    pub fn new(nodes: Vec<NodeFwCompliance>) -> Self {
+        let mut summary = FwSummary::default();
+
+        for node in &nodes {
+            *match node.status {
+                FwStatus::UpToDate => &mut summary.up_to_date,
+                FwStatus::Outdated => &mut summary.outdated,
+                FwStatus::PendingApproval => &mut summary.pending_approval,
+                FwStatus::Updating => &mut summary.updating,
+                FwStatus::Failed => &mut summary.failed,
+                FwStatus::UnknownHardware => &mut summary.unknown_hardware
+            } += 1;
+
+            if node.violation.is_some() {
+                summary.violating += 1;
+            }
+        }
+
+        FwComplianceReport { summary: summary, nodes: nodes }
+    }
+
+    /// One line per node with header, for spreadsheets of customer reports
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("address,name,hw_vid,hw_pid,hw_rev,running,latest,violation,status,failures\n");
+        let opt = |value: Option<String>| value.unwrap_or_default();
+
+        for node in &self.nodes {
+            let hw = node.hw_version.as_ref();
+            let fields = [
+                node.address.clone(),
+                csv_quote(&opt(node.name.clone())),
+                opt(hw.map(|hw| u32::from(hw.vid).to_string())),
+                opt(hw.map(|hw| u32::from(hw.pid).to_string())),
+                opt(hw.map(|hw| u32::from(hw.rev).to_string())),
+                opt(node.running.as_ref().map(|fw| fw.to_string())),
+                opt(node.latest.as_ref().map(|fw| fw.to_string())),
+                opt(node.violation.map(|violation| format!("{:?}", violation))),
+                node.status.as_str().to_string(),
+                node.failures.to_string()
+            ];
+            csv.push_str(&fields.join(","));
+            csv.push('\n');
+        }
+
+        csv
+    }
+}
+
+/// Quote field containing separator, quote or line break
+fn csv_quote(field: &str) -> String {
+    match field.contains(|c| matches!(c, ',' | '"' | '\n' | '\r')) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(status: FwStatus, violation: Option<Violation>) -> NodeFwCompliance {
+        NodeFwCompliance {
+            address: "fe:ed:de:af:be:ef".to_string(),
+            name: Some("hall, north".to_string()),
+            hw_version: None,
+            running: None,
+            latest: None,
+            violation: violation,
+            status: status,
+            failures: 0
+        }
+    }
+
+    #[test]
+    fn summary_and_csv() {
+        let report = FwComplianceReport::new(vec![
+            entry(FwStatus::UpToDate, None),
+            entry(FwStatus::Outdated, Some(Violation::BelowMinimum)),
+            entry(FwStatus::Outdated, None)
+        ]);
+
+        assert_eq!(FwSummary { up_to_date: 1, outdated: 2, violating: 1, ..Default::default() }, report.summary);
+
+        let csv = report.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(4, lines.len());
+        assert_eq!("fe:ed:de:af:be:ef,\"hall, north\",,,,,,BelowMinimum,outdated,0", lines[2]);
+    }
+}
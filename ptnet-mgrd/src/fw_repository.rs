@@ -0,0 +1,276 @@
+use std::{collections::BTreeSet, fs, path::{Path, PathBuf}, time::Duration};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use log::{debug, error, info, warn};
+use reqwest::{StatusCode, header::{ETAG, IF_NONE_MATCH}};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use tokio::time::interval;
+
+use crate::fw_index::FirmwareIndex;
+
+/// Bookkeeping of synced images, kept in cache directory
+const STATE_FILE: &str = ".repository.json";
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct FirmwareRepoConfig {
+    /// URL of index manifest, image file names are resolved relative to it;
+    /// base64 signature of manifest is served at same URL with `.sig` appended
+    pub url: String,
+    /// base64 ed25519 public key images and manifest are signed with
+    pub public_key: String,
+    /// pause between syncs (seconds)
+    pub period: u64,
+    /// timeout of single HTTP request (seconds)
+    pub timeout: u64
+}
+
+impl Default for FirmwareRepoConfig {
+    fn default() -> Self {
+        Self {
+            url: "https://firmware.example.com/ptnet/index.json".to_string(),
+            public_key: String::new(),
+            period: 3600,
+            timeout: 300
+        }
+    }
+}
+
+/// Image listed by manifest
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub struct ManifestImage {
+    pub file: String,
+    /// hex SHA-256 of image
+    pub sha256: String,
+    /// base64 ed25519 signature of image
+    pub signature: String
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub struct Manifest {
+    pub images: Vec<ManifestImage>
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq,Default)]
+#[serde(default)]
+struct SyncState {
+    manifest_etag: Option<String>,
+    manifest: Option<Manifest>,
+    /// signature of manifest matched, only then withdrawn images are removed
+    manifest_verified: bool,
+    /// file names of downloaded images
+    images: BTreeSet<String>
+}
+
+/// Result of conditional fetch
+enum Fetched {
+    NotModified,
+    Body(Vec<u8>, Option<String>)
+}
+
+/// Keeps local firmware directory in sync with remote repository
+///
+/// Images are kept when repository is unreachable, so updates continue offline.
+pub struct FirmwareRepository {
+    conf: FirmwareRepoConfig,
+    cache: PathBuf,
+    key: VerifyingKey,
+    client: reqwest::Client,
+    fw_index: &'static FirmwareIndex
+}
+
+impl FirmwareRepository {
+    pub fn new(conf: FirmwareRepoConfig, cache: PathBuf, fw_index: &'static FirmwareIndex) -> Result<Self, Box<dyn std::error::Error>> {
+        let key: [u8; 32] = BASE64.decode(&conf.public_key)?
+            .try_into()
+            .map_err(|_| "Firmware repository public key must be 32 bytes")?;
+
+        Ok(Self {
+            key: VerifyingKey::from_bytes(&key)?,
+            client: reqwest::Client::builder().timeout(Duration::from_secs(conf.timeout)).build()?,
+            conf: conf,
+            cache: cache,
+            fw_index: fw_index
+        })
+    }
+
+    fn load_state(&self) -> SyncState {
+        fs::read(self.cache.join(STATE_FILE)).ok()
+            .and_then(|raw| serde_json::from_slice(&raw).ok())
+            .unwrap_or_default()
+    }
+
+    fn save_state(&self, state: &SyncState) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        write_atomic(&self.cache.join(STATE_FILE), &serde_json::to_vec_pretty(state)?)
+    }
+
+    async fn fetch(&self, url: &str, etag: Option<&str>) -> Result<Fetched, Box<dyn std::error::Error + Send + Sync>> {
+        let mut request = self.client.get(url);
+        if let Some(etag) = etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(Fetched::NotModified);
+        }
+
+        let response = response.error_for_status()?;
+        let etag = response.headers().get(ETAG).and_then(|etag| etag.to_str().ok()).map(str::to_string);
+
+        Ok(Fetched::Body(response.bytes().await?.to_vec(), etag))
+    }
+
+    fn verify_signature(&self, data: &[u8], signature: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let signature = Signature::from_slice(&BASE64.decode(signature.trim())?)?;
+        self.key.verify(data, &signature)?;
+
+        Ok(())
+    }
+
+    fn verify(&self, image: &ManifestImage, data: &[u8]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let digest = Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        if !digest.eq_ignore_ascii_case(&image.sha256) {
+            return Err(format!("SHA-256 mismatch, expected {} got {}", image.sha256, digest).into());
+        }
+
+        self.verify_signature(data, &image.signature)
+    }
+
+    /// Manifest matches its signature, images are signed each, so unsigned manifest only can't remove them
+    async fn verify_manifest(&self, raw: &[u8]) -> bool {
+        let url = format!("{}.sig", self.conf.url);
+        let result = match self.fetch(&url, None).await {
+            Ok(Fetched::Body(signature, _)) => String::from_utf8(signature)
+                .map_err(|err| err.into())
+                .and_then(|signature| self.verify_signature(raw, &signature)),
+            Ok(Fetched::NotModified) => Err("Not Modified without condition".into()),
+            Err(err) => Err(err)
+        };
+
+        if let Err(err) = &result {
+            warn!("Signature of firmware manifest not verified, withdrawn images are kept! ({})", err);
+        }
+
+        result.is_ok()
+    }
+
+    /// Image is cached and matches manifest, images are immutable so ETag of manifest suffices
+    fn cached(&self, image: &ManifestImage) -> bool {
+        fs::read(self.cache.join(&image.file)).map_or(false, |data| self.verify(image, &data).is_ok())
+    }
+
+    /// Sync once, returns true if cache directory changed
+    pub async fn sync(&self) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let mut state = self.load_state();
+        let mut changed = false;
+
+        let manifest = match self.fetch(&self.conf.url, state.manifest_etag.as_deref()).await? {
+            Fetched::NotModified => match state.manifest.clone() {
+                Some(manifest) => manifest,
+                // state lost, fetch unconditionally
+                None => match self.fetch(&self.conf.url, None).await? {
+                    Fetched::Body(raw, etag) => {
+                        state.manifest_etag = etag;
+                        state.manifest_verified = self.verify_manifest(&raw).await;
+                        serde_json::from_slice(&raw)?
+                    },
+                    Fetched::NotModified => return Err("Repository answered unconditional fetch with Not Modified".into())
+                }
+            },
+            Fetched::Body(raw, etag) => {
+                state.manifest_etag = etag;
+                state.manifest_verified = self.verify_manifest(&raw).await;
+                serde_json::from_slice(&raw)?
+            }
+        };
+
+        let base = reqwest::Url::parse(&self.conf.url)?;
+
+        for image in &manifest.images {
+            // manifest must not write outside of cache directory
+            if image.file.starts_with('.') || image.file.contains('/') || image.file.contains('\\') {
+                warn!("Firmware repository lists invalid file name '{}', skip", image.file);
+                continue;
+            }
+
+            if self.cached(image) {
+                continue;
+            }
+
+            let url = base.join(&image.file)?;
+
+            let data = match self.fetch(url.as_str(), None).await {
+                Ok(Fetched::Body(data, _)) => data,
+                Ok(Fetched::NotModified) => {
+                    error!("Download of firmware {} failed! (Not Modified without condition)", url);
+                    continue;
+                },
+                Err(err) => {
+                    error!("Download of firmware {} failed! ({})", url, err);
+                    continue;
+                }
+            };
+
+            if let Err(err) = self.verify(image, &data) {
+                error!("Firmware {} rejected! ({})", url, err);
+                continue;
+            }
+
+            write_atomic(&self.cache.join(&image.file), &data)?;
+            state.images.insert(image.file.clone());
+            info!("Firmware {} downloaded", image.file);
+            changed = true;
+        }
+
+        // only images synced from repository are removed, files copied by hand stay;
+        // forged manifest must not empty cache
+        let listed: Vec<&String> = manifest.images.iter().map(|image| &image.file).collect();
+        let removed: Vec<String> = match state.manifest_verified {
+            true => state.images.iter().filter(|file| !listed.contains(file)).cloned().collect(),
+            false => Vec::new()
+        };
+        for file in removed {
+            info!("Firmware {} withdrawn from repository, removing", file);
+            fs::remove_file(self.cache.join(&file)).unwrap_or_default();
+            state.images.remove(&file);
+            changed = true;
+        }
+
+        state.manifest = Some(manifest);
+        self.save_state(&state)?;
+
+        Ok(changed)
+    }
+
+    pub async fn run(self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut ticker = interval(Duration::from_secs(self.conf.period));
+
+        loop {
+            ticker.tick().await;
+            debug!("Syncing firmware repository {}", self.conf.url);
+
+            match self.sync().await {
+                // index keeps images it has, next change retries
+                Ok(true) => if let Err(err) = self.fw_index.reload() {
+                    error!("Firmware index not reloaded after repository sync! ({})", err);
+                },
+                Ok(false) => debug!("Firmware repository unchanged"),
+                Err(err) => warn!("Firmware repository unreachable, using cached images! ({})", err)
+            }
+        }
+    }
+}
+
+/// Readers never see partially written file
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let name = path.file_name().and_then(|name| name.to_str()).ok_or("Invalid file name")?;
+    let part = path.with_file_name(format!(".{}.part", name));
+
+    fs::write(&part, data)?;
+    fs::rename(&part, path)?;
+
+    Ok(())
+}
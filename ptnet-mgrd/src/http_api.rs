@@ -0,0 +1,811 @@
+use std::{collections::BTreeMap, io, net::SocketAddr, str::FromStr, time::Duration, convert::Infallible, marker::PhantomData, sync::Arc};
+
+use axum::{Router, Json, async_trait, body::Bytes, routing::{get, post, put, delete}, extract::{State, Path, Query, FromRequestParts, DefaultBodyLimit}, http::{StatusCode, header::{AUTHORIZATION, CONTENT_TYPE}, request::Parts}, response::{IntoResponse, Response, sse::{Sse, Event, KeepAlive}}};
+use futures::{stream::{self, PollNext}, Stream, StreamExt};
+use log::{info, debug};
+use ptnet::{IE, image_header::{FWVersion, HWVersion}};
+use serde::{Serialize, Deserialize};
+use tokio::sync::{mpsc, broadcast};
+
+use crate::{error::{self, DbError}, database::{Database, NodeAddress, NodeAddr, query::NodeInfo, node_table::{self, NodeRecord}, fwu_state_table::{self, FWUStateRecord, Progress}, fwu_history_table::HistoryEntry, health_table::HealthSummary, port_table::PortRecord, group_table::{GroupId, GroupRecord}, alarm_table::{self, AlarmRecord}, derived_table::{self, DerivedRecord, DerivedSample}, parameter_table::ParametersRecord, journal_table::JournalEvent, schedule_table::{ScheduleId, ScheduleRecord}, em_test_table::{Compliance, EmTestsRecord, FUNCTION_TEST_DAYS, DURATION_TEST_DAYS}, raw_frame_table::RawFramesRecord, runtime_state_table::RuntimeState, replace::Replacement, compaction::StorageStats}, management::{Management, PendingApproval, ActiveAlarm, ProcessStatus, ScheduleSpec}, ptnet_process::{ApiRequest, Reply, ReadTarget, ReadValue, BulkSummary, SubmitError, UpdatePlan, submit}, client_connection::{ConnectionEvent, IOBMessage}, auth::{AuthConfig, Authenticator, Role}, fw_index::{FirmwareIndex, FirmwareList}, sol::{self, sync::{SyncReport, SyncSettings}}, journal, site::{Labels, LabelFilter, Tags}};
+
+/// Largest firmware image accepted by upload
+const MAX_IMAGE_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// address HTTP API listens on
+    pub bind: String,
+    /// how long to wait for requests executed on ptlink connection (seconds)
+    pub request_timeout: u64,
+    /// authentication of clients, anyone may do anything if not set
+    pub auth: Option<AuthConfig>
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1:8080".to_string(),
+            request_timeout: 60,
+            auth: None
+        }
+    }
+}
+
+pub struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for ApiError {
+    fn from(err: Box<dyn std::error::Error>) -> Self {
+        let status = match error::kind_of(&*err) {
+            Some(io::ErrorKind::NotFound) => StatusCode::NOT_FOUND,
+            Some(io::ErrorKind::InvalidInput) | Some(io::ErrorKind::InvalidData) => StatusCode::BAD_REQUEST,
+            Some(io::ErrorKind::AlreadyExists) => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR
+        };
+
+        ApiError(status, err.to_string())
+    }
+}
+
+impl From<DbError> for ApiError {
+    fn from(err: DbError) -> Self {
+        ApiError::from(Box::new(err) as Box<dyn std::error::Error>)
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    conf: HttpConfig,
+    db: &'static Database<'static>,
+    requests: mpsc::Sender<ApiRequest>,
+    conn_events: broadcast::Sender<ConnectionEvent>,
+    spontaneous: broadcast::Sender<IOBMessage>,
+    auth: Option<Arc<Authenticator>>,
+    fw_index: Option<&'static FirmwareIndex>,
+    sync_settings: Option<SyncSettings>
+}
+
+impl AppState {
+    fn fw_index(&self) -> Result<&'static FirmwareIndex, ApiError> {
+        self.fw_index.ok_or_else(|| ApiError(StatusCode::NOT_FOUND, "Firmware updates are disabled".to_string()))
+    }
+
+    fn sync_settings(&self) -> Result<&SyncSettings, ApiError> {
+        self.sync_settings.as_ref().ok_or_else(|| ApiError(StatusCode::NOT_FOUND, "No SOL model configured".to_string()))
+    }
+
+    /// Execute request on ptlink connection, waits until it's done
+    async fn request<T, F>(&self, make_request: F) -> Result<T, ApiError>
+    where
+        F: FnOnce(Reply<T>) -> ApiRequest
+    {
+        self.request_within(Duration::from_secs(self.conf.request_timeout), make_request).await
+    }
+
+    async fn request_within<T, F>(&self, wait: Duration, make_request: F) -> Result<T, ApiError>
+    where
+        F: FnOnce(Reply<T>) -> ApiRequest
+    {
+        submit(&self.requests, wait, make_request).await
+            .map_err(|err| {
+                let status = match err {
+                    SubmitError::Busy | SubmitError::NoLink => StatusCode::SERVICE_UNAVAILABLE,
+                    SubmitError::NotExecuted => StatusCode::GATEWAY_TIMEOUT,
+                    SubmitError::Failed(_) => StatusCode::BAD_GATEWAY
+                };
+                ApiError(status, err.to_string())
+            })
+    }
+}
+
+/// Role required by endpoint
+trait RequiredRole {
+    const ROLE: Role;
+}
+
+struct Viewer;
+struct Operator;
+struct Admin;
+
+impl RequiredRole for Viewer { const ROLE: Role = Role::Viewer; }
+impl RequiredRole for Operator { const ROLE: Role = Role::Operator; }
+impl RequiredRole for Admin { const ROLE: Role = Role::Admin; }
+
+/// Extracted when client presents bearer token granting role R or higher
+struct Authorized<R>(PhantomData<R>);
+
+#[async_trait]
+impl<R: RequiredRole + Send> FromRequestParts<AppState> for Authorized<R> {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let auth = match &state.auth {
+            None => return Ok(Authorized(PhantomData)),
+            Some(auth) => auth
+        };
+
+        let token = parts.headers.get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| ApiError(StatusCode::UNAUTHORIZED, "Bearer token required".to_string()))?;
+
+        let principal = auth.authenticate(token).map_err(|err| ApiError(StatusCode::UNAUTHORIZED, err))?;
+
+        if principal.role < R::ROLE {
+            debug!("{} {} denied to {} ({:?})", parts.method, parts.uri.path(), principal.name, principal.role);
+            return Err(ApiError(StatusCode::FORBIDDEN, format!("Role {:?} required", R::ROLE)));
+        }
+
+        Ok(Authorized(PhantomData))
+    }
+}
+
+fn parse_address(address: &str) -> Result<NodeAddress, ApiError> {
+    address.parse::<NodeAddr>()
+        .map(|addr| addr.0)
+        .map_err(|err| ApiError(StatusCode::BAD_REQUEST, err.to_string()))
+}
+
+#[derive(Debug,Serialize)]
+struct FWUInfo {
+    state: Option<FWUStateRecord>,
+    history: Vec<HistoryEntry>
+}
+
+#[derive(Debug,Deserialize)]
+struct ApproveBody {
+    version: FWVersion
+}
+
+#[derive(Debug,Deserialize)]
+struct CommandBody {
+    ioa: u32,
+    ti: u8,
+    value: u32,
+    /// select with this value before executing
+    select: Option<u32>
+}
+
+#[derive(Debug,Deserialize)]
+struct BulkCommandBody {
+    /// nodes to command, or
+    addresses: Option<Vec<String>>,
+    /// nodes with these labels, `key=value[,key=value...]`
+    label: Option<String>,
+    #[serde(flatten)]
+    command: CommandBody
+}
+
+#[derive(Debug,Deserialize)]
+struct NodeFilter {
+    /// `key=value[,key=value...]`, only nodes with all these labels
+    label: Option<String>
+}
+
+#[derive(Debug,Deserialize)]
+struct EmReportQuery {
+    /// `key=value[,key=value...]`, emergency ballasts, never tested ones included, all tested nodes if not given
+    label: Option<String>,
+    /// longest age of passed tests (days)
+    function_days: Option<u64>,
+    duration_days: Option<u64>
+}
+
+async fn list_nodes(_: Authorized<Viewer>, State(state): State<AppState>, Query(filter): Query<NodeFilter>) -> Result<Json<Vec<NodeInfo>>, ApiError> {
+    let labels = match &filter.label {
+        Some(label) => LabelFilter::from_str(label).map_err(|err| ApiError(StatusCode::BAD_REQUEST, err.to_string()))?,
+        None => LabelFilter::default()
+    };
+
+    Ok(Json(state.db.query_nodes(&labels)?))
+}
+
+async fn get_node(_: Authorized<Viewer>, State(state): State<AppState>, Path(address): Path<String>) -> Result<Json<NodeInfo>, ApiError> {
+    let address = parse_address(&address)?;
+    Ok(Json(state.db.query_node(&address)?))
+}
+
+async fn get_fwu(_: Authorized<Viewer>, State(state): State<AppState>, Path(address): Path<String>) -> Result<Json<FWUInfo>, ApiError> {
+    let address = parse_address(&address)?;
+
+    Ok(Json(FWUInfo {
+        state: state.db.fwu_state.get(&address)?,
+        history: state.db.fwu_history.get(&address)?
+    }))
+}
+
+async fn list_pending(_: Authorized<Viewer>, State(state): State<AppState>) -> Result<Json<Vec<NodeRecord>>, ApiError> {
+    Ok(Json(Management::new(state.db).pending_nodes()?))
+}
+
+async fn adopt(_: Authorized<Admin>, State(state): State<AppState>, Path(address): Path<String>) -> Result<StatusCode, ApiError> {
+    let address = parse_address(&address)?;
+    Management::new(state.db).adopt_node(&address)?;
+    info!("Node {} adopted", NodeAddr(address));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_approvals(_: Authorized<Viewer>, State(state): State<AppState>) -> Result<Json<Vec<PendingApproval>>, ApiError> {
+    Ok(Json(Management::new(state.db).approvals()?))
+}
+
+async fn approve(_: Authorized<Admin>, State(state): State<AppState>, Path(address): Path<String>, Json(body): Json<ApproveBody>) -> Result<StatusCode, ApiError> {
+    let address = parse_address(&address)?;
+    Management::new(state.db).approve(&address, &body.version)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn reject(_: Authorized<Admin>, State(state): State<AppState>, Path(address): Path<String>) -> Result<StatusCode, ApiError> {
+    let address = parse_address(&address)?;
+    Management::new(state.db).reject(&address)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn purge(_: Authorized<Admin>, State(state): State<AppState>, Path(address): Path<String>) -> Result<StatusCode, ApiError> {
+    let address = parse_address(&address)?;
+    Management::new(state.db).purge_node(&address)?;
+    info!("Node {} purged", NodeAddr(address));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug,Deserialize)]
+struct ReplaceBody {
+    new: String,
+    #[serde(default)]
+    push_parameters: bool
+}
+
+async fn replace(_: Authorized<Admin>, State(state): State<AppState>, Path(address): Path<String>, Json(body): Json<ReplaceBody>) -> Result<Json<Replacement>, ApiError> {
+    let old = parse_address(&address)?;
+    let new = parse_address(&body.new)?;
+    let replacement = Management::new(state.db).replace_node(&old, &new, body.push_parameters)?;
+    info!("Node {} replaced by {}", NodeAddr(old), NodeAddr(new));
+    Ok(Json(replacement))
+}
+
+async fn scan(_: Authorized<Operator>, State(state): State<AppState>, Path(address): Path<String>) -> Result<StatusCode, ApiError> {
+    let address = parse_address(&address)?;
+    state.request(|reply| ApiRequest::Scan(address, reply)).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Read nodes now, responds with values they returned
+async fn read_now(state: &AppState, addresses: Vec<NodeAddress>, target: Option<Json<ReadTarget>>) -> Result<Json<Vec<ReadValue>>, ApiError> {
+    let target = target.map(|Json(target)| target).unwrap_or_default();
+
+    Ok(Json(state.request(|reply| ApiRequest::Read {
+        addresses: addresses,
+        target: target,
+        reply: reply
+    }).await?))
+}
+
+async fn read_node(_: Authorized<Operator>, State(state): State<AppState>, Path(address): Path<String>, target: Option<Json<ReadTarget>>) -> Result<Json<Vec<ReadValue>>, ApiError> {
+    let address = parse_address(&address)?;
+    read_now(&state, vec![address], target).await
+}
+
+async fn list_groups(_: Authorized<Viewer>, State(state): State<AppState>) -> Result<Json<Vec<GroupRecord>>, ApiError> {
+    Ok(Json(Management::new(state.db).groups()?))
+}
+
+async fn add_group_member(_: Authorized<Operator>, State(state): State<AppState>, Path((id, address)): Path<(GroupId, String)>) -> Result<StatusCode, ApiError> {
+    let address = parse_address(&address)?;
+    Management::new(state.db).add_to_group(id, &address)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove_group_member(_: Authorized<Operator>, State(state): State<AppState>, Path((id, address)): Path<(GroupId, String)>) -> Result<StatusCode, ApiError> {
+    let address = parse_address(&address)?;
+    Management::new(state.db).remove_from_group(id, &address)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn read_group(_: Authorized<Operator>, State(state): State<AppState>, Path(id): Path<GroupId>, target: Option<Json<ReadTarget>>) -> Result<Json<Vec<ReadValue>>, ApiError> {
+    let addresses = Management::new(state.db).group_members(id)?;
+    read_now(&state, addresses, target).await
+}
+
+#[derive(Debug,Deserialize)]
+struct LabelsBody {
+    labels: Labels
+}
+
+async fn set_labels(_: Authorized<Operator>, State(state): State<AppState>, Path(address): Path<String>, Json(body): Json<LabelsBody>) -> Result<StatusCode, ApiError> {
+    let address = parse_address(&address)?;
+    Management::new(state.db).set_labels(&address, body.labels)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug,Deserialize)]
+struct TagsBody {
+    tags: Tags
+}
+
+async fn set_tags(_: Authorized<Operator>, State(state): State<AppState>, Path(address): Path<String>, Json(body): Json<TagsBody>) -> Result<StatusCode, ApiError> {
+    let address = parse_address(&address)?;
+    Management::new(state.db).set_tags(&address, body.tags)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_tags(_: Authorized<Viewer>, State(state): State<AppState>) -> Result<Json<BTreeMap<String, Vec<NodeAddress>>>, ApiError> {
+    Ok(Json(Management::new(state.db).tags()?))
+}
+
+#[derive(Debug,Deserialize)]
+struct TagNodesBody {
+    /// nodes to tag, or
+    addresses: Option<Vec<String>>,
+    /// nodes with these labels, `key=value[,key=value...]`
+    label: Option<String>
+}
+
+/// Nodes tag was added to or removed from
+#[derive(Debug,Serialize)]
+struct TagChange {
+    nodes: usize
+}
+
+async fn add_tag(_: Authorized<Operator>, State(state): State<AppState>, Path(tag): Path<String>, Json(body): Json<TagNodesBody>) -> Result<Json<TagChange>, ApiError> {
+    let addresses = match body.addresses {
+        Some(addresses) => Some(addresses.iter().map(|address| parse_address(address)).collect::<Result<Vec<_>, _>>()?),
+        None => None
+    };
+    let filter = match &body.label {
+        Some(label) => Some(label.parse::<LabelFilter>().map_err(|err| ApiError(StatusCode::BAD_REQUEST, err.to_string()))?),
+        None => None
+    };
+    let management = Management::new(state.db);
+    let addresses = management.select_nodes(addresses, filter.as_ref())?;
+
+    Ok(Json(TagChange { nodes: management.add_tag(&tag, &addresses)? }))
+}
+
+async fn remove_tag(_: Authorized<Operator>, State(state): State<AppState>, Path(tag): Path<String>) -> Result<Json<TagChange>, ApiError> {
+    Ok(Json(TagChange { nodes: Management::new(state.db).remove_tag(&tag)? }))
+}
+
+#[derive(Debug,Deserialize)]
+struct ParameterBody {
+    ti: u8,
+    value: u32
+}
+
+async fn get_parameters(_: Authorized<Viewer>, State(state): State<AppState>, Path(address): Path<String>) -> Result<Json<ParametersRecord>, ApiError> {
+    let address = parse_address(&address)?;
+    Ok(Json(Management::new(state.db).parameters(&address)?))
+}
+
+async fn get_em_tests(_: Authorized<Viewer>, State(state): State<AppState>, Path(address): Path<String>) -> Result<Json<EmTestsRecord>, ApiError> {
+    let address = parse_address(&address)?;
+    Ok(Json(Management::new(state.db).em_tests(&address)?))
+}
+
+async fn get_raw_frames(_: Authorized<Viewer>, State(state): State<AppState>, Path(address): Path<String>) -> Result<Json<RawFramesRecord>, ApiError> {
+    let address = parse_address(&address)?;
+    Ok(Json(Management::new(state.db).raw_frames(&address)?))
+}
+
+async fn clear_raw_frames(_: Authorized<Operator>, State(state): State<AppState>, Path(address): Path<String>) -> Result<StatusCode, ApiError> {
+    let address = parse_address(&address)?;
+    Management::new(state.db).clear_raw_frames(&address)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn em_report(_: Authorized<Viewer>, State(state): State<AppState>, Query(query): Query<EmReportQuery>) -> Result<Json<Vec<Compliance>>, ApiError> {
+    let filter = match &query.label {
+        Some(label) => Some(label.parse::<LabelFilter>().map_err(|err| ApiError(StatusCode::BAD_REQUEST, err.to_string()))?),
+        None => None
+    };
+
+    Ok(Json(Management::new(state.db).em_report(
+        filter.as_ref(),
+        query.function_days.unwrap_or(FUNCTION_TEST_DAYS),
+        query.duration_days.unwrap_or(DURATION_TEST_DAYS)
+    )?))
+}
+
+async fn set_parameter(_: Authorized<Admin>, State(state): State<AppState>, Path((address, ioa)): Path<(String, u32)>, Json(body): Json<ParameterBody>) -> Result<StatusCode, ApiError> {
+    let address = parse_address(&address)?;
+    Management::new(state.db).set_parameter(&address, ioa, body.ti, body.value)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove_parameter(_: Authorized<Admin>, State(state): State<AppState>, Path((address, ioa)): Path<(String, u32)>) -> Result<StatusCode, ApiError> {
+    let address = parse_address(&address)?;
+    Management::new(state.db).remove_parameter(&address, ioa)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn bulk_command(_: Authorized<Operator>, State(state): State<AppState>, Json(body): Json<BulkCommandBody>) -> Result<Json<BulkSummary>, ApiError> {
+    let addresses = match body.addresses {
+        Some(addresses) => Some(addresses.iter().map(|address| parse_address(address)).collect::<Result<Vec<_>, _>>()?),
+        None => None
+    };
+    let filter = match &body.label {
+        Some(label) => Some(label.parse::<LabelFilter>().map_err(|err| ApiError(StatusCode::BAD_REQUEST, err.to_string()))?),
+        None => None
+    };
+    let addresses = Management::new(state.db).select_nodes(addresses, filter.as_ref())?;
+
+    // each node may take as long as single command, run stops when all are done
+    let wait = Duration::from_secs(state.conf.request_timeout) * (addresses.len() as u32).max(1);
+    let command = body.command;
+    Ok(Json(state.request_within(wait, |reply| ApiRequest::BulkCommand {
+        addresses: addresses,
+        ioa: command.ioa,
+        ti: command.ti,
+        value: command.value,
+        select: command.select,
+        reply: reply
+    }).await?))
+}
+
+async fn command(_: Authorized<Operator>, State(state): State<AppState>, Path(address): Path<String>, Json(body): Json<CommandBody>) -> Result<StatusCode, ApiError> {
+    let address = parse_address(&address)?;
+
+    state.request(|reply| ApiRequest::Command {
+        address: address,
+        ioa: body.ioa,
+        ti: body.ti,
+        value: body.value,
+        select: body.select,
+        reply: reply
+    }).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Event sent to stream subscribers
+#[derive(Debug,Serialize)]
+#[serde(tag = "type")]
+enum StreamEvent {
+    Node { node: NodeRecord },
+    NodeRemoved { address: String },
+    FWUState { address: String, state: FWUStateRecord },
+    FWUProgress { address: String, progress: Progress },
+    Connection { event: ConnectionEvent },
+    Spontaneous { address: String, ca: u8, ioa: u32, ti: u8, value: IE },
+    Alarm { address: String, alarm: AlarmRecord },
+    Derived { name: String, sample: DerivedSample }
+}
+
+impl StreamEvent {
+    fn from_node(evt: node_table::Event) -> Self {
+        match evt {
+            node_table::Event::NodeAdded(rec) | node_table::Event::NodeModified(rec) => StreamEvent::Node { node: (*rec).clone() },
+            node_table::Event::NodeRemoved(address) => StreamEvent::NodeRemoved { address: NodeAddr(address).to_string() }
+        }
+    }
+
+    fn from_fwu(evt: fwu_state_table::Event) -> Self {
+        match evt {
+            fwu_state_table::Event::FWUStateAdded(address, rec) | fwu_state_table::Event::FWUStateModified(address, rec) =>
+                StreamEvent::FWUState { address: NodeAddr(address).to_string(), state: (*rec).clone() },
+            fwu_state_table::Event::FWUProgress(address, progress) =>
+                StreamEvent::FWUProgress { address: NodeAddr(address).to_string(), progress: (*progress).clone() }
+        }
+    }
+
+    fn from_alarm(evt: alarm_table::Event) -> Self {
+        match evt {
+            alarm_table::Event::AlarmChanged(address, rec) => StreamEvent::Alarm { address: NodeAddr(address).to_string(), alarm: (*rec).clone() }
+        }
+    }
+
+    fn from_derived(evt: derived_table::Event) -> Self {
+        match evt {
+            derived_table::Event::DerivedUpdated(name, sample) => StreamEvent::Derived { name: (*name).clone(), sample: (*sample).clone() }
+        }
+    }
+
+    fn from_journal(evt: JournalEvent) -> Self {
+        match evt {
+            JournalEvent::NodeAdded(node) | JournalEvent::NodeModified(node) => StreamEvent::Node { node: node },
+            JournalEvent::NodeRemoved(address) => StreamEvent::NodeRemoved { address: NodeAddr(address).to_string() },
+            JournalEvent::FWUState(address, state) => StreamEvent::FWUState { address: NodeAddr(address).to_string(), state: state },
+            JournalEvent::AlarmChanged(address, alarm) => StreamEvent::Alarm { address: NodeAddr(address).to_string(), alarm: alarm }
+        }
+    }
+
+    fn from_spontaneous(msg: IOBMessage) -> Self {
+        StreamEvent::Spontaneous {
+            address: NodeAddr(msg.message.header.address).to_string(),
+            ca: msg.iob.asdh.ca,
+            ioa: msg.iob.ioa,
+            ti: msg.iob.ie.type_id(),
+            value: msg.iob.ie
+        }
+    }
+
+    /// Node event is about, None for connection events and derived points
+    fn address(&self) -> Option<NodeAddress> {
+        match self {
+            StreamEvent::Node { node } => Some(node.address),
+            StreamEvent::NodeRemoved { address } | StreamEvent::FWUState { address, .. } | StreamEvent::FWUProgress { address, .. } | StreamEvent::Spontaneous { address, .. } | StreamEvent::Alarm { address, .. } => address.parse::<NodeAddr>().ok().map(|addr| addr.0),
+            StreamEvent::Connection { .. } | StreamEvent::Derived { .. } => None
+        }
+    }
+}
+
+/// Receive broadcast as stream, events missed by slow subscriber are skipped
+fn broadcast_stream<T: Clone + Send + 'static>(rcvr: broadcast::Receiver<T>) -> impl Stream<Item = T> {
+    stream::unfold(rcvr, |mut rcvr| async move {
+        loop {
+            match rcvr.recv().await {
+                Ok(item) => return Some((item, rcvr)),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None
+            }
+        }
+    })
+}
+
+#[derive(Debug,Deserialize)]
+struct EventFilter {
+    /// only events of this node, connection events are always sent
+    address: Option<String>,
+    /// node, firmware update state and alarm events are replayed from journal after offset
+    /// this consumer acknowledged, SSE event id is journal offset
+    consumer: Option<String>
+}
+
+#[derive(Debug,Deserialize)]
+struct AckBody {
+    consumer: String,
+    offset: u64
+}
+
+async fn events(_: Authorized<Viewer>, State(state): State<AppState>, Query(filter): Query<EventFilter>) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let address = match &filter.address {
+        Some(address) => Some(parse_address(address)?),
+        None => None
+    };
+
+    // journaled events carry their offset
+    let journaled = match &filter.consumer {
+        None => stream::select(
+            stream::select(
+                broadcast_stream(state.db.nodes.events.subscribe()).map(StreamEvent::from_node),
+                broadcast_stream(state.db.alarms.events.subscribe()).map(StreamEvent::from_alarm)
+            ),
+            broadcast_stream(state.db.fwu_state.events.subscribe()).map(StreamEvent::from_fwu)
+        ).map(|evt| (None, evt)).boxed(),
+        Some(consumer) => {
+            let acked = state.db.journal.acked(consumer)?;
+            stream::select(
+                journal::follow(state.db, acked).map(|entry| (Some(entry.offset), StreamEvent::from_journal(entry.event))),
+                // progress isn't journaled
+                broadcast_stream(state.db.fwu_state.events.subscribe())
+                    .filter(|evt| futures::future::ready(matches!(evt, fwu_state_table::Event::FWUProgress(..))))
+                    .map(|evt| (None, StreamEvent::from_fwu(evt)))
+            ).boxed()
+        }
+    };
+
+    let events = stream::select(
+        journaled,
+        stream::select(
+            broadcast_stream(state.conn_events.subscribe()).map(|event| StreamEvent::Connection { event }),
+            broadcast_stream(state.db.derived.events.subscribe()).map(StreamEvent::from_derived)
+        ).map(|evt| (None, evt))
+    );
+
+    // spontaneous alarms go out first when events pile up
+    let events = stream::select_with_strategy(
+        broadcast_stream(state.spontaneous.subscribe()).map(|msg| (None, StreamEvent::from_spontaneous(msg))),
+        events,
+        |_: &mut ()| PollNext::Left
+    );
+
+    let events = events
+        .filter(move |(_, evt)| futures::future::ready(address.is_none() || evt.address().map_or(true, |a| Some(a) == address)))
+        .map(|(offset, evt)| {
+            let event = Event::default().json_data(&evt).unwrap_or_default();
+            Ok(match offset {
+                Some(offset) => event.id(offset.to_string()),
+                None => event
+            })
+        });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+async fn ack_events(_: Authorized<Viewer>, State(state): State<AppState>, Json(body): Json<AckBody>) -> Result<StatusCode, ApiError> {
+    Management::new(state.db).ack_events(&body.consumer, body.offset)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct FirmwareBody {
+    hw_version: HWVersion,
+    fw_version: FWVersion
+}
+
+async fn sync_report(_: Authorized<Viewer>, State(state): State<AppState>) -> Result<Json<SyncReport>, ApiError> {
+    Ok(Json(sol::sync::report(state.db, state.sync_settings()?)?))
+}
+
+async fn list_firmware(_: Authorized<Viewer>, State(state): State<AppState>) -> Result<Json<Vec<FirmwareList>>, ApiError> {
+    Ok(Json(state.fw_index()?.list()))
+}
+
+#[derive(Debug,Deserialize)]
+struct ReportQuery {
+    /// `json` (default) or `csv`
+    format: Option<String>
+}
+
+async fn fw_compliance(_: Authorized<Viewer>, State(state): State<AppState>, Query(query): Query<ReportQuery>) -> Result<Response, ApiError> {
+    let report = Management::new(state.db).fw_compliance(state.fw_index()?)?;
+
+    match query.format.as_deref() {
+        None | Some("json") => Ok(Json(report).into_response()),
+        Some("csv") => Ok(([(CONTENT_TYPE, "text/csv")], report.to_csv()).into_response()),
+        Some(format) => Err(ApiError(StatusCode::BAD_REQUEST, format!("Unknown report format '{}'", format)))
+    }
+}
+
+async fn fwu_plan(_: Authorized<Viewer>, State(state): State<AppState>) -> Result<Json<UpdatePlan>, ApiError> {
+    Ok(Json(Management::new(state.db).fwu_plan(state.fw_index()?)?))
+}
+
+async fn upload_firmware(_: Authorized<Admin>, State(state): State<AppState>, image: Bytes) -> Result<(StatusCode, Json<serde_json::Value>), ApiError> {
+    let (hw_version, fw_version) = state.fw_index()?.add(&image).map_err(|err| ApiError::from(Box::new(err) as Box<dyn std::error::Error>))?;
+    info!("Firmware {} uploaded", fw_version);
+    Ok((StatusCode::CREATED, Json(serde_json::json!({ "hw_version": hw_version, "fw_version": fw_version }))))
+}
+
+async fn delete_firmware(_: Authorized<Admin>, State(state): State<AppState>, Json(body): Json<FirmwareBody>) -> Result<StatusCode, ApiError> {
+    Management::new(state.db).delete_firmware(state.fw_index()?, &body.hw_version, &body.fw_version)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn health(_: Authorized<Viewer>, State(state): State<AppState>) -> Result<Json<HealthSummary>, ApiError> {
+    Ok(Json(Management::new(state.db).health_summary()?))
+}
+
+async fn list_ports(_: Authorized<Viewer>, State(state): State<AppState>) -> Result<Json<Vec<PortRecord>>, ApiError> {
+    Ok(Json(Management::new(state.db).ports()?))
+}
+
+async fn list_alarms(_: Authorized<Viewer>, State(state): State<AppState>) -> Result<Json<Vec<ActiveAlarm>>, ApiError> {
+    Ok(Json(Management::new(state.db).active_alarms()?))
+}
+
+async fn list_processes(_: Authorized<Viewer>, State(state): State<AppState>) -> Result<Json<Vec<ProcessStatus>>, ApiError> {
+    Ok(Json(Management::new(state.db).processes()?))
+}
+
+#[derive(Debug,Deserialize)]
+struct PauseBody {
+    reason: Option<String>
+}
+
+async fn pause_process(_: Authorized<Admin>, State(state): State<AppState>, Path(name): Path<String>, body: Option<Json<PauseBody>>) -> Result<StatusCode, ApiError> {
+    let reason = body.and_then(|Json(body)| body.reason);
+    Management::new(state.db).pause_process(&name, reason)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn resume_process(_: Authorized<Admin>, State(state): State<AppState>, Path(name): Path<String>) -> Result<StatusCode, ApiError> {
+    Management::new(state.db).resume_process(&name)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn runtime_state(_: Authorized<Viewer>, State(state): State<AppState>) -> Result<Json<Vec<RuntimeState>>, ApiError> {
+    Ok(Json(Management::new(state.db).runtime_state()?))
+}
+
+async fn reset_runtime_state(_: Authorized<Admin>, State(state): State<AppState>, Path(key): Path<String>) -> Result<StatusCode, ApiError> {
+    Management::new(state.db).reset_runtime_state(&key)?;
+    info!("Runtime state {} reset", key);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn storage_stats(_: Authorized<Viewer>, State(state): State<AppState>) -> Result<Json<StorageStats>, ApiError> {
+    Ok(Json(Management::new(state.db).storage_stats()?))
+}
+
+/// State gauges in Prometheus text format
+async fn metrics(_: Authorized<Viewer>, State(state): State<AppState>) -> Result<Response, ApiError> {
+    let metrics = Management::new(state.db).state_metrics()?;
+    Ok(([(CONTENT_TYPE, "text/plain; version=0.0.4")], metrics.to_prometheus()).into_response())
+}
+
+async fn list_schedules(_: Authorized<Viewer>, State(state): State<AppState>) -> Result<Json<Vec<ScheduleRecord>>, ApiError> {
+    Ok(Json(Management::new(state.db).schedules()?))
+}
+
+async fn get_schedule(_: Authorized<Viewer>, State(state): State<AppState>, Path(id): Path<ScheduleId>) -> Result<Json<ScheduleRecord>, ApiError> {
+    Ok(Json(Management::new(state.db).schedule(id)?))
+}
+
+async fn create_schedule(_: Authorized<Operator>, State(state): State<AppState>, Json(spec): Json<ScheduleSpec>) -> Result<(StatusCode, Json<ScheduleRecord>), ApiError> {
+    let management = Management::new(state.db);
+    let id = management.create_schedule(spec)?;
+    info!("Schedule {} created", id);
+    Ok((StatusCode::CREATED, Json(management.schedule(id)?)))
+}
+
+async fn update_schedule(_: Authorized<Operator>, State(state): State<AppState>, Path(id): Path<ScheduleId>, Json(spec): Json<ScheduleSpec>) -> Result<StatusCode, ApiError> {
+    Management::new(state.db).update_schedule(id, spec)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove_schedule(_: Authorized<Operator>, State(state): State<AppState>, Path(id): Path<ScheduleId>) -> Result<StatusCode, ApiError> {
+    Management::new(state.db).remove_schedule(id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_derived(_: Authorized<Viewer>, State(state): State<AppState>) -> Result<Json<Vec<DerivedRecord>>, ApiError> {
+    Ok(Json(Management::new(state.db).derived()?))
+}
+
+/// Serve HTTP API until error
+pub async fn serve(conf: HttpConfig, db: &'static Database<'static>, fw_index: Option<&'static FirmwareIndex>, sync_settings: Option<SyncSettings>, requests: mpsc::Sender<ApiRequest>, conn_events: broadcast::Sender<ConnectionEvent>, spontaneous: broadcast::Sender<IOBMessage>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let addr = SocketAddr::from_str(&conf.bind)?;
+    let auth = match &conf.auth {
+        None => None,
+        Some(auth_conf) => Some(Arc::new(Authenticator::new(auth_conf).map_err(|err| err.to_string())?))
+    };
+
+    let app = Router::new()
+        .route("/nodes", get(list_nodes))
+        .route("/nodes/:address", get(get_node).delete(purge))
+        .route("/nodes/:address/replace", post(replace))
+        .route("/nodes/:address/adopt", post(adopt))
+        .route("/nodes/:address/fwu", get(get_fwu))
+        .route("/nodes/:address/approve", post(approve))
+        .route("/nodes/:address/reject", post(reject))
+        .route("/nodes/:address/scan", post(scan))
+        .route("/nodes/:address/read", post(read_node))
+        .route("/nodes/:address/labels", put(set_labels))
+        .route("/nodes/:address/tags", put(set_tags))
+        .route("/tags", get(list_tags))
+        .route("/tags/:tag", post(add_tag).delete(remove_tag))
+        .route("/nodes/:address/parameters", get(get_parameters))
+        .route("/nodes/:address/parameters/:ioa", put(set_parameter).delete(remove_parameter))
+        .route("/nodes/:address/em-tests", get(get_em_tests))
+        .route("/nodes/:address/raw-frames", get(get_raw_frames).delete(clear_raw_frames))
+        .route("/groups", get(list_groups))
+        .route("/groups/:id/members/:address", put(add_group_member).delete(remove_group_member))
+        .route("/groups/:id/read", post(read_group))
+        .route("/nodes/:address/command", post(command))
+        .route("/commands/bulk", post(bulk_command))
+        .route("/approvals", get(list_approvals))
+        .route("/adoptions", get(list_pending))
+        .route("/firmware", get(list_firmware).post(upload_firmware).delete(delete_firmware).layer(DefaultBodyLimit::max(MAX_IMAGE_SIZE)))
+        .route("/firmware/compliance", get(fw_compliance))
+        .route("/firmware/plan", get(fwu_plan))
+        .route("/sync-report", get(sync_report))
+        .route("/health", get(health))
+        .route("/ports", get(list_ports))
+        .route("/alarms", get(list_alarms))
+        .route("/processes", get(list_processes))
+        .route("/processes/:name/pause", post(pause_process))
+        .route("/processes/:name/resume", post(resume_process))
+        .route("/runtime-state", get(runtime_state))
+        .route("/runtime-state/:key", delete(reset_runtime_state))
+        .route("/database", get(storage_stats))
+        .route("/metrics", get(metrics))
+        .route("/em-report", get(em_report))
+        .route("/schedules", get(list_schedules).post(create_schedule))
+        .route("/schedules/:id", get(get_schedule).put(update_schedule).delete(remove_schedule))
+        .route("/derived", get(list_derived))
+        .route("/events", get(events))
+        .route("/events/ack", post(ack_events))
+        .with_state(AppState { conf: conf, db: db, requests: requests, conn_events: conn_events, spontaneous: spontaneous, auth: auth, fw_index: fw_index, sync_settings: sync_settings });
+
+    info!("HTTP API listening on {}", addr);
+    axum::Server::bind(&addr).serve(app.into_make_service()).await?;
+
+    Ok(())
+}
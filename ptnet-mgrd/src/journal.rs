@@ -0,0 +1,111 @@
+use std::collections::VecDeque;
+
+use futures::{stream, Stream};
+use log::{warn, error};
+use serde::{Serialize, Deserialize};
+use tokio::{sync::broadcast, select};
+
+use crate::database::{Database, node_table, fwu_state_table, alarm_table, journal_table::{self, JournalEvent, JournalEntry}};
+
+/// Entries read from journal at once
+const READ_BATCH: usize = 64;
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct JournalConfig {
+    /// entries kept for consumers, oldest are dropped first
+    pub max_entries: usize
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 10000
+        }
+    }
+}
+
+/// Appends node, firmware update state and alarm events to persistent journal
+pub struct JournalWriter {
+    conf: JournalConfig,
+    db: &'static Database<'static>,
+    nodes: broadcast::Receiver<node_table::Event>,
+    fwu: broadcast::Receiver<fwu_state_table::Event>,
+    alarms: broadcast::Receiver<alarm_table::Event>
+}
+
+impl JournalWriter {
+    pub fn new(conf: JournalConfig, db: &'static Database<'static>) -> Self {
+        Self {
+            conf: conf,
+            db: db,
+            // subscribed right away, events until writer runs aren't lost
+            nodes: db.nodes.events.subscribe(),
+            fwu: db.fwu_state.events.subscribe(),
+            alarms: db.alarms.events.subscribe()
+        }
+    }
+
+    fn append(&self, event: Option<JournalEvent>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(event) = event {
+            self.db.journal.append(event, self.conf.max_entries).map_err(|err| err.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    /// Journal events until error
+    pub async fn run(mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        loop {
+            let event = select! {
+                evt = self.nodes.recv() => evt.map(|evt| Some(match evt {
+                    node_table::Event::NodeAdded(rec) => JournalEvent::NodeAdded((*rec).clone()),
+                    node_table::Event::NodeModified(rec) => JournalEvent::NodeModified((*rec).clone()),
+                    node_table::Event::NodeRemoved(address) => JournalEvent::NodeRemoved(address)
+                })),
+                evt = self.fwu.recv() => evt.map(|evt| match evt {
+                    fwu_state_table::Event::FWUStateAdded(address, rec) | fwu_state_table::Event::FWUStateModified(address, rec) =>
+                        Some(JournalEvent::FWUState(address, (*rec).clone())),
+                    // progress is transient, only its latest value matters
+                    fwu_state_table::Event::FWUProgress(..) => None
+                }),
+                evt = self.alarms.recv() => evt.map(|evt| match evt {
+                    alarm_table::Event::AlarmChanged(address, rec) => Some(JournalEvent::AlarmChanged(address, (*rec).clone()))
+                })
+            };
+
+            match event {
+                Ok(event) => self.append(event)?,
+                Err(broadcast::error::RecvError::Lagged(n)) => warn!("Event journal missed {} events!", n),
+                Err(err) => return Err(Box::new(err))
+            }
+        }
+    }
+}
+
+/// Journal entries following `offset`, then entries as they are appended
+pub fn follow<'a>(db: &'a Database<'a>, offset: u64) -> impl Stream<Item = JournalEntry> + 'a {
+    // subscribed before first read, nothing appended in between is missed
+    let rcvr = db.journal.events.subscribe();
+
+    stream::unfold((offset, rcvr, VecDeque::<JournalEntry>::new()), move |(mut offset, mut rcvr, mut pending)| async move {
+        loop {
+            if let Some(entry) = pending.pop_front() {
+                offset = entry.offset;
+                return Some((entry, (offset, rcvr, pending)));
+            }
+
+            match db.journal.read_after(offset, READ_BATCH) {
+                Ok(entries) if !entries.is_empty() => pending.extend(entries),
+                Ok(_) => match rcvr.recv().await {
+                    Ok(journal_table::Event::Appended(_)) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None
+                },
+                Err(err) => {
+                    error!("Error reading event journal! ({})", err);
+                    return None;
+                }
+            }
+        }
+    })
+}
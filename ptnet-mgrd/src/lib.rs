@@ -0,0 +1,675 @@
+use std::{str::FromStr, path::{Path, PathBuf}, collections::HashMap, process::ExitCode, sync::Arc};
+
+use serde::{Serialize, Deserialize};
+use tokio::{time::{Duration, sleep}, net::{TcpStream, tcp::WriteHalf}, sync::{Mutex, mpsc, broadcast, oneshot, watch}, task::JoinSet, select};
+use log::{warn, info, error, debug};
+use clap::{Parser};
+
+mod client_connection;
+mod dedup;
+mod database;
+mod error;
+mod ptnet_process;
+mod sol;
+mod fw_index;
+mod fw_compat;
+mod fw_policy;
+mod fw_report;
+mod fw_repository;
+mod time_window;
+mod management;
+mod watchdog;
+mod http_api;
+mod auth;
+mod mqtt;
+mod journal;
+mod site;
+mod common_address;
+mod sun;
+mod sparkplug;
+mod control_socket;
+mod logging;
+mod log_file;
+mod reload;
+mod config;
+mod backfill;
+mod maintenance;
+mod metrics;
+mod profile;
+mod webhook;
+#[cfg(feature = "systemd")]
+mod systemd;
+
+/// Fixtures of benchmarks in `benches`, not an API
+#[doc(hidden)]
+pub mod bench;
+
+use client_connection::{ClientConnection};
+use database::{Database};
+
+use crate::{client_connection::{ClientConnectionDispatcher, ClientConnectionSender, ConnectionEvent, IOBMessage, BroadcastConfig, MAX_PAYLOAD}, database::{NodeAddr, AddressFormat, set_address_format, compaction, storage::{Store, StorageBackend}}, ptnet_process::{UpdateLimiter, UpdateLimits, BandwidthConfig, Router, RoutingConfig, CommandEngine, CommandTimeouts, Supervisor, RestartPolicy, ProcessRegistry, ProcessContext, ProcessSection, PersistConfig, GroupControl, GroupAddressing, ApiRequest}, fw_index::FirmwareIndex, fw_repository::{FirmwareRepoConfig, FirmwareRepository}, fw_policy::FirmwarePolicy, time_window::UpdateWindows, watchdog::{Watchdog, WatchdogConfig, Heartbeat}, dedup::DedupConfig, http_api::HttpConfig, mqtt::{MqttConfig, MqttPublisher}, journal::{JournalConfig, JournalWriter}, maintenance::{MaintenanceConfig, DatabaseMaintenance}, profile::Profile, webhook::{WebhookConfig, WebhookNotifier}, site::SiteConfig, common_address::CommonAddressConfig, control_socket::{ControlConfig, ControlServer}, logging::LogConfig, reload::ConfigReloader, sol::{state_writer::{StateWriter, StateWriterConfig}, sync::SyncSettings}};
+
+#[derive(Parser,Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    /// configuration file, JSON, TOML or YAML by extension
+    config: Option<String>,
+    /// validate configuration file and exit
+    #[arg(long)]
+    check_config: bool,
+    /// print what syncing nodes with SOL model would change and exit, nothing is modified
+    #[arg(long)]
+    sync_report: bool,
+    /// store points of captured traffic (JSON debug log of dispatcher) into database and exit,
+    /// run against offline copy of database
+    #[arg(long)]
+    backfill: Option<PathBuf>,
+    /// serve database, firmware index and APIs without connecting to ptlink server,
+    /// requests needing connection fail
+    #[arg(long)]
+    api_only: bool,
+    /// copy database into SQLite file for audits or migration and exit
+    #[cfg(feature = "sqlite")]
+    #[arg(long)]
+    export_sqlite: Option<PathBuf>,
+    /// copy tables of SQLite file made by --export-sqlite into database and exit,
+    /// run against stopped daemon
+    #[cfg(feature = "sqlite")]
+    #[arg(long, conflicts_with = "export_sqlite")]
+    import_sqlite: Option<PathBuf>,
+    /// compact database file and exit, run against stopped daemon
+    #[arg(long)]
+    compact: bool,
+    /// copy records of database in other file into database and exit, run against stopped daemon;
+    /// moves existing database to backend configured by `storage`
+    #[arg(long)]
+    migrate_from: Option<PathBuf>,
+    /// storage backend of database given by --migrate-from
+    #[arg(long, default_value = "redb", requires = "migrate_from")]
+    migrate_backend: StorageBackend,
+    /// database file, of backend configured by `storage`
+    #[arg(long, default_value = "ptnet-mgr.redb")]
+    database: PathBuf,
+    /// override configuration key, e.g. `--set processes.nodescan.period=30`; wins over file and
+    /// PTNET_MGR_* environment variables, `json:` prefix gives JSON value
+    #[arg(long, value_name = "KEY=VALUE")]
+    set: Vec<String>
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub enum NodeModelSource {
+    /// don't load initial node seed, only detect nodes
+    None,
+    /// load initial node seed from SOL model
+    SOL(String /* model root */),
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct Configuration {
+    /// deployment type (`street-lighting`, `office` or `lab`) whose defaults apply to keys not set in file
+    profile: Option<Profile>,
+    /// ptlink server address
+    server_address: String,
+    /// log format and levels
+    log: LogConfig,
+    /// node addresses in logs and APIs, `full` six bytes or `short` four bytes as in SOL model
+    address_format: AddressFormat,
+    /// ptlink reconnect interval
+    t_reconnect: u64,
+    /// where to load initial node list from
+    node_model_source: NodeModelSource,
+    /// backend of database file, `redb` or `sqlite` (daemon built with feature `sqlite`),
+    /// records of other backend are copied over by `--migrate-from`
+    storage: StorageBackend,
+    /// how long nodes missing in model are kept with their history (seconds), forever if 0
+    orphan_retention: u64,
+    /// modified model orphaning more than this percentage of nodes isn't synced, 100 disables check
+    orphan_limit: f64,
+    /// nodes new in model or heard on link are adopted without operator, otherwise they are pending until adopted
+    auto_adopt: bool,
+    /// directory with firmware images, firmware updates are disabled if not set
+    firmware_path: Option<String>,
+    /// remote repository synced into `firmware_path`, disabled if not set
+    firmware_repository: Option<FirmwareRepoConfig>,
+    /// local time windows in which firmware updates may be started
+    fwu_windows: UpdateWindows,
+    /// limits of simultaneous firmware downloads
+    fwu_limits: UpdateLimits,
+    /// bytes processes may transmit per time window
+    bandwidth: BandwidthConfig,
+    /// minimum and blacklisted firmware versions
+    fw_policy: FirmwarePolicy,
+    /// how long to wait for command confirmations
+    command_timeouts: CommandTimeouts,
+    /// addressing of group commands
+    group_addressing: GroupAddressing,
+    /// common addresses of device profiles, globally and per node type
+    common_addresses: CommonAddressConfig,
+    /// ports allowing group-addressed messages
+    broadcast: BroadcastConfig,
+    /// passive observer, only link tests and scans are sent to nodes (no commands, no firmware updates)
+    read_only: bool,
+    /// longest message payload ptlink server accepts (bytes), at most 255
+    max_payload: usize,
+    /// pinning of nodes to ports
+    routing: RoutingConfig,
+    /// suppression of frames delivered twice
+    dedup: DedupConfig,
+    /// restarting of failed processes
+    restart: RestartPolicy,
+    /// reconnecting of silently dead connection
+    watchdog: WatchdogConfig,
+    /// HTTP management API, disabled if not set
+    http: Option<HttpConfig>,
+    /// MQTT publisher, disabled if not set
+    mqtt: Option<MqttConfig>,
+    /// site ID and labels attached to published data, location of site for sun-relative schedules
+    site: SiteConfig,
+    /// persistent journal of events MQTT and HTTP event streams resume from
+    journal: JournalConfig,
+    /// JSON-RPC control interface on unix socket, disabled if not set
+    control: Option<ControlConfig>,
+    /// HTTP endpoints notified of node, firmware update and alarm events
+    webhooks: Vec<WebhookConfig>,
+    /// device state written next to SOL model, disabled if not set
+    sol_state: Option<StateWriterConfig>,
+    /// database size monitoring and compaction
+    maintenance: MaintenanceConfig,
+    /// per-process sections by process name, processes without section run with defaults
+    processes: HashMap<String, ProcessSection>
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Configuration {
+            profile: None,
+            server_address: "127.0.0.1:9885".to_string(),
+            log: Default::default(),
+            address_format: Default::default(),
+            t_reconnect: 10,
+            node_model_source: NodeModelSource::SOL("/var/lib/kvds".to_string()),
+            storage: Default::default(),
+            orphan_retention: 30 * 86400,
+            orphan_limit: 50.0,
+            auto_adopt: true,
+            firmware_path: None,
+            firmware_repository: None,
+            fwu_windows: Default::default(),
+            fwu_limits: Default::default(),
+            bandwidth: Default::default(),
+            fw_policy: Default::default(),
+            command_timeouts: Default::default(),
+            group_addressing: Default::default(),
+            common_addresses: Default::default(),
+            broadcast: Default::default(),
+            read_only: false,
+            max_payload: MAX_PAYLOAD,
+            routing: Default::default(),
+            dedup: Default::default(),
+            restart: Default::default(),
+            watchdog: Default::default(),
+            http: None,
+            mqtt: None,
+            site: Default::default(),
+            journal: Default::default(),
+            control: None,
+            webhooks: Vec::new(),
+            sol_state: None,
+            maintenance: Default::default(),
+            processes: HashMap::new()
+        }
+    }
+}
+
+impl Configuration {
+    fn reconnect_duration(&self) -> Duration {
+        Duration::from_secs(self.t_reconnect)
+    }
+}
+
+/// Run processes of connection, rebuilt when their configuration changes
+async fn run_processes<'a>(mut conf_rx: watch::Receiver<Arc<Configuration>>, base: ProcessContext<'a>) -> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        let conf = conf_rx.borrow_and_update().clone();
+        base.limiter.set_limits(conf.fwu_limits.clone());
+        base.db.set_fw_policy(conf.fw_policy.clone());
+        base.db.set_auto_adopt(conf.auto_adopt);
+        base.db.set_fwu_schedule(conf.fwu_windows.clone(), conf.fwu_limits.clone());
+        base.db.bandwidth.set_config(conf.bandwidth.clone());
+
+        let ctx = ProcessContext { windows: &conf.fwu_windows, fw_policy: &conf.fw_policy, ..base };
+        let mut processes = ProcessRegistry::builtin().build(&ctx, &conf.processes)?;
+
+        // processes are restarted by supervisor, connection lives as long as dispatcher
+        let supervisor = Supervisor::new(conf.restart.clone(), base.db);
+        let run = supervisor.run(&mut processes);
+        tokio::pin!(run);
+
+        loop {
+            select! {
+                _ = &mut run => return Ok(()),
+                changed = conf_rx.changed() => {
+                    if changed.is_err() {
+                        // configuration can't change anymore
+                        (&mut run).await;
+                        return Ok(());
+                    }
+
+                    let new = conf_rx.borrow().clone();
+                    base.limiter.set_limits(new.fwu_limits.clone());
+                    base.db.set_fw_policy(new.fw_policy.clone());
+                    base.db.set_auto_adopt(new.auto_adopt);
+                    base.db.set_fwu_schedule(new.fwu_windows.clone(), new.fwu_limits.clone());
+                    base.db.bandwidth.set_config(new.bandwidth.clone());
+
+                    if reload::process_parts_differ(&conf, &new) {
+                        info!("Restarting processes with changed configuration");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn client_connect<'a,'evt>(conf_rx: watch::Receiver<Arc<Configuration>>, db: &Database<'a>, fw_index: Option<&FirmwareIndex>, api_requests: Option<&Mutex<mpsc::Receiver<ApiRequest>>>, conn_events: &broadcast::Sender<ConnectionEvent>, spontaneous: &broadcast::Sender<IOBMessage>, heartbeat: &Heartbeat) -> Result<(), Box<dyn std::error::Error>>
+{
+    loop {
+        // changes needing reconnect are picked up here
+        let conf = conf_rx.borrow().clone();
+        let t_reconnect = conf.reconnect_duration();
+        // APIs keep running, address may be fixed by reload
+        let addr = match std::net::SocketAddr::from_str(&conf.server_address) {
+            Ok(addr) => addr,
+            Err(err) => {
+                error!("Invalid ptlink server address {}! ({})", conf.server_address, err);
+                sleep(t_reconnect).await;
+                continue;
+            }
+        };
+
+        info!("Connecting to {}", conf.server_address);
+
+        let mut stream = match TcpStream::connect(addr).await {
+            Err(err) => {
+                error!("Error connecting to ptlink server at {}! {}", addr, err);
+                tokio::time::sleep(t_reconnect).await;
+                continue;
+            },
+            Ok(stream) => {
+                info!("Connected to ptlink server at {}", addr);
+                conn_events.send(ConnectionEvent::Connected(addr.to_string())).unwrap_or_default();
+                stream
+            }
+        };
+
+        let (mut reader, writer) = stream.split();
+        let guarded_writer: Mutex<WriteHalf> = Mutex::new(writer);
+
+        // connected
+        let conn = ClientConnection::new(spontaneous.clone());
+        let sender = ClientConnectionSender::new(&conn, &guarded_writer, conf.broadcast.clone(), conf.read_only, conf.max_payload);
+        let mut dispatcher = ClientConnectionDispatcher::new(&conn, &mut reader, &conf.dedup);
+        let limiter = UpdateLimiter::new(conf.fwu_limits.clone());
+        let router = Router::new(conf.routing.clone(), db);
+        let commands = CommandEngine::new(&sender, db, conf.command_timeouts.clone());
+        let groups = GroupControl::new(&sender, conf.group_addressing.clone());
+
+        info!("Init connection");
+        let ctx = ProcessContext {
+            db: db,
+            conn: &conn,
+            sender: &sender,
+            limiter: &limiter,
+            router: &router,
+            commands: &commands,
+            groups: &groups,
+            fw_index: fw_index,
+            windows: &conf.fwu_windows,
+            fw_policy: &conf.fw_policy,
+            api_requests: api_requests
+        };
+
+        let watchdog = Watchdog::new(conf.watchdog.clone(), &conn, &sender, heartbeat);
+
+        // dispatcher isn't cancel-safe, processes are rebuilt beside it
+        let results = select! {
+            result = dispatcher.dispatch() => result.map_err(|err| Box::new(err) as Box<dyn std::error::Error>),
+            result = watchdog.run() => result,
+            result = run_processes(conf_rx.clone(), ctx) => result
+        };
+
+        let reason = match results {
+            Err(err) => {
+                error!("Connection terminated with error! ({err})");
+                err.to_string()
+            },
+            Ok(_) => {
+                warn!("Dispatcher terminated without error");
+                "Dispatcher terminated".to_string()
+            }
+        };
+        conn_events.send(ConnectionEvent::Disconnected(reason)).unwrap_or_default();
+
+        info!("Fini connection");
+
+        sleep(t_reconnect).await;
+    };
+}
+
+/// Daemon with arguments of process, returns when it exits
+pub async fn run() -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let overrides = config::Overrides::collect(std::env::vars(), &args.set)?;
+
+    if args.check_config {
+        let source = args.config.clone().unwrap_or("configuration".to_string());
+
+        match config::check(args.config.as_deref().map(Path::new), &overrides) {
+            Ok(_) => println!("{}: configuration OK", source),
+            Err(errors) => {
+                for error in &errors.0 {
+                    eprintln!("{}: {}", source, error);
+                }
+                std::process::exit(1);
+            }
+        }
+
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let loaded = config::load(args.config.as_deref().map(Path::new), &overrides)?;
+    let conf = loaded.conf;
+    let unknown_keys = loaded.unknown_keys;
+
+    config::validate(&conf)?;
+    logging::init(&conf.log)?;
+    set_address_format(conf.address_format);
+
+    for key in unknown_keys {
+        warn!("Unknown configuration key {} ignored", key);
+    }
+
+    if let Some(profile) = conf.profile {
+        info!("Using defaults of configuration profile {:?}", profile);
+    }
+
+    if args.compact {
+        match maintenance::compact_on_start(&conf.maintenance, conf.storage, &args.database, true)? {
+            Some(report) => println!("{}", serde_json::to_string_pretty(&report)?),
+            None => println!("No database to compact")
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // file is swapped, nothing may have database open yet
+    maintenance::compact_on_start(&conf.maintenance, conf.storage, &args.database, false)?;
+
+    info!("Loading ptnet-mgr database ({})", conf.storage);
+    // database lives as long as the daemon, HTTP API needs it 'static
+    let store: &'static Store = Box::leak(Box::new(Store::open(conf.storage, &args.database)?));
+    let mut db = Database::new(store);
+    db.init()?;
+    // db.load()?;
+    info!("Database loaded");
+
+    if let Some(path) = &args.migrate_from {
+        let src = Store::open(args.migrate_backend, path)?;
+        Database::new(&src).init()?;
+        compaction::copy_tables(&src, store)?;
+        println!("Database {} ({}) copied into {} ({})", path.display(), args.migrate_backend, args.database.display(), conf.storage);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let sync_settings = match &conf.node_model_source {
+        NodeModelSource::None => None,
+        NodeModelSource::SOL(model_root) => Some(SyncSettings { model_root: model_root.clone(), orphan_retention: conf.orphan_retention, orphan_limit: conf.orphan_limit })
+    };
+
+    if args.sync_report {
+        let settings = sync_settings.ok_or("No SOL model to compare node table with")?;
+        println!("{}", serde_json::to_string_pretty(&sol::sync::report(&db, &settings)?)?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = &args.export_sqlite {
+        println!("{}", serde_json::to_string_pretty(&database::sqlite::export(store, path)?)?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = &args.import_sqlite {
+        println!("{}", serde_json::to_string_pretty(&database::sqlite::import(store, path)?)?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(capture) = &args.backfill {
+        let persist: PersistConfig = match conf.processes.get("persist") {
+            Some(section) => config::params(serde_json::Value::Object(section.params.clone()))?,
+            None => Default::default()
+        };
+        db.set_common_addresses(conf.common_addresses.clone());
+        println!("{}", serde_json::to_string_pretty(&backfill::backfill(&db, capture, persist, &conf.dedup)?)?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // nodes added by reconciliation are pending already
+    db.set_auto_adopt(conf.auto_adopt);
+    if let Some(settings) = &sync_settings {
+        // refused reconciliation keeps nodes as they are, watcher retries once model changes
+        if let Err(err) = sol::sync::reconcile(&db, settings) {
+            error!("Nodes not synced with SOL model! ({})", err);
+        }
+    }
+
+    db.set_fw_policy(conf.fw_policy.clone());
+    db.set_fwu_schedule(conf.fwu_windows.clone(), conf.fwu_limits.clone());
+    db.bandwidth.set_config(conf.bandwidth.clone());
+    db.set_site(conf.site.clone());
+    db.set_common_addresses(conf.common_addresses.clone());
+    let db: &'static Database<'static> = Box::leak(Box::new(db));
+
+    // background tasks are stopped before daemon exits for restart
+    let mut tasks = JoinSet::new();
+
+    let db_maintenance = DatabaseMaintenance::new(conf.maintenance.clone(), db, args.database.clone());
+    let (restart_tx, restart_rx) = oneshot::channel::<()>();
+    tasks.spawn(async move {
+        match db_maintenance.run().await {
+            Ok(_) => restart_tx.send(()).unwrap_or_default(),
+            Err(err) => error!("Database maintenance terminated with error! ({})", err)
+        }
+    });
+
+    let journal_writer = JournalWriter::new(conf.journal.clone(), db);
+    tasks.spawn(async move {
+        if let Err(err) = journal_writer.run().await {
+            error!("Event journal terminated with error! ({})", err);
+        }
+    });
+
+    for webhook_conf in conf.webhooks.iter() {
+        let notifier = WebhookNotifier::new(webhook_conf.clone(), db)?;
+        let name = webhook_conf.name.clone();
+
+        tasks.spawn(async move {
+            if let Err(err) = notifier.run().await {
+                error!("Webhook {} terminated with error! ({})", name, err);
+            }
+        });
+    }
+
+    let fw_index = match &conf.firmware_path {
+        None => None,
+        Some(path) => {
+            info!("Loading firmware index from {}", path);
+            if conf.firmware_repository.is_some() {
+                // first sync fills empty cache
+                std::fs::create_dir_all(path)?;
+            }
+            let fw_index: &'static FirmwareIndex = Box::leak(Box::new(FirmwareIndex::load_from(&PathBuf::from(path))?));
+            Some(fw_index)
+        }
+    };
+
+    // states may be stale after nodes or firmwares changed while daemon was down
+    ptnet_process::resync_fwu_state(db, fw_index)?;
+
+    if let (Some(repo_conf), Some(path), Some(fw_index)) = (&conf.firmware_repository, &conf.firmware_path, fw_index) {
+        let repository = FirmwareRepository::new(repo_conf.clone(), PathBuf::from(path), fw_index)?;
+
+        tasks.spawn(async move {
+            if let Err(err) = repository.run().await {
+                error!("Firmware repository sync terminated with error! ({})", err);
+            }
+        });
+    }
+
+    if let Some(settings) = &sync_settings {
+        let watcher = sol::sync::ModelWatcher::new(db, settings.clone());
+
+        tasks.spawn(async move {
+            if let Err(err) = watcher.run().await {
+                error!("SOL model watcher terminated with error! ({})", err);
+            }
+        });
+    }
+
+    if let Some(state_conf) = &conf.sol_state {
+        let model_root = match &conf.node_model_source {
+            NodeModelSource::SOL(model_root) => Some(model_root.as_str()),
+            NodeModelSource::None => None
+        };
+        let writer = StateWriter::new(state_conf.clone(), model_root, db)?;
+
+        tasks.spawn(async move {
+            if let Err(err) = writer.run().await {
+                error!("SOL state writer terminated with error! ({})", err);
+            }
+        });
+    }
+
+    let (conn_events, _) = broadcast::channel::<ConnectionEvent>(16);
+    // spontaneous IOBs of all connections, kept small so alarms aren't queued behind bulk traffic
+    let (spontaneous, _) = broadcast::channel::<IOBMessage>(32);
+
+    // requests of HTTP API, control socket and MQTT commands, executed on current connection
+    let (requests, api_requests) = match conf.http.is_some() || conf.control.is_some() || conf.mqtt.as_ref().map_or(false, |mqtt| mqtt.commands) {
+        false => (None, None),
+        true => {
+            let (requests, rcvr) = mpsc::channel::<ApiRequest>(32);
+            (Some(requests), Some(Mutex::new(rcvr)))
+        }
+    };
+
+    if let (Some(http_conf), Some(requests)) = (&conf.http, &requests) {
+        let http_conf = http_conf.clone();
+        let requests = requests.clone();
+        let conn_events = conn_events.clone();
+        let spontaneous = spontaneous.clone();
+        let sync_settings = sync_settings.clone();
+
+        tasks.spawn(async move {
+            if let Err(err) = http_api::serve(http_conf, db, fw_index, sync_settings, requests, conn_events, spontaneous).await {
+                error!("HTTP API terminated with error! ({})", err);
+            }
+        });
+    }
+
+    if let (Some(control_conf), Some(requests)) = (&conf.control, &requests) {
+        let server = ControlServer::new(control_conf.clone(), db, fw_index, sync_settings.clone(), requests.clone());
+
+        tasks.spawn(async move {
+            if let Err(err) = server.serve().await {
+                error!("Control socket terminated with error! ({})", err);
+            }
+        });
+    }
+
+    if let Some(mqtt_conf) = &conf.mqtt {
+        let (publisher, eventloop) = MqttPublisher::new(mqtt_conf.clone(), db, requests.clone());
+
+        tasks.spawn(async move {
+            if let Err(err) = publisher.run(eventloop).await {
+                error!("MQTT publisher terminated with error! ({})", err);
+            }
+        });
+    }
+
+    let heartbeat = Arc::new(Heartbeat::new());
+
+    #[cfg(feature = "systemd")]
+    {
+        let notifier = systemd::SystemdNotifier::new(db, heartbeat.clone(), conn_events.subscribe());
+
+        tasks.spawn(async move {
+            if let Err(err) = notifier.run().await {
+                error!("systemd notifier terminated with error! ({})", err);
+            }
+        });
+    }
+
+    let (conf_tx, conf_rx) = watch::channel(Arc::new(conf));
+
+    match &args.config {
+        // nothing to reload, sender is kept so connection doesn't see configuration closed
+        None => std::mem::forget(conf_tx),
+        Some(conf_file) => {
+            let reloader = ConfigReloader::new(PathBuf::from(conf_file), overrides, conf_tx);
+
+            tasks.spawn(async move {
+                if let Err(err) = reloader.run().await {
+                    error!("Configuration reloading terminated with error! ({})", err);
+                }
+            });
+        }
+    }
+
+    let api_requests = match args.api_only {
+        true => {
+            // closed request channel fails requests needing connection right away
+            drop(api_requests);
+            info!("Running API-only, not connecting to ptlink server");
+            conn_events.send(ConnectionEvent::Disconnected("API-only mode".to_string())).unwrap_or_default();
+            None
+        },
+        false => api_requests
+    };
+
+    let serve = async {
+        match args.api_only {
+            true => std::future::pending().await,
+            false => client_connect(
+                conf_rx,
+                db,
+                fw_index,
+                api_requests.as_ref(),
+                &conn_events,
+                &spontaneous,
+                &heartbeat
+            ).await
+        }
+    };
+
+    let restart_due = async {
+        if restart_rx.await.is_err() {
+            // maintenance failed, daemon keeps running without it
+            std::future::pending::<()>().await;
+        }
+    };
+
+    select! {
+        result = serve => {
+            result?;
+            Ok(ExitCode::SUCCESS)
+        },
+        _ = restart_due => {
+            // connection and its processes are cancelled by leaving select, store stays open
+            // until exit; every write is committed transaction and redb recovers file on next open
+            tasks.shutdown().await;
+            Ok(ExitCode::from(maintenance::RESTART_EXIT_CODE))
+        }
+    }
+}
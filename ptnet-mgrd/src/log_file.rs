@@ -0,0 +1,230 @@
+use std::{fs::{self, File, OpenOptions}, io::{self, Write}, path::{Path, PathBuf}, thread::JoinHandle};
+
+use chrono::{DateTime, Local, Timelike};
+use flate2::{Compression, write::GzEncoder};
+use serde::{Serialize, Deserialize};
+
+use crate::logging::LogFormat;
+
+#[derive(Debug,Clone,Copy,Serialize,Deserialize,PartialEq)]
+pub enum RotatePeriod {
+    Hourly,
+    Daily
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct LogFileConfig {
+    /// log file, rotated files get suffix `.1`, `.2`, ... (`.gz` if compressed)
+    pub path: String,
+    pub format: LogFormat,
+    /// rotate when file grows over this size (bytes), never if 0
+    pub max_size: u64,
+    /// rotate at start of each hour or day, regardless of size
+    pub rotate: Option<RotatePeriod>,
+    /// number of rotated files kept
+    pub keep: usize,
+    /// gzip rotated files
+    pub compress: bool
+}
+
+impl Default for LogFileConfig {
+    fn default() -> Self {
+        Self {
+            path: "/var/log/ptnet-mgrd.log".to_string(),
+            format: LogFormat::Plain,
+            max_size: 10 * 1024 * 1024,
+            rotate: None,
+            keep: 5,
+            compress: true
+        }
+    }
+}
+
+/// Log file rotated by size and time
+pub struct RotatingFile {
+    conf: LogFileConfig,
+    file: File,
+    size: u64,
+    opened: DateTime<Local>,
+    /// compression of last rotated file, writes `.1.gz`
+    compressing: Option<JoinHandle<()>>
+}
+
+impl RotatingFile {
+    pub fn open(conf: LogFileConfig) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&conf.path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            conf: conf,
+            file: file,
+            size: size,
+            opened: Local::now(),
+            compressing: None
+        })
+    }
+
+    pub fn format(&self) -> LogFormat {
+        self.conf.format
+    }
+
+    fn rotated(&self, n: usize, compressed: bool) -> PathBuf {
+        PathBuf::from(format!("{}.{}{}", self.conf.path, n, if compressed { ".gz" } else { "" }))
+    }
+
+    /// Rotated file waiting for compression, outside of numbered files so shifting never touches it
+    fn pending(&self) -> PathBuf {
+        PathBuf::from(format!("{}.pending", self.conf.path))
+    }
+
+    fn period_passed(&self, now: &DateTime<Local>) -> bool {
+        match self.conf.rotate {
+            None => false,
+            Some(RotatePeriod::Hourly) => now.date_naive() != self.opened.date_naive() || now.hour() != self.opened.hour(),
+            Some(RotatePeriod::Daily) => now.date_naive() != self.opened.date_naive()
+        }
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let now = Local::now();
+
+        if (self.conf.max_size > 0 && self.size + line.len() as u64 + 1 > self.conf.max_size && self.size > 0) || self.period_passed(&now) {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    /// Shift rotated files, oldest falls off
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        // previous compression must finish its `.1.gz` before files shift
+        if let Some(compressing) = self.compressing.take() {
+            compressing.join().unwrap_or_default();
+        }
+
+        for compressed in [false, true] {
+            fs::remove_file(self.rotated(self.conf.keep, compressed)).unwrap_or_default();
+        }
+
+        for n in (1..self.conf.keep).rev() {
+            for compressed in [false, true] {
+                let from = self.rotated(n, compressed);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(n + 1, compressed))?;
+                }
+            }
+        }
+
+        let compressing = match (self.conf.keep > 0, self.conf.compress) {
+            (true, true) => {
+                let from = self.pending();
+                let to = self.rotated(1, true);
+                fs::rename(&self.conf.path, &from)?;
+
+                // don't stall logging callers while compressing
+                Some(std::thread::spawn(move || {
+                    if let Err(err) = compress(&from, &to) {
+                        eprintln!("Compressing {} failed! ({})", from.display(), err);
+                    }
+                }))
+            },
+            (true, false) => {
+                fs::rename(&self.conf.path, self.rotated(1, false))?;
+                None
+            },
+            (false, _) => {
+                fs::remove_file(&self.conf.path)?;
+                None
+            }
+        };
+
+        *self = Self::open(self.conf.clone())?;
+        self.compressing = compressing;
+
+        Ok(())
+    }
+}
+
+fn compress(from: &Path, to: &Path) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(File::create(to)?, Compression::default());
+    io::copy(&mut File::open(from)?, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_by_size() {
+        let dir = std::env::temp_dir().join(format!("ptnet-mgrd-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ptnet-mgrd.log");
+
+        let mut file = RotatingFile::open(LogFileConfig {
+            path: path.to_string_lossy().to_string(),
+            max_size: 20,
+            keep: 2,
+            compress: false,
+            ..Default::default()
+        }).unwrap();
+
+        for line in ["first line", "second line", "third line", "fourth line"] {
+            file.write_line(line).unwrap();
+        }
+        file.flush().unwrap();
+
+        assert_eq!("fourth line\n", fs::read_to_string(&path).unwrap());
+        assert_eq!("third line\n", fs::read_to_string(dir.join("ptnet-mgrd.log.1")).unwrap());
+        assert_eq!("second line\n", fs::read_to_string(dir.join("ptnet-mgrd.log.2")).unwrap());
+        assert!(!dir.join("ptnet-mgrd.log.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap_or_default();
+    }
+
+    #[test]
+    fn rotation_with_compression() {
+        let dir = std::env::temp_dir().join(format!("ptnet-mgrd-log-gz-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ptnet-mgrd.log");
+
+        let mut file = RotatingFile::open(LogFileConfig {
+            path: path.to_string_lossy().to_string(),
+            max_size: 20,
+            keep: 2,
+            compress: true,
+            ..Default::default()
+        }).unwrap();
+
+        // rotations follow each other faster than compression may finish
+        for line in ["first line", "second line", "third line", "fourth line"] {
+            file.write_line(line).unwrap();
+        }
+        file.flush().unwrap();
+        file.compressing.take().unwrap().join().unwrap();
+
+        let gunzip = |name: &str| {
+            let mut text = String::new();
+            io::Read::read_to_string(&mut flate2::read::GzDecoder::new(File::open(dir.join(name)).unwrap()), &mut text).unwrap();
+            text
+        };
+        assert_eq!("fourth line\n", fs::read_to_string(&path).unwrap());
+        assert_eq!("third line\n", gunzip("ptnet-mgrd.log.1.gz"));
+        assert_eq!("second line\n", gunzip("ptnet-mgrd.log.2.gz"));
+        assert!(!dir.join("ptnet-mgrd.log.1").exists());
+        assert!(!dir.join("ptnet-mgrd.log.pending").exists());
+
+        fs::remove_dir_all(&dir).unwrap_or_default();
+    }
+}
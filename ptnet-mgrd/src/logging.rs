@@ -0,0 +1,239 @@
+use std::{collections::{BTreeMap, HashMap}, io::Write, str::FromStr, sync::{Mutex, OnceLock, RwLock}};
+
+use log::{kv, LevelFilter, Log, Metadata, Record};
+use serde::{Serialize, Deserialize};
+
+use crate::log_file::{LogFileConfig, RotatingFile};
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+#[derive(Debug,Clone,Copy,Serialize,Deserialize,PartialEq)]
+pub enum LogFormat {
+    /// human readable lines, structured fields are omitted
+    Plain,
+    /// one JSON object per line with structured fields
+    Json
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// level of modules without override
+    pub level: String,
+    /// level overrides by module path prefix, e.g. `ptnet_mgrd::ptnet_process::fwu`
+    pub modules: HashMap<String, String>,
+    /// also log to rotated file, same levels apply
+    pub file: Option<LogFileConfig>
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Plain,
+            level: "debug".to_string(),
+            modules: HashMap::new(),
+            file: None
+        }
+    }
+}
+
+/// Current levels, as reported by control API
+#[derive(Debug,Clone,Serialize,PartialEq)]
+pub struct LogLevels {
+    pub level: String,
+    pub modules: BTreeMap<String, String>
+}
+
+struct Filter {
+    default: LevelFilter,
+    modules: HashMap<String, LevelFilter>
+}
+
+impl Filter {
+    /// Configured levels, `RUST_LOG` directives override them
+    fn from_config(conf: &LogConfig) -> Result<Self, String> {
+        let mut filter = Filter {
+            default: parse_level(&conf.level)?,
+            modules: HashMap::new()
+        };
+
+        for (module, level) in &conf.modules {
+            filter.set(Some(module), parse_level(level)?);
+        }
+
+        if let Ok(directives) = std::env::var("RUST_LOG") {
+            filter.apply_directives(&directives)?;
+        }
+
+        Ok(filter)
+    }
+
+    /// Level of most specific module matching target
+    fn level(&self, target: &str) -> LevelFilter {
+        self.modules.iter()
+            .filter(|(module, _)| target == module.as_str() || target.strip_prefix(module.as_str()).map_or(false, |rest| rest.starts_with("::")))
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max(&self) -> LevelFilter {
+        self.modules.values().copied().fold(self.default, LevelFilter::max)
+    }
+
+    fn set(&mut self, module: Option<&str>, level: LevelFilter) {
+        match module {
+            None => self.default = level,
+            Some(module) => { self.modules.insert(module.to_string(), level); }
+        }
+    }
+
+    /// Apply `RUST_LOG` style directives, `level` or `module=level` separated by commas
+    fn apply_directives(&mut self, directives: &str) -> Result<(), String> {
+        for directive in directives.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                None => self.set(None, parse_level(directive)?),
+                Some((module, level)) => self.set(Some(module), parse_level(level)?)
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    LevelFilter::from_str(level).map_err(|_| format!("Invalid log level '{}'", level))
+}
+
+/// Collects structured fields of record
+struct JsonFields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'a, 'kvs> kv::Visitor<'kvs> for JsonFields<'a> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = serde_json::to_value(&value).unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+fn format_record(format: LogFormat, record: &Record, now: &str) -> String {
+    match format {
+        LogFormat::Plain => format!("[{} {:5} {}] {}", now, record.level(), record.target(), record.args()),
+        LogFormat::Json => {
+            let mut fields = serde_json::Map::new();
+            fields.insert("ts".to_string(), now.into());
+            fields.insert("level".to_string(), record.level().as_str().into());
+            fields.insert("target".to_string(), record.target().into());
+            fields.insert("msg".to_string(), record.args().to_string().into());
+            record.key_values().visit(&mut JsonFields(&mut fields)).unwrap_or_default();
+
+            serde_json::Value::Object(fields).to_string()
+        }
+    }
+}
+
+struct Logger {
+    format: LogFormat,
+    filter: RwLock<Filter>,
+    file: Option<Mutex<RotatingFile>>
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.read().unwrap().level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let now = chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z").to_string();
+
+        writeln!(std::io::stderr(), "{}", format_record(self.format, record, &now)).unwrap_or_default();
+
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap();
+            let line = format_record(file.format(), record, &now);
+
+            // logging can't log its own failure
+            if let Err(err) = file.write_line(&line) {
+                eprintln!("Writing log file failed! ({})", err);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        std::io::stderr().flush().unwrap_or_default();
+
+        if let Some(file) = &self.file {
+            file.lock().unwrap().flush().unwrap_or_default();
+        }
+    }
+}
+
+/// Install logger
+pub fn init(conf: &LogConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let filter = Filter::from_config(conf)?;
+    let max = filter.max();
+    let file = match &conf.file {
+        None => None,
+        Some(file_conf) => Some(Mutex::new(RotatingFile::open(file_conf.clone())?))
+    };
+    let logger = LOGGER.get_or_init(|| Logger { format: conf.format, filter: RwLock::new(filter), file: file });
+
+    log::set_logger(logger)?;
+    log::set_max_level(max);
+
+    Ok(())
+}
+
+/// Replace all levels by configured ones, format and file can't be changed at runtime
+pub fn reconfigure(conf: &LogConfig) -> Result<(), String> {
+    let logger = LOGGER.get().ok_or_else(|| "Logger not initialized".to_string())?;
+    let mut filter = logger.filter.write().unwrap();
+
+    *filter = Filter::from_config(conf)?;
+    log::set_max_level(filter.max());
+
+    Ok(())
+}
+
+/// Change level of module, or default level if module is None
+pub fn set_level(module: Option<&str>, level: &str) -> Result<(), String> {
+    let logger = LOGGER.get().ok_or_else(|| "Logger not initialized".to_string())?;
+    let mut filter = logger.filter.write().unwrap();
+
+    filter.set(module, parse_level(level)?);
+    log::set_max_level(filter.max());
+
+    Ok(())
+}
+
+pub fn levels() -> Option<LogLevels> {
+    let filter = LOGGER.get()?.filter.read().unwrap();
+
+    Some(LogLevels {
+        level: filter.default.as_str().to_lowercase(),
+        modules: filter.modules.iter().map(|(module, level)| (module.clone(), level.as_str().to_lowercase())).collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_levels() {
+        let mut filter = Filter { default: LevelFilter::Info, modules: HashMap::new() };
+        filter.apply_directives("warn,ptnet_mgrd::ptnet_process=debug,ptnet_mgrd::ptnet_process::fwu=trace").unwrap();
+
+        assert_eq!(LevelFilter::Warn, filter.level("redb"));
+        assert_eq!(LevelFilter::Debug, filter.level("ptnet_mgrd::ptnet_process::nodescan"));
+        assert_eq!(LevelFilter::Trace, filter.level("ptnet_mgrd::ptnet_process::fwu"));
+        // prefix must end at module boundary
+        assert_eq!(LevelFilter::Warn, filter.level("ptnet_mgrd::ptnet_processes"));
+        assert_eq!(LevelFilter::Trace, filter.max());
+        assert!(filter.apply_directives("loud").is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use std::{path::{Path, PathBuf}, time::Duration};
+
+use chrono::{Local, NaiveDateTime};
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+use tokio::time::interval;
+
+use crate::{database::{Database, compaction::{self, StorageStats, CompactionReport}, storage::{Store, StorageBackend}}, time_window::TimeWindow};
+
+/// Exit code of daemon leaving to compact database on restart, service manager has to restart it;
+/// systemd counts it as failure, so unit needs `Restart=on-failure` (or `always`)
+pub const RESTART_EXIT_CODE: u8 = 75;
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct MaintenanceConfig {
+    /// how often size of database is measured (seconds)
+    pub check_interval: u64,
+    /// local time windows in which database may be compacted, never compacted if empty
+    pub quiet_hours: Vec<TimeWindow>,
+    /// compact when at least this share of file is free space (percent)
+    pub fragmentation: f64,
+    /// files smaller than this aren't compacted (bytes)
+    pub min_size: u64,
+    /// warn when database file grows over this (bytes), never if not set
+    pub size_warning: Option<u64>,
+    /// shut down with RESTART_EXIT_CODE when compaction is due in quiet hours, compaction runs on start,
+    /// needs `Restart=on-failure` in systemd unit; otherwise database is compacted when daemon starts
+    /// in quiet hours next time
+    pub restart: bool
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            check_interval: 3600,
+            quiet_hours: Vec::new(),
+            fragmentation: 30.0,
+            min_size: 16 * 1024 * 1024,
+            size_warning: None,
+            restart: false
+        }
+    }
+}
+
+impl MaintenanceConfig {
+    fn quiet(&self, now: &NaiveDateTime) -> bool {
+        self.quiet_hours.iter().any(|window| window.contains(now))
+    }
+
+    /// Database is worth compacting, regardless of time
+    fn worth_compacting(&self, stats: &StorageStats) -> bool {
+        stats.file_size >= self.min_size && stats.fragmentation() >= self.fragmentation
+    }
+
+    pub fn compaction_due(&self, stats: &StorageStats, now: &NaiveDateTime) -> bool {
+        self.quiet(now) && self.worth_compacting(stats)
+    }
+}
+
+/// Compact database before it's opened if compaction is due or forced, None if it isn't
+pub fn compact_on_start(conf: &MaintenanceConfig, backend: StorageBackend, path: &Path, force: bool) -> Result<Option<CompactionReport>, Box<dyn std::error::Error>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let stats = compaction::measure(&Store::open(backend, path)?, path)?;
+    if !force && !conf.compaction_due(&stats, &Local::now().naive_local()) {
+        return Ok(None);
+    }
+
+    info!("Compacting database, {} of {} bytes are free space", stats.fragmented, stats.file_size);
+    let report = compaction::compact(backend, path)?;
+    info!("Database compacted from {} to {} bytes", report.size_before, report.size_after);
+
+    Ok(Some(report))
+}
+
+/// Measures database file, warns when it grows too big and asks daemon to restart for compaction
+pub struct DatabaseMaintenance {
+    conf: MaintenanceConfig,
+    db: &'static Database<'static>,
+    path: PathBuf
+}
+
+impl DatabaseMaintenance {
+    pub fn new(conf: MaintenanceConfig, db: &'static Database<'static>, path: PathBuf) -> Self {
+        Self {
+            conf: conf,
+            db: db,
+            path: path
+        }
+    }
+
+    /// Returns when daemon should shut down to compact database on restart
+    pub async fn run(self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut ticker = interval(Duration::from_secs(self.conf.check_interval.max(60)));
+        let mut reported_due = false;
+
+        loop {
+            ticker.tick().await;
+
+            let stats = compaction::measure(self.db.inner_db, &self.path)?;
+            self.db.set_storage_stats(stats.clone());
+
+            if let Some(limit) = self.conf.size_warning {
+                if stats.file_size > limit {
+                    warn!("Database file has {} bytes, over warning limit of {} bytes", stats.file_size, limit);
+                }
+            }
+
+            if !self.conf.worth_compacting(&stats) {
+                reported_due = false;
+                continue;
+            }
+
+            if self.conf.restart && self.conf.quiet(&Local::now().naive_local()) {
+                info!("Shutting down to compact database ({:.0} % free space), expecting restart by service manager", stats.fragmentation());
+                return Ok(());
+            }
+
+            if !reported_due {
+                info!("Database has {:.0} % free space, it's compacted on next start in quiet hours", stats.fragmentation());
+                reported_due = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, NaiveTime};
+
+    use super::*;
+
+    #[test]
+    fn compaction_due() {
+        let conf = MaintenanceConfig {
+            quiet_hours: vec![TimeWindow { from: NaiveTime::from_hms_opt(2, 0, 0).unwrap(), to: NaiveTime::from_hms_opt(4, 0, 0).unwrap(), days: Vec::new() }],
+            min_size: 1000,
+            ..Default::default()
+        };
+        let night = NaiveDate::from_ymd_opt(2023, 6, 1).unwrap().and_hms_opt(3, 0, 0).unwrap();
+        let day = NaiveDate::from_ymd_opt(2023, 6, 1).unwrap().and_hms_opt(12, 0, 0).unwrap();
+        let fragmented = StorageStats { file_size: 10000, stored: 5000, fragmented: 4000, at: 0 };
+
+        assert!(conf.compaction_due(&fragmented, &night));
+        assert!(!conf.compaction_due(&fragmented, &day), "outside quiet hours");
+        assert!(!conf.compaction_due(&StorageStats { fragmented: 1000, ..fragmented.clone() }, &night), "little free space");
+        assert!(!conf.compaction_due(&StorageStats { file_size: 900, fragmented: 800, ..fragmented.clone() }, &night), "small file");
+        assert!(!MaintenanceConfig::default().compaction_due(&fragmented, &night), "no quiet hours");
+    }
+}
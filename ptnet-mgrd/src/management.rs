@@ -0,0 +1,648 @@
+use std::{collections::BTreeMap, io};
+
+use chrono::{Weekday, Local};
+use ptnet::image_header::{FWVersion, HWVersion};
+use serde::{Serialize, Deserialize};
+
+use crate::{client_connection::is_group_address, metrics::StateMetrics, fw_index::FirmwareIndex, fw_report::{self, FwComplianceReport}, site::{self, Labels, LabelFilter, Tags}, ptnet_process::{self, ProcessRegistry, UpdatePlan, BandwidthUsage}, database::{Database, NodeAddress, NodeAddr, node_table::NodeRecord, fwu_state_table::Goal, health_table::HealthSummary, group_table::{GroupId, GroupRecord, Member}, energy_table::EnergyRecord, port_table::PortRecord, alarm_table::AlarmRecord, derived_table::DerivedRecord, parameter_table::{Parameter, ParametersRecord}, process_table::PausedRecord, schedule_table::{ScheduleId, ScheduleRecord, ScheduleStep, Trigger}, em_test_table::{Compliance, EmTestsRecord}, raw_frame_table::RawFramesRecord, runtime_state_table::RuntimeState, replace::Replacement, compaction::StorageStats, unix_now}};
+
+/// Firmware update waiting for user decision
+#[derive(Debug,Serialize,Clone,PartialEq)]
+pub struct PendingApproval {
+    pub address: NodeAddress,
+    /// version node currently runs, if known
+    pub current: Option<FWVersion>,
+    pub offered: FWVersion
+}
+
+/// Alarm raised by alarm engine
+#[derive(Debug,Serialize,Clone,PartialEq)]
+pub struct ActiveAlarm {
+    pub address: NodeAddress,
+    #[serde(flatten)]
+    pub alarm: AlarmRecord
+}
+
+/// Process with its administrative state
+#[derive(Debug,Serialize,Clone,PartialEq)]
+pub struct ProcessStatus {
+    pub name: String,
+    /// set while process is paused
+    pub paused: Option<PausedRecord>,
+    /// bytes transmitted, None if process sent nothing and has no budget
+    pub bandwidth: Option<BandwidthUsage>
+}
+
+/// Schedule as given by user
+#[derive(Debug,Deserialize,Clone,PartialEq)]
+pub struct ScheduleSpec {
+    pub name: String,
+    pub trigger: Trigger,
+    /// days on which schedule runs, every day if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub steps: Vec<StepSpec>,
+    #[serde(default = "enabled_default")]
+    pub enabled: bool
+}
+
+fn enabled_default() -> bool {
+    true
+}
+
+/// Schedule step as given by user
+#[derive(Debug,Deserialize,Clone,PartialEq)]
+pub struct StepSpec {
+    /// nodes to command, or
+    pub addresses: Option<Vec<String>>,
+    /// nodes with these labels, `key=value[,key=value...]`, resolved on each run
+    pub label: Option<String>,
+    pub ioa: u32,
+    pub ti: u8,
+    pub value: u32,
+    /// select with given value first
+    pub select: Option<u32>
+}
+
+/// Operations available to operators and higher layers
+pub struct Management<'a> {
+    db: &'a Database<'a>
+}
+
+impl<'a> Management<'a> {
+    pub fn new(db: &'a Database) -> Self {
+        Management {
+            db: db
+        }
+    }
+
+    /// Firmware updates waiting for approval
+    pub fn approvals(&self) -> Result<Vec<PendingApproval>, Box<dyn std::error::Error>> {
+        let mut results: Vec<PendingApproval> = Vec::new();
+
+        for (address, rec) in self.db.fwu_state.list()? {
+            if let Goal::ApproveUpdateTo(offered) = rec.goal {
+                let current = self.db.query_node(&address)
+                    .ok()
+                    .and_then(|info| info.node.device_status)
+                    .map(|st| st.fw_version.into());
+
+                results.push(PendingApproval { address, current, offered });
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Firmware status of all nodes against firmware index and policy, orphaned nodes are left out
+    pub fn fw_compliance(&self, fw_index: &FirmwareIndex) -> Result<FwComplianceReport, Box<dyn std::error::Error>> {
+        let policy = self.db.fw_policy();
+        let mut nodes = Vec::new();
+
+        for node in self.db.nodes.load_many(self.db.nodes.list()?.iter())?.iter().filter(|node| node.orphaned_at.is_none()) {
+            let fwu_state = self.db.fwu_state.get(&node.address)?;
+            nodes.push(fw_report::node_compliance(node, fwu_state.as_ref(), fw_index, &policy));
+        }
+
+        Ok(FwComplianceReport::new(nodes))
+    }
+
+    /// Dry run of firmware update process with current goals, policy and schedule
+    pub fn fwu_plan(&self, fw_index: &FirmwareIndex) -> Result<UpdatePlan, Box<dyn std::error::Error>> {
+        let (windows, limits) = self.db.fwu_schedule();
+        ptnet_process::plan_updates(self.db, fw_index, &self.db.fw_policy(), &windows, &limits, Local::now())
+    }
+
+    /// Approve offered firmware update, `version` must match the offer
+    pub fn approve(&self, address: &NodeAddress, version: &FWVersion) -> Result<(), Box<dyn std::error::Error>> {
+        let mut result: Result<(), Box<dyn std::error::Error>> = Ok(());
+
+        self.db.fwu_state.modify(address, |opt_rec| {
+            match opt_rec {
+                Some(mut rec) if rec.goal == Goal::ApproveUpdateTo(version.clone()) => {
+                    rec.goal = Goal::UpdateTo(version.clone());
+                    Some(rec)
+                },
+                _ => {
+                    result = Err(Box::new(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Update of node {} to {} is not pending approval", NodeAddr(*address), version)
+                    )));
+                    None
+                }
+            }
+        })?;
+
+        result
+    }
+
+    /// Reject offered firmware update, node keeps its firmware until newer one is available
+    pub fn reject(&self, address: &NodeAddress) -> Result<(), Box<dyn std::error::Error>> {
+        let mut result: Result<(), Box<dyn std::error::Error>> = Ok(());
+
+        self.db.fwu_state.modify(address, |opt_rec| {
+            match opt_rec {
+                Some(mut rec) => match rec.goal.clone() {
+                    Goal::ApproveUpdateTo(offered) => {
+                        rec.goal = Goal::None;
+                        rec.rejected = Some(offered);
+                        Some(rec)
+                    },
+                    _ => {
+                        result = Err(Box::new(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("Node {} has no update pending approval", NodeAddr(*address))
+                        )));
+                        None
+                    }
+                },
+                None => {
+                    result = Err(Box::new(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("Node {} has no firmware update state", NodeAddr(*address))
+                    )));
+                    None
+                }
+            }
+        })?;
+
+        result
+    }
+
+    /// Delete firmware image, refused while some node is being updated to it
+    pub fn delete_firmware(&self, fw_index: &FirmwareIndex, hw: &HWVersion, fw: &FWVersion) -> Result<(), Box<dyn std::error::Error>> {
+        for (address, rec) in self.db.fwu_state.list()? {
+            let targeted = matches!(&rec.goal, Goal::UpdateTo(ver) if ver == fw)
+                || rec.attempt.as_ref().map_or(false, |attempt| attempt.to == *fw);
+
+            // node of unknown hardware might need the image as well
+            let served = self.db.query_node(&address)
+                .ok()
+                .and_then(|info| info.node.device_status)
+                .map_or(true, |st| fw_index.serves(&st.hw_version.into(), hw, fw));
+
+            if targeted && served {
+                return Err(Box::new(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Firmware {} is target of update of node {}", fw, NodeAddr(address))
+                )));
+            }
+        }
+
+        fw_index.remove(hw, fw)?;
+        Ok(())
+    }
+
+    /// Remove orphaned node with its history before retention period ends
+    pub fn purge_node(&self, address: &NodeAddress) -> Result<(), Box<dyn std::error::Error>> {
+        let node = self.db.query_node(address)?.node;
+
+        if node.orphaned_at.is_none() {
+            // reconciliation would re-add node still in model
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Node {} is not orphaned", node.mac())
+            )));
+        }
+
+        Ok(self.db.purge_node(address)?)
+    }
+
+    /// Let `new` take over identity, history and configuration of failed node `old`
+    pub fn replace_node(&self, old: &NodeAddress, new: &NodeAddress, push_parameters: bool) -> Result<Replacement, Box<dyn std::error::Error>> {
+        if old == new {
+            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "Replacement must have other address")));
+        }
+
+        if is_group_address(new) {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is group address", NodeAddr(*new))
+            )));
+        }
+
+        Ok(self.db.replace_node(old, new, push_parameters)?)
+    }
+
+    /// Number of online, degraded and offline nodes
+    pub fn health_summary(&self) -> Result<HealthSummary, Box<dyn std::error::Error>> {
+        Ok(self.db.health.summary()?)
+    }
+
+    /// ptlink ports with their status, as discovered from traffic
+    pub fn ports(&self) -> Result<Vec<PortRecord>, Box<dyn std::error::Error>> {
+        Ok(self.db.ports.list()?)
+    }
+
+    /// Alarms currently raised by alarm engine
+    pub fn active_alarms(&self) -> Result<Vec<ActiveAlarm>, Box<dyn std::error::Error>> {
+        Ok(self.db.alarms.list_active()?.into_iter().map(|(address, alarm)| ActiveAlarm { address: address, alarm: alarm }).collect())
+    }
+
+    /// Derived points with their recent values
+    pub fn derived(&self) -> Result<Vec<DerivedRecord>, Box<dyn std::error::Error>> {
+        Ok(self.db.derived.list()?)
+    }
+
+    /// Event stream consumer processed journal up to offset
+    pub fn ack_events(&self, consumer: &str, offset: u64) -> Result<(), Box<dyn std::error::Error>> {
+        if consumer.is_empty() {
+            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "Consumer name is empty")));
+        }
+
+        Ok(self.db.journal.ack(consumer, offset)?)
+    }
+
+    pub fn groups(&self) -> Result<Vec<GroupRecord>, Box<dyn std::error::Error>> {
+        Ok(self.db.groups.list()?)
+    }
+
+    /// Addresses of group members, error if group doesn't exist
+    pub fn group_members(&self, id: GroupId) -> Result<Vec<NodeAddress>, Box<dyn std::error::Error>> {
+        match self.db.groups.get(id)? {
+            Some(rec) => Ok(rec.members.iter().map(|m| m.address).collect()),
+            None => Err(Box::new(io::Error::new(io::ErrorKind::NotFound, format!("Group {} doesn't exist", id))))
+        }
+    }
+
+    /// Add node to group, overwritten by SOL model if group process follows it
+    pub fn add_to_group(&self, id: GroupId, address: &NodeAddress) -> Result<(), Box<dyn std::error::Error>> {
+        self.select_nodes(Some(vec![*address]), None)?;
+
+        Ok(self.db.groups.modify(id, |mut rec| {
+            match rec.is_member(address) {
+                true => None,
+                false => {
+                    rec.members.push(Member { address: *address, verified: None, checked_at: None });
+                    Some(rec)
+                }
+            }
+        })?)
+    }
+
+    pub fn remove_from_group(&self, id: GroupId, address: &NodeAddress) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(self.db.groups.modify(id, |mut rec| {
+            match rec.is_member(address) {
+                true => {
+                    rec.members.retain(|m| m.address != *address);
+                    Some(rec)
+                },
+                false => None
+            }
+        })?)
+    }
+
+    /// Nodes of bulk operation, either listed ones or the ones with matching labels
+    pub fn select_nodes(&self, addresses: Option<Vec<NodeAddress>>, filter: Option<&LabelFilter>) -> Result<Vec<NodeAddress>, Box<dyn std::error::Error>> {
+        let selected = match (addresses, filter) {
+            (Some(addresses), None) => {
+                let known = self.db.nodes.list()?;
+                if let Some(address) = addresses.iter().find(|address| !known.contains(address)) {
+                    return Err(Box::new(io::Error::new(io::ErrorKind::NotFound, format!("Node {} doesn't exist", NodeAddr(*address)))));
+                }
+                addresses
+            },
+            (None, Some(filter)) => self.db.query_nodes(filter)?.into_iter().map(|info| info.node.address).collect(),
+            _ => return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "Give either addresses or label filter of nodes")))
+        };
+
+        match selected.is_empty() {
+            true => Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "No nodes selected"))),
+            false => Ok(selected)
+        }
+    }
+
+    /// Intended device parameters of node with their state on device
+    pub fn parameters(&self, address: &NodeAddress) -> Result<ParametersRecord, Box<dyn std::error::Error>> {
+        Ok(self.db.parameters.get(address)?)
+    }
+
+    /// Nodes discovered but not adopted yet
+    pub fn pending_nodes(&self) -> Result<Vec<NodeRecord>, Box<dyn std::error::Error>> {
+        let nodes = self.db.nodes.load_many(self.db.nodes.list()?.iter())?;
+        Ok(nodes.into_iter().filter(|node| node.pending).collect())
+    }
+
+    /// Let daemon scan, test and update pending node
+    pub fn adopt_node(&self, address: &NodeAddress) -> Result<(), Box<dyn std::error::Error>> {
+        let mut found = false;
+        self.db.nodes.modify(address, |opt_rec| {
+            let rec = opt_rec?;
+            found = true;
+            match rec.pending {
+                true => Some(NodeRecord { pending: false, ..rec }),
+                false => None
+            }
+        })?;
+
+        match found {
+            true => Ok(()),
+            false => Err(Box::new(io::Error::new(io::ErrorKind::NotFound, format!("Node {} doesn't exist", NodeAddr(*address)))))
+        }
+    }
+
+    /// Replace labels of node, site labels apply to keys node doesn't have
+    pub fn set_labels(&self, address: &NodeAddress, labels: Labels) -> Result<(), Box<dyn std::error::Error>> {
+        if labels.keys().any(|key| key.is_empty() || key.contains(',') || key.contains('=')) {
+            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "Label keys must be non-empty and contain no ',' or '='")));
+        }
+
+        let mut found = false;
+        self.db.nodes.modify(address, |opt_rec| {
+            let rec = opt_rec?;
+            found = true;
+            Some(NodeRecord { labels: labels, ..rec })
+        })?;
+
+        match found {
+            true => Ok(()),
+            false => Err(Box::new(io::Error::new(io::ErrorKind::NotFound, format!("Node {} doesn't exist", NodeAddr(*address)))))
+        }
+    }
+
+    /// Replace tags of node
+    pub fn set_tags(&self, address: &NodeAddress, tags: Tags) -> Result<(), Box<dyn std::error::Error>> {
+        for tag in tags.iter() {
+            site::check_tag(tag).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        }
+
+        let mut found = false;
+        self.db.nodes.modify(address, |opt_rec| {
+            let rec = opt_rec?;
+            found = true;
+            Some(NodeRecord { tags: tags, ..rec })
+        })?;
+
+        match found {
+            true => Ok(()),
+            false => Err(Box::new(io::Error::new(io::ErrorKind::NotFound, format!("Node {} doesn't exist", NodeAddr(*address)))))
+        }
+    }
+
+    /// Nodes by tag
+    pub fn tags(&self) -> Result<BTreeMap<String, Vec<NodeAddress>>, Box<dyn std::error::Error>> {
+        let mut tags: BTreeMap<String, Vec<NodeAddress>> = BTreeMap::new();
+
+        for node in self.db.nodes.load_many(self.db.nodes.list()?.iter())? {
+            for tag in node.tags {
+                tags.entry(tag).or_default().push(node.address);
+            }
+        }
+
+        Ok(tags)
+    }
+
+    /// Tag nodes, returns how many of them weren't tagged yet
+    pub fn add_tag(&self, tag: &str, addresses: &[NodeAddress]) -> Result<usize, Box<dyn std::error::Error>> {
+        site::check_tag(tag).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+        let mut tagged = 0;
+        for address in addresses {
+            self.db.nodes.modify(address, |opt_rec| {
+                let mut rec = opt_rec?;
+                if !rec.tags.insert(tag.to_string()) {
+                    return None;
+                }
+                tagged += 1;
+                Some(rec)
+            })?;
+        }
+
+        Ok(tagged)
+    }
+
+    /// Remove tag from all nodes, returns how many had it
+    pub fn remove_tag(&self, tag: &str) -> Result<usize, Box<dyn std::error::Error>> {
+        let mut untagged = 0;
+        for address in self.db.nodes.list()? {
+            self.db.nodes.modify(&address, |opt_rec| {
+                let mut rec = opt_rec?;
+                if !rec.tags.remove(tag) {
+                    return None;
+                }
+                untagged += 1;
+                Some(rec)
+            })?;
+        }
+
+        match untagged {
+            0 => Err(Box::new(io::Error::new(io::ErrorKind::NotFound, format!("No node is tagged {}", tag)))),
+            n => Ok(n)
+        }
+    }
+
+    /// Set intended value of device parameter, it's written to device by parameter process
+    pub fn set_parameter(&self, address: &NodeAddress, ioa: u32, ti: u8, value: u32) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(self.db.parameters.modify(address, |mut rec| {
+            match rec.params.get(&ioa) {
+                Some(p) if p.ti == ti && p.intended == value => None,
+                _ => {
+                    rec.params.insert(ioa, Parameter::new(ti, value));
+                    Some(rec)
+                }
+            }
+        })?)
+    }
+
+    /// Stop managing device parameter, value on device is left as it is
+    pub fn remove_parameter(&self, address: &NodeAddress, ioa: u32) -> Result<(), Box<dyn std::error::Error>> {
+        let mut found = false;
+
+        self.db.parameters.modify(address, |mut rec| {
+            found = rec.params.remove(&ioa).is_some();
+            Some(rec).filter(|_| found)
+        })?;
+
+        match found {
+            true => Ok(()),
+            false => Err(Box::new(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Node {} has no parameter at IOA {}", NodeAddr(*address), ioa)
+            )))
+        }
+    }
+
+    /// Known processes and whether they are paused, disabled processes included
+    pub fn processes(&self) -> Result<Vec<ProcessStatus>, Box<dyn std::error::Error>> {
+        let paused = self.db.processes.list()?;
+
+        Ok(ProcessRegistry::builtin().names()
+            .map(|name| ProcessStatus {
+                name: name.to_string(),
+                paused: paused.iter().find(|(n, _)| n == name).map(|(_, rec)| rec.clone()),
+                bandwidth: self.db.bandwidth.usage(name)
+            })
+            .collect())
+    }
+
+    /// Stop process until resumed, stays paused across restarts
+    pub fn pause_process(&self, name: &str, reason: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_process(name)?;
+        self.db.processes.pause(name, reason)?;
+        Ok(())
+    }
+
+    pub fn resume_process(&self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_process(name)?;
+        self.db.processes.resume(name)?;
+        Ok(())
+    }
+
+    /// Cursors processes resume from after restart
+    pub fn runtime_state(&self) -> Result<Vec<RuntimeState>, Box<dyn std::error::Error>> {
+        Ok(self.db.runtime_state.list()?)
+    }
+
+    /// Forget cursor, process starts over after its next restart
+    pub fn reset_runtime_state(&self, key: &str) -> Result<(), Box<dyn std::error::Error>> {
+        match self.db.runtime_state.remove(key)? {
+            true => Ok(()),
+            false => Err(Box::new(io::Error::new(io::ErrorKind::NotFound, format!("No runtime state {}", key))))
+        }
+    }
+
+    /// Size and free space of database file as last measured by maintenance
+    pub fn storage_stats(&self) -> Result<StorageStats, Box<dyn std::error::Error>> {
+        match self.db.storage_stats() {
+            Some(stats) => Ok(stats),
+            None => Err(Box::new(io::Error::new(io::ErrorKind::NotFound, "Database not measured yet")))
+        }
+    }
+
+    /// Gauges of node, firmware update, alarm and campaign state for monitoring
+    pub fn state_metrics(&self) -> Result<StateMetrics, Box<dyn std::error::Error>> {
+        Ok(StateMetrics::collect(self.db)?)
+    }
+
+    fn check_process(&self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        match ProcessRegistry::builtin().names().any(|n| n == name) {
+            true => Ok(()),
+            false => Err(Box::new(io::Error::new(io::ErrorKind::NotFound, format!("Process {} doesn't exist", name))))
+        }
+    }
+
+    pub fn schedules(&self) -> Result<Vec<ScheduleRecord>, Box<dyn std::error::Error>> {
+        Ok(self.db.schedules.list()?)
+    }
+
+    pub fn schedule(&self, id: ScheduleId) -> Result<ScheduleRecord, Box<dyn std::error::Error>> {
+        match self.db.schedules.get(id)? {
+            Some(rec) => Ok(rec),
+            None => Err(Box::new(io::Error::new(io::ErrorKind::NotFound, format!("Schedule {} doesn't exist", id))))
+        }
+    }
+
+    /// Store new schedule, scheduler process runs it from its next trigger on
+    pub fn create_schedule(&self, spec: ScheduleSpec) -> Result<ScheduleId, Box<dyn std::error::Error>> {
+        Ok(self.db.schedules.create(self.schedule_of(spec)?)?)
+    }
+
+    /// Replace schedule, its last run is kept
+    pub fn update_schedule(&self, id: ScheduleId, spec: ScheduleSpec) -> Result<(), Box<dyn std::error::Error>> {
+        let new_rec = self.schedule_of(spec)?;
+
+        Ok(self.db.schedules.modify(id, |rec| Some(ScheduleRecord {
+            id: rec.id,
+            last_run: rec.last_run,
+            created_at: rec.created_at,
+            ..new_rec
+        }))?)
+    }
+
+    pub fn remove_schedule(&self, id: ScheduleId) -> Result<(), Box<dyn std::error::Error>> {
+        match self.db.schedules.remove(id)? {
+            true => Ok(()),
+            false => Err(Box::new(io::Error::new(io::ErrorKind::NotFound, format!("Schedule {} doesn't exist", id))))
+        }
+    }
+
+    fn schedule_of(&self, spec: ScheduleSpec) -> Result<ScheduleRecord, Box<dyn std::error::Error>> {
+        let invalid = |msg: String| -> Box<dyn std::error::Error> { Box::new(io::Error::new(io::ErrorKind::InvalidInput, msg)) };
+
+        if spec.name.is_empty() {
+            return Err(invalid("Schedule name can't be empty".to_string()));
+        }
+        if spec.steps.is_empty() {
+            return Err(invalid("Schedule has no steps".to_string()));
+        }
+        if let Trigger::Sun { offset, .. } = spec.trigger {
+            if self.db.site().location.is_none() {
+                return Err(invalid("Schedules relative to sun events need site.location in configuration".to_string()));
+            }
+            if offset.abs() > 720 {
+                return Err(invalid(format!("Offset {} min is out of range, use -720 to 720 min", offset)));
+            }
+        }
+
+        let mut steps = Vec::new();
+        for (idx, step) in spec.steps.into_iter().enumerate() {
+            let (addresses, label) = match (step.addresses, step.label) {
+                (Some(addresses), None) => {
+                    let addresses = addresses.iter()
+                        .map(|address| address.parse::<NodeAddr>().map(|addr| addr.0).map_err(|err| invalid(err.to_string())))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    (self.select_nodes(Some(addresses), None)?, None)
+                },
+                (None, Some(label)) => {
+                    label.parse::<LabelFilter>().map_err(|err| invalid(err.to_string()))?;
+                    (Vec::new(), Some(label))
+                },
+                _ => return Err(invalid(format!("Step {}: give either addresses or label filter of nodes", idx + 1)))
+            };
+
+            steps.push(ScheduleStep {
+                addresses: addresses,
+                label: label,
+                ioa: step.ioa,
+                ti: step.ti,
+                value: step.value,
+                select: step.select
+            });
+        }
+
+        let mut rec = ScheduleRecord::new(spec.name, spec.trigger, steps);
+        rec.days = spec.days;
+        rec.enabled = spec.enabled;
+        Ok(rec)
+    }
+
+    /// Emergency lighting tests of node with their results
+    pub fn em_tests(&self, address: &NodeAddress) -> Result<EmTestsRecord, Box<dyn std::error::Error>> {
+        Ok(self.db.em_tests.get(address)?)
+    }
+
+    /// Compliance of emergency lighting with required test periods, of nodes matching filter (never tested ones included) or of all tested nodes
+    pub fn em_report(&self, filter: Option<&LabelFilter>, function_days: u64, duration_days: u64) -> Result<Vec<Compliance>, Box<dyn std::error::Error>> {
+        let now = unix_now();
+        let records = match filter {
+            Some(filter) => {
+                let mut records = Vec::new();
+                for info in self.db.query_nodes(filter)? {
+                    records.push((info.node.address, self.db.em_tests.get(&info.node.address)?));
+                }
+                records
+            },
+            None => self.db.em_tests.list()?
+        };
+
+        Ok(records.iter()
+            .map(|(address, rec)| rec.compliance(address, now, function_days, duration_days))
+            .collect())
+    }
+
+    /// Frames of node decoder didn't understand, captured if raw capture is enabled for node
+    pub fn raw_frames(&self, address: &NodeAddress) -> Result<RawFramesRecord, Box<dyn std::error::Error>> {
+        Ok(self.db.raw_frames.get(address)?)
+    }
+
+    /// Forget captured frames of node, e.g. after they were analyzed
+    pub fn clear_raw_frames(&self, address: &NodeAddress) -> Result<(), Box<dyn std::error::Error>> {
+        self.db.raw_frames.clear(address)?;
+        Ok(())
+    }
+
+    /// Energy meters of node with daily consumption
+    pub fn energy(&self, address: &NodeAddress) -> Result<EnergyRecord, Box<dyn std::error::Error>> {
+        Ok(self.db.energy.get(address)?)
+    }
+}
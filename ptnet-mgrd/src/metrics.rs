@@ -0,0 +1,184 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use serde::Serialize;
+
+use crate::{database::{Database, campaign_table::{CampaignId, CampaignRecord, CampaignState, NodeState}, compaction::StorageStats}, error::DbError};
+
+/// Nodes of campaign in each state
+#[derive(Debug,Clone,Default,Serialize,PartialEq)]
+pub struct CampaignProgress {
+    pub id: CampaignId,
+    pub state: CampaignState,
+    pub pending: usize,
+    pub updating: usize,
+    pub done: usize,
+    pub failed: usize
+}
+
+impl CampaignProgress {
+    fn from_record(rec: &CampaignRecord) -> Self {
+        Self {
+            id: rec.id,
+            state: rec.state,
+            pending: rec.count(|st| *st == NodeState::Pending),
+            updating: rec.count(|st| matches!(st, NodeState::Updating(_))),
+            done: rec.count(|st| *st == NodeState::Done),
+            failed: rec.count(|st| *st == NodeState::Failed)
+        }
+    }
+
+    /// Share of selected nodes which are done or failed, 0 to 1
+    pub fn progress(&self) -> f64 {
+        match self.pending + self.updating + self.done + self.failed {
+            0 => 0.0,
+            total => (self.done + self.failed) as f64 / total as f64
+        }
+    }
+}
+
+/// Current state of network, as gauges alert rules can be written against
+#[derive(Debug,Clone,Default,Serialize,PartialEq)]
+pub struct StateMetrics {
+    pub nodes_online: usize,
+    pub nodes_degraded: usize,
+    pub nodes_offline: usize,
+    /// discovered nodes awaiting adoption
+    pub nodes_pending: usize,
+    /// nodes with update attempt running
+    pub fwu_in_progress: usize,
+    /// nodes whose last update attempt failed
+    pub fwu_failed: usize,
+    /// active alarms by rule
+    pub alarms_active: BTreeMap<String, usize>,
+    pub campaigns: Vec<CampaignProgress>,
+    /// last measurement of database, None until measured
+    pub storage: Option<StorageStats>
+}
+
+/// Label value with backslash, quote and newline escaped
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn campaign_state(state: CampaignState) -> &'static str {
+    match state {
+        CampaignState::Running => "running",
+        CampaignState::Paused => "paused",
+        CampaignState::Finished => "finished"
+    }
+}
+
+/// Write HELP and TYPE of gauge
+fn gauge(out: &mut String, name: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} gauge", name).unwrap();
+}
+
+impl StateMetrics {
+    pub fn collect(db: &Database) -> Result<Self, DbError> {
+        let health = db.health.summary()?;
+        let fwu_states = db.fwu_state.list()?;
+        let mut alarms_active: BTreeMap<String, usize> = BTreeMap::new();
+
+        for (_, alarm) in db.alarms.list_active()? {
+            *alarms_active.entry(alarm.rule).or_default() += 1;
+        }
+
+        Ok(Self {
+            nodes_online: health.online,
+            nodes_degraded: health.degraded,
+            nodes_offline: health.offline,
+            nodes_pending: db.nodes.load_many(db.nodes.list()?.iter())?.iter().filter(|node| node.pending).count(),
+            fwu_in_progress: fwu_states.iter().filter(|(_, rec)| rec.attempt.is_some()).count(),
+            fwu_failed: fwu_states.iter().filter(|(_, rec)| rec.failures > 0).count(),
+            alarms_active: alarms_active,
+            campaigns: db.campaigns.list()?.iter().map(CampaignProgress::from_record).collect(),
+            storage: db.storage_stats()
+        })
+    }
+
+    /// Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        gauge(&mut out, "ptnet_nodes", "Nodes by health state");
+        for (state, count) in [("online", self.nodes_online), ("degraded", self.nodes_degraded), ("offline", self.nodes_offline)] {
+            writeln!(out, "ptnet_nodes{{state=\"{}\"}} {}", state, count).unwrap();
+        }
+
+        gauge(&mut out, "ptnet_nodes_online", "Nodes responding");
+        writeln!(out, "ptnet_nodes_online {}", self.nodes_online).unwrap();
+        gauge(&mut out, "ptnet_nodes_offline", "Nodes not responding");
+        writeln!(out, "ptnet_nodes_offline {}", self.nodes_offline).unwrap();
+        gauge(&mut out, "ptnet_nodes_pending", "Discovered nodes awaiting adoption");
+        writeln!(out, "ptnet_nodes_pending {}", self.nodes_pending).unwrap();
+
+        gauge(&mut out, "ptnet_fwu_in_progress", "Nodes with firmware update running");
+        writeln!(out, "ptnet_fwu_in_progress {}", self.fwu_in_progress).unwrap();
+        gauge(&mut out, "ptnet_fwu_failed", "Nodes whose last firmware update attempt failed");
+        writeln!(out, "ptnet_fwu_failed {}", self.fwu_failed).unwrap();
+
+        gauge(&mut out, "ptnet_alarms_active", "Active alarms");
+        writeln!(out, "ptnet_alarms_active {}", self.alarms_active.values().sum::<usize>()).unwrap();
+        gauge(&mut out, "ptnet_alarms_active_by_rule", "Active alarms by rule");
+        for (rule, count) in self.alarms_active.iter() {
+            writeln!(out, "ptnet_alarms_active_by_rule{{rule=\"{}\"}} {}", label(rule), count).unwrap();
+        }
+
+        gauge(&mut out, "ptnet_campaign_nodes", "Nodes of firmware update campaign by node state");
+        for campaign in self.campaigns.iter() {
+            for (state, count) in [("pending", campaign.pending), ("updating", campaign.updating), ("done", campaign.done), ("failed", campaign.failed)] {
+                writeln!(out, "ptnet_campaign_nodes{{campaign=\"{}\",state=\"{}\"}} {}", campaign.id, state, count).unwrap();
+            }
+        }
+        gauge(&mut out, "ptnet_campaign_progress", "Share of campaign nodes done or failed, 0 to 1");
+        for campaign in self.campaigns.iter() {
+            writeln!(out, "ptnet_campaign_progress{{campaign=\"{}\"}} {}", campaign.id, campaign.progress()).unwrap();
+        }
+        gauge(&mut out, "ptnet_campaign_state", "1 for current state of campaign");
+        for campaign in self.campaigns.iter() {
+            for state in [CampaignState::Running, CampaignState::Paused, CampaignState::Finished] {
+                writeln!(out, "ptnet_campaign_state{{campaign=\"{}\",state=\"{}\"}} {}", campaign.id, campaign_state(state), (campaign.state == state) as u8).unwrap();
+            }
+        }
+
+        if let Some(storage) = &self.storage {
+            gauge(&mut out, "ptnet_database_size_bytes", "Size of database file");
+            writeln!(out, "ptnet_database_size_bytes {}", storage.file_size).unwrap();
+            gauge(&mut out, "ptnet_database_fragmented_bytes", "Free space inside database file");
+            writeln!(out, "ptnet_database_fragmented_bytes {}", storage.fragmented).unwrap();
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prometheus_format() {
+        let metrics = StateMetrics {
+            nodes_online: 10,
+            nodes_offline: 2,
+            fwu_failed: 1,
+            alarms_active: BTreeMap::from([("lamp \"fail\"".to_string(), 3)]),
+            campaigns: vec![CampaignProgress { id: 7, state: CampaignState::Paused, pending: 1, updating: 1, done: 1, failed: 1 }],
+            ..Default::default()
+        };
+        let text = metrics.to_prometheus();
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert!(lines.contains(&"ptnet_nodes_online 10"));
+        assert!(lines.contains(&"ptnet_nodes{state=\"offline\"} 2"));
+        assert!(lines.contains(&"ptnet_fwu_failed 1"));
+        assert!(lines.contains(&"ptnet_alarms_active 3"));
+        assert!(lines.contains(&"ptnet_alarms_active_by_rule{rule=\"lamp \\\"fail\\\"\"} 3"));
+        assert!(lines.contains(&"ptnet_campaign_nodes{campaign=\"7\",state=\"updating\"} 1"));
+        assert!(lines.contains(&"ptnet_campaign_progress{campaign=\"7\"} 0.5"));
+        assert!(lines.contains(&"ptnet_campaign_state{campaign=\"7\",state=\"paused\"} 1"));
+        assert!(lines.contains(&"ptnet_campaign_state{campaign=\"7\",state=\"running\"} 0"));
+        assert!(!text.contains("ptnet_database_size_bytes"), "database not measured yet");
+    }
+}
@@ -0,0 +1,714 @@
+use std::{collections::{HashMap, HashSet}, sync::{Arc, Mutex}, time::Duration};
+
+use futures::{stream, StreamExt};
+
+use log::{debug, info, warn, error};
+use rumqttc::{AsyncClient, EventLoop, MqttOptions, LastWill, QoS, Event, Packet, Publish};
+use serde::{Serialize, Deserialize};
+use tokio::{sync::{broadcast, mpsc, Notify}, time::sleep, select};
+
+use crate::{sparkplug::{EdgeNode, SparkplugConfig, MetricValue, Payload, REBIRTH_METRIC, now_ms}, database::{Database, NodeAddress, NodeAddr, node_table, point_table, health_table, fwu_state_table, alarm_table, derived_table, link_quality_table, journal_table::{JournalEntry, JournalEvent}}, ptnet_process::{ApiRequest, submit}, journal, site::{SiteConfig, Labels, Tags}};
+
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+/// Max. MQTT commands executed at once
+const MAX_CONCURRENT_COMMANDS: usize = 8;
+
+#[derive(Debug,Clone,Copy,Serialize,Deserialize,PartialEq)]
+pub enum PayloadFormat {
+    /// JSON on `<base_topic>/<site>/...` topics
+    Json,
+    /// Sparkplug B, gateway is edge node and nodes are its devices
+    SparkplugB
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// topics are `<base_topic>/<site>/...`
+    pub base_topic: String,
+    /// site part of topics, `site.id` if not set
+    pub site: Option<String>,
+    /// keep alive interval (seconds)
+    pub keep_alive: u64,
+    /// pause before reconnecting to broker (seconds)
+    pub t_reconnect: u64,
+    /// publish Home Assistant discovery messages
+    pub discovery: bool,
+    pub discovery_prefix: String,
+    /// node part of state topics `<base_topic>/<site>/<node_topic>/...`: `{node}` is node address,
+    /// `{label:<key>}` value of label and `{tag:<a>|<b>...}` first of listed tags node has, `_` if missing
+    pub node_topic: String,
+    /// accept commands on `<base_topic>/<site>/<node>/set/<ioa>`, regardless of `node_topic`
+    pub commands: bool,
+    /// how long to wait for command to be executed on ptlink connection (seconds)
+    pub command_timeout: u64,
+    /// format of published data, commands are always JSON
+    pub payload: PayloadFormat,
+    pub sparkplug: SparkplugConfig
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: 1883,
+            client_id: "ptnet-mgrd".to_string(),
+            username: None,
+            password: None,
+            base_topic: "ptnet".to_string(),
+            site: None,
+            keep_alive: 30,
+            t_reconnect: 10,
+            discovery: false,
+            discovery_prefix: "homeassistant".to_string(),
+            node_topic: "{node}".to_string(),
+            commands: false,
+            command_timeout: 60,
+            payload: PayloadFormat::Json,
+            sparkplug: Default::default()
+        }
+    }
+}
+
+/// Node address as used in topics, hex digits without separators
+fn topic_address(address: &NodeAddress) -> String {
+    address.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Inverse of topic_address()
+fn parse_topic_address(s: &str) -> Option<NodeAddress> {
+    s.parse::<NodeAddr>().ok().map(|addr| addr.0)
+}
+
+/// Topic-safe value of placeholder
+fn topic_level(value: &str) -> String {
+    value.chars().map(|c| if "/+#".contains(c) { '_' } else { c }).collect()
+}
+
+/// Node part of state topics, `node_topic` template filled in
+fn render_node_topic(template: &str, address: &NodeAddress, labels: &Labels, tags: &Tags) -> String {
+    let mut topic = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break
+        };
+        topic.push_str(&rest[..start]);
+
+        let placeholder = &rest[start + 1..end];
+        let value = match placeholder.split_once(':') {
+            None if placeholder == "node" => Some(topic_address(address)),
+            Some(("label", key)) => labels.get(key).map(|value| topic_level(value)),
+            Some(("tag", names)) => names.split('|').find(|name| tags.contains(*name)).map(|name| name.to_string()),
+            _ => None
+        };
+        topic.push_str(value.as_deref().unwrap_or("_"));
+        rest = &rest[end + 1..];
+    }
+
+    topic.push_str(rest);
+    topic
+}
+
+/// Node and IOA of command topic `<prefix><node>/set/<ioa>`
+fn parse_command_topic(prefix: &str, topic: &str) -> Option<(NodeAddress, u32)> {
+    let parts: Vec<&str> = topic.strip_prefix(prefix)?.split('/').collect();
+
+    match parts.as_slice() {
+        [address, "set", ioa] => Some((parse_topic_address(address)?, ioa.parse().ok()?)),
+        _ => None
+    }
+}
+
+/// Daemon availability, also used as last will
+fn availability_topic(conf: &MqttConfig, site: &str) -> String {
+    format!("{}/{}/status", conf.base_topic, site)
+}
+
+#[derive(Debug,Serialize)]
+struct DiscoveryDevice {
+    identifiers: Vec<String>,
+    name: String,
+    manufacturer: &'static str
+}
+
+/// Home Assistant sensor config
+#[derive(Debug,Serialize)]
+struct DiscoveryConfig {
+    name: String,
+    unique_id: String,
+    state_topic: String,
+    value_template: String,
+    availability_topic: String,
+    device: DiscoveryDevice
+}
+
+/// Payload of command topic
+#[derive(Debug,Deserialize)]
+struct CommandPayload {
+    /// echoed in result
+    id: Option<String>,
+    ti: u8,
+    value: u32,
+    /// select with this value before executing
+    select: Option<u32>
+}
+
+/// Payload of command result topic
+#[derive(Debug,Serialize)]
+struct CommandResult {
+    id: Option<String>,
+    ok: bool,
+    error: Option<String>
+}
+
+/// Publishes node status, measurements and FWU progress to MQTT broker, executes commands received from it
+pub struct MqttPublisher<'a> {
+    conf: MqttConfig,
+    /// site published with, ID is site part of topics
+    site: SiteConfig,
+    db: &'a Database<'a>,
+    client: AsyncClient,
+    /// (node, series) measurement sensors already announced to Home Assistant
+    announced: Mutex<HashSet<(NodeAddress, String)>>,
+    /// node part of state topics by node, labels and tags change it
+    node_topics: Mutex<HashMap<NodeAddress, String>>,
+    /// broker (re)connected, signalled by event loop
+    reconnected: Notify,
+    /// commands are executed by ApiProcess, None if there's nothing to execute them
+    requests: Option<mpsc::Sender<ApiRequest>>,
+    incoming: (mpsc::Sender<Publish>, tokio::sync::Mutex<mpsc::Receiver<Publish>>),
+    /// Sparkplug state, None in JSON mode
+    edge: Option<Mutex<EdgeNode>>,
+    /// rebirth requested by Sparkplug host application
+    rebirth: Notify
+}
+
+impl<'a> MqttPublisher<'a> {
+    pub fn new(conf: MqttConfig, db: &'a Database<'a>, requests: Option<mpsc::Sender<ApiRequest>>) -> (Self, EventLoop) {
+        let mut options = MqttOptions::new(&conf.client_id, &conf.host, conf.port);
+        options.set_keep_alive(Duration::from_secs(conf.keep_alive));
+        if let Some(username) = &conf.username {
+            options.set_credentials(username, conf.password.as_deref().unwrap_or(""));
+        }
+
+        let mut site = db.site();
+        if let Some(topic_site) = &conf.site {
+            site.id = topic_site.clone();
+        }
+
+        let edge = match conf.payload {
+            PayloadFormat::Json => {
+                options.set_last_will(LastWill::new(availability_topic(&conf, &site.id), OFFLINE, QoS::AtLeastOnce, true));
+                None
+            },
+            PayloadFormat::SparkplugB => {
+                let edge = EdgeNode::new(conf.sparkplug.clone(), &site);
+                let (topic, death) = edge.death();
+                options.set_last_will(LastWill::new(topic, death, QoS::AtLeastOnce, false));
+                Some(Mutex::new(edge))
+            }
+        };
+
+        let (client, eventloop) = AsyncClient::new(options, 64);
+        let (incoming, incoming_rcvr) = mpsc::channel(32);
+        let publisher = MqttPublisher {
+            conf: conf,
+            site: site,
+            db: db,
+            client: client,
+            announced: Mutex::new(HashSet::new()),
+            node_topics: Mutex::new(HashMap::new()),
+            reconnected: Notify::new(),
+            requests: requests,
+            incoming: (incoming, tokio::sync::Mutex::new(incoming_rcvr)),
+            edge: edge,
+            rebirth: Notify::new()
+        };
+
+        (publisher, eventloop)
+    }
+
+    fn availability_topic(&self) -> String {
+        availability_topic(&self.conf, &self.site.id)
+    }
+
+    fn command_filter(&self) -> String {
+        format!("{}/{}/+/set/+", self.conf.base_topic, self.site.id)
+    }
+
+    fn site_topic(&self, suffix: &str) -> String {
+        format!("{}/{}/{}", self.conf.base_topic, self.site.id, suffix)
+    }
+
+    /// Node part of state topics from current labels and tags of node
+    fn render_node_topic(&self, address: &NodeAddress) -> String {
+        let node = self.db.nodes.load_many(std::iter::once(address)).ok().and_then(|mut nodes| nodes.pop()).unwrap_or_default();
+        render_node_topic(&self.conf.node_topic, address, &self.site.labels_of(&node.labels), &node.tags)
+    }
+
+    fn node_topic(&self, address: &NodeAddress, suffix: &str) -> String {
+        let mut node_topics = self.node_topics.lock().unwrap();
+        let node = node_topics.entry(*address).or_insert_with(|| self.render_node_topic(address));
+        format!("{}/{}/{}/{}", self.conf.base_topic, self.site.id, node, suffix)
+    }
+
+    /// Topic of command results, same node part as command topics
+    fn command_topic(&self, address: &NodeAddress, suffix: &str) -> String {
+        format!("{}/{}/{}/{}", self.conf.base_topic, self.site.id, topic_address(address), suffix)
+    }
+
+    /// Delete retained state of node on its topics
+    async fn clear_node(&self, address: &NodeAddress) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // empty retained message deletes retained state of node
+        for suffix in ["node", "status", "link_quality"] {
+            self.client.publish(self.node_topic(address, suffix), QoS::AtLeastOnce, true, Vec::new()).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn publish<T: Serialize>(&self, topic: String, payload: &T, retain: bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.client.publish(topic, QoS::AtLeastOnce, retain, serde_json::to_vec(payload)?).await?;
+        Ok(())
+    }
+
+    async fn publish_sparkplug(&self, messages: Vec<(String, Vec<u8>)>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        for (topic, payload) in messages {
+            self.client.publish(topic, QoS::AtMostOnce, false, payload).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Update Sparkplug metrics of node, publishing births or data as needed
+    async fn update_metrics(&self, edge: &Mutex<EdgeNode>, address: &NodeAddress, metrics: Vec<(&str, MetricValue, u64)>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let messages: Vec<_> = {
+            let mut edge = edge.lock().unwrap();
+            metrics.into_iter().map(|(name, value, timestamp)| edge.update(address, name, value, timestamp)).collect()
+        };
+
+        self.publish_sparkplug(messages).await
+    }
+
+    /// NBIRTH and DBIRTH of all nodes
+    async fn births(&self, edge: &Mutex<EdgeNode>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let nodes = self.db.nodes.list().map_err(|err| err.to_string())?;
+        let messages = {
+            let mut edge = edge.lock().unwrap();
+            for address in &nodes {
+                edge.add_device(address);
+            }
+            edge.births()
+        };
+
+        self.publish_sparkplug(messages).await
+    }
+
+    /// Announce sensor of node to Home Assistant
+    async fn announce(&self, address: &NodeAddress, object: &str, state_topic: String, value_template: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let node = topic_address(address);
+        let config = DiscoveryConfig {
+            name: format!("{} {}", node, object),
+            unique_id: format!("ptnet_{}_{}", node, object),
+            state_topic: state_topic,
+            value_template: value_template.to_string(),
+            availability_topic: self.availability_topic(),
+            device: DiscoveryDevice {
+                identifiers: vec![format!("ptnet_{}", node)],
+                name: format!("ptnet {}", node),
+                manufacturer: "ptnet"
+            }
+        };
+
+        let topic = format!("{}/sensor/ptnet_{}/{}/config", self.conf.discovery_prefix, node, object);
+        self.publish(topic, &config, true).await
+    }
+
+    async fn announce_node(&self, address: &NodeAddress) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.announce(address, "health", self.node_topic(address, "status"), "{{ value_json.health }}").await
+    }
+
+    async fn announce_series(&self, address: &NodeAddress, series: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let new = self.announced.lock().unwrap().insert((*address, series.to_string()));
+        if new {
+            self.announce(address, series, self.node_topic(address, &format!("measurement/{}", series)), "{{ value_json.value }}").await?;
+        }
+
+        Ok(())
+    }
+
+    /// Called on every (re)connect to broker, retained messages may have been lost
+    async fn connected(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if self.conf.commands && self.requests.is_some() {
+            self.client.subscribe(self.command_filter(), QoS::AtLeastOnce).await?;
+        }
+
+        if let Some(edge) = &self.edge {
+            let command_topic = edge.lock().unwrap().command_topic();
+            self.client.subscribe(command_topic, QoS::AtLeastOnce).await?;
+            return self.births(edge).await;
+        }
+
+        self.client.publish(self.availability_topic(), QoS::AtLeastOnce, true, ONLINE).await?;
+        // lets backends tell sites apart without knowing topic layout
+        self.publish(self.site_topic("site"), &self.site, true).await?;
+
+        if self.conf.discovery {
+            self.announced.lock().unwrap().clear();
+            for address in self.db.nodes.list().map_err(|err| err.to_string())? {
+                self.announce_node(&address).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn poll(&self, mut eventloop: EventLoop) {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to MQTT broker at {}:{}", self.conf.host, self.conf.port);
+                    // publishing needs event loop running, so it's done by forward()
+                    self.reconnected.notify_one();
+                },
+                Ok(Event::Incoming(Packet::Publish(publish))) if self.is_rebirth(&publish) => {
+                    info!("Sparkplug rebirth requested");
+                    self.rebirth.notify_one();
+                },
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    if self.incoming.0.try_send(publish).is_err() {
+                        warn!("Too many MQTT commands in progress, command dropped");
+                    }
+                },
+                Ok(_) => {},
+                Err(err) => {
+                    warn!("MQTT connection error! ({})", err);
+
+                    // reconnect starts new Sparkplug session with its own death certificate
+                    if let Some(edge) = &self.edge {
+                        let (topic, death) = {
+                            let mut edge = edge.lock().unwrap();
+                            edge.next_session();
+                            edge.death()
+                        };
+                        eventloop.mqtt_options.set_last_will(LastWill::new(topic, death, QoS::AtLeastOnce, false));
+                    }
+
+                    sleep(Duration::from_secs(self.conf.t_reconnect)).await;
+                }
+            }
+        }
+    }
+
+    /// Node control command of Sparkplug host requesting rebirth
+    fn is_rebirth(&self, publish: &Publish) -> bool {
+        let edge = match &self.edge {
+            Some(edge) => edge,
+            None => return false
+        };
+
+        if publish.topic != edge.lock().unwrap().command_topic() {
+            return false;
+        }
+
+        match Payload::decode_booleans(&publish.payload) {
+            Ok(metrics) => metrics.iter().any(|(name, value)| name == REBIRTH_METRIC && *value),
+            Err(err) => {
+                warn!("Ignoring Sparkplug command! ({})", err);
+                false
+            }
+        }
+    }
+
+    async fn on_node(&self, evt: node_table::Event) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(edge) = &self.edge {
+            return match evt {
+                node_table::Event::NodeAdded(rec) => {
+                    let birth = edge.lock().unwrap().add_device(&rec.address);
+                    self.publish_sparkplug(birth.into_iter().collect()).await
+                },
+                node_table::Event::NodeModified(rec) => {
+                    let mut metrics = Vec::new();
+                    if let Some(online) = rec.online {
+                        metrics.push(("online", MetricValue::Boolean(online), now_ms()));
+                    }
+                    if let Some(last_seen) = rec.last_seen {
+                        metrics.push(("last_seen", MetricValue::UInt64(last_seen * 1000), now_ms()));
+                    }
+                    self.update_metrics(edge, &rec.address, metrics).await
+                },
+                node_table::Event::NodeRemoved(address) => {
+                    let death = edge.lock().unwrap().remove_device(&address);
+                    self.publish_sparkplug(death.into_iter().collect()).await
+                }
+            };
+        }
+
+        match evt {
+            node_table::Event::NodeAdded(rec) => {
+                if self.conf.discovery {
+                    self.announce_node(&rec.address).await?;
+                }
+                self.publish(self.node_topic(&rec.address, "node"), &*rec, true).await
+            },
+            node_table::Event::NodeModified(rec) => {
+                let current = self.node_topics.lock().unwrap().get(&rec.address).cloned();
+                let changed = self.render_node_topic(&rec.address);
+                // labels or tags moved node to other topics
+                if matches!(&current, Some(current) if *current != changed) {
+                    self.clear_node(&rec.address).await?;
+                    self.node_topics.lock().unwrap().insert(rec.address, changed);
+                }
+                self.publish(self.node_topic(&rec.address, "node"), &*rec, true).await
+            },
+            node_table::Event::NodeRemoved(address) => {
+                self.clear_node(&address).await?;
+                self.node_topics.lock().unwrap().remove(&address);
+                Ok(())
+            }
+        }
+    }
+
+    async fn on_health(&self, evt: health_table::Event) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match evt {
+            health_table::Event::HealthChanged(address, _, rec) if self.edge.is_some() =>
+                self.update_metrics(self.edge.as_ref().unwrap(), &address, vec![("health", MetricValue::String(format!("{:?}", rec.health)), rec.since * 1000)]).await,
+            health_table::Event::HealthChanged(address, _, rec) => self.publish(self.node_topic(&address, "status"), &*rec, true).await
+        }
+    }
+
+    async fn on_sample(&self, evt: point_table::Event) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match evt {
+            point_table::Event::SampleAdded(address, series, sample) if self.edge.is_some() => {
+                match MetricValue::from_json(&serde_json::to_value(&sample.value)?) {
+                    Some(value) => self.update_metrics(self.edge.as_ref().unwrap(), &address, vec![(series.as_str(), value, sample.at * 1000)]).await,
+                    None => {
+                        debug!("Sample of {} has no numeric value, not published", series);
+                        Ok(())
+                    }
+                }
+            },
+            point_table::Event::SampleAdded(address, series, sample) => {
+                if self.conf.discovery {
+                    self.announce_series(&address, &series).await?;
+                }
+                self.publish(self.node_topic(&address, &format!("measurement/{}", series)), &*sample, true).await
+            }
+        }
+    }
+
+    async fn on_fwu(&self, evt: fwu_state_table::Event) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match evt {
+            fwu_state_table::Event::FWUProgress(address, progress) if self.edge.is_some() => {
+                let at = progress.updated_at * 1000;
+                self.update_metrics(self.edge.as_ref().unwrap(), &address, vec![
+                    ("fwu/phase", MetricValue::String(format!("{:?}", progress.phase)), at)
+                ]).await
+            },
+            fwu_state_table::Event::FWUProgress(address, progress) => self.publish(self.node_topic(&address, "fwu"), &*progress, false).await,
+            _ => Ok(())
+        }
+    }
+
+    async fn on_alarm(&self, evt: alarm_table::Event) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match evt {
+            alarm_table::Event::AlarmChanged(address, rec) if self.edge.is_some() => {
+                let name = format!("alarm/{}", rec.rule);
+                let at = rec.cleared_at.unwrap_or(rec.raised_at) * 1000;
+                self.update_metrics(self.edge.as_ref().unwrap(), &address, vec![(name.as_str(), MetricValue::Boolean(rec.active), at)]).await
+            },
+            alarm_table::Event::AlarmChanged(address, rec) => self.publish(self.node_topic(&address, &format!("alarm/{}", rec.rule)), &*rec, true).await
+        }
+    }
+
+    async fn on_link_quality(&self, evt: link_quality_table::Event) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match evt {
+            link_quality_table::Event::LinkQualityUpdated(address, rec) if self.edge.is_some() => {
+                let at = rec.updated_at * 1000;
+                let mut metrics = vec![("link/scans", MetricValue::UInt64(rec.scans), at), ("link/retries", MetricValue::UInt64(rec.retries), at)];
+                if let Some(success_rate) = rec.success_rate() {
+                    metrics.push(("link/success_rate", MetricValue::Double(success_rate), at));
+                }
+                if let Some(median) = rec.percentile(50) {
+                    metrics.push(("link/median_response", MetricValue::UInt64(median as u64), at));
+                }
+                self.update_metrics(self.edge.as_ref().unwrap(), &address, metrics).await
+            },
+            link_quality_table::Event::LinkQualityUpdated(address, rec) => self.publish(self.node_topic(&address, "link_quality"), &rec.summary(), true).await
+        }
+    }
+
+    async fn on_derived(&self, evt: derived_table::Event) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match evt {
+            // derived points belong to no device
+            derived_table::Event::DerivedUpdated(name, _) if self.edge.is_some() => {
+                debug!("Derived point {} is not published in Sparkplug mode", name);
+                Ok(())
+            },
+            derived_table::Event::DerivedUpdated(name, sample) =>
+                self.publish(self.site_topic(&format!("derived/{}", name)), &*sample, true).await
+        }
+    }
+
+    /// Journal consumer name, acknowledged offset survives restarts
+    fn journal_consumer(&self) -> String {
+        format!("mqtt:{}", self.conf.client_id)
+    }
+
+    /// Publish journaled event, acknowledged once handed over to MQTT client
+    async fn on_journal(&self, entry: JournalEntry) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match entry.event {
+            JournalEvent::NodeAdded(rec) => self.on_node(node_table::Event::NodeAdded(Arc::new(rec))).await?,
+            JournalEvent::NodeModified(rec) => self.on_node(node_table::Event::NodeModified(Arc::new(rec))).await?,
+            JournalEvent::NodeRemoved(address) => self.on_node(node_table::Event::NodeRemoved(address)).await?,
+            JournalEvent::AlarmChanged(address, rec) => self.on_alarm(alarm_table::Event::AlarmChanged(address, Arc::new(rec))).await?,
+            // only progress of updates is published
+            JournalEvent::FWUState(..) => {}
+        };
+
+        self.db.journal.ack(&self.journal_consumer(), entry.offset).map_err(|err| err.to_string())?;
+
+        Ok(())
+    }
+
+    async fn forward(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // node and alarm events resume from journal where previous run stopped
+        let acked = self.db.journal.acked(&self.journal_consumer()).map_err(|err| err.to_string())?;
+        let mut journal = Box::pin(journal::follow(self.db, acked));
+        let mut health = self.db.health.events.subscribe();
+        let mut points = self.db.points.events.subscribe();
+        let mut fwu = self.db.fwu_state.events.subscribe();
+        let mut derived = self.db.derived.events.subscribe();
+        let mut link_quality = self.db.link_quality.events.subscribe();
+
+        loop {
+            let result = select! {
+                _ = self.reconnected.notified() => self.connected().await,
+                _ = self.rebirth.notified() => match &self.edge {
+                    Some(edge) => self.births(edge).await,
+                    None => Ok(())
+                },
+                entry = journal.next() => match entry { Some(entry) => self.on_journal(entry).await, None => Err("Event journal closed".into()) },
+                evt = health.recv() => match evt { Ok(evt) => self.on_health(evt).await, Err(err) => lagged(err) },
+                evt = points.recv() => match evt { Ok(evt) => self.on_sample(evt).await, Err(err) => lagged(err) },
+                evt = fwu.recv() => match evt { Ok(evt) => self.on_fwu(evt).await, Err(err) => lagged(err) },
+                evt = derived.recv() => match evt { Ok(evt) => self.on_derived(evt).await, Err(err) => lagged(err) },
+                evt = link_quality.recv() => match evt { Ok(evt) => self.on_link_quality(evt).await, Err(err) => lagged(err) }
+            };
+            result?;
+        }
+    }
+
+    /// Execute command through ApiProcess
+    async fn command(&self, address: NodeAddress, ioa: u32, cmd: CommandPayload) -> Result<(), String> {
+        let requests = self.requests.as_ref().ok_or_else(|| "Commands not available".to_string())?;
+
+        submit(requests, Duration::from_secs(self.conf.command_timeout), |reply| ApiRequest::Command {
+            address: address,
+            ioa: ioa,
+            ti: cmd.ti,
+            value: cmd.value,
+            select: cmd.select,
+            reply: reply
+        }).await.map_err(|err| err.to_string())
+    }
+
+    /// Validate and execute received command, result is published unless topic is malformed
+    async fn execute(&self, publish: Publish) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let prefix = format!("{}/{}/", self.conf.base_topic, self.site.id);
+        let (address, ioa) = match parse_command_topic(&prefix, &publish.topic) {
+            Some(target) => target,
+            None => {
+                warn!("Ignoring MQTT command on unexpected topic {}", publish.topic);
+                return Ok(());
+            }
+        };
+
+        let result = match serde_json::from_slice::<CommandPayload>(&publish.payload) {
+            Err(err) => CommandResult { id: None, ok: false, error: Some(format!("Invalid command ({})", err)) },
+            Ok(cmd) => {
+                let id = cmd.id.clone();
+                debug!("MQTT command for IOA {} of node {}", ioa, NodeAddr(address));
+
+                match self.command(address, ioa, cmd).await {
+                    Ok(()) => CommandResult { id: id, ok: true, error: None },
+                    Err(err) => CommandResult { id: id, ok: false, error: Some(err) }
+                }
+            }
+        };
+
+        self.publish(self.command_topic(&address, &format!("result/{}", ioa)), &result, false).await
+    }
+
+    async fn commands(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut incoming = self.incoming.1.lock().await;
+
+        stream::poll_fn(|cx| incoming.poll_recv(cx))
+            .for_each_concurrent(MAX_CONCURRENT_COMMANDS, |publish| async move {
+                if let Err(err) = self.execute(publish).await {
+                    error!("Error executing MQTT command! ({})", err);
+                }
+            })
+            .await;
+
+        Ok(())
+    }
+
+    /// Publish until error
+    pub async fn run(&self, eventloop: EventLoop) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        select! {
+            _ = self.poll(eventloop) => Ok(()),
+            result = self.forward() => result,
+            result = self.commands() => result
+        }
+    }
+}
+
+/// Events missed by slow publisher are skipped
+fn lagged(err: broadcast::error::RecvError) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match err {
+        broadcast::error::RecvError::Lagged(n) => {
+            debug!("MQTT publisher skipped {} events", n);
+            Ok(())
+        },
+        broadcast::error::RecvError::Closed => Err(Box::new(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_topic() {
+        let address = [0x01, 0x02, 0x03, 0x0A, 0x0B, 0xFF];
+
+        assert_eq!("0102030a0bff", topic_address(&address));
+        assert_eq!(Some((address, 42)), parse_command_topic("ptnet/site/", "ptnet/site/0102030a0bff/set/42"));
+        assert_eq!(None, parse_command_topic("ptnet/site/", "ptnet/other/0102030a0bff/set/42"));
+        assert_eq!(None, parse_command_topic("ptnet/site/", "ptnet/site/0102030a0b/set/42"));
+        assert_eq!(None, parse_command_topic("ptnet/site/", "ptnet/site/0102030a0bff/result/42"));
+    }
+
+    #[test]
+    fn node_topic_template() {
+        let address = [0x01, 0x02, 0x03, 0x0A, 0x0B, 0xFF];
+        let labels = Labels::from([("floor".to_string(), "3".to_string()), ("room".to_string(), "a/1".to_string())]);
+        let tags = Tags::from(["corridor".to_string()]);
+
+        assert_eq!("0102030a0bff", render_node_topic("{node}", &address, &labels, &tags));
+        assert_eq!("floor-3/corridor/0102030a0bff", render_node_topic("floor-{label:floor}/{tag:office|corridor}/{node}", &address, &labels, &tags));
+        assert_eq!("a_1/_/_", render_node_topic("{label:room}/{label:zone}/{tag:office}", &address, &labels, &tags));
+        assert_eq!("_/x", render_node_topic("{other}/x", &address, &labels, &tags));
+    }
+}
@@ -0,0 +1,91 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+
+/// Named bundle of configuration defaults for type of deployment, selected by `profile` key.
+/// Keys set in configuration file override values of profile.
+#[derive(Debug,Clone,Copy,Serialize,Deserialize,PartialEq,Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    /// large networks of luminaires on shared links, slow scanning and cautious updates
+    StreetLighting,
+    /// building lighting with mandatory emergency lighting tests
+    Office,
+    /// bench with few nodes, fast scanning and capture of all frames
+    Lab
+}
+
+impl Profile {
+    /// Configuration keys set by profile, in layout of configuration file
+    pub fn defaults(&self) -> Value {
+        match self {
+            Profile::StreetLighting => json!({
+                // luminaires are lit at night
+                "fwu_windows": { "allowed": [{ "from": "09:00:00", "to": "15:00:00" }] },
+                "fwu_limits": { "max_concurrent": 2, "max_concurrent_per_port": 1, "scan_slowdown": 5 },
+                "processes": {
+                    "nodescan": { "period": 30 },
+                    "linktest": { "period": 300, "silence": 1800 },
+                    "health": { "degraded_silence": 1800, "offline_silence": 7200 },
+                    "scheduler": { "catch_up": 3600 },
+                    "emtest": { "enabled": false },
+                    "rawcapture": { "enabled": false }
+                }
+            }),
+            Profile::Office => json!({
+                // building is empty at night, discharged emergency batteries recharge by morning
+                "fwu_windows": { "allowed": [{ "from": "20:00:00", "to": "05:00:00" }] },
+                "processes": {
+                    "emtest": { "windows": [{ "from": "20:00:00", "to": "02:00:00" }], "max_concurrent": 2 },
+                    "rawcapture": { "enabled": false }
+                }
+            }),
+            Profile::Lab => json!({
+                "auto_adopt": true,
+                "log": { "level": "debug" },
+                "fwu_limits": { "max_concurrent": 8, "max_concurrent_per_port": 8, "scan_slowdown": 1 },
+                "processes": {
+                    "nodescan": { "period": 2 },
+                    "linktest": { "period": 10, "silence": 30 },
+                    "health": { "period": 5, "degraded_silence": 60, "offline_silence": 300 },
+                    "rawcapture": { "all": true, "max_frames": 64 }
+                }
+            })
+        }
+    }
+}
+
+/// Merge `overrides` into `base`, objects are merged key by key, other values are replaced
+pub fn merge(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => { base.insert(key, value); }
+                }
+            }
+        },
+        (base, overrides) => *base = overrides
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explicit_keys_override() {
+        let mut conf = Profile::StreetLighting.defaults();
+        merge(&mut conf, json!({
+            "profile": "street-lighting",
+            "fwu_limits": { "max_concurrent": 3 },
+            "processes": { "nodescan": { "window": 2 }, "emtest": { "enabled": true } }
+        }));
+
+        assert_eq!(json!(3), conf["fwu_limits"]["max_concurrent"]);
+        assert_eq!(json!(1), conf["fwu_limits"]["max_concurrent_per_port"], "kept from profile");
+        assert_eq!(json!({ "period": 30, "window": 2 }), conf["processes"]["nodescan"]);
+        assert_eq!(json!(true), conf["processes"]["emtest"]["enabled"]);
+        assert_eq!(Profile::Lab, serde_json::from_value(json!("lab")).unwrap());
+    }
+}
@@ -0,0 +1,269 @@
+use std::{collections::HashMap, time::Duration};
+
+use async_trait::async_trait;
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+use tokio::{sync::{broadcast, Mutex}, time::interval, select};
+
+use crate::{database::{Database, NodeAddress, unix_now, NodeAddr, point_table::{self, Sample}}, error::{PtnetMgrError, DbError}};
+
+use super::PtNetProcess;
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub enum Condition {
+    /// value above limit, clears once it drops below `limit - deadband`
+    Above { limit: f64, deadband: f64 },
+    /// value below limit, clears once it rises above `limit + deadband`
+    Below { limit: f64, deadband: f64 },
+    /// value didn't change for this long (seconds), clears on first change
+    Stuck { after: u64 }
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub struct AlarmRule {
+    /// name of alarm raised, unique among rules
+    pub name: String,
+    /// measurement series rule is evaluated on
+    pub series: String,
+    pub condition: Condition,
+    /// condition must hold this long before alarm is raised (seconds)
+    #[serde(default)]
+    pub delay: u64
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct AlarmConfig {
+    pub rules: Vec<AlarmRule>,
+    /// pause between checks of delayed and stuck conditions (seconds)
+    pub period: u64
+}
+
+impl Default for AlarmConfig {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            period: 10
+        }
+    }
+}
+
+/// Evaluation state of one rule on one node
+#[derive(Debug,Clone,Default,PartialEq)]
+struct RuleState {
+    active: bool,
+    last_value: Option<f64>,
+    /// unix time value last changed
+    changed_at: u64,
+    /// unix time condition started to hold, None if it doesn't
+    pending_since: Option<u64>
+}
+
+impl RuleState {
+    fn new(active: bool) -> Self {
+        Self { active: active, ..Default::default() }
+    }
+
+    /// Account new value, Some(true) if alarm is to be raised, Some(false) if cleared
+    fn update(&mut self, rule: &AlarmRule, value: f64, now: u64) -> Option<bool> {
+        if self.last_value != Some(value) {
+            self.last_value = Some(value);
+            self.changed_at = now;
+        }
+
+        let holds = match rule.condition {
+            Condition::Above { limit, deadband } => value > if self.active { limit - deadband } else { limit },
+            Condition::Below { limit, deadband } => value < if self.active { limit + deadband } else { limit },
+            Condition::Stuck { after } => now.saturating_sub(self.changed_at) >= after
+        };
+
+        match holds {
+            true => {
+                self.pending_since.get_or_insert(now);
+                self.tick(rule, now)
+            },
+            false => {
+                self.pending_since = None;
+                match self.active {
+                    true => { self.active = false; Some(false) },
+                    false => None
+                }
+            }
+        }
+    }
+
+    /// Raise alarm once condition held long enough, stuck value is re-checked without new samples
+    fn tick(&mut self, rule: &AlarmRule, now: u64) -> Option<bool> {
+        if let (Condition::Stuck { after }, Some(_), None) = (&rule.condition, self.last_value, self.pending_since) {
+            if now.saturating_sub(self.changed_at) >= *after {
+                self.pending_since = Some(now);
+            }
+        }
+
+        match self.pending_since {
+            Some(since) if !self.active && now.saturating_sub(since) >= rule.delay => {
+                self.active = true;
+                Some(true)
+            },
+            _ => None
+        }
+    }
+}
+
+/// Raises and clears alarms by rules evaluated on persisted measurements
+pub struct AlarmProcess<'a> {
+    conf: AlarmConfig,
+    db: &'a Database<'a>,
+    point_evt_rcvr: Mutex<broadcast::Receiver<point_table::Event>>,
+    /// by node and rule index
+    states: std::sync::Mutex<HashMap<(NodeAddress, usize), RuleState>>
+}
+
+impl<'a> AlarmProcess<'a> {
+    pub fn new(conf: AlarmConfig, db: &'a Database) -> Self {
+        AlarmProcess {
+            conf: conf,
+            db: db,
+            point_evt_rcvr: Mutex::new(db.points.events.subscribe()),
+            states: std::sync::Mutex::new(HashMap::new())
+        }
+    }
+
+    async fn track_samples(&self) -> Result<(), PtnetMgrError> {
+        let mut point_evt_rcvr = self.point_evt_rcvr.lock().await;
+
+        loop {
+            let point_table::Event::SampleAdded(address, series, sample) = match point_evt_rcvr.recv().await {
+                Ok(evt) => evt,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("Alarm engine skipped {} samples", n);
+                    continue;
+                },
+                Err(err) => return Err(err.into())
+            };
+
+            self.evaluate(&address, &series, &sample)?;
+        }
+    }
+
+    fn evaluate(&self, address: &NodeAddress, series: &str, sample: &Sample) -> Result<(), DbError> {
+        let value = match sample.number() {
+            Some(value) => value,
+            None => return Ok(())
+        };
+
+        for (idx, rule) in self.conf.rules.iter().enumerate().filter(|(_, rule)| rule.series == series) {
+            let mut state = self.state(address, idx, rule)?;
+            let transition = state.update(rule, value, sample.at);
+            self.states.lock().unwrap().insert((*address, idx), state);
+
+            self.apply(address, rule, transition, value, sample.at)?;
+        }
+
+        Ok(())
+    }
+
+    async fn check_all(&self) -> Result<(), PtnetMgrError> {
+        let mut ticker = interval(Duration::from_secs(self.conf.period.max(1)));
+
+        loop {
+            ticker.tick().await;
+
+            let now = unix_now();
+            let transitions: Vec<_> = self.states.lock().unwrap().iter_mut()
+                .filter_map(|((address, idx), state)| {
+                    let transition = state.tick(&self.conf.rules[*idx], now);
+                    transition.map(|t| (*address, *idx, t, state.last_value.unwrap_or_default()))
+                })
+                .collect();
+
+            for (address, idx, transition, value) in transitions {
+                self.apply(&address, &self.conf.rules[idx], Some(transition), value, now)?;
+            }
+        }
+    }
+
+    /// State of rule on node, active alarm is picked up from database
+    fn state(&self, address: &NodeAddress, idx: usize, rule: &AlarmRule) -> Result<RuleState, DbError> {
+        if let Some(state) = self.states.lock().unwrap().get(&(*address, idx)) {
+            return Ok(state.clone());
+        }
+
+        let active = self.db.alarms.get(address)?.alarms.get(&rule.name).map_or(false, |alarm| alarm.active);
+        Ok(RuleState::new(active))
+    }
+
+    fn apply(&self, address: &NodeAddress, rule: &AlarmRule, transition: Option<bool>, value: f64, now: u64) -> Result<(), DbError> {
+        let node = NodeAddr(*address).to_string();
+
+        match transition {
+            Some(true) => {
+                warn!(node = node.as_str(); "Alarm {} raised on node {} ({} = {})", rule.name, node, rule.series, value);
+                self.db.alarms.raise(address, &rule.name, &rule.series, value, now)?;
+            },
+            Some(false) => {
+                info!(node = node.as_str(); "Alarm {} cleared on node {} ({} = {})", rule.name, node, rule.series, value);
+                self.db.alarms.clear(address, &rule.name, value, now)?;
+            },
+            None => ()
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<'a> PtNetProcess for AlarmProcess<'a> {
+    fn name(&self) -> &str {
+        "alarm"
+    }
+
+    async fn run(&mut self) -> Result<(), PtnetMgrError> {
+        select! {
+            result = self.track_samples() => result,
+            result = self.check_all() => result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(condition: Condition, delay: u64) -> AlarmRule {
+        AlarmRule { name: "test".to_string(), series: "lux".to_string(), condition: condition, delay: delay }
+    }
+
+    #[test]
+    fn deadband() {
+        let rule = rule(Condition::Below { limit: 10.0, deadband: 5.0 }, 0);
+        let mut state = RuleState::new(false);
+
+        assert_eq!(None, state.update(&rule, 20.0, 0));
+        assert_eq!(Some(true), state.update(&rule, 5.0, 10));
+        assert_eq!(None, state.update(&rule, 12.0, 20), "within deadband");
+        assert_eq!(Some(false), state.update(&rule, 16.0, 30));
+    }
+
+    #[test]
+    fn delay() {
+        let rule = rule(Condition::Above { limit: 10.0, deadband: 0.0 }, 60);
+        let mut state = RuleState::new(false);
+
+        assert_eq!(None, state.update(&rule, 20.0, 0));
+        assert_eq!(None, state.tick(&rule, 30));
+        assert_eq!(Some(true), state.tick(&rule, 60));
+        assert_eq!(None, state.tick(&rule, 90));
+    }
+
+    #[test]
+    fn stuck() {
+        let rule = rule(Condition::Stuck { after: 100 }, 0);
+        let mut state = RuleState::new(false);
+
+        assert_eq!(None, state.update(&rule, 7.0, 0));
+        assert_eq!(None, state.update(&rule, 7.0, 50));
+        assert_eq!(Some(true), state.tick(&rule, 100), "no samples needed");
+        assert_eq!(Some(false), state.update(&rule, 8.0, 120));
+    }
+}
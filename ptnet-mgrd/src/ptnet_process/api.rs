@@ -0,0 +1,351 @@
+use std::{fmt, time::Duration};
+
+use async_trait::async_trait;
+use futures::{stream, StreamExt};
+use log::debug;
+use ptnet::{PtNetPacket, ASDHConstruct, COT, DUIConstruct, FC, IE};
+use serde::{Serialize, Deserialize};
+use tokio::{sync::{mpsc, oneshot, broadcast, Mutex}, time::{timeout, timeout_at, Instant}};
+
+use crate::{database::{Database, NodeAddress, NodeAddr}, client_connection::{IOBMessage, IOBClass}, error::{PtnetMgrError, ProtocolError}};
+
+use super::{PtNetProcess, CommandEngine, CommandMode, Retrier, SetupPoint, setting_ie, build_reads};
+
+/// Max. API requests executed at once
+const MAX_CONCURRENT: usize = 8;
+
+pub type Reply<T> = oneshot::Sender<Result<T, String>>;
+pub type ApiReply = Reply<()>;
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct ApiConfig {
+    /// read now collects responses until none arrives for this long (milliseconds)
+    pub read_settle: u64,
+    /// max. nodes commanded at once by bulk command
+    pub bulk_concurrency: usize
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            read_settle: 500,
+            bulk_concurrency: 8
+        }
+    }
+}
+
+/// What read now reads, device status if not told otherwise
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct ReadTarget {
+    /// system information CA of each node if not set
+    pub ca: Option<u8>,
+    pub ioas: Vec<u32>
+}
+
+impl Default for ReadTarget {
+    fn default() -> Self {
+        Self {
+            ca: None,
+            ioas: vec![0]
+        }
+    }
+}
+
+/// Value returned by read now
+#[derive(Debug,Clone,Serialize,PartialEq)]
+pub struct ReadValue {
+    pub address: NodeAddress,
+    pub ca: u8,
+    pub ioa: u32,
+    pub ti: u8,
+    pub value: IE
+}
+
+/// Result of bulk command for one node
+#[derive(Debug,Clone,Serialize,PartialEq)]
+pub struct BulkOutcome {
+    pub address: NodeAddress,
+    /// 2 if first attempt failed
+    pub attempts: u32,
+    /// None if command succeeded
+    pub error: Option<String>
+}
+
+/// Results of bulk command
+#[derive(Debug,Clone,Serialize,PartialEq)]
+pub struct BulkSummary {
+    pub succeeded: usize,
+    pub failed: usize,
+    /// by node, in order nodes were given
+    pub results: Vec<BulkOutcome>
+}
+
+impl BulkSummary {
+    fn of(results: Vec<BulkOutcome>) -> Self {
+        let failed = results.iter().filter(|outcome| outcome.error.is_some()).count();
+
+        Self {
+            succeeded: results.len() - failed,
+            failed: failed,
+            results: results
+        }
+    }
+}
+
+/// Request of management API which needs live connection
+pub enum ApiRequest {
+    /// request device status now, response is persisted as usual
+    Scan(NodeAddress, ApiReply),
+    Command {
+        address: NodeAddress,
+        ioa: u32,
+        ti: u8,
+        value: u32,
+        /// select with given value first
+        select: Option<u32>,
+        reply: ApiReply
+    },
+    /// the same command to many nodes, nodes failing it are tried once more
+    BulkCommand {
+        addresses: Vec<NodeAddress>,
+        ioa: u32,
+        ti: u8,
+        value: u32,
+        select: Option<u32>,
+        reply: Reply<BulkSummary>
+    },
+    /// read points of nodes now, bypassing scan schedule, responses are persisted as usual too
+    Read {
+        addresses: Vec<NodeAddress>,
+        target: ReadTarget,
+        reply: Reply<Vec<ReadValue>>
+    }
+}
+
+impl ApiRequest {
+    /// Submitter stopped waiting, e.g. request was queued while connection was down
+    fn abandoned(&self) -> bool {
+        match self {
+            ApiRequest::Scan(_, reply) | ApiRequest::Command { reply, .. } => reply.is_closed(),
+            ApiRequest::BulkCommand { reply, .. } => reply.is_closed(),
+            ApiRequest::Read { reply, .. } => reply.is_closed()
+        }
+    }
+}
+
+/// Why submitted request wasn't executed successfully
+#[derive(Debug)]
+pub enum SubmitError {
+    /// too many requests in progress
+    Busy,
+    /// daemon runs without ptlink connection
+    NoLink,
+    /// not executed in time, ptlink connection may be down
+    NotExecuted,
+    Failed(String)
+}
+
+impl fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubmitError::Busy => write!(f, "Too many requests in progress"),
+            SubmitError::NoLink => write!(f, "Not connected to ptlink server, daemon runs API-only"),
+            SubmitError::NotExecuted => write!(f, "Request not executed, ptlink connection may be down"),
+            SubmitError::Failed(err) => write!(f, "{}", err)
+        }
+    }
+}
+
+impl std::error::Error for SubmitError {}
+
+/// Submit request to ApiProcess of current connection, waits until it's done
+pub async fn submit<T, F>(requests: &mpsc::Sender<ApiRequest>, wait: Duration, make_request: F) -> Result<T, SubmitError>
+where
+    F: FnOnce(Reply<T>) -> ApiRequest
+{
+    let (reply, rcvr) = oneshot::channel();
+
+    requests.try_send(make_request(reply)).map_err(|err| match err {
+        mpsc::error::TrySendError::Full(_) => SubmitError::Busy,
+        mpsc::error::TrySendError::Closed(_) => SubmitError::NoLink
+    })?;
+
+    match timeout(wait, rcvr).await {
+        Ok(Ok(Ok(result))) => Ok(result),
+        Ok(Ok(Err(err))) => Err(SubmitError::Failed(err)),
+        _ => Err(SubmitError::NotExecuted)
+    }
+}
+
+/// Executes management API requests on current connection
+pub struct ApiProcess<'a> {
+    conf: ApiConfig,
+    requests: &'a Mutex<mpsc::Receiver<ApiRequest>>,
+    db: &'a Database<'a>,
+    commands: &'a CommandEngine<'a>,
+    retrier: Retrier<'a>
+}
+
+impl<'a> ApiProcess<'a> {
+    pub fn new(conf: ApiConfig, requests: &'a Mutex<mpsc::Receiver<ApiRequest>>, db: &'a Database, commands: &'a CommandEngine<'a>, retrier: Retrier<'a>) -> Self {
+        ApiProcess {
+            conf: conf,
+            requests: requests,
+            db: db,
+            commands: commands,
+            retrier: retrier
+        }
+    }
+
+    async fn handle(&self, request: ApiRequest) {
+        if request.abandoned() {
+            debug!("Dropping API request nobody waits for anymore");
+            return;
+        }
+
+        match request {
+            ApiRequest::Scan(address, reply) => {
+                let result = self.scan(&address).await.map_err(|err| err.to_string());
+                reply.send(result).unwrap_or_default();
+            },
+            ApiRequest::Command { address, ioa, ti, value, select, reply } => {
+                let result = self.command(&address, ioa, ti, value, select).await;
+                reply.send(result).unwrap_or_default();
+            },
+            ApiRequest::BulkCommand { addresses, ioa, ti, value, select, reply } => {
+                let result = self.bulk_command(&addresses, ioa, ti, value, select).await;
+                reply.send(Ok(result)).unwrap_or_default();
+            },
+            ApiRequest::Read { addresses, target, reply } => {
+                let result = self.read(&addresses, &target).await.map_err(|err| err.to_string());
+                reply.send(result).unwrap_or_default();
+            }
+        }
+    }
+
+    async fn scan(&self, address: &NodeAddress) -> Result<(), PtnetMgrError> {
+        let mut buf = packet::buffer::Dynamic::new();
+        PtNetPacket::with_asdh(&ptnet::ASDH::with(self.db.common_addresses(address).system, COT::REQ, false), &mut buf)
+            .and_then(|builder| builder.begin_asdu(&ptnet::DUI::with_direct(ptnet::TC_C_RD, 1, false)))
+            .and_then(|builder| builder.add_ioa(0))
+            .and_then(|builder| builder.end_asdu())
+            .map_err(ProtocolError::encoding)?;
+
+        debug!("Scan node {} on request", NodeAddr(*address));
+        let _node_lock = self.retrier.conn().lock_node(address).await;
+        Ok(self.retrier.send_prm(FC::PrmSendNoreply, address, &buf).await?)
+    }
+
+    /// Send read requests, then collect responses until nodes fall silent
+    async fn read(&self, addresses: &[NodeAddress], target: &ReadTarget) -> Result<Vec<ReadValue>, PtnetMgrError> {
+        // subscribed before sending, response may arrive before send result
+        let mut iob_rcvr = self.retrier.conn().subscribe_iob();
+        let settle = Duration::from_millis(self.conf.read_settle);
+
+        for address in addresses {
+            let _node_lock = self.retrier.conn().lock_node(address).await;
+            debug!("Read node {} on request", NodeAddr(*address));
+            let ca = target.ca.unwrap_or_else(|| self.db.common_addresses(address).system);
+
+            for payload in build_reads(ca, &target.ioas, self.retrier.max_payload())? {
+                self.retrier.send_prm(FC::PrmSendNoreply, address, &payload).await?;
+            }
+        }
+
+        let mut values = Vec::new();
+        let mut deadline = Instant::now() + settle;
+
+        loop {
+            let IOBMessage { iob, message: msg, .. } = match timeout_at(deadline, iob_rcvr.recv()).await {
+                Err(_) => break,
+                Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+                Ok(Err(err)) => return Err(err.into()),
+                Ok(Ok(rsp)) if rsp.class == IOBClass::Response && addresses.contains(&rsp.message.header.address) => rsp,
+                Ok(Ok(_)) => continue
+            };
+
+            deadline = Instant::now() + settle;
+            values.push(ReadValue {
+                address: msg.header.address,
+                ca: iob.asdh.ca,
+                ioa: iob.ioa,
+                ti: iob.ie.type_id(),
+                value: iob.ie
+            });
+        }
+
+        match values.is_empty() {
+            true => Err(PtnetMgrError::NoResponse),
+            false => Ok(values)
+        }
+    }
+
+    async fn command(&self, address: &NodeAddress, ioa: u32, ti: u8, value: u32, select: Option<u32>) -> Result<(), String> {
+        command_node(self.commands, address, ioa, ti, value, select).await
+    }
+
+    async fn bulk_command(&self, addresses: &[NodeAddress], ioa: u32, ti: u8, value: u32, select: Option<u32>) -> BulkSummary {
+        bulk_command(self.commands, self.conf.bulk_concurrency, addresses, ioa, ti, value, select).await
+    }
+}
+
+/// Send command to node, select it with `select` value first if given
+pub async fn command_node(commands: &CommandEngine<'_>, address: &NodeAddress, ioa: u32, ti: u8, value: u32, select: Option<u32>) -> Result<(), String> {
+    let point = SetupPoint { ioa: ioa, ti: ti };
+    let ie = setting_ie(&point, value).map_err(|err| err.to_string())?;
+    let mode = match select {
+        Some(select_value) => CommandMode::SelectBeforeOperate(setting_ie(&point, select_value).map_err(|err| err.to_string())?),
+        None => CommandMode::Direct
+    };
+
+    commands.send_command(address, ioa, ie, mode).await.map_err(|err| err.to_string())
+}
+
+/// Command nodes `concurrency` at a time, then retry the failed ones once
+pub async fn bulk_command(commands: &CommandEngine<'_>, concurrency: usize, addresses: &[NodeAddress], ioa: u32, ti: u8, value: u32, select: Option<u32>) -> BulkSummary {
+    let concurrency = concurrency.max(1);
+    debug!("Bulk command to IOA {} of {} nodes", ioa, addresses.len());
+
+    let mut results: Vec<BulkOutcome> = stream::iter(addresses)
+        .map(|address| async move {
+            BulkOutcome { address: *address, attempts: 1, error: command_node(commands, address, ioa, ti, value, select).await.err() }
+        })
+        .buffered(concurrency)
+        .collect()
+        .await;
+
+    let retried: Vec<BulkOutcome> = stream::iter(results.iter().filter(|outcome| outcome.error.is_some()))
+        .map(|outcome| async move {
+            BulkOutcome { address: outcome.address, attempts: 2, error: command_node(commands, &outcome.address, ioa, ti, value, select).await.err() }
+        })
+        .buffered(concurrency)
+        .collect()
+        .await;
+
+    for outcome in retried {
+        if let Some(result) = results.iter_mut().find(|result| result.address == outcome.address) {
+            *result = outcome;
+        }
+    }
+
+    BulkSummary::of(results)
+}
+
+#[async_trait]
+impl<'a> PtNetProcess for ApiProcess<'a> {
+    fn name(&self) -> &str {
+        "api"
+    }
+
+    async fn run(&mut self) -> Result<(), PtnetMgrError> {
+        let mut requests = self.requests.lock().await;
+
+        stream::poll_fn(|cx| requests.poll_recv(cx))
+            .for_each_concurrent(MAX_CONCURRENT, |request| self.handle(request))
+            .await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+use std::{collections::HashMap, sync::{Mutex, RwLock}, time::{Duration, Instant}};
+
+use log::debug;
+use serde::{Serialize, Deserialize};
+use tokio::time::sleep;
+
+/// Link header of frame, control byte and node address
+const HEADER_SIZE: u64 = 7;
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct BandwidthConfig {
+    /// accounting window (seconds)
+    pub window: u64,
+    /// bytes process may transmit per window by process name, unlimited if not set
+    pub budgets: HashMap<String, u64>
+}
+
+impl Default for BandwidthConfig {
+    fn default() -> Self {
+        Self {
+            window: 3600,
+            budgets: HashMap::new()
+        }
+    }
+}
+
+/// Transmit statistics of process
+#[derive(Debug,Clone,Serialize,PartialEq)]
+pub struct BandwidthUsage {
+    /// bytes transmitted in current window
+    pub sent: u64,
+    pub budget: Option<u64>,
+    /// seconds until current window ends
+    pub resets_in: u64,
+    /// bytes transmitted since start of daemon
+    pub total: u64,
+    /// sends delayed because budget was used up
+    pub throttled: u64
+}
+
+struct Account {
+    window_start: Instant,
+    sent: u64,
+    total: u64,
+    throttled: u64
+}
+
+/// Bytes of frame with given payload on link
+pub fn frame_size(payload: &[u8]) -> u64 {
+    HEADER_SIZE + payload.len() as u64
+}
+
+/// Accounting of bytes processes transmit, shared links have duty-cycle limits.
+/// Process which used up its budget waits for next window before it sends again.
+pub struct BandwidthMeter {
+    conf: RwLock<BandwidthConfig>,
+    accounts: Mutex<HashMap<String, Account>>
+}
+
+impl BandwidthMeter {
+    pub fn new(conf: BandwidthConfig) -> Self {
+        Self {
+            conf: RwLock::new(conf),
+            accounts: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Apply changed budgets, bytes sent in current window stay accounted
+    pub fn set_config(&self, conf: BandwidthConfig) {
+        *self.conf.write().unwrap() = conf;
+    }
+
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.conf.read().unwrap().window.max(1))
+    }
+
+    /// Account of process with window containing `now`
+    fn account<'m>(accounts: &'m mut HashMap<String, Account>, process: &str, window: Duration, now: Instant) -> &'m mut Account {
+        let account = accounts.entry(process.to_string())
+            .or_insert(Account { window_start: now, sent: 0, total: 0, throttled: 0 });
+
+        if now.saturating_duration_since(account.window_start) >= window {
+            account.window_start = now;
+            account.sent = 0;
+        }
+
+        account
+    }
+
+    pub fn record(&self, process: &str, bytes: u64, now: Instant) {
+        let window = self.window();
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = BandwidthMeter::account(&mut accounts, process, window, now);
+
+        account.sent += bytes;
+        account.total += bytes;
+    }
+
+    /// How long process has to wait before sending, None if it has budget left
+    pub fn delay(&self, process: &str, now: Instant) -> Option<Duration> {
+        let window = self.window();
+        let budget = *self.conf.read().unwrap().budgets.get(process)?;
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = BandwidthMeter::account(&mut accounts, process, window, now);
+
+        if account.sent < budget {
+            return None;
+        }
+
+        account.throttled += 1;
+        Some((account.window_start + window).saturating_duration_since(now))
+    }
+
+    /// Wait until process may send
+    pub async fn throttle(&self, process: &str) {
+        if let Some(delay) = self.delay(process, Instant::now()) {
+            debug!(process = process; "Process {} used up its bandwidth budget, waiting {} s", process, delay.as_secs());
+            sleep(delay).await;
+        }
+    }
+
+    /// Statistics of process, None if it sent nothing yet and has no budget
+    pub fn usage(&self, process: &str) -> Option<BandwidthUsage> {
+        let now = Instant::now();
+        let window = self.window();
+        let budget = self.conf.read().unwrap().budgets.get(process).cloned();
+        let mut accounts = self.accounts.lock().unwrap();
+
+        if budget.is_none() && !accounts.contains_key(process) {
+            return None;
+        }
+        let account = BandwidthMeter::account(&mut accounts, process, window, now);
+
+        Some(BandwidthUsage {
+            sent: account.sent,
+            budget: budget,
+            resets_in: (account.window_start + window).saturating_duration_since(now).as_secs(),
+            total: account.total,
+            throttled: account.throttled
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budgets() {
+        let meter = BandwidthMeter::new(BandwidthConfig {
+            window: 60,
+            budgets: HashMap::from([("nodescan".to_string(), 100)])
+        });
+        let start = Instant::now();
+
+        assert_eq!(None, meter.delay("nodescan", start));
+        meter.record("nodescan", 60, start);
+        meter.record("fwu", 1000, start);
+        assert_eq!(None, meter.delay("nodescan", start + Duration::from_secs(10)));
+        assert_eq!(None, meter.delay("fwu", start), "no budget, unlimited");
+
+        meter.record("nodescan", 60, start + Duration::from_secs(10));
+        assert_eq!(Some(Duration::from_secs(40)), meter.delay("nodescan", start + Duration::from_secs(20)));
+
+        // next window
+        assert_eq!(None, meter.delay("nodescan", start + Duration::from_secs(60)));
+        meter.record("nodescan", 10, start + Duration::from_secs(60));
+
+        let usage = meter.usage("nodescan").unwrap();
+        assert_eq!((10, Some(100), 130, 1), (usage.sent, usage.budget, usage.total, usage.throttled));
+        assert!(meter.usage("command").is_none());
+    }
+}
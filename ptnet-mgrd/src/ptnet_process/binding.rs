@@ -0,0 +1,299 @@
+use std::{collections::HashMap, time::Duration};
+
+use async_trait::async_trait;
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+use tokio::{sync::{broadcast, Mutex}, time::interval, select};
+
+use crate::{database::{Database, NodeAddress, unix_now, point_table, group_table::GroupId}, error::PtnetMgrError};
+
+use super::{PtNetProcess, GroupControl, SetupPoint, setting_ie};
+
+#[derive(Debug,Clone,Copy,Serialize,Deserialize,PartialEq)]
+pub enum Threshold {
+    /// value above limit, stops holding once it drops below `limit - hysteresis`
+    Above {
+        limit: f64,
+        #[serde(default)]
+        hysteresis: f64
+    },
+    /// value below limit, stops holding once it rises above `limit + hysteresis`
+    Below {
+        limit: f64,
+        #[serde(default)]
+        hysteresis: f64
+    }
+}
+
+impl Threshold {
+    fn holds(&self, value: f64, active: bool) -> bool {
+        match *self {
+            Threshold::Above { limit, hysteresis } => value > if active { limit - hysteresis } else { limit },
+            Threshold::Below { limit, hysteresis } => value < if active { limit + hysteresis } else { limit }
+        }
+    }
+}
+
+/// Reports of node suspend binding, e.g. wall switch pressed
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub struct OverrideRule {
+    pub node: NodeAddress,
+    pub series: String,
+    /// binding stays suspended this long after last report (seconds)
+    pub hold: u64
+}
+
+/// Commands group when sensor measurement meets condition, e.g. lights on at occupancy
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub struct Binding {
+    /// name used in logs, unique among bindings
+    pub name: String,
+    pub sensor: NodeAddress,
+    /// measurement series of sensor, e.g. occupancy or lux
+    pub series: String,
+    pub threshold: Threshold,
+    pub group: GroupId,
+    /// point of group members commanded
+    pub ioa: u32,
+    pub ti: u8,
+    /// sent when condition starts to hold
+    pub on_value: u32,
+    /// sent when condition stops holding, nothing is sent if not set
+    #[serde(default)]
+    pub off_value: Option<u32>,
+    /// condition must not hold this long before off value is sent (seconds), e.g. occupancy hold time
+    #[serde(default)]
+    pub off_delay: u64,
+    #[serde(default)]
+    pub overrides: Vec<OverrideRule>
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct BindingConfig {
+    pub bindings: Vec<Binding>,
+    /// pause between checks of delayed off commands (seconds)
+    pub period: u64
+}
+
+impl Default for BindingConfig {
+    fn default() -> Self {
+        Self {
+            bindings: Vec::new(),
+            period: 1
+        }
+    }
+}
+
+/// Evaluation state of one binding
+#[derive(Debug,Clone,Default,PartialEq)]
+struct BindingState {
+    active: bool,
+    /// unix time condition stopped holding while active, off command is pending
+    off_since: Option<u64>,
+    /// no commands are sent before this unix time
+    suspended_until: u64
+}
+
+impl BindingState {
+    /// Account new sensor value, returns value to send to group
+    fn update(&mut self, binding: &Binding, value: f64, now: u64) -> Option<u32> {
+        match binding.threshold.holds(value, self.active) {
+            true => {
+                self.off_since = None;
+                match self.active {
+                    true => None,
+                    false => {
+                        self.active = true;
+                        Some(binding.on_value).filter(|_| now >= self.suspended_until)
+                    }
+                }
+            },
+            false => {
+                if self.active && self.off_since.is_none() {
+                    self.off_since = Some(now);
+                }
+                self.tick(binding, now)
+            }
+        }
+    }
+
+    /// Deactivate binding once off delay is over
+    fn tick(&mut self, binding: &Binding, now: u64) -> Option<u32> {
+        match self.off_since {
+            Some(since) if now.saturating_sub(since) >= binding.off_delay => {
+                self.active = false;
+                self.off_since = None;
+                binding.off_value.filter(|_| now >= self.suspended_until)
+            },
+            _ => None
+        }
+    }
+
+    fn suspend(&mut self, until: u64) {
+        self.suspended_until = self.suspended_until.max(until);
+    }
+}
+
+/// Local control loops from sensors to groups, work without upstream connection
+pub struct BindingProcess<'a> {
+    conf: BindingConfig,
+    groups: &'a GroupControl<'a>,
+    point_evt_rcvr: Mutex<broadcast::Receiver<point_table::Event>>,
+    /// by binding index
+    states: std::sync::Mutex<HashMap<usize, BindingState>>
+}
+
+impl<'a> BindingProcess<'a> {
+    pub fn new(conf: BindingConfig, db: &'a Database, groups: &'a GroupControl<'a>) -> Self {
+        BindingProcess {
+            conf: conf,
+            groups: groups,
+            point_evt_rcvr: Mutex::new(db.points.events.subscribe()),
+            states: std::sync::Mutex::new(HashMap::new())
+        }
+    }
+
+    async fn track_samples(&self) -> Result<(), PtnetMgrError> {
+        let mut point_evt_rcvr = self.point_evt_rcvr.lock().await;
+
+        loop {
+            let point_table::Event::SampleAdded(address, series, sample) = match point_evt_rcvr.recv().await {
+                Ok(evt) => evt,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("Local control skipped {} samples", n);
+                    continue;
+                },
+                Err(err) => return Err(err.into())
+            };
+
+            for (idx, binding) in self.conf.bindings.iter().enumerate() {
+                if let Some(rule) = binding.overrides.iter().find(|rule| rule.node == address && rule.series == *series) {
+                    info!("Binding {} suspended for {} s", binding.name, rule.hold);
+                    self.states.lock().unwrap().entry(idx).or_default().suspend(sample.at + rule.hold);
+                }
+
+                if binding.sensor != address || binding.series != *series {
+                    continue;
+                }
+
+                let value = match sample.number() {
+                    Some(value) => value,
+                    None => continue
+                };
+
+                let command = self.states.lock().unwrap().entry(idx).or_default().update(binding, value, sample.at);
+                self.apply(binding, command).await;
+            }
+        }
+    }
+
+    async fn check_delays(&self) -> Result<(), PtnetMgrError> {
+        let mut ticker = interval(Duration::from_secs(self.conf.period.max(1)));
+
+        loop {
+            ticker.tick().await;
+
+            let now = unix_now();
+            let commands: Vec<(usize, Option<u32>)> = self.states.lock().unwrap().iter_mut()
+                .map(|(idx, state)| (*idx, state.tick(&self.conf.bindings[*idx], now)))
+                .collect();
+
+            for (idx, command) in commands {
+                self.apply(&self.conf.bindings[idx], command).await;
+            }
+        }
+    }
+
+    async fn apply(&self, binding: &Binding, command: Option<u32>) {
+        let value = match command {
+            Some(value) => value,
+            None => return
+        };
+
+        info!("Binding {} sets IOA {} of group {} to {}", binding.name, binding.ioa, binding.group, value);
+
+        let ie = match setting_ie(&SetupPoint { ioa: binding.ioa, ti: binding.ti }, value) {
+            Ok(ie) => ie,
+            Err(err) => {
+                warn!("Binding {} has invalid value {} for TI {}! ({})", binding.name, value, binding.ti, err);
+                return;
+            }
+        };
+
+        if let Err(err) = self.groups.send_command(binding.group, binding.ioa, &ie).await {
+            warn!("Binding {} failed to command group {}! ({})", binding.name, binding.group, err);
+        }
+    }
+}
+
+#[async_trait]
+impl<'a> PtNetProcess for BindingProcess<'a> {
+    fn name(&self) -> &str {
+        "binding"
+    }
+
+    async fn run(&mut self) -> Result<(), PtnetMgrError> {
+        select! {
+            result = self.track_samples() => result,
+            result = self.check_delays() => result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn binding(threshold: Threshold, off_delay: u64) -> Binding {
+        Binding {
+            name: "hall".to_string(),
+            sensor: [0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0xEF],
+            series: "lux".to_string(),
+            threshold: threshold,
+            group: 1,
+            ioa: 0x100,
+            ti: 48,
+            on_value: 100,
+            off_value: Some(0),
+            off_delay: off_delay,
+            overrides: Vec::new()
+        }
+    }
+
+    #[test]
+    fn hysteresis() {
+        let binding = binding(Threshold::Below { limit: 100.0, hysteresis: 50.0 }, 0);
+        let mut state = BindingState::default();
+
+        assert_eq!(None, state.update(&binding, 200.0, 0));
+        assert_eq!(Some(100), state.update(&binding, 80.0, 10));
+        assert_eq!(None, state.update(&binding, 120.0, 20), "within hysteresis");
+        assert_eq!(Some(0), state.update(&binding, 160.0, 30));
+    }
+
+    #[test]
+    fn off_delay() {
+        let binding = binding(Threshold::Above { limit: 0.5, hysteresis: 0.0 }, 300);
+        let mut state = BindingState::default();
+
+        assert_eq!(Some(100), state.update(&binding, 1.0, 0));
+        assert_eq!(None, state.update(&binding, 0.0, 100));
+        assert_eq!(None, state.tick(&binding, 200));
+        assert_eq!(None, state.update(&binding, 1.0, 250), "occupancy again restarts delay");
+        assert_eq!(None, state.update(&binding, 0.0, 300));
+        assert_eq!(None, state.tick(&binding, 500));
+        assert_eq!(Some(0), state.tick(&binding, 600));
+    }
+
+    #[test]
+    fn suspended() {
+        let binding = binding(Threshold::Above { limit: 0.5, hysteresis: 0.0 }, 0);
+        let mut state = BindingState::default();
+
+        state.suspend(100);
+        assert_eq!(None, state.update(&binding, 1.0, 0));
+        assert_eq!(None, state.update(&binding, 0.0, 50));
+        assert_eq!(Some(100), state.update(&binding, 1.0, 150));
+    }
+}
@@ -0,0 +1,195 @@
+use std::{time::Duration, iter};
+
+use async_trait::async_trait;
+use log::{error, info, warn};
+use serde::{Serialize, Deserialize};
+use tokio::time::interval;
+
+use crate::{time_window::UpdateWindows, site::LabelFilter, error::{PtnetMgrError, DbError}};
+use crate::database::{Database, NodeAddress, unix_now, NodeAddr, fwu_state_table::{Goal, Phase}, campaign_table::{CampaignRecord, CampaignState, NodeState, Selector}};
+
+use super::PtNetProcess;
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct CampaignConfig {
+    /// pause between campaign evaluations (seconds)
+    pub period: u64
+}
+
+impl Default for CampaignConfig {
+    fn default() -> Self {
+        Self {
+            period: 10
+        }
+    }
+}
+
+/// Drives per-node firmware update goals of running campaigns
+pub struct CampaignProcess<'a> {
+    period: Duration,
+    db: &'a Database<'a>,
+    /// global firmware update windows
+    windows: &'a UpdateWindows
+}
+
+impl<'a> CampaignProcess<'a> {
+    pub fn new(period: Duration, db: &'a Database, windows: &'a UpdateWindows) -> Self {
+        CampaignProcess {
+            period: period,
+            db: db,
+            windows: windows
+        }
+    }
+
+    fn select_nodes(&self, selector: &Selector) -> Result<Vec<NodeAddress>, PtnetMgrError> {
+        match selector {
+            Selector::Nodes(nodes) => Ok(nodes.clone()),
+            Selector::HWVersion(hw) => {
+                let nodes = self.db.nodes.load_many(self.db.nodes.list()?.iter())?;
+
+                Ok(nodes.iter()
+                    .filter(|node| node.device_status.map_or(false, |st| *hw == st.hw_version.into()))
+                    .map(|node| node.address)
+                    .collect())
+            },
+            Selector::Filter(filter) => Ok(self.db.query_nodes(&filter.parse::<LabelFilter>()?)?
+                .into_iter()
+                .map(|info| info.node.address)
+                .collect())
+        }
+    }
+
+    /// Evaluate node being updated by campaign
+    fn check_node(&self, campaign: &CampaignRecord, address: &NodeAddress, state: NodeState, now: u64) -> Result<NodeState, DbError> {
+        let node = match self.db.nodes.load_many(iter::once(address)) {
+            Ok(mut nodes) => nodes.remove(0),
+            Err(err) => {
+                warn!("Campaign {} node '{}' can't be loaded! ({})", campaign.id, NodeAddr(*address), err);
+                return Ok(NodeState::Failed);
+            }
+        };
+
+        let runs_target = node.device_status.map_or(false, |st| campaign.target == st.fw_version.into());
+
+        match state {
+            NodeState::Pending if runs_target => Ok(NodeState::Done),
+            NodeState::Updating(_) if runs_target => Ok(NodeState::Done),
+            NodeState::Updating(since) => {
+                if now.saturating_sub(since) > campaign.node_timeout {
+                    warn!("Campaign {} node '{}' update timed out", campaign.id, node.mac());
+                    return Ok(NodeState::Failed);
+                }
+
+                // node went through update and is idle again, but not running target firmware
+                let progress = self.db.fwu_state.get(address)?.and_then(|rec| rec.progress);
+                match progress {
+                    Some(p) if p.started_at >= since && p.phase == Phase::Idle => {
+                        warn!("Campaign {} node '{}' finished update without running target firmware", campaign.id, node.mac());
+                        Ok(NodeState::Failed)
+                    },
+                    _ => Ok(state)
+                }
+            },
+            _ => Ok(state)
+        }
+    }
+
+    fn drive(&self, org_campaign: &CampaignRecord) -> Result<(), PtnetMgrError> {
+        let now = unix_now();
+        let mut campaign = org_campaign.clone();
+
+        for address in self.select_nodes(&campaign.selector)? {
+            if !campaign.nodes.iter().any(|(a, _)| *a == address) {
+                campaign.nodes.push((address, NodeState::Pending));
+            }
+        }
+
+        for idx in 0..campaign.nodes.len() {
+            let (address, state) = campaign.nodes[idx];
+            let new_state = self.check_node(&campaign, &address, state, now)?;
+
+            if new_state == NodeState::Failed {
+                // stop retrying, operator decides what's next
+                self.db.fwu_state.modify(&address, |opt_rec| {
+                    let mut rec = opt_rec.unwrap_or_default();
+                    rec.goal = Goal::None;
+                    Some(rec)
+                })?;
+            }
+
+            campaign.nodes[idx].1 = new_state;
+        }
+
+        let in_window = campaign.window.map_or(true, |(start, end)| now >= start && now < end);
+        let window_over = campaign.window.map_or(false, |(_, end)| now >= end);
+        let unfinished = campaign.count(|st| matches!(st, NodeState::Pending | NodeState::Updating(_)));
+
+        if campaign.failure_rate_exceeded() {
+            warn!("Campaign {} paused, failure rate exceeds {}%", campaign.id, campaign.max_failure_percent);
+            campaign.state = CampaignState::Paused;
+        } else if unfinished == 0 || (window_over && campaign.count(|st| matches!(st, NodeState::Updating(_))) == 0) {
+            info!("Campaign {} finished", campaign.id);
+            campaign.state = CampaignState::Finished;
+        } else if in_window && self.windows.permits_now() {
+            let mut free_slots = campaign.max_concurrent
+                .saturating_sub(campaign.count(|st| matches!(st, NodeState::Updating(_))));
+
+            for (address, state) in campaign.nodes.iter_mut() {
+                if free_slots == 0 {
+                    break;
+                }
+
+                if *state == NodeState::Pending {
+                    info!("Campaign {} updates node '{}' to {}", campaign.id, NodeAddr(*address), campaign.target);
+
+                    let target = campaign.target.clone();
+                    self.db.fwu_state.modify(address, |opt_rec| {
+                        let mut rec = opt_rec.unwrap_or_default();
+                        rec.goal = Goal::UpdateTo(target);
+                        Some(rec)
+                    })?;
+
+                    *state = NodeState::Updating(now);
+                    free_slots -= 1;
+                }
+            }
+        }
+
+        if campaign != *org_campaign {
+            self.db.campaigns.modify(campaign.id, |cur_rec| {
+                // state changed by operator in the meantime takes precedence
+                if cur_rec.state != org_campaign.state {
+                    campaign.state = cur_rec.state;
+                }
+                Some(campaign)
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<'a> PtNetProcess for CampaignProcess<'a> {
+    fn name(&self) -> &str {
+        "campaign"
+    }
+
+    async fn run(&mut self) -> Result<(), PtnetMgrError> {
+        let mut interval = interval(self.period);
+        loop {
+            interval.tick().await;
+
+            for campaign in self.db.campaigns.list()? {
+                if campaign.state != CampaignState::Running {
+                    continue;
+                }
+
+                if let Err(err) = self.drive(&campaign) {
+                    error!("Error driving campaign {}! ({})", campaign.id, err);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use std::{collections::HashMap, time::{Duration, Instant}, fmt};
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use ptnet::{FC, PtNetPacket, ASDHConstruct, COT, DUIConstruct, IE};
+use serde::{Serialize, Deserialize};
+use tokio::{sync::{broadcast, mpsc}, time::timeout};
+
+use crate::{database::{Database, NodeAddress, NodeAddr}, error::{PtnetMgrError, ProtocolError}, client_connection::{ClientConnection, ClientConnectionSender, IOBMessage, SendOutcome}};
+
+use super::{PtNetProcess, frame_size};
+
+/// Name of command process, account of bandwidth commands take
+const COMMAND: &str = "command";
+
+#[derive(Debug,Clone)]
+pub enum CommandMode {
+    /// execute immediately
+    Direct,
+    /// send given select IE first, execute after it's confirmed
+    SelectBeforeOperate(IE)
+}
+
+/// Step of command lifecycle
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Serialize)]
+pub enum Stage {
+    Select,
+    Execute,
+    Terminate
+}
+
+#[derive(Debug)]
+pub enum CommandError {
+    /// another command for the same point is running
+    Busy,
+    Transmit(String),
+    /// device confirmed negatively
+    Rejected(Stage),
+    TimedOut(Stage)
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Busy => write!(f, "Another command for this point is in progress"),
+            CommandError::Transmit(err) => write!(f, "Transmit failed ({})", err),
+            CommandError::Rejected(stage) => write!(f, "Command rejected by device at {:?}", stage),
+            CommandError::TimedOut(stage) => write!(f, "Command timed out at {:?}", stage)
+        }
+    }
+}
+
+impl std::error::Error for CommandError {
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct CommandTimeouts {
+    /// wait for send result from ptlink (milliseconds)
+    pub result: u64,
+    /// wait for ACT_CON (milliseconds)
+    pub confirm: u64,
+    /// wait for ACT_TERM (milliseconds), don't wait if 0
+    pub terminate: u64
+}
+
+impl Default for CommandTimeouts {
+    fn default() -> Self {
+        Self {
+            result: 10000,
+            confirm: 5000,
+            terminate: 30000
+        }
+    }
+}
+
+type PointKey = (NodeAddress, u32);
+
+/// Executes commands and setpoints on nodes
+pub struct CommandEngine<'a> {
+    sender: &'a ClientConnectionSender<'a>,
+    /// common addresses of nodes
+    db: &'a Database<'a>,
+    timeouts: CommandTimeouts,
+    /// running commands, responses are routed here by CommandProcess
+    pending: std::sync::Mutex<HashMap<PointKey, mpsc::UnboundedSender<IOBMessage>>>
+}
+
+impl<'a> CommandEngine<'a> {
+    pub fn new(sender: &'a ClientConnectionSender<'a>, db: &'a Database<'a>, timeouts: CommandTimeouts) -> Self {
+        Self {
+            sender: sender,
+            db: db,
+            timeouts: timeouts,
+            pending: std::sync::Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Send command `ie` to point `ioa` of node, returns after device terminated it
+    pub async fn send_command(&self, address: &NodeAddress, ioa: u32, ie: IE, mode: CommandMode) -> Result<(), CommandError> {
+        let key: PointKey = (*address, ioa);
+        let (rsp_sender, mut rsp_rcvr) = mpsc::unbounded_channel::<IOBMessage>();
+
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.contains_key(&key) {
+                return Err(CommandError::Busy);
+            }
+            pending.insert(key, rsp_sender);
+        }
+
+        // whole select-execute-terminate sequence is one exchange with node
+        let result = {
+            let _node_lock = self.sender.conn().lock_node(address).await;
+            self.execute(address, ioa, ie, mode, &mut rsp_rcvr).await
+        };
+
+        self.pending.lock().unwrap().remove(&key);
+
+        let node = NodeAddr(*address).to_string();
+        match &result {
+            Ok(_) => info!(node = node.as_str(), ioa = ioa; "Command to {} IOA {} completed", node, ioa),
+            Err(err) => warn!(node = node.as_str(), ioa = ioa; "Command to {} IOA {} failed! ({})", node, ioa, err)
+        };
+
+        result
+    }
+
+    async fn execute(&self, address: &NodeAddress, ioa: u32, ie: IE, mode: CommandMode, rsp_rcvr: &mut mpsc::UnboundedReceiver<IOBMessage>) -> Result<(), CommandError> {
+        if let CommandMode::SelectBeforeOperate(select_ie) = mode {
+            self.transmit(address, ioa, &select_ie).await?;
+            self.await_cot(rsp_rcvr, Stage::Select, COT::ACTCON, self.timeouts.confirm).await?;
+        }
+
+        self.transmit(address, ioa, &ie).await?;
+        self.await_cot(rsp_rcvr, Stage::Execute, COT::ACTCON, self.timeouts.confirm).await?;
+
+        if self.timeouts.terminate > 0 {
+            self.await_cot(rsp_rcvr, Stage::Terminate, COT::ACTTERM, self.timeouts.terminate).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn transmit(&self, address: &NodeAddress, ioa: u32, ie: &IE) -> Result<(), CommandError> {
+        let mut buf = packet::buffer::Dynamic::new();
+        build_command(&mut buf, self.db.common_addresses(address).control, ioa, ie).map_err(|err| CommandError::Transmit(err.to_string()))?;
+
+        // commands of all sources share budget of command process
+        self.db.bandwidth.throttle(COMMAND).await;
+
+        let node = NodeAddr(*address).to_string();
+        debug!(node = node.as_str(), ioa = ioa; "Transmit command to {} IOA {}", node, ioa);
+        let rcvr = self.sender.send_prm(FC::PrmSendNoreply, address, &buf).await
+            .map_err(|err| CommandError::Transmit(err.to_string()))?;
+        self.db.bandwidth.record(COMMAND, frame_size(&buf), Instant::now());
+
+        // command isn't repeated, node may have executed it although its confirmation got lost
+        match timeout(Duration::from_millis(self.timeouts.result), rcvr).await {
+            Ok(Ok(result)) if result.outcome == SendOutcome::Delivered => Ok(()),
+            Ok(Ok(result)) => Err(CommandError::Transmit(format!("{:?}", result.outcome))),
+            Ok(Err(err)) => Err(CommandError::Transmit(err.to_string())),
+            Err(_) => Err(CommandError::Transmit("no send result".to_string()))
+        }
+    }
+
+    /// Wait for response with given COT, negative confirmation fails the command
+    async fn await_cot(&self, rsp_rcvr: &mut mpsc::UnboundedReceiver<IOBMessage>, stage: Stage, cot: COT, timeout_ms: u64) -> Result<(), CommandError> {
+        let wait = async {
+            while let Some(rsp) = rsp_rcvr.recv().await {
+                if rsp.iob.asdh.cot == cot {
+                    return match rsp.iob.asdh.pn {
+                        true => Err(CommandError::Rejected(stage)),
+                        false => Ok(())
+                    };
+                }
+            }
+            Err(CommandError::TimedOut(stage))
+        };
+
+        match timeout(Duration::from_millis(timeout_ms), wait).await {
+            Ok(result) => result,
+            Err(_) => Err(CommandError::TimedOut(stage))
+        }
+    }
+
+    /// Hand response over to command awaiting it, returns false if nobody waits for it
+    fn route(&self, rsp: IOBMessage) -> bool {
+        let key: PointKey = (rsp.message.header.address, rsp.iob.ioa);
+
+        match self.pending.lock().unwrap().get(&key) {
+            Some(rsp_sender) => rsp_sender.send(rsp).is_ok(),
+            None => false
+        }
+    }
+}
+
+/// Build command packet activating `ie` on point `ioa` of common address `ca`
+pub(super) fn build_command(buf: &mut packet::buffer::Dynamic, ca: u8, ioa: u32, ie: &IE) -> Result<(), ProtocolError> {
+    PtNetPacket::with_asdh(&ptnet::ASDH::with(ca, COT::ACT, false), buf)
+        .and_then(|builder| builder.begin_asdu(&ptnet::DUI::with_direct(ie.type_id(), 1, false)))
+        .and_then(|builder| builder.add_ioa(ioa))
+        .and_then(|builder| builder.add_ie(ie))
+        .and_then(|builder| builder.end_asdu())
+        .map_err(ProtocolError::encoding)?;
+
+    Ok(())
+}
+
+/// Feeds command confirmations to CommandEngine
+pub struct CommandProcess<'a> {
+    engine: &'a CommandEngine<'a>,
+    iob_rcvr: broadcast::Receiver<IOBMessage>
+}
+
+impl<'a> CommandProcess<'a> {
+    pub fn new(engine: &'a CommandEngine<'a>, conn: &'a ClientConnection) -> Self {
+        CommandProcess {
+            engine: engine,
+            iob_rcvr: conn.subscribe_iob()
+        }
+    }
+}
+
+#[async_trait]
+impl<'a> PtNetProcess for CommandProcess<'a> {
+    fn name(&self) -> &str {
+        COMMAND
+    }
+
+    async fn run(&mut self) -> Result<(), PtnetMgrError> {
+        loop {
+            let rsp = self.iob_rcvr.recv().await?;
+
+            if matches!(rsp.iob.asdh.cot, COT::ACTCON | COT::ACTTERM) {
+                self.engine.route(rsp);
+            }
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{info, warn};
+use ptnet::{IE, PtNetPacket, ASDHConstruct, COT, DUIConstruct};
+use serde::{Serialize, Deserialize};
+use tokio::{sync::{broadcast, Mutex}, time::interval, select};
+
+use crate::{database::{Database, NodeAddress, unix_now, NodeAddr, node_table::NodeRecord, commissioning_table::{NodeSetup, CommissioningRecord}}, client_connection::{ClientConnection, IOBMessage}, error::{PtnetMgrError, ProtocolError}, sol};
+
+use super::{PtNetProcess, CommandEngine, CommandMode, Retrier, RetryPolicy, ResponseMatcher, response_key};
+
+/// Device point a setting is written to
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub struct SetupPoint {
+    pub ioa: u32,
+    pub ti: u8
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct CommissioningConfig {
+    /// pause between commissioning rounds (seconds)
+    pub period: u64,
+    /// SOL model root to take node configuration from, process doesn't run if not set
+    pub model_root: Option<String>,
+    pub ca: SetupPoint,
+    pub report_interval: SetupPoint,
+    /// written once for each group ballast is member of
+    pub group: SetupPoint,
+    /// value of `ca` point on device in factory-default state, such devices are commissioned
+    pub factory_ca: u32,
+    /// wait for response to reading `ca` point (milliseconds)
+    pub read_timeout: u64,
+    /// retrying of undelivered reads
+    pub retry: RetryPolicy
+}
+
+impl Default for CommissioningConfig {
+    fn default() -> Self {
+        Self {
+            period: 60,
+            model_root: None,
+            ca: SetupPoint { ioa: 0x100, ti: 48 },
+            report_interval: SetupPoint { ioa: 0x101, ti: 48 },
+            group: SetupPoint { ioa: 0x102, ti: 48 },
+            factory_ca: 0,
+            read_timeout: 5000,
+            retry: Default::default()
+        }
+    }
+}
+
+/// Assigns configuration from SOL model to nodes reporting factory-default state
+pub struct CommissioningProcess<'a> {
+    conf: CommissioningConfig,
+    model_root: String,
+    db: &'a Database<'a>,
+    commands: &'a CommandEngine<'a>,
+    retrier: Retrier<'a>,
+    iob_rcvr: Mutex<broadcast::Receiver<IOBMessage>>,
+    /// reads of `ca` point awaiting response
+    responses: ResponseMatcher
+}
+
+impl<'a> CommissioningProcess<'a> {
+    pub fn new(conf: CommissioningConfig, model_root: String, db: &'a Database, conn: &'a ClientConnection, commands: &'a CommandEngine<'a>, retrier: Retrier<'a>) -> Self {
+        CommissioningProcess {
+            conf: conf,
+            model_root: model_root,
+            db: db,
+            commands: commands,
+            retrier: retrier,
+            iob_rcvr: Mutex::new(conn.subscribe_iob()),
+            responses: ResponseMatcher::new()
+        }
+    }
+
+    async fn commission_all(&self) -> Result<(), PtnetMgrError> {
+        let mut ticker = interval(Duration::from_secs(self.conf.period));
+
+        loop {
+            ticker.tick().await;
+
+            // model is re-read each round, changed configuration is applied again
+            let setups = sol::loader::load_setups(&self.model_root).map_err(PtnetMgrError::Model)?;
+
+            for node in self.db.nodes.load_many(self.db.nodes.list()?.iter())? {
+                if let Some(setup) = setups.get(&node.address) {
+                    if self.needs_commissioning(&node, setup).await? {
+                        self.commission(&node.address, setup).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Node answering scans which is in factory-default state, or was commissioned with configuration
+    /// model doesn't have anymore. Nodes configured otherwise (e.g. by hand) are left alone.
+    async fn needs_commissioning(&self, node: &NodeRecord, setup: &NodeSetup) -> Result<bool, PtnetMgrError> {
+        if node.device_status.is_none() || node.online == Some(false) {
+            return Ok(false);
+        }
+
+        if let Some(rec) = self.db.commissioning.get(&node.address)? {
+            if rec.applied != *setup {
+                return Ok(true);
+            }
+        }
+
+        // node reset to factory defaults is commissioned again, whatever is recorded
+        let factory_ca = setting_ie(&self.conf.ca, self.conf.factory_ca)?;
+        Ok(self.read_ca(&node.address).await == Some(factory_ca))
+    }
+
+    /// Read `ca` point of node, None if node didn't answer
+    async fn read_ca(&self, address: &NodeAddress) -> Option<IE> {
+        let ca = self.db.common_addresses(address).control;
+        let ioa = self.conf.ca.ioa;
+        let mut buf = packet::buffer::Dynamic::new();
+        PtNetPacket::with_asdh(&ptnet::ASDH::with(ca, COT::REQ, false), &mut buf).ok()?
+            .begin_asdu(&ptnet::DUI::with_direct(ptnet::TC_C_RD, 1, false)).ok()?
+            .add_ioa(ioa).ok()?
+            .end_asdu().ok()?;
+
+        let _node_lock = self.retrier.conn().lock_node(address).await;
+
+        // register before transmitting, response may arrive before request result
+        let expectation = self.responses.expect((*address, ca, ioa), |rsp| rsp.iob.asdh.cot == COT::REQ);
+
+        let rsp = match self.retrier.send_prm(ptnet::FC::PrmSendNoreply, address, &buf).await {
+            Ok(_) => expectation.wait(Duration::from_millis(self.conf.read_timeout)).await,
+            Err(_) => None
+        };
+
+        rsp.map(|rsp| rsp.iob.ie)
+    }
+
+    /// Hand responses over to reads awaiting them
+    async fn route_responses(&self) -> Result<(), PtnetMgrError> {
+        let mut iob_rcvr = self.iob_rcvr.lock().await;
+
+        loop {
+            let rsp = iob_rcvr.recv().await?;
+            self.responses.dispatch(&response_key(&rsp), rsp);
+        }
+    }
+
+    async fn commission(&self, address: &NodeAddress, setup: &NodeSetup) -> Result<(), PtnetMgrError> {
+        info!("Commission node {}", NodeAddr(*address));
+
+        let mut settings: Vec<(&SetupPoint, u32)> = Vec::new();
+        if let Some(ca) = setup.ca {
+            settings.push((&self.conf.ca, ca.into()));
+        }
+        if let Some(report_interval) = setup.report_interval {
+            settings.push((&self.conf.report_interval, report_interval));
+        }
+        settings.extend(setup.groups.iter().map(|group| (&self.conf.group, u32::from(*group))));
+
+        for (point, value) in settings {
+            let ie = setting_ie(point, value)?;
+
+            // node stays uncommissioned, next round tries again
+            if let Err(err) = self.commands.send_command(address, point.ioa, ie, CommandMode::Direct).await {
+                warn!("Commissioning of node {} failed! ({})", NodeAddr(*address), err);
+                return Ok(());
+            }
+        }
+
+        self.db.commissioning.set(address, CommissioningRecord {
+            applied: setup.clone(),
+            commissioned_at: unix_now()
+        })?;
+
+        info!("Node {} commissioned", NodeAddr(*address));
+
+        Ok(())
+    }
+}
+
+/// Encode setting value as information element of point's type
+pub(super) fn setting_ie(point: &SetupPoint, value: u32) -> Result<IE, ProtocolError> {
+    IE::from_bytes(point.ti, &value.to_le_bytes()).map_err(ProtocolError::encoding)
+}
+
+#[async_trait]
+impl<'a> PtNetProcess for CommissioningProcess<'a> {
+    fn name(&self) -> &str {
+        "commissioning"
+    }
+
+    async fn run(&mut self) -> Result<(), PtnetMgrError> {
+        select! {
+            result = self.commission_all() => result,
+            result = self.route_responses() => result
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::{database::{Database, NodeAddress, unix_now, point_table, group_table::GroupId, derived_table::DerivedSample}, error::{PtnetMgrError, DbError}};
+
+use super::PtNetProcess;
+
+#[derive(Debug,Clone,Copy,Serialize,Deserialize,PartialEq)]
+pub enum Aggregate {
+    Avg,
+    Sum,
+    Min,
+    Max
+}
+
+impl Aggregate {
+    /// None if there are no values
+    fn apply(&self, values: &[f64]) -> Option<f64> {
+        if values.is_empty() {
+            return None;
+        }
+
+        Some(match self {
+            Aggregate::Avg => values.iter().sum::<f64>() / values.len() as f64,
+            Aggregate::Sum => values.iter().sum(),
+            Aggregate::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+            Aggregate::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max)
+        })
+    }
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub enum Members {
+    /// members of group, as they are at the time of computation
+    Group(GroupId),
+    Nodes(Vec<NodeAddress>)
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub struct DerivedPoint {
+    /// name derived point is stored and published under
+    pub name: String,
+    /// measurement series of members aggregated
+    pub series: String,
+    pub members: Members,
+    pub aggregate: Aggregate,
+    /// multiplies aggregated value, for unit conversions
+    #[serde(default = "scale_default")]
+    pub scale: f64
+}
+
+fn scale_default() -> f64 {
+    1.0
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct DerivedConfig {
+    pub points: Vec<DerivedPoint>,
+    /// values older than this are left out (seconds), disabled if 0
+    pub max_age: u64,
+    /// max. values of derived point kept in database
+    pub max_samples: usize
+}
+
+impl Default for DerivedConfig {
+    fn default() -> Self {
+        Self {
+            points: Vec::new(),
+            max_age: 3600,
+            max_samples: 16
+        }
+    }
+}
+
+/// Computes derived points from measurements of their members
+///
+/// Only points fed by series of received sample are recomputed, from latest values kept in memory.
+pub struct DerivedProcess<'a> {
+    conf: DerivedConfig,
+    db: &'a Database<'a>,
+    /// latest value and its unix time, by node and series
+    latest: HashMap<(NodeAddress, String), (f64, u64)>
+}
+
+impl<'a> DerivedProcess<'a> {
+    pub fn new(conf: DerivedConfig, db: &'a Database) -> Self {
+        DerivedProcess {
+            conf: conf,
+            db: db,
+            latest: HashMap::new()
+        }
+    }
+
+    fn members(&self, members: &Members) -> Result<Vec<NodeAddress>, DbError> {
+        Ok(match members {
+            Members::Group(id) => self.db.groups.get(*id)?.map(|rec| rec.members.iter().map(|m| m.address).collect()).unwrap_or_default(),
+            Members::Nodes(nodes) => nodes.clone()
+        })
+    }
+
+    /// Latest values of members not known yet are loaded from points table
+    fn load(&mut self) -> Result<(), DbError> {
+        for point in self.conf.points.clone() {
+            for address in self.members(&point.members)? {
+                let key = (address, point.series.clone());
+                if self.latest.contains_key(&key) {
+                    continue;
+                }
+
+                let rec = self.db.points.get(&address)?;
+                if let Some(sample) = rec.series.get(&point.series).and_then(|samples| samples.last()) {
+                    if let Some(value) = sample.number() {
+                        self.latest.insert(key, (value, sample.at));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn compute(&self, point: &DerivedPoint, members: &[NodeAddress], now: u64) -> Option<f64> {
+        let values: Vec<f64> = members.iter()
+            .filter_map(|address| self.latest.get(&(*address, point.series.clone())))
+            .filter(|(_, at)| self.conf.max_age == 0 || now.saturating_sub(*at) <= self.conf.max_age)
+            .map(|(value, _)| *value)
+            .collect();
+
+        point.aggregate.apply(&values).map(|value| value * point.scale)
+    }
+
+    fn on_sample(&mut self, address: NodeAddress, series: &str, value: f64, at: u64) -> Result<(), DbError> {
+        self.latest.insert((address, series.to_string()), (value, at));
+        let now = unix_now();
+
+        for point in self.conf.points.iter().filter(|point| point.series == series) {
+            let members = self.members(&point.members)?;
+            if !members.contains(&address) {
+                continue;
+            }
+
+            match self.compute(point, &members, now) {
+                Some(value) => self.db.derived.record(&point.name, DerivedSample { at: now, value: value }, self.conf.max_samples)?,
+                None => debug!("Derived point {} has no current values", point.name)
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<'a> PtNetProcess for DerivedProcess<'a> {
+    fn name(&self) -> &str {
+        "derived"
+    }
+
+    async fn run(&mut self) -> Result<(), PtnetMgrError> {
+        let mut point_evt_rcvr = self.db.points.events.subscribe();
+        self.load()?;
+
+        loop {
+            let point_table::Event::SampleAdded(address, series, sample) = match point_evt_rcvr.recv().await {
+                Ok(evt) => evt,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("Derived points skipped {} samples", n);
+                    continue;
+                },
+                Err(err) => return Err(err.into())
+            };
+
+            if let Some(value) = sample.number() {
+                self.on_sample(address, &series, value, sample.at)?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregate() {
+        let values = [2.0, 4.0, 9.0];
+
+        assert_eq!(Some(5.0), Aggregate::Avg.apply(&values));
+        assert_eq!(Some(15.0), Aggregate::Sum.apply(&values));
+        assert_eq!(Some(2.0), Aggregate::Min.apply(&values));
+        assert_eq!(Some(9.0), Aggregate::Max.apply(&values));
+        assert_eq!(None, Aggregate::Avg.apply(&[]));
+    }
+}
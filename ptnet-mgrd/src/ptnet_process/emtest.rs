@@ -0,0 +1,216 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::Local;
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+use tokio::{sync::{broadcast, Mutex}, time::interval, select};
+
+use crate::{database::{Database, NodeAddress, unix_now, NodeAddr, point_table::Sample, em_test_table::{EmTestsRecord, TestKind, FUNCTION_TEST_DAYS, DURATION_TEST_DAYS}}, client_connection::{ClientConnection, IOBMessage}, site::LabelFilter, time_window::TimeWindow, error::{PtnetMgrError, DbError}};
+
+use super::{PtNetProcess, CommandEngine, SetupPoint, command_node};
+
+const DAY: u64 = 86400;
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct EmTestConfig {
+    /// label filter of emergency ballasts, `key=value[,key=value...]`
+    pub nodes: String,
+    /// longest age of function and duration tests (days), next test starts a day before
+    pub function_days: u64,
+    pub duration_days: u64,
+    /// point tests are started by
+    pub start: SetupPoint,
+    pub function_value: u32,
+    pub duration_value: u32,
+    /// point ballast reports test result on, 0 is pass, other values are failure codes
+    pub result_ioa: u32,
+    /// test without result after this long fails (seconds)
+    pub function_timeout: u64,
+    pub duration_timeout: u64,
+    /// tests start only inside these windows, anytime if empty
+    pub windows: Vec<TimeWindow>,
+    /// max. nodes testing at once, area shouldn't rely on discharged batteries all at once
+    pub max_concurrent: usize,
+    /// pause between evaluations (seconds)
+    pub period: u64
+}
+
+impl Default for EmTestConfig {
+    fn default() -> Self {
+        Self {
+            nodes: "emergency".to_string(),
+            function_days: FUNCTION_TEST_DAYS,
+            duration_days: DURATION_TEST_DAYS,
+            start: SetupPoint { ioa: 0x500, ti: 48 },
+            function_value: 1,
+            duration_value: 2,
+            result_ioa: 0x501,
+            function_timeout: 600,
+            duration_timeout: 4 * 3600,
+            windows: Vec::new(),
+            max_concurrent: 4,
+            period: 60
+        }
+    }
+}
+
+impl EmTestConfig {
+    /// Test node is due for, duration test first as it covers function test too
+    fn due(&self, rec: &EmTestsRecord, now: u64) -> Option<TestKind> {
+        [(TestKind::Duration, self.duration_days), (TestKind::Function, self.function_days)].into_iter()
+            .find(|(kind, days)| match rec.last(*kind) {
+                None => true,
+                // failed test is repeated next day, e.g. after battery replacement
+                Some(result) if !result.passed => now.saturating_sub(result.finished_at) >= DAY,
+                Some(result) => now.saturating_sub(result.finished_at) >= days.saturating_sub(1) * DAY
+            })
+            .map(|(kind, _)| kind)
+    }
+
+    fn timeout(&self, kind: TestKind) -> u64 {
+        match kind {
+            TestKind::Function => self.function_timeout,
+            TestKind::Duration => self.duration_timeout
+        }
+    }
+}
+
+/// Runs emergency lighting function and duration tests, results are kept as compliance records
+pub struct EmTestProcess<'a> {
+    conf: EmTestConfig,
+    nodes: LabelFilter,
+    db: &'a Database<'a>,
+    commands: &'a CommandEngine<'a>,
+    iob_rcvr: Mutex<broadcast::Receiver<IOBMessage>>
+}
+
+impl<'a> EmTestProcess<'a> {
+    pub fn new(conf: EmTestConfig, nodes: LabelFilter, db: &'a Database, conn: &'a ClientConnection, commands: &'a CommandEngine<'a>) -> Self {
+        EmTestProcess {
+            conf: conf,
+            nodes: nodes,
+            db: db,
+            commands: commands,
+            iob_rcvr: Mutex::new(conn.subscribe_iob())
+        }
+    }
+
+    async fn drive(&self) -> Result<(), PtnetMgrError> {
+        let mut ticker = interval(Duration::from_secs(self.conf.period.max(1)));
+
+        loop {
+            ticker.tick().await;
+
+            let now = unix_now();
+            let mut testing = 0;
+            let mut due = Vec::new();
+
+            for info in self.db.query_nodes(&self.nodes)? {
+                let address = info.node.address;
+                let rec = self.db.em_tests.get(&address)?;
+
+                match rec.pending {
+                    Some(test) if now.saturating_sub(test.started_at) > self.conf.timeout(test.kind) => {
+                        warn!("Node {} reported no result of {:?} test", NodeAddr(address), test.kind);
+                        self.db.em_tests.finish(&address, false, None, now)?;
+                    },
+                    Some(_) => testing += 1,
+                    None => due.extend(self.conf.due(&rec, now).map(|kind| (address, kind)))
+                }
+            }
+
+            let local_now = Local::now().naive_local();
+            if !self.conf.windows.is_empty() && !self.conf.windows.iter().any(|window| window.contains(&local_now)) {
+                continue;
+            }
+
+            for (address, kind) in due.into_iter().take(self.conf.max_concurrent.saturating_sub(testing)) {
+                self.start(&address, kind).await?;
+            }
+        }
+    }
+
+    async fn start(&self, address: &NodeAddress, kind: TestKind) -> Result<(), DbError> {
+        let node = NodeAddr(*address).to_string();
+        let value = match kind {
+            TestKind::Function => self.conf.function_value,
+            TestKind::Duration => self.conf.duration_value
+        };
+
+        info!(node = node.as_str(); "Starting {:?} test of node {}", kind, node);
+        match command_node(self.commands, address, self.conf.start.ioa, self.conf.start.ti, value, None).await {
+            Ok(()) => { self.db.em_tests.start(address, kind, unix_now())?; },
+            Err(err) => warn!(node = node.as_str(); "Test of node {} not started! ({})", node, err)
+        }
+
+        Ok(())
+    }
+
+    async fn collect(&self) -> Result<(), PtnetMgrError> {
+        let mut iob_rcvr = self.iob_rcvr.lock().await;
+
+        loop {
+            let IOBMessage { iob, message: msg, .. } = iob_rcvr.recv().await?;
+            if iob.ioa != self.conf.result_ioa {
+                continue;
+            }
+
+            let now = unix_now();
+            let code = match (Sample { at: now, value: iob.ie }).number() {
+                Some(code) => code as u32,
+                None => continue
+            };
+
+            let node = NodeAddr(msg.header.address).to_string();
+            match self.db.em_tests.finish(&msg.header.address, code == 0, Some(code), now)? {
+                Some(result) if result.passed => info!(node = node.as_str(); "{:?} test of node {} passed", result.kind, node),
+                Some(result) => warn!(node = node.as_str(); "{:?} test of node {} failed with code {}", result.kind, node, code),
+                None => ()
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<'a> PtNetProcess for EmTestProcess<'a> {
+    fn name(&self) -> &str {
+        "emtest"
+    }
+
+    async fn run(&mut self) -> Result<(), PtnetMgrError> {
+        select! {
+            result = self.drive() => result,
+            result = self.collect() => result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::em_test_table::TestResult;
+
+    use super::*;
+
+    fn result(kind: TestKind, finished_at: u64, passed: bool) -> TestResult {
+        TestResult { kind: kind, started_at: finished_at, finished_at: finished_at, passed: passed, code: None }
+    }
+
+    #[test]
+    fn due_tests() {
+        let conf = EmTestConfig::default();
+        let mut rec = EmTestsRecord::default();
+
+        assert_eq!(Some(TestKind::Duration), conf.due(&rec, 0));
+
+        rec.results.push(result(TestKind::Duration, 0, true));
+        assert_eq!(None, conf.due(&rec, 10 * DAY));
+        assert_eq!(Some(TestKind::Function), conf.due(&rec, 29 * DAY));
+
+        rec.results.push(result(TestKind::Function, 29 * DAY, false));
+        assert_eq!(None, conf.due(&rec, 29 * DAY + 3600));
+        assert_eq!(Some(TestKind::Function), conf.due(&rec, 30 * DAY));
+        assert_eq!(Some(TestKind::Duration), conf.due(&rec, 364 * DAY));
+    }
+}
@@ -0,0 +1,149 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, error};
+use ptnet::{IE, PtNetPacket, ASDHConstruct, COT, DUIConstruct, FC};
+use serde::{Serialize, Deserialize};
+use tokio::{sync::{broadcast, Mutex}, time::{interval, sleep}, select};
+
+use crate::{database::{Database, NodeAddress, unix_now, NodeAddr, point_table::Sample}, client_connection::{ClientConnection, IOBMessage}, error::{PtnetMgrError, ProtocolError}};
+
+use super::{PtNetProcess, Retrier, RetryPolicy};
+
+/// Counter point read from metering nodes
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub struct MeterPoint {
+    pub ioa: u32,
+    /// TI129 or TI161
+    pub ti: u8,
+    /// measurement series readings are stored as
+    pub series: String,
+    /// counter width, counter wraps to 0 after 2^bits - 1
+    pub bits: u8
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct EnergyConfig {
+    /// pause between reading rounds (seconds)
+    pub period: u64,
+    /// pause between two read requests (milliseconds)
+    pub pace: u64,
+    pub points: Vec<MeterPoint>,
+    /// samples kept per series
+    pub max_samples: usize,
+    /// retrying of undelivered read requests
+    pub retry: RetryPolicy
+}
+
+impl Default for EnergyConfig {
+    fn default() -> Self {
+        Self {
+            period: 900,
+            pace: 200,
+            points: vec![
+                MeterPoint { ioa: 0x400, ti: 129, series: "energy".to_string(), bits: 32 },
+                MeterPoint { ioa: 0x401, ti: 161, series: "energy_reactive".to_string(), bits: 32 }
+            ],
+            max_samples: 96,
+            retry: Default::default()
+        }
+    }
+}
+
+/// Reads energy counters and accumulates daily consumption
+pub struct EnergyProcess<'a> {
+    conf: EnergyConfig,
+    db: &'a Database<'a>,
+    retrier: Retrier<'a>,
+    iob_rcvr: Mutex<broadcast::Receiver<IOBMessage>>
+}
+
+impl<'a> EnergyProcess<'a> {
+    pub fn new(conf: EnergyConfig, db: &'a Database, conn: &'a ClientConnection, retrier: Retrier<'a>) -> Self {
+        EnergyProcess {
+            conf: conf,
+            db: db,
+            retrier: retrier,
+            iob_rcvr: Mutex::new(conn.subscribe_iob())
+        }
+    }
+
+    async fn read_all(&self) -> Result<(), PtnetMgrError> {
+        let mut ticker = interval(Duration::from_secs(self.conf.period));
+
+        loop {
+            ticker.tick().await;
+
+            for address in self.db.nodes.list()? {
+                for point in &self.conf.points {
+                    if let Err(err) = self.read(&address, point).await {
+                        error!("Error reading {} of node {}! ({})", point.series, NodeAddr(address), err);
+                    }
+                    sleep(Duration::from_millis(self.conf.pace)).await;
+                }
+            }
+        }
+    }
+
+    /// Request counter, reading is accounted when response arrives
+    async fn read(&self, address: &NodeAddress, point: &MeterPoint) -> Result<(), PtnetMgrError> {
+        let mut buf = packet::buffer::Dynamic::new();
+        PtNetPacket::with_asdh(&ptnet::ASDH::with(self.db.common_addresses(address).metering, COT::REQ, false), &mut buf)
+            .and_then(|builder| builder.begin_asdu(&ptnet::DUI::with_direct(ptnet::TC_C_RD, 1, false)))
+            .and_then(|builder| builder.add_ioa(point.ioa))
+            .and_then(|builder| builder.end_asdu())
+            .map_err(ProtocolError::encoding)?;
+
+        debug!("Read {} of node {}", point.series, NodeAddr(*address));
+        let _node_lock = self.retrier.conn().lock_node(address).await;
+        Ok(self.retrier.send_prm(FC::PrmSendNoreply, address, &buf).await?)
+    }
+
+    /// Account counter readings, both requested and spontaneous
+    async fn collect(&self) -> Result<(), PtnetMgrError> {
+        let mut iob_rcvr = self.iob_rcvr.lock().await;
+
+        loop {
+            let IOBMessage { iob, message: msg, .. } = iob_rcvr.recv().await?;
+            let ti = iob.ie.type_id();
+
+            let point = match self.conf.points.iter().find(|p| p.ioa == iob.ioa && p.ti == ti) {
+                Some(point) => point,
+                None => continue
+            };
+
+            if let Some(raw) = counter_value(&iob.ie) {
+                let at = unix_now();
+                let day = chrono::Local::now().date_naive();
+
+                self.db.points.record(&msg.header.address, &point.series, Sample { at: at, value: iob.ie }, self.conf.max_samples)?;
+                let delta = self.db.energy.add_reading(&msg.header.address, &point.series, raw, point.bits, at, day)?;
+                debug!("Node {} {} +{}", NodeAddr(msg.header.address), point.series, delta);
+            }
+        }
+    }
+}
+
+/// Raw value of integrated totals counter
+fn counter_value(ie: &IE) -> Option<u32> {
+    match ie {
+        IE::TI129(counter) => Some(counter.value),
+        IE::TI161(counter) => Some(counter.value),
+        _ => None
+    }
+}
+
+#[async_trait]
+impl<'a> PtNetProcess for EnergyProcess<'a> {
+    fn name(&self) -> &str {
+        "energy"
+    }
+
+    async fn run(&mut self) -> Result<(), PtnetMgrError> {
+        select! {
+            result = self.read_all() => result,
+            result = self.collect() => result
+        }
+    }
+}
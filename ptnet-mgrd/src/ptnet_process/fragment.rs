@@ -0,0 +1,72 @@
+use ptnet::{PtNetPacket, ASDHConstruct, COT, DUIConstruct};
+
+use crate::error::{PtnetMgrError, LinkError, ProtocolError};
+
+/// Read request of `ioas` in single ASDU
+fn build_read(ca: u8, ioas: &[u32]) -> Result<Vec<u8>, ProtocolError> {
+    let mut buf = packet::buffer::Dynamic::new();
+    let mut builder = PtNetPacket::with_asdh(&ptnet::ASDH::with(ca, COT::REQ, false), &mut buf)
+        .and_then(|builder| builder.begin_asdu(&ptnet::DUI::with_direct(ptnet::TC_C_RD, ioas.len() as u8, false)))
+        .map_err(ProtocolError::encoding)?;
+
+    for ioa in ioas {
+        builder = builder.add_ioa(*ioa).map_err(ProtocolError::encoding)?;
+    }
+    builder.end_asdu().map_err(ProtocolError::encoding)?;
+
+    Ok(buf.into())
+}
+
+/// Read requests of `ioas`, each ASDU takes as many IOAs as fit into `max_payload`
+pub fn build_reads(ca: u8, ioas: &[u32], max_payload: usize) -> Result<Vec<Vec<u8>>, PtnetMgrError> {
+    let mut payloads = Vec::new();
+    let mut start = 0;
+
+    while start < ioas.len() {
+        let mut end = start + 1;
+        let mut payload = build_read(ca, &ioas[start..end])?;
+        if payload.len() > max_payload {
+            return Err(LinkError::PayloadTooLarge(payload.len(), max_payload).into());
+        }
+
+        // DUI counts IOBs in single octet
+        while end < ioas.len() && end - start < usize::from(u8::MAX) {
+            let longer = build_read(ca, &ioas[start..=end])?;
+            if longer.len() > max_payload {
+                break;
+            }
+            payload = longer;
+            end += 1;
+        }
+
+        payloads.push(payload);
+        start = end;
+    }
+
+    Ok(payloads)
+}
+
+#[cfg(test)]
+mod tests {
+    use ptnet::Scanner;
+
+    use super::*;
+
+    fn ioas_of(payload: &[u8]) -> Vec<u32> {
+        Scanner::new(payload).into_iob_iter().map(|item| item.unwrap().ioa).collect()
+    }
+
+    #[test]
+    fn split_to_fit() {
+        let two = build_read(0x3E, &[1, 2]).unwrap().len();
+
+        let payloads = build_reads(0x3E, &[1, 2, 3], two).unwrap();
+        assert_eq!(2, payloads.len());
+        assert_eq!(vec![1, 2], ioas_of(&payloads[0]));
+        assert_eq!(vec![3], ioas_of(&payloads[1]));
+
+        assert_eq!(1, build_reads(0x3E, &[1, 2, 3], 255).unwrap().len());
+        assert!(build_reads(0x3E, &[], 255).unwrap().is_empty());
+        assert!(build_reads(0x3E, &[1], 2).is_err(), "single IOA doesn't fit");
+    }
+}
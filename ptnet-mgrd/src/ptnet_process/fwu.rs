@@ -1,95 +1,844 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
 
 use async_trait::async_trait;
-use log::{error, info};
-use ptnet::{FW_State_A, FC, PtNetPacket, ASDHConstruct, COT, DUIConstruct, FW_Version_A};
-use tokio::sync::broadcast;
+use chrono::{DateTime, Local, NaiveDateTime};
+use log::{error, info, debug, warn};
+use ptnet::{FC, PtNetPacket, ASDHConstruct, COT, DUIConstruct, FW_Version_A, image_header::FWVersion};
+use serde::{Serialize, Deserialize};
+use tokio::{sync::broadcast, time::interval, select};
 
-use crate::{database::{Database, node_table::{self, NodeRecord, Event::{NodeAdded, NodeModified}}, fwu_state_table::Goal}, client_connection::{ClientConnection, ClientConnectionSender}, fw_index::FirmwareIndex};
+use crate::{database::{Database, NodeAddress, unix_now, NodeAddr, node_table::{self, NodeRecord, Event::{NodeAdded, NodeModified, NodeRemoved}}, fwu_state_table::{Goal, Phase, FwState, FWUStateRecord, Attempt}, fwu_history_table::{HistoryEntry, Outcome}}, client_connection::ClientConnection, error::{PtnetMgrError, FwuError, ProtocolError}, fw_index::{self, FirmwareIndex}, fw_policy::{FirmwarePolicy, Violation}, time_window::UpdateWindows};
 
-use super::PtNetProcess;
+use super::{PtNetProcess, UpdateLimiter, UpdateLimits, Retrier, RetryPolicy};
+
+/// Violating node not reported since is no longer considered waiting for update
+const VIOLATOR_WAIT_EXPIRY: Duration = Duration::from_secs(600);
+
+/// Alarm raised while node reports firmware state unknown to this version
+const UNKNOWN_FW_STATE_ALARM: &str = "unknown_fw_state";
+
+/// How to verify finished updates and handle failed ones
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct RollbackPolicy {
+    /// fail update not finished within this time (seconds)
+    pub verify_timeout: u64,
+    /// wait before retrying failed update (seconds), doubles with each failure
+    pub backoff: u64,
+    /// max. wait before retry (seconds)
+    pub max_backoff: u64,
+    /// pin current firmware after this many consecutive failures
+    pub pin_after: Option<u32>
+}
+
+impl Default for RollbackPolicy {
+    fn default() -> Self {
+        Self {
+            verify_timeout: 900,
+            backoff: 600,
+            max_backoff: 86400,
+            pin_after: None
+        }
+    }
+}
+
+impl RollbackPolicy {
+    fn backoff_for(&self, failures: u32) -> u64 {
+        let factor = 1u64 << failures.saturating_sub(1).min(16);
+        self.backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct FWUConfig {
+    /// verification of finished firmware updates
+    pub rollback: RollbackPolicy,
+    /// retrying of undelivered update commands
+    pub retry: RetryPolicy
+}
+
+impl Default for FWUConfig {
+    fn default() -> Self {
+        Self {
+            rollback: Default::default(),
+            retry: Default::default()
+        }
+    }
+}
 
 pub struct FWUProcess<'a> {
     db: &'a Database<'a>,
     conn: &'a ClientConnection,
-    sender: &'a ClientConnectionSender<'a>,
+    /// transmits update commands, retrying undelivered ones
+    retrier: Retrier<'a>,
     fw_index: &'a FirmwareIndex,
-    node_evt_rcvr: broadcast::Receiver<node_table::Event>
+    /// when updates may be started
+    windows: &'a UpdateWindows,
+    /// versions nodes may run
+    policy: &'a FirmwarePolicy,
+    /// caps number of simultaneous downloads
+    limiter: &'a UpdateLimiter,
+    rollback: RollbackPolicy,
+    node_evt_rcvr: broadcast::Receiver<node_table::Event>,
+    fw_evt_rcvr: broadcast::Receiver<fw_index::Event>,
+    /// nodes violating firmware policy deferred by limiter, they get download slots first
+    waiting_violators: Mutex<HashMap<NodeAddress, Instant>>
 }
 
 impl<'a> FWUProcess<'a> {
-    pub fn new(db: &'a Database, conn: &'a ClientConnection, sender: &'a ClientConnectionSender<'a>, fw_index: &'a FirmwareIndex) -> Self {
+    pub fn new(db: &'a Database, conn: &'a ClientConnection, retrier: Retrier<'a>, fw_index: &'a FirmwareIndex, windows: &'a UpdateWindows, policy: &'a FirmwarePolicy, limiter: &'a UpdateLimiter, rollback: RollbackPolicy) -> Self {
         let fwu = Self {
             db: db,
             conn: conn,
-            sender: sender,
+            retrier: retrier,
             fw_index: fw_index,
-            node_evt_rcvr: db.nodes.events.subscribe()
+            windows: windows,
+            policy: policy,
+            limiter: limiter,
+            rollback: rollback,
+            node_evt_rcvr: db.nodes.events.subscribe(),
+            fw_evt_rcvr: fw_index.events.subscribe(),
+            waiting_violators: Mutex::new(HashMap::new())
         };
 
         return fwu;
     }
 
-    async fn process_node(&self, node: &NodeRecord) -> Result<(), Box<dyn std::error::Error>> {
+    fn latest_allowed(&self, node: &NodeRecord, policy: &FirmwarePolicy) -> Option<FWVersion> {
+        latest_allowed(self.fw_index, node, policy)
+    }
+
+    /// Some violating node waits for download slot
+    fn violators_waiting(&self) -> bool {
+        let mut waiting = self.waiting_violators.lock().unwrap();
+        waiting.retain(|_, since| since.elapsed() < VIOLATOR_WAIT_EXPIRY);
+        !waiting.is_empty()
+    }
+
+    async fn process_node(&self, node: &NodeRecord) -> Result<(), FwuError> {
+        // node gone offline won't report its download, slot is taken again once it's back downloading
+        if node.online == Some(false) {
+            self.limiter.release(&node.address);
+        }
+
+        // orphaned node is kept for its history only, pending one isn't adopted yet, neither is updated
+        if node.orphaned_at.is_some() || node.pending {
+            return Ok(());
+        }
+
         let fwu_state = self.db.fwu_state.get_or_create_for(&node.address)?;
+        let policy = self.policy;
         // if device_status is not known, it's impossible to do anything with this node
         if let Some(device_status) = node.device_status {
-            let fw_state: FW_State_A = device_status.fw_state.try_into()?;
+            let fw_state = FwState::from(device_status.fw_state);
+            let phase = match self.check_fw_state(node, fw_state) {
+                Ok(phase) => phase,
+                // not knowing what node does, leave it alone
+                Err(FwuError::InvalidFwState(..)) => return Ok(()),
+                Err(err) => return Err(err)
+            };
+            self.track_phase(node, &fwu_state, phase)?;
+
+            if let Some(attempt) = &fwu_state.attempt {
+                if self.verify_attempt(node, &fwu_state, attempt, fw_state, device_status.fw_version.into())? {
+                    // goal is re-evaluated on next status report
+                    return Ok(());
+                }
+            }
+
+            let running: FWVersion = device_status.fw_version.into();
+            let violation = policy.violation(&running);
+            // security fix rollouts don't wait for approval
+            let enforced = violation.is_some() && policy.enforce;
+
+            if violation.is_none() {
+                self.waiting_violators.lock().unwrap().remove(&node.address);
+            }
+
             match fwu_state.goal {
                 Goal::None => {
                     match fw_state {
-                        FW_State_A::Idle => {
-                            if let Some(fws) = self.fw_index.get_firmwares_for(&device_status.hw_version.into()) {
-                                // get latest firmware
-                                if let Some((latest_ver, _)) = fws.last_key_value() {
-                                    // is firmware newer than currently running on node?
-                                    if *latest_ver > device_status.fw_version.into() {
-                                        // yes, it's newer
-                                        info!("Newer firmware {} available for node '{}'", latest_ver, node.mac());
-
-                                        // self.db.fwu_state.modify(address, cb)
-                                    }
+                        FwState::Idle => {
+                            // latest firmware not forbidden by policy
+                            if let Some(latest_ver) = self.latest_allowed(node, policy) {
+                                if enforced && latest_ver != running {
+                                    warn!("Node '{}' runs firmware {} violating policy ({:?}), updating to {}", node.mac(), running, violation.unwrap(), latest_ver);
+
+                                    self.db.fwu_state.modify(&node.address, |opt_rec| {
+                                        let mut rec = opt_rec.unwrap_or_default();
+                                        rec.goal = Goal::UpdateTo(latest_ver);
+                                        Some(rec)
+                                    })?;
+                                } else if latest_ver > running
+                                    && fwu_state.rejected.as_ref().map_or(true, |rejected| latest_ver > *rejected) {
+                                    // is firmware newer than currently running on node? yes, it's newer
+                                    info!("Newer firmware {} available for node '{}', awaiting approval", latest_ver, node.mac());
+
+                                    self.db.fwu_state.modify(&node.address, |opt_rec| {
+                                        let mut rec = opt_rec.unwrap_or_default();
+                                        rec.goal = Goal::ApproveUpdateTo(latest_ver);
+                                        Some(rec)
+                                    })?;
                                 }
                             }
                         },
-                        FW_State_A::Download | FW_State_A::Flashing | FW_State_A::Updated => {
+                        FwState::Download | FwState::Flashing | FwState::Updated => {
                             info!("cancel firmware update on '{}' in progress, since it's non-goal", node.mac());
+                            if let Err(err) = self.send_fw_iu(node, COT::DEACT).await {
+                                error!("Error sending TI240 to '{}'! ({})", node.mac(), err);
+                            }
+                        },
+                        // returned above
+                        FwState::Unknown(_) => {}
+                    }
+                },
+                Goal::KeepCurrent => {
+                    if !matches!(fw_state, FwState::Idle) {
+                        info!("cancel firmware update on '{}' in progress, current firmware shall be kept", node.mac());
+                        if let Err(err) = self.send_fw_iu(node, COT::DEACT).await {
+                            error!("Error sending TI240 to '{}'! ({})", node.mac(), err);
+                        }
+                    }
+                },
+                Goal::ApproveUpdateTo(ver) => {
+                    let latest = self.latest_allowed(node, policy);
 
-                            let mut buf = packet::buffer::Dynamic::new();
-
-                            PtNetPacket::with_asdh(&ptnet::ASDH::with(0x3E, COT::DEACT, false), &mut buf)?
-                                .begin_asdu(&ptnet::DUI::with_direct(ptnet::TC_C_FW_IU, 1, false))?
-                                .add_ioa(0)?
-                                .end_asdu()?;
+                    if let (true, Some(latest_ver)) = (enforced, latest.clone()) {
+                        warn!("Node '{}' runs firmware {} violating policy ({:?}), updating to {} without approval", node.mac(), running, violation.unwrap(), latest_ver);
+                        self.db.fwu_state.modify(&node.address, |opt_rec| {
+                            let mut rec = opt_rec.unwrap_or_default();
+                            rec.goal = Goal::UpdateTo(latest_ver);
+                            Some(rec)
+                        })?;
+                    } else if !policy.allows(&ver) {
+                        // offer blacklisted after it was made
+                        info!("Offered firmware {} for node '{}' forbidden by policy, withdrawn", ver, node.mac());
+                        self.db.fwu_state.modify(&node.address, |opt_rec| {
+                            let mut rec = opt_rec.unwrap_or_default();
+                            rec.goal = Goal::None;
+                            Some(rec)
+                        })?;
+                    } else if let Some(latest_ver) = latest.filter(|latest_ver| *latest_ver > ver) {
+                        // waiting for user, just keep offer up to date
+                        info!("Newer firmware {} available for node '{}', replaces offered {}", latest_ver, node.mac(), ver);
+                        self.db.fwu_state.modify(&node.address, |opt_rec| {
+                            let mut rec = opt_rec.unwrap_or_default();
+                            rec.goal = Goal::ApproveUpdateTo(latest_ver);
+                            Some(rec)
+                        })?;
+                    }
+                },
+                Goal::UpdateTo(ver) => {
+                    if matches!(fw_state, FwState::Idle) && ver != device_status.fw_version.into() {
+                        let image = self.fw_index.get_firmwares_for(&device_status.hw_version.into()).and_then(|fws| fws.get(&ver).cloned());
 
-                            if let Err(err) = self.sender.send_prm(FC::PrmSendNoreply, &node.address, &buf).await {
+                        if image.is_none() {
+                            error!("Firmware {} for node '{}' not found in index!", ver, node.mac());
+                        } else if !policy.allows(&ver) {
+                            error!("Update of '{}' to {} refused, version forbidden by firmware policy!", node.mac(), ver);
+                        } else if fwu_state.attempt.is_some() {
+                            debug!("update of '{}' to {} requested, waiting for node to start", node.mac(), ver);
+                        } else if unix_now() < fwu_state.retry_after {
+                            debug!("update of '{}' to {} deferred, backing off after failure", node.mac(), ver);
+                        } else if !self.windows.permits_now() {
+                            // updates already in progress are let finish, only start is deferred
+                            debug!("update of '{}' to {} deferred, outside of update window", node.mac(), ver);
+                        } else if violation.is_none() && self.violators_waiting() {
+                            debug!("update of '{}' to {} deferred, nodes violating firmware policy go first", node.mac(), ver);
+                        } else if !self.limiter.try_acquire(&node.address, node.port.unwrap_or(ptnet::PORT_AUTO)) {
+                            debug!("update of '{}' to {} deferred, too many downloads running", node.mac(), ver);
+                            if violation.is_some() {
+                                self.waiting_violators.lock().unwrap().insert(node.address, Instant::now());
+                            }
+                        } else {
+                            self.waiting_violators.lock().unwrap().remove(&node.address);
+                            let image = image.unwrap();
+                            info!("start firmware update of '{}' to {} ({} bytes)", node.mac(), ver, image.payload().len());
+                            if let Err(err) = self.send_fw_iu(node, COT::ACT).await {
                                 error!("Error sending TI240 to '{}'! ({})", node.mac(), err);
+                                self.limiter.release(&node.address);
+                            } else {
+                                let attempt = Attempt {
+                                    from: device_status.fw_version.into(),
+                                    to: ver,
+                                    started_at: unix_now(),
+                                    updated_at: None
+                                };
+                                self.db.fwu_state.modify(&node.address, |opt_rec| {
+                                    let mut rec = opt_rec.unwrap_or_default();
+                                    rec.attempt = Some(attempt);
+                                    Some(rec)
+                                })?;
                             }
-                        },
+                        }
                     }
                 },
-                Goal::KeepCurrent => todo!(),
-                Goal::ApproveUpdateTo(ver) => todo!(),
-                Goal::UpdateTo(ver) => todo!(),
             }
         }
         Ok(())
     }
+
+    /// Re-evaluate nodes of hardware whose available firmwares changed
+    async fn firmware_changed(&self, evt: fw_index::Event) -> Result<(), FwuError> {
+        let (hw, fw, removed) = match evt {
+            fw_index::Event::FirmwareAdded(hw, fw) => (hw, fw, None),
+            fw_index::Event::FirmwareRemoved(hw, fw) => (hw, fw, Some(fw))
+        };
+
+        let nodes = self.db.nodes.load_many(self.db.nodes.list()?.iter())?;
+
+        // image may serve other revisions through compatibility rules
+        for node in nodes.iter().filter(|node| node.device_status.map_or(false, |st| self.fw_index.serves(&st.hw_version.into(), &hw, &fw))) {
+            if let Some(removed) = removed {
+                // offer of deleted image is withdrawn, latest remaining one is offered instead
+                self.db.fwu_state.modify(&node.address, |opt_rec| match opt_rec {
+                    Some(mut rec) if rec.goal == Goal::ApproveUpdateTo(removed) => {
+                        rec.goal = Goal::None;
+                        Some(rec)
+                    },
+                    _ => None
+                })?;
+            }
+
+            if let Err(err) = self.process_node(node).await {
+                error!("Error processing node '{}'! ({})", node.mac(), err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check whether running attempt succeeded or failed, returns true if it's resolved
+    fn verify_attempt(&self, node: &NodeRecord, fwu_state: &FWUStateRecord, attempt: &Attempt, fw_state: FwState, running: FWVersion) -> Result<bool, FwuError> {
+        let prev_phase = fwu_state.progress.as_ref().map(|p| p.phase).unwrap_or_default();
+
+        match fw_state {
+            FwState::Updated => {
+                if attempt.updated_at.is_none() {
+                    self.db.fwu_state.modify(&node.address, |opt_rec| {
+                        let mut rec = opt_rec.unwrap_or_default();
+                        if let Some(attempt) = rec.attempt.as_mut() {
+                            attempt.updated_at = Some(unix_now());
+                        }
+                        Some(rec)
+                    })?;
+                }
+                Ok(false)
+            },
+            FwState::Idle => {
+                let outcome = if running == attempt.to {
+                    Outcome::Succeeded
+                } else if attempt.updated_at.is_some() {
+                    Outcome::RolledBack(running)
+                } else if prev_phase == Phase::Download || prev_phase == Phase::Flashing {
+                    Outcome::Aborted
+                } else {
+                    // update not started yet
+                    return Ok(false);
+                };
+
+                self.finish_attempt(&node.address, attempt, outcome)?;
+                Ok(true)
+            },
+            _ => Ok(false)
+        }
+    }
+
+    /// Record attempt outcome to history and schedule retry of failed one
+    fn finish_attempt(&self, address: &NodeAddress, attempt: &Attempt, outcome: Outcome) -> Result<(), FwuError> {
+        let now = unix_now();
+        let failed = outcome != Outcome::Succeeded;
+
+        let node = NodeAddr(*address).to_string();
+        match &outcome {
+            Outcome::Succeeded => info!(node = node.as_str(); "firmware of '{}' updated to {}", node, attempt.to),
+            _ => warn!(node = node.as_str(); "firmware update of '{}' to {} failed! ({:?})", node, attempt.to, outcome)
+        };
+
+        self.db.fwu_history.append(address, HistoryEntry {
+            from: attempt.from.clone(),
+            to: attempt.to.clone(),
+            started_at: attempt.started_at,
+            finished_at: now,
+            outcome: outcome
+        })?;
+
+        self.db.fwu_state.modify(address, |opt_rec| {
+            let mut rec = opt_rec.unwrap_or_default();
+            rec.attempt = None;
+
+            if failed {
+                rec.failures += 1;
+                rec.retry_after = now + self.rollback.backoff_for(rec.failures);
+
+                if self.rollback.pin_after.map_or(false, |n| rec.failures >= n) {
+                    warn!(node = node.as_str(); "pin current firmware of '{}' after {} failed updates", node, rec.failures);
+                    rec.goal = Goal::KeepCurrent;
+                }
+            } else {
+                rec.failures = 0;
+                rec.retry_after = 0;
+            }
+
+            Some(rec)
+        })?;
+
+        self.limiter.release(address);
+
+        Ok(())
+    }
+
+    /// Fail attempts of nodes which stopped responding
+    fn check_timeouts(&self) -> Result<(), FwuError> {
+        let now = unix_now();
+
+        for (address, rec) in self.db.fwu_state.list()? {
+            if let Some(attempt) = rec.attempt {
+                let since = attempt.updated_at.unwrap_or(attempt.started_at);
+                if now.saturating_sub(since) > self.rollback.verify_timeout {
+                    self.finish_attempt(&address, &attempt, Outcome::NoResponse)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send firmware image update command (TI240) to node
+    async fn send_fw_iu(&self, node: &NodeRecord, cot: COT) -> Result<(), FwuError> {
+        let mut buf = packet::buffer::Dynamic::new();
+
+        PtNetPacket::with_asdh(&ptnet::ASDH::with(self.db.common_addresses(&node.address).system, cot, false), &mut buf)
+            .and_then(|builder| builder.begin_asdu(&ptnet::DUI::with_direct(ptnet::TC_C_FW_IU, 1, false)))
+            .and_then(|builder| builder.add_ioa(0))
+            .and_then(|builder| builder.end_asdu())
+            .map_err(ProtocolError::encoding)?;
+
+        let _node_lock = self.conn.lock_node(&node.address).await;
+        self.retrier.send_prm(FC::PrmSendNoreply, &node.address, &buf).await?;
+
+        Ok(())
+    }
+
+    /// Raise alarm while node reports unknown firmware state, clear it once state is known again; returns phase of known state,
+    /// unknown state fails with `FwuError::InvalidFwState`
+    fn check_fw_state(&self, node: &NodeRecord, fw_state: FwState) -> Result<Phase, FwuError> {
+        let raised = self.db.alarms.get(&node.address)?.alarms.get(UNKNOWN_FW_STATE_ALARM).map_or(false, |alarm| alarm.active);
+
+        match (fw_state, raised) {
+            (FwState::Unknown(state), raised) => {
+                let err = FwuError::InvalidFwState(state, node.mac());
+                if !raised {
+                    warn!("{}, node isn't updated until it reports known state", err);
+                    self.db.alarms.raise(&node.address, UNKNOWN_FW_STATE_ALARM, "fw_state", state as f64, unix_now())?;
+                }
+                return Err(err);
+            },
+            (_, true) => {
+                info!("Node '{}' reports known firmware state again", node.mac());
+                self.db.alarms.clear(&node.address, UNKNOWN_FW_STATE_ALARM, 0.0, unix_now())?;
+            },
+            (_, false) => {}
+        };
+
+        // only unknown state has no phase
+        Ok(fw_state.phase().unwrap_or_default())
+    }
+
+    /// Record update phase change reported by node into progress
+    fn track_phase(&self, node: &NodeRecord, fwu_state: &FWUStateRecord, phase: Phase) -> Result<(), FwuError> {
+        let prev_phase = fwu_state.progress.as_ref().map(|p| p.phase).unwrap_or_default();
+
+        match phase {
+            Phase::Download | Phase::Flashing => self.limiter.mark_started(&node.address, node.port.unwrap_or(ptnet::PORT_AUTO)),
+            Phase::Idle | Phase::Updated => if prev_phase != Phase::Idle && prev_phase != Phase::Updated {
+                self.limiter.release(&node.address)
+            }
+        };
+
+        if phase == prev_phase {
+            return Ok(());
+        }
+
+        Ok(self.db.fwu_state.update_progress(&node.address, |progress| {
+            if prev_phase == Phase::Idle {
+                // new update started, forget previous one
+                *progress = Default::default();
+                progress.started_at = unix_now();
+            }
+            progress.phase = phase;
+        })?)
+    }
 }
 
 #[async_trait]
 impl<'a> PtNetProcess for FWUProcess<'a> {
-    async fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        loop {
-            let evt = self.node_evt_rcvr.recv().await?;
+    fn name(&self) -> &str {
+        "fwu"
+    }
 
-            match evt {
-                NodeAdded(node) | NodeModified(node) => {
-                    if let Err(err) = self.process_node(&node).await {
-                        error!("Error processing node '{}'! ({})", node.mac(), err);
+    async fn run(&mut self) -> Result<(), PtnetMgrError> {
+        let mut timeout_check = interval(Duration::from_secs(60));
+        loop {
+            select! {
+                evt = self.node_evt_rcvr.recv() => {
+                    match evt? {
+                        NodeAdded(node) | NodeModified(node) => {
+                            if let Err(err) = self.process_node(&node).await {
+                                error!("Error processing node '{}'! ({})", node.mac(), err);
+                            }
+                        },
+                        NodeRemoved(address) => self.limiter.release(&address)
+                    }
+                },
+                evt = self.fw_evt_rcvr.recv() => {
+                    if let Err(err) = self.firmware_changed(evt?).await {
+                        error!("Error re-evaluating nodes after firmware index change! ({})", err);
+                    }
+                },
+                _ = timeout_check.tick() => {
+                    if let Err(err) = self.check_timeouts() {
+                        error!("Error checking firmware update timeouts! ({})", err);
                     }
                 }
             }
         }
     }
-}
\ No newline at end of file
+}
+/// Counts of changes done by `resync_fwu_state`
+#[derive(Debug,Clone,Default,Serialize,PartialEq)]
+pub struct ResyncReport {
+    /// states of nodes no longer in node table
+    pub removed: usize,
+    /// default states of nodes which had none
+    pub created: usize,
+    /// offered or approved versions no longer in firmware index
+    pub goals_reset: usize
+}
+
+/// Latest firmware for node allowed by policy
+fn latest_allowed(fw_index: &FirmwareIndex, node: &NodeRecord, policy: &FirmwarePolicy) -> Option<FWVersion> {
+    let device_status = node.device_status?;
+
+    fw_index.get_firmwares_for(&device_status.hw_version.into())
+        .and_then(|fws| fws.keys().rev().find(|ver| policy.allows(ver)).cloned())
+}
+
+/// What FWU process would do with node
+#[derive(Debug,Clone,Copy,Serialize,PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PlannedAction {
+    /// update starts right away
+    Start,
+    /// update starts after earlier waves finish
+    Queued,
+    /// update waits for update window or backoff after failure
+    Deferred,
+    /// update can't start until operator intervenes
+    Blocked,
+    /// update already started
+    InProgress,
+    /// newer firmware is offered, update waits for approval
+    AwaitApproval,
+    /// offered firmware is forbidden by policy, offer is withdrawn
+    Withdraw,
+    /// update running on node is not wanted and gets cancelled
+    Cancel
+}
+
+#[derive(Debug,Clone,Serialize,PartialEq)]
+pub struct PlannedUpdate {
+    pub address: String,
+    /// name of node in model
+    pub name: Option<String>,
+    pub running: FWVersion,
+    pub target: Option<FWVersion>,
+    pub action: PlannedAction,
+    /// why update doesn't start now
+    pub reason: Option<String>,
+    pub violation: Option<Violation>,
+    /// size of image payload (bytes)
+    pub size: Option<usize>,
+    /// group of updates started together within concurrency limits, 0 starts first
+    pub wave: Option<usize>
+}
+
+/// Dry run of firmware updates, nothing is sent to nodes nor stored
+#[derive(Debug,Clone,Serialize,PartialEq)]
+pub struct UpdatePlan {
+    /// updates may start now
+    pub window_open: bool,
+    /// when update window opens, None if open or not opening within a week
+    pub next_window: Option<NaiveDateTime>,
+    pub waves: usize,
+    /// nodes with something to do, in order updates would start
+    pub nodes: Vec<PlannedUpdate>
+}
+
+/// Slots of running downloads, per port
+#[derive(Default)]
+struct Wave {
+    total: usize,
+    ports: HashMap<i32, usize>
+}
+
+impl Wave {
+    fn has_slot(&self, port: i32, limits: &UpdateLimits) -> bool {
+        self.total < limits.max_concurrent.max(1)
+            && self.ports.get(&port).map_or(0, |n| *n) < limits.max_concurrent_per_port.max(1)
+    }
+
+    fn take(&mut self, port: i32) {
+        self.total += 1;
+        *self.ports.entry(port).or_default() += 1;
+    }
+}
+
+/// Wave of each download on given ports, in order they get slots
+fn assign_waves(ports: &[i32], first: Wave, limits: &UpdateLimits) -> Vec<usize> {
+    let mut waves: Vec<Wave> = vec![first];
+
+    ports.iter().map(|port| {
+        let wave = match waves.iter().position(|wave| wave.has_slot(*port, limits)) {
+            Some(wave) => wave,
+            None => {
+                waves.push(Wave::default());
+                waves.len() - 1
+            }
+        };
+        waves[wave].take(*port);
+        wave
+    }).collect()
+}
+
+/// Evaluate goals, firmware policy, compatibility of images, update windows and concurrency limits
+/// the way FWU process does, without sending anything. Goals which FWU process would change
+/// are planned as changed, e.g. update enforced by policy starts without approval. Downloads
+/// started but not yet confirmed occupy slots of first wave.
+pub fn plan_updates(db: &Database, fw_index: &FirmwareIndex, policy: &FirmwarePolicy, windows: &UpdateWindows, limits: &UpdateLimits, now: DateTime<Local>) -> Result<UpdatePlan, FwuError> {
+    let window_open = windows.permits(&now.naive_local());
+    let mut first = Wave::default();
+    let mut ready: Vec<(PlannedUpdate, i32)> = Vec::new();
+    let mut others: Vec<PlannedUpdate> = Vec::new();
+
+    for node in db.nodes.load_many(db.nodes.list()?.iter())?.iter().filter(|node| node.orphaned_at.is_none() && !node.pending) {
+        let device_status = match node.device_status {
+            Some(device_status) => device_status,
+            None => continue
+        };
+        let fw_state = FwState::from(device_status.fw_state);
+        if let FwState::Unknown(_) = fw_state {
+            continue;
+        }
+        let fwu_state = db.fwu_state.get(&node.address)?.unwrap_or_default();
+        let port = node.port.unwrap_or(ptnet::PORT_AUTO);
+        let running: FWVersion = device_status.fw_version.into();
+        let violation = policy.violation(&running);
+        let enforced = violation.is_some() && policy.enforce;
+        let latest = latest_allowed(fw_index, node, policy);
+
+        let entry = |target: Option<FWVersion>, action: PlannedAction, reason: Option<&str>| PlannedUpdate {
+            address: node.mac(),
+            name: node.model.as_ref().map(|model| model.name.clone()),
+            running: running,
+            target: target,
+            action: action,
+            reason: reason.map(String::from),
+            violation: violation,
+            size: None,
+            wave: None
+        };
+
+        if fwu_state.attempt.is_some() {
+            first.take(port);
+        }
+
+        let update_to = match (&fwu_state.goal, fw_state) {
+            (Goal::KeepCurrent, FwState::Idle) => continue,
+            (Goal::None, FwState::Idle) => match latest {
+                Some(latest_ver) if enforced && latest_ver != running => latest_ver,
+                Some(latest_ver) if latest_ver > running
+                    && fwu_state.rejected.as_ref().map_or(true, |rejected| latest_ver > *rejected) => {
+                    others.push(entry(Some(latest_ver), PlannedAction::AwaitApproval, None));
+                    continue;
+                },
+                _ => continue
+            },
+            (Goal::None | Goal::KeepCurrent, _) => {
+                others.push(entry(None, PlannedAction::Cancel, Some("update not wanted")));
+                continue;
+            },
+            (Goal::ApproveUpdateTo(ver), _) => match latest {
+                Some(latest_ver) if enforced => latest_ver,
+                _ if !policy.allows(ver) => {
+                    others.push(entry(Some(*ver), PlannedAction::Withdraw, Some("version forbidden by firmware policy")));
+                    continue;
+                },
+                latest_ver => {
+                    others.push(entry(Some(latest_ver.filter(|latest_ver| latest_ver > ver).unwrap_or(*ver)), PlannedAction::AwaitApproval, None));
+                    continue;
+                }
+            },
+            (Goal::UpdateTo(ver), FwState::Idle) if *ver != running => *ver,
+            (Goal::UpdateTo(ver), FwState::Idle) => {
+                if fwu_state.attempt.is_some() {
+                    others.push(entry(Some(*ver), PlannedAction::InProgress, Some("verifying update")));
+                }
+                continue;
+            },
+            (Goal::UpdateTo(ver), _) => {
+                others.push(entry(Some(*ver), PlannedAction::InProgress, None));
+                continue;
+            }
+        };
+
+        let image = match fw_index.get_firmwares_for(&device_status.hw_version.into()).and_then(|fws| fws.get(&update_to).cloned()) {
+            Some(image) => image,
+            None => {
+                others.push(entry(Some(update_to), PlannedAction::Blocked, Some("firmware not found in index")));
+                continue;
+            }
+        };
+        let mut planned = PlannedUpdate {
+            size: Some(image.payload().len()),
+            ..entry(Some(update_to), PlannedAction::Start, None)
+        };
+
+        if !policy.allows(&update_to) {
+            planned.action = PlannedAction::Blocked;
+            planned.reason = Some("version forbidden by firmware policy".to_string());
+        } else if fwu_state.attempt.is_some() {
+            planned.action = PlannedAction::InProgress;
+            planned.reason = Some("waiting for node to start".to_string());
+        } else if (now.timestamp() as u64) < fwu_state.retry_after {
+            planned.action = PlannedAction::Deferred;
+            planned.reason = Some(format!("backing off after {} failures", fwu_state.failures));
+        } else {
+            ready.push((planned, port));
+            continue;
+        }
+        others.push(planned);
+    }
+
+    // nodes violating policy get download slots first
+    ready.sort_by(|(a, _), (b, _)| (a.violation.is_none(), &a.address).cmp(&(b.violation.is_none(), &b.address)));
+    let ports: Vec<i32> = ready.iter().map(|(_, port)| *port).collect();
+    let mut nodes: Vec<PlannedUpdate> = Vec::new();
+
+    for ((mut planned, _), wave) in ready.into_iter().zip(assign_waves(&ports, first, limits)) {
+        planned.wave = Some(wave);
+        if !window_open {
+            planned.action = PlannedAction::Deferred;
+            planned.reason = Some("outside of update window".to_string());
+        } else if wave > 0 {
+            planned.action = PlannedAction::Queued;
+            planned.reason = Some("too many downloads running".to_string());
+        }
+        nodes.push(planned);
+    }
+
+    nodes.sort_by_key(|planned| planned.wave);
+    let planned_waves = nodes.iter().filter_map(|planned| planned.wave).max().map_or(0, |wave| wave + 1);
+    others.sort_by(|a, b| a.address.cmp(&b.address));
+    nodes.extend(others);
+
+    Ok(UpdatePlan {
+        window_open: window_open,
+        next_window: match window_open {
+            true => None,
+            false => windows.next_permitted(&now.naive_local())
+        },
+        waves: planned_waves,
+        nodes: nodes
+    })
+}
+
+/// Reconcile firmware update states with node table at startup
+///
+/// Drops states of deleted nodes, creates default states of new ones and resets goals
+/// whose version firmware index no longer has for hardware of node. Goals are left
+/// as they are without firmware index, or when hardware of node isn't known yet.
+pub fn resync_fwu_state(db: &Database, fw_index: Option<&FirmwareIndex>) -> Result<ResyncReport, FwuError> {
+    let mut report = ResyncReport::default();
+    let nodes = db.nodes.load_many(db.nodes.list()?.iter())?;
+    let states: HashMap<NodeAddress, FWUStateRecord> = db.fwu_state.list()?.into_iter().collect();
+
+    for address in states.keys().filter(|address| !nodes.iter().any(|node| node.address == **address)) {
+        info!("Dropping firmware update state of deleted node '{}'", NodeAddr(*address));
+        if db.fwu_state.remove(address)? {
+            report.removed += 1;
+        }
+    }
+
+    for node in nodes.iter() {
+        let goal = match states.get(&node.address) {
+            None => {
+                db.fwu_state.get_or_create_for(&node.address)?;
+                report.created += 1;
+                continue;
+            },
+            Some(state) => state.goal.clone()
+        };
+
+        let ver = match goal {
+            Goal::ApproveUpdateTo(ver) | Goal::UpdateTo(ver) => ver,
+            Goal::None | Goal::KeepCurrent => continue
+        };
+
+        let (fw_index, device_status) = match (fw_index, node.device_status) {
+            (Some(fw_index), Some(device_status)) => (fw_index, device_status),
+            _ => continue
+        };
+
+        let available = fw_index.get_firmwares_for(&device_status.hw_version.into()).map_or(false, |fws| fws.contains_key(&ver));
+        if !available {
+            warn!("Firmware {} targeted for node '{}' no longer in index, goal reset", ver, node.mac());
+            db.fwu_state.modify(&node.address, |opt_rec| opt_rec.map(|rec| FWUStateRecord { goal: Goal::None, ..rec }))?;
+            report.goals_reset += 1;
+        }
+    }
+
+    info!("Firmware update state resync: {} removed, {} created, {} goals reset", report.removed, report.created, report.goals_reset);
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::{testing::{make_redb, make_db}, UpdateMode};
+
+    use super::*;
+
+    #[test]
+    fn resync_states() {
+        let rdb = make_redb("fwu-resync-db.redb");
+        let db = make_db(&rdb);
+        let kept: NodeAddress = [0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0x01];
+        let added: NodeAddress = [0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0x02];
+        let deleted: NodeAddress = [0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0x03];
+
+        for address in [kept, added] {
+            db.nodes.update(&address, &NodeRecord { address: address, ..Default::default() }, UpdateMode::MustCreate).unwrap();
+        }
+        db.fwu_state.modify(&kept, |_| Some(FWUStateRecord { goal: Goal::KeepCurrent, ..Default::default() })).unwrap();
+        db.fwu_state.get_or_create_for(&deleted).unwrap();
+
+        let report = resync_fwu_state(&db, None).unwrap();
+
+        assert_eq!(ResyncReport { removed: 1, created: 1, goals_reset: 0 }, report);
+        assert_eq!(Goal::KeepCurrent, db.fwu_state.get(&kept).unwrap().unwrap().goal);
+        assert!(db.fwu_state.get(&added).unwrap().is_some());
+        assert!(db.fwu_state.get(&deleted).unwrap().is_none());
+    }
+
+    #[test]
+    fn waves_respect_limits() {
+        let limits = UpdateLimits { max_concurrent: 3, max_concurrent_per_port: 2, ..Default::default() };
+        let mut running = Wave::default();
+        running.take(1);
+
+        assert_eq!(vec![0, 1, 0, 1, 1, 2, 2], assign_waves(&[1, 1, 2, 3, 2, 2, 1], running, &limits));
+        assert_eq!(Vec::<usize>::new(), assign_waves(&[], Wave::default(), &limits));
+    }
+}
@@ -0,0 +1,238 @@
+use std::{collections::HashMap, time::Duration};
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use ptnet::{IE, PtNetPacket, ASDHConstruct, COT, DUIConstruct, BIT_PRM, FC_PRM_SEND_NOREPLY, PORT_AUTO};
+use serde::{Serialize, Deserialize};
+use tokio::{sync::{broadcast, Mutex}, time::interval, select};
+
+use crate::{database::{Database, NodeAddress, unix_now, NodeAddr, group_table::{GroupId, Member}}, client_connection::{ClientConnection, ClientConnectionSender, IOBMessage, Message, BROADCAST_ADDRESS}, error::PtnetMgrError, sol};
+
+use super::{PtNetProcess, Retrier, RetryPolicy, SetupPoint, ResponseMatcher, ResponseTimeout, build_command, setting_ie, response_key};
+
+/// How group commands are addressed
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct GroupAddressing {
+    /// group N is addressed by common address `ca_base + N`
+    pub ca_base: u8,
+    /// point scene number is written to to recall scene
+    pub scene_ioa: u32,
+    /// information element type of scene point
+    pub scene_ti: u8
+}
+
+impl Default for GroupAddressing {
+    fn default() -> Self {
+        Self {
+            ca_base: 0x80,
+            scene_ioa: 0x300,
+            scene_ti: 48
+        }
+    }
+}
+
+/// Sends group-addressed commands, devices don't confirm them
+pub struct GroupControl<'a> {
+    sender: &'a ClientConnectionSender<'a>,
+    addressing: GroupAddressing
+}
+
+impl<'a> GroupControl<'a> {
+    pub fn new(sender: &'a ClientConnectionSender<'a>, addressing: GroupAddressing) -> Self {
+        Self {
+            sender: sender,
+            addressing: addressing
+        }
+    }
+
+    /// Send command `ie` to point `ioa` of all members of group
+    pub async fn send_command(&self, group: GroupId, ioa: u32, ie: &IE) -> Result<(), PtnetMgrError> {
+        let mut buf = packet::buffer::Dynamic::new();
+        build_command(&mut buf, self.addressing.ca_base.wrapping_add(group), ioa, ie)?;
+
+        let msg = Message {
+            port: PORT_AUTO,
+            header: ptnet::Header {
+                C: (BIT_PRM | FC_PRM_SEND_NOREPLY) as u8,
+                address: BROADCAST_ADDRESS,
+            },
+            payload: buf.into()
+        };
+
+        debug!("Transmit command to group {} IOA {}", group, ioa);
+        self.sender.send_broadcast(&msg).await?;
+
+        Ok(())
+    }
+
+    pub async fn recall_scene(&self, group: GroupId, scene: u8) -> Result<(), PtnetMgrError> {
+        let point = SetupPoint { ioa: self.addressing.scene_ioa, ti: self.addressing.scene_ti };
+        let ie = setting_ie(&point, scene.into())?;
+
+        info!("Recall scene {} of group {}", scene, group);
+        self.send_command(group, point.ioa, &ie).await
+    }
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct GroupConfig {
+    /// pause between membership verification rounds (seconds)
+    pub period: u64,
+    /// SOL model root to take group membership from, membership is kept as is if not set
+    pub model_root: Option<String>,
+    /// membership in group N is read from point `membership.ioa + N`, set to 1 for members
+    pub membership: SetupPoint,
+    /// retrying of undelivered membership reads
+    pub retry: RetryPolicy,
+    /// waiting for membership read responses
+    pub response_timeout: ResponseTimeout
+}
+
+impl Default for GroupConfig {
+    fn default() -> Self {
+        Self {
+            period: 300,
+            model_root: None,
+            membership: SetupPoint { ioa: 0x200, ti: 48 },
+            retry: Default::default(),
+            response_timeout: Default::default()
+        }
+    }
+}
+
+/// Maintains group membership and verifies it on devices
+pub struct GroupProcess<'a> {
+    conf: GroupConfig,
+    db: &'a Database<'a>,
+    retrier: Retrier<'a>,
+    iob_rcvr: Mutex<broadcast::Receiver<IOBMessage>>,
+    /// membership reads awaiting response
+    responses: ResponseMatcher
+}
+
+impl<'a> GroupProcess<'a> {
+    pub fn new(conf: GroupConfig, db: &'a Database, conn: &'a ClientConnection, retrier: Retrier<'a>) -> Self {
+        GroupProcess {
+            conf: conf,
+            db: db,
+            retrier: retrier,
+            iob_rcvr: Mutex::new(conn.subscribe_iob()),
+            responses: ResponseMatcher::new()
+        }
+    }
+
+    /// Make group table follow membership in SOL model
+    fn sync_model(&self, model_root: &str) -> Result<(), PtnetMgrError> {
+        let mut model: HashMap<GroupId, Vec<NodeAddress>> = HashMap::new();
+        for (address, setup) in sol::loader::load_setups(model_root).map_err(PtnetMgrError::Model)? {
+            for group in setup.groups {
+                model.entry(group).or_default().push(address);
+            }
+        }
+
+        for rec in self.db.groups.list()? {
+            if !model.contains_key(&rec.id) {
+                info!("Group {} removed from model", rec.id);
+                self.db.groups.remove(rec.id)?;
+            }
+        }
+
+        for (id, addresses) in model {
+            self.db.groups.modify(id, |mut rec| {
+                let org_rec = rec.clone();
+
+                rec.members.retain(|m| addresses.contains(&m.address));
+                for address in addresses.iter().filter(|a| !org_rec.is_member(a)) {
+                    rec.members.push(Member { address: *address, verified: None, checked_at: None });
+                }
+
+                match rec == org_rec {
+                    true => None,
+                    false => Some(rec)
+                }
+            })?;
+        }
+
+        Ok(())
+    }
+
+    async fn verify_all(&self) -> Result<(), PtnetMgrError> {
+        let mut ticker = interval(Duration::from_secs(self.conf.period));
+
+        loop {
+            ticker.tick().await;
+
+            if let Some(model_root) = &self.conf.model_root {
+                self.sync_model(model_root)?;
+            }
+
+            for rec in self.db.groups.list()? {
+                for member in &rec.members {
+                    let verified = self.verify(rec.id, &member.address).await;
+
+                    if verified == Some(false) {
+                        warn!("Node {} is not member of group {}!", NodeAddr(member.address), rec.id);
+                    }
+
+                    self.db.groups.modify(rec.id, |mut group_rec| {
+                        let m = group_rec.members.iter_mut().find(|m| m.address == member.address)?;
+                        m.verified = verified;
+                        m.checked_at = Some(unix_now());
+                        Some(group_rec)
+                    })?;
+                }
+            }
+        }
+    }
+
+    /// Read membership point of node, None if node didn't answer
+    async fn verify(&self, group: GroupId, address: &NodeAddress) -> Option<bool> {
+        let ioa = self.conf.membership.ioa + u32::from(group);
+        let expected = setting_ie(&self.conf.membership, 1).ok()?;
+        let ca = self.db.common_addresses(address).control;
+
+        let mut buf = packet::buffer::Dynamic::new();
+        PtNetPacket::with_asdh(&ptnet::ASDH::with(ca, COT::REQ, false), &mut buf).ok()?
+            .begin_asdu(&ptnet::DUI::with_direct(ptnet::TC_C_RD, 1, false)).ok()?
+            .add_ioa(ioa).ok()?
+            .end_asdu().ok()?;
+
+        let _node_lock = self.retrier.conn().lock_node(address).await;
+
+        // register before transmitting, response may arrive before request result
+        let expectation = self.responses.expect((*address, ca, ioa), |rsp| rsp.iob.asdh.cot == COT::REQ);
+
+        let rsp = match self.retrier.send_prm(ptnet::FC::PrmSendNoreply, address, &buf).await {
+            Ok(_) => expectation.wait(self.conf.response_timeout.for_node(self.db, address)).await,
+            Err(_) => None
+        };
+
+        rsp.map(|rsp| rsp.iob.ie == expected)
+    }
+
+    /// Hand responses over to reads awaiting them
+    async fn route_responses(&self) -> Result<(), PtnetMgrError> {
+        let mut iob_rcvr = self.iob_rcvr.lock().await;
+
+        loop {
+            let rsp = iob_rcvr.recv().await?;
+            self.responses.dispatch(&response_key(&rsp), rsp);
+        }
+    }
+}
+
+#[async_trait]
+impl<'a> PtNetProcess for GroupProcess<'a> {
+    fn name(&self) -> &str {
+        "group"
+    }
+
+    async fn run(&mut self) -> Result<(), PtnetMgrError> {
+        select! {
+            result = self.verify_all() => result,
+            result = self.route_responses() => result
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::{collections::HashMap, time::Duration};
+
+use async_trait::async_trait;
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+use tokio::{sync::{broadcast, Mutex}, time::interval, select};
+
+use crate::{database::{Database, NodeAddress, unix_now, NodeAddr, node_table::{self, NodeRecord, Event::{NodeAdded, NodeModified, NodeRemoved}}, health_table::Health}, client_connection::{ClientConnection, IOBMessage}, error::{PtnetMgrError, DbError}};
+
+use super::PtNetProcess;
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// pause between re-evaluations of all nodes (seconds)
+    pub period: u64,
+    /// node missing this many consecutive scans is degraded
+    pub degraded_missed_scans: u32,
+    /// node missing this many consecutive scans is offline
+    pub offline_missed_scans: u32,
+    /// node silent for longer than this is degraded (seconds)
+    pub degraded_silence: u64,
+    /// node silent for longer than this is offline (seconds)
+    pub offline_silence: u64
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            period: 30,
+            degraded_missed_scans: 1,
+            offline_missed_scans: 5,
+            degraded_silence: 600,
+            offline_silence: 3600
+        }
+    }
+}
+
+impl HealthConfig {
+    /// Health of node heard last at `heard`, None if nothing is known about it yet
+    fn classify(&self, rec: &NodeRecord, heard: Option<u64>, now: u64) -> Option<Health> {
+        let silence = heard.map(|t| now.saturating_sub(t));
+
+        if rec.online == Some(false)
+            || rec.missed_scans >= self.offline_missed_scans
+            || silence.map_or(false, |s| s > self.offline_silence) {
+            return Some(Health::Offline);
+        }
+
+        if rec.missed_scans >= self.degraded_missed_scans
+            || silence.map_or(false, |s| s > self.degraded_silence) {
+            return Some(Health::Degraded);
+        }
+
+        match heard.is_some() || rec.online == Some(true) {
+            true => Some(Health::Online),
+            false => None
+        }
+    }
+}
+
+/// Classifies nodes as online / degraded / offline from link tests and scans recorded in database
+/// and from spontaneous traffic
+pub struct HealthProcess<'a> {
+    conf: HealthConfig,
+    db: &'a Database<'a>,
+    node_evt_rcvr: Mutex<broadcast::Receiver<node_table::Event>>,
+    spontaneous_rcvr: Mutex<broadcast::Receiver<IOBMessage>>,
+    /// unix time of last spontaneous IOB, by node
+    last_spontaneous: std::sync::Mutex<HashMap<NodeAddress, u64>>
+}
+
+impl<'a> HealthProcess<'a> {
+    pub fn new(conf: HealthConfig, db: &'a Database, conn: &'a ClientConnection) -> Self {
+        HealthProcess {
+            conf: conf,
+            db: db,
+            node_evt_rcvr: Mutex::new(db.nodes.events.subscribe()),
+            spontaneous_rcvr: Mutex::new(conn.subscribe_spontaneous()),
+            last_spontaneous: std::sync::Mutex::new(HashMap::new())
+        }
+    }
+
+    async fn track_spontaneous(&self) -> Result<(), PtnetMgrError> {
+        let mut spontaneous_rcvr = self.spontaneous_rcvr.lock().await;
+
+        loop {
+            match spontaneous_rcvr.recv().await {
+                Ok(iob_msg) => { self.last_spontaneous.lock().unwrap().insert(iob_msg.message.header.address, unix_now()); },
+                // nodes of missed IOBs are heard by scans and link tests too
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(err) => return Err(err.into())
+            }
+        }
+    }
+
+    /// Re-evaluate node whenever scans or link tests change its record
+    async fn track_nodes(&self) -> Result<(), PtnetMgrError> {
+        let mut node_evt_rcvr = self.node_evt_rcvr.lock().await;
+
+        loop {
+            match node_evt_rcvr.recv().await? {
+                NodeAdded(rec) | NodeModified(rec) => self.evaluate(&rec)?,
+                NodeRemoved(address) => {
+                    self.last_spontaneous.lock().unwrap().remove(&address);
+                    self.db.health.remove(&address)?;
+                }
+            }
+        }
+    }
+
+    async fn evaluate_all(&self) -> Result<(), PtnetMgrError> {
+        let mut ticker = interval(Duration::from_secs(self.conf.period));
+
+        loop {
+            ticker.tick().await;
+
+            for rec in self.db.nodes.load_many(self.db.nodes.list()?.iter())? {
+                self.evaluate(&rec)?;
+            }
+        }
+    }
+
+    fn evaluate(&self, rec: &NodeRecord) -> Result<(), DbError> {
+        // orphaned node is kept for its history only, it isn't counted in health
+        if rec.orphaned_at.is_some() {
+            return self.db.health.remove(&rec.address);
+        }
+
+        let now = unix_now();
+        // frames heard by link test, answered scans and spontaneous IOBs
+        let heard = rec.last_seen
+            .max(self.db.link_quality.get(&rec.address)?.and_then(|quality| quality.answered_at))
+            .max(self.last_spontaneous.lock().unwrap().get(&rec.address).copied());
+
+        let health = match self.conf.classify(rec, heard, now) {
+            Some(health) => health,
+            None => return Ok(())
+        };
+
+        if self.db.health.get(&rec.address)?.map(|h| h.health) != Some(health) {
+            let node = NodeAddr(rec.address).to_string();
+            match health {
+                Health::Online => info!(node = node.as_str(); "Node {} is online", node),
+                Health::Degraded => warn!(node = node.as_str(); "Node {} is degraded!", node),
+                Health::Offline => warn!(node = node.as_str(); "Node {} is offline!", node)
+            };
+        }
+
+        self.db.health.set(&rec.address, health, now)
+    }
+}
+
+#[async_trait]
+impl<'a> PtNetProcess for HealthProcess<'a> {
+    fn name(&self) -> &str {
+        "health"
+    }
+
+    async fn run(&mut self) -> Result<(), PtnetMgrError> {
+        select! {
+            result = self.track_spontaneous() => result,
+            result = self.track_nodes() => result,
+            result = self.evaluate_all() => result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify() {
+        let conf = HealthConfig::default();
+        let mut rec = NodeRecord::default();
+
+        assert_eq!(None, conf.classify(&rec, None, 1000));
+        assert_eq!(Some(Health::Online), conf.classify(&rec, Some(900), 1000));
+
+        rec.missed_scans = 1;
+        assert_eq!(Some(Health::Degraded), conf.classify(&rec, Some(900), 1000));
+
+        rec.missed_scans = 0;
+        assert_eq!(Some(Health::Degraded), conf.classify(&rec, Some(100), 1000));
+        assert_eq!(Some(Health::Offline), conf.classify(&rec, Some(100), 10000));
+
+        rec.online = Some(false);
+        assert_eq!(Some(Health::Offline), conf.classify(&rec, Some(900), 1000));
+    }
+}
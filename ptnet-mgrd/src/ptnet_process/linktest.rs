@@ -0,0 +1,157 @@
+use std::{collections::HashMap, time::Duration};
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use ptnet::{BIT_PRM, FC_PRM_LINK_TEST, PORT_AUTO};
+use serde::{Serialize, Deserialize};
+use tokio::{sync::{broadcast, Mutex}, time::{sleep, timeout}, select};
+
+use crate::{database::{Database, NodeAddress, unix_now, NodeAddr}, client_connection::{ClientConnection, ClientConnectionSender, Message, SendOutcome}, error::{PtnetMgrError, DbError, LinkError}};
+
+use super::PtNetProcess;
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct LinkTestConfig {
+    /// pause between link test rounds (seconds)
+    pub period: u64,
+    /// test only nodes silent for longer than this (seconds)
+    pub silence: u64,
+    /// pause between two link tests (milliseconds)
+    pub pace: u64
+}
+
+impl Default for LinkTestConfig {
+    fn default() -> Self {
+        Self {
+            period: 60,
+            silence: 300,
+            pace: 1000
+        }
+    }
+}
+
+/// Tracks node reachability, link-tests nodes which went silent
+pub struct LinkTestProcess<'a> {
+    conf: LinkTestConfig,
+    db: &'a Database<'a>,
+    sender: &'a ClientConnectionSender<'a>,
+    message_rcvr: Mutex<broadcast::Receiver<Message>>,
+    /// unix time of last frame received, by node
+    last_heard: std::sync::Mutex<HashMap<NodeAddress, u64>>
+}
+
+impl<'a> LinkTestProcess<'a> {
+    pub fn new(conf: LinkTestConfig, db: &'a Database, conn: &'a ClientConnection, sender: &'a ClientConnectionSender<'a>) -> Self {
+        LinkTestProcess {
+            conf: conf,
+            db: db,
+            sender: sender,
+            message_rcvr: Mutex::new(conn.subscribe()),
+            last_heard: std::sync::Mutex::new(HashMap::new())
+        }
+    }
+
+    async fn track_traffic(&self) -> Result<(), PtnetMgrError> {
+        let mut message_rcvr = self.message_rcvr.lock().await;
+
+        loop {
+            let msg = message_rcvr.recv().await?;
+            self.last_heard.lock().unwrap().insert(msg.header.address, unix_now());
+        }
+    }
+
+    async fn test_links(&self) -> Result<(), PtnetMgrError> {
+        loop {
+            sleep(Duration::from_secs(self.conf.period)).await;
+
+            let nodes = self.db.nodes.load_many(self.db.nodes.list()?.iter())?;
+            for address in nodes.iter().filter(|node| !node.pending).map(|node| node.address) {
+                let heard = self.last_heard.lock().unwrap().get(&address).copied();
+
+                match heard {
+                    Some(t) if unix_now().saturating_sub(t) <= self.conf.silence => {
+                        self.set_online(&address, true, Some(t))?;
+                    },
+                    _ => {
+                        let reachable = self.link_test(&address).await?;
+                        // unreachable node was last heard before it went silent
+                        let seen = match reachable {
+                            true => Some(unix_now()),
+                            false => heard
+                        };
+                        self.set_online(&address, reachable, seen)?;
+                        sleep(Duration::from_millis(self.conf.pace)).await;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn link_test(&self, address: &NodeAddress) -> Result<bool, LinkError> {
+        debug!("Link test {}", NodeAddr(*address));
+
+        let msg = Message {
+            port: PORT_AUTO,
+            header: ptnet::Header {
+                C: (BIT_PRM | FC_PRM_LINK_TEST) as u8,
+                address: *address,
+            },
+            payload: Vec::new()
+        };
+
+        let _node_lock = self.sender.conn().lock_node(address).await;
+        let rcvr = self.sender.send_message(&msg).await?;
+
+        Ok(match timeout(Duration::from_secs(10), rcvr).await {
+            Ok(Ok(result)) => result.outcome == SendOutcome::Delivered,
+            _ => false
+        })
+    }
+
+    /// Store reachability of node with time it was last heard, written when reachability changed
+    /// or stored time is older than link test period
+    fn set_online(&self, address: &NodeAddress, online: bool, seen: Option<u64>) -> Result<(), DbError> {
+        self.db.nodes.modify(address, |opt_rec| {
+            // node may have been removed meanwhile
+            let mut rec = opt_rec?;
+            let stale = match (seen, rec.last_seen) {
+                (Some(seen), Some(last_seen)) => seen.saturating_sub(last_seen) >= self.conf.period,
+                (Some(_), None) => true,
+                (None, _) => false
+            };
+
+            if rec.online == Some(online) && !stale {
+                return None;
+            }
+
+            if rec.online != Some(online) {
+                match online {
+                    true => info!("Node {} is reachable", rec.mac()),
+                    false => warn!("Node {} is not reachable!", rec.mac())
+                };
+                rec.online = Some(online);
+            }
+
+            if seen.is_some() {
+                rec.last_seen = seen;
+            }
+
+            Some(rec)
+        })
+    }
+}
+
+#[async_trait]
+impl<'a> PtNetProcess for LinkTestProcess<'a> {
+    fn name(&self) -> &str {
+        "linktest"
+    }
+
+    async fn run(&mut self) -> Result<(), PtnetMgrError> {
+        select! {
+            result = self.track_traffic() => result,
+            result = self.test_links() => result
+        }
+    }
+}
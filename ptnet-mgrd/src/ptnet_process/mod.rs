@@ -1,16 +1,70 @@
 mod nodescan;
 mod persist;
 mod fwu;
+mod campaign;
+mod update_limiter;
+mod command;
+mod linktest;
+mod supervisor;
+mod registry;
+mod health;
+mod retrier;
+mod commissioning;
+mod group;
+mod energy;
+mod api;
+mod response_matcher;
+mod port;
+mod router;
+mod alarm;
+mod derived;
+mod parameter;
+mod scheduler;
+mod binding;
+mod emtest;
+mod fragment;
+mod rawcapture;
+mod pipeline;
+mod bandwidth;
 
 pub use nodescan::*;
 pub use persist::*;
 pub use fwu::*;
+pub use campaign::*;
+pub use update_limiter::*;
+pub use command::*;
+pub use linktest::*;
+pub use supervisor::*;
+pub use registry::*;
+pub use health::*;
+pub use retrier::*;
+pub use commissioning::*;
+pub use group::*;
+pub use energy::*;
+pub use api::*;
+pub use response_matcher::*;
+pub use port::*;
+pub use router::*;
+pub use alarm::*;
+pub use derived::*;
+pub use parameter::*;
+pub use scheduler::*;
+pub use binding::*;
+pub use emtest::*;
+pub use fragment::*;
+pub use rawcapture::*;
+pub use pipeline::*;
+pub use bandwidth::*;
 
 use async_trait::async_trait;
 
+use crate::error::PtnetMgrError;
+
 #[async_trait]
 pub trait PtNetProcess {
-    async fn run(&mut self) -> Result<(), Box<dyn std::error::Error>>;
+    /// process name used in logs
+    fn name(&self) -> &str;
+    async fn run(&mut self) -> Result<(), PtnetMgrError>;
     //async fn run(&mut self) -> Result<(), Box<dyn std::error::Error>>;
     //fn start(&mut self) -> JoinHandle<()>;
     //fn start(&mut self) -> BoxFuture<'static, Result<(), Box<dyn std::error::Error>>>;
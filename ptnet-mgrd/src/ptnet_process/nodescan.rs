@@ -1,64 +1,156 @@
-use std::{time::Duration};
+use std::time::{Duration, Instant};
 use async_trait::async_trait;
 
-use log::{info, debug, warn};
-use tokio::{time::{interval, sleep}, sync::broadcast, select};
+use futures::{stream, StreamExt};
+use serde::{Serialize, Deserialize};
+use log::{info, debug, warn, error};
+use tokio::{time::sleep, sync::{broadcast, Mutex}, select};
 
-use crate::{database::{Database, node_table::NodeRecord}, client_connection::IOBMessage};
-use crate::client_connection::{ClientConnection, Message, ClientConnectionSender};
-use crate::ptnet_process::{PtNetProcess};
+use crate::{database::{Database, NodeAddress, NodeAddr, node_table::NodeRecord}, client_connection::IOBMessage, error::{PtnetMgrError, DbError, ProtocolError}};
+use ptnet::image_header::FWVersion;
+use crate::client_connection::{ClientConnection, Message};
+use crate::ptnet_process::{PtNetProcess, UpdateLimiter, Retrier, RetryPolicy, SendError, ResponseMatcher, ResponseTimeout, response_key};
 
 use ptnet::*;
 
+/// Runtime state key of node scan was last started for
+const CURSOR: &str = "nodescan.cursor";
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct NodeScanConfig {
+    /// pause after each node scan (seconds)
+    pub period: u64,
+    /// max. node scans in flight
+    pub window: usize,
+    /// retrying of undelivered scan requests
+    pub retry: RetryPolicy,
+    /// waiting for scan responses
+    pub response_timeout: ResponseTimeout
+}
+
+impl Default for NodeScanConfig {
+    fn default() -> Self {
+        Self {
+            period: 10,
+            window: 4,
+            retry: Default::default(),
+            response_timeout: Default::default()
+        }
+    }
+}
+
 pub struct NodeScanProcess<'a> {
     scan_period: Duration,
+    /// max. scans in flight
+    window: usize,
     db: &'a Database<'a>,
     conn: &'a ClientConnection,
-    sender: &'a ClientConnectionSender<'a>,
-    message_rcvr: broadcast::Receiver<IOBMessage>
+    /// transmits scan requests, retrying undelivered ones
+    retrier: Retrier<'a>,
+    /// running firmware downloads slow scanning down
+    limiter: &'a UpdateLimiter,
+    message_rcvr: Mutex<broadcast::Receiver<IOBMessage>>,
+    /// scans awaiting response
+    responses: ResponseMatcher,
+    response_timeout: ResponseTimeout
 }
 
 #[async_trait]
 impl<'a> PtNetProcess for NodeScanProcess<'a> {
-    async fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut interval = interval(self.scan_period);
-        loop {
-            let node_records = self.db.nodes.load_many(self.db.nodes.list()?.iter())?;
-            for node_record in node_records.iter() {
-                self.scan(node_record).await?;
-                interval.tick().await;
-                debug!("tick");
-            }
+    fn name(&self) -> &str {
+        "nodescan"
+    }
 
-            if node_records.is_empty() {
-                interval.tick().await;
-                debug!("tick");
-            }
+    async fn run(&mut self) -> Result<(), PtnetMgrError> {
+        select! {
+            result = self.scan_all() => result,
+            result = self.route_responses() => result
         }
     }
 }
 
 impl<'a> NodeScanProcess<'a> {
-    pub fn new(scan_period: Duration, db: &'a Database, conn: &'a ClientConnection, sender: &'a ClientConnectionSender<'a>) -> Self {
+    pub fn new(scan_period: Duration, window: usize, response_timeout: ResponseTimeout, db: &'a Database, conn: &'a ClientConnection, retrier: Retrier<'a>, limiter: &'a UpdateLimiter) -> Self {
         NodeScanProcess {
             scan_period: scan_period,
+            window: window,
             db: db,
             conn: conn,
-            sender: sender,
-            message_rcvr: conn.subscribe_iob()
+            retrier: retrier,
+            limiter: limiter,
+            message_rcvr: Mutex::new(conn.subscribe_iob()),
+            responses: ResponseMatcher::new(),
+            response_timeout: response_timeout
         }
     }
 
-    async fn scan(&mut self, node: &NodeRecord) -> Result<(), Box<dyn std::error::Error>> {
+    async fn scan_all(&self) -> Result<(), PtnetMgrError> {
+        // first round after restart continues where previous one stopped
+        let mut cursor: Option<NodeAddress> = self.db.runtime_state.get(CURSOR)?;
+
+        loop {
+            // nodes pending adoption are left alone until operator adopts them
+            let mut node_records: Vec<NodeRecord> = self.db.nodes.load_many(self.db.nodes.list()?.iter())?
+                .into_iter()
+                .filter(|node| !node.pending)
+                .collect();
+
+            // node table lists nodes in order of address
+            if let Some(address) = cursor.take() {
+                let next = node_records.iter().position(|node| node.address > address).unwrap_or(0);
+                debug!("Resuming node scan after {}", NodeAddr(address));
+                node_records.rotate_left(next);
+            }
+
+            // each of `window` scans in flight is followed by scan period pause
+            stream::iter(node_records.iter())
+                .for_each_concurrent(self.window.max(1), |node_record| async move {
+                    if let Err(err) = self.db.runtime_state.set(CURSOR, &node_record.address) {
+                        warn!("Node scan cursor not stored! ({})", err);
+                    }
+
+                    if let Err(err) = self.scan(node_record).await {
+                        error!("Error scanning node {}! ({})", node_record.mac(), err);
+                    }
+
+                    // leave link capacity to running firmware downloads
+                    sleep(self.limiter.scan_period(self.scan_period)).await;
+                    debug!("tick");
+                })
+                .await;
+
+            if node_records.is_empty() {
+                sleep(self.scan_period).await;
+                debug!("tick");
+            }
+        }
+    }
+
+    /// Hand responses over to scans awaiting them
+    async fn route_responses(&self) -> Result<(), PtnetMgrError> {
+        let mut message_rcvr = self.message_rcvr.lock().await;
+
+        loop {
+            let rsp = message_rcvr.recv().await?;
+
+            // frames of other nodes or requests don't disturb running scans
+            self.responses.dispatch(&response_key(&rsp), rsp);
+        }
+    }
+
+    async fn scan(&self, node: &NodeRecord) -> Result<(), PtnetMgrError> {
         info!("Scan node {}", node.mac());
 
+        let ca = self.db.common_addresses(&node.address).system;
         let msg;
         {
             let mut buf = packet::buffer::Dynamic::new();
-            PtNetPacket::with_asdh(&ptnet::ASDH::with(0x3E, COT::REQ, false), &mut buf)?
-                .begin_asdu(&ptnet::DUI::with_direct(ptnet::TC_C_RD, 1, false))?
-                .add_ioa(0)?
-                .end_asdu()?;
+            PtNetPacket::with_asdh(&ptnet::ASDH::with(ca, COT::REQ, false), &mut buf)
+                .and_then(|builder| builder.begin_asdu(&ptnet::DUI::with_direct(ptnet::TC_C_RD, 1, false)))
+                .and_then(|builder| builder.add_ioa(0))
+                .and_then(|builder| builder.end_asdu())
+                .map_err(ProtocolError::encoding)?;
 
             msg = Message {
                 port: PORT_AUTO,
@@ -71,51 +163,132 @@ impl<'a> NodeScanProcess<'a> {
 
         }
 
-        debug!("Transmit request");
-        let rcvr = self.sender.send_message(&msg).await?;
+        // request and response must not interleave with other exchanges with node
+        let _node_lock = self.conn.lock_node(&node.address).await;
 
-        debug!("Await request result");
-        let result = rcvr.await?;
-        debug!("result = {}", result);
+        // register before transmitting, response may arrive before request result
+        let expectation = self.responses.expect((node.address, ca, 1), NodeScanProcess::match_rsp_ti232);
 
-        let rsp: IOBMessage;
-        {
-            let timeout = sleep(Duration::from_secs(5));
-            tokio::pin!(timeout);
-            'rsp_loop: loop {
-                select! {
-                    msg = self.message_rcvr.recv() => {
-                        rsp = msg?;
-                        debug!("Some response arrived");
-
-                        if NodeScanProcess::match_rsp_ti232(&rsp, node) {
-                            break 'rsp_loop;
-                        }
-                        break;
-                    },
-                    _ = &mut timeout => {
-                        warn!("Response timed out!");
-                        return Ok(());
-                    }
-                }
+        // statistics from before this scan, it mustn't stretch its own timeout
+        let within = self.response_timeout.for_node(self.db, &node.address);
+        let sent_at = Instant::now();
+        let attempts = match self.retrier.send_counted(&msg).await {
+            Ok(attempts) => attempts,
+            Err(err) => {
+                let retries = match err {
+                    SendError::Exhausted(_) => self.retrier.max_attempts() - 1,
+                    _ => 0
+                };
+                self.record_scan(&node.address, false)?;
+                self.db.link_quality.record_scan(&node.address, false, retries, None)?;
+                return Err(err.into());
             }
-        }
+        };
 
-        info!("Matching response arrived");
+        let status = match expectation.wait(within).await {
+            Some(IOBMessage { iob: IOB { ie: IE::TI232(status), .. }, .. }) => {
+                info!("Matching response arrived");
+                Some(status)
+            },
+            _ => {
+                warn!("Response from {} timed out!", node.mac());
+                None
+            }
+        };
+
+        self.record_scan(&node.address, status.is_some())?;
+        let response_time = sent_at.elapsed().as_millis().min(u32::MAX as u128) as u32;
+        self.db.link_quality.record_scan(&node.address, status.is_some(), attempts - 1, Some(response_time))?;
+
+        // FWU compatibility checks need descriptor matching running firmware
+        if let Some(status) = status {
+            let fw_version: FWVersion = status.fw_version.into();
+            if node.device_descriptor.is_none() || node.descriptor_fw_version.as_ref() != Some(&fw_version) {
+                self.read_descriptor(node, ca, fw_version).await?;
+            }
+        }
 
         Ok(())
     }
 
-    fn match_rsp_ti232(rsp: &IOBMessage, node: &NodeRecord) -> bool {
-        let IOBMessage { iob, message } = rsp;
-        if message.header.address == node.address {
-            if iob.asdh == ASDH::with(0x3E, COT::REQ, false) && iob.ioa == 1 {
-                if let IE::TI232(_) = iob.ie {
-                    return true;
+    /// Request device descriptor, node lock must be held
+    async fn read_descriptor(&self, node: &NodeRecord, ca: u8, fw_version: FWVersion) -> Result<(), PtnetMgrError> {
+        debug!("Read descriptor of node {}", node.mac());
+
+        let mut buf = packet::buffer::Dynamic::new();
+        PtNetPacket::with_asdh(&ptnet::ASDH::with(ca, COT::REQ, false), &mut buf)
+            .and_then(|builder| builder.begin_asdu(&ptnet::DUI::with_direct(ptnet::TC_C_RD, 1, false)))
+            .and_then(|builder| builder.add_ioa(2))
+            .and_then(|builder| builder.end_asdu())
+            .map_err(ProtocolError::encoding)?;
+
+        let msg = Message {
+            port: PORT_AUTO,
+            header: ptnet::Header {
+                C: (BIT_PRM | FC_PRM_SEND_NOREPLY) as u8,
+                address: node.address,
+            },
+            payload: buf.into(),
+        };
+
+        let expectation = self.responses.expect((node.address, ca, 2), NodeScanProcess::match_rsp_ti233);
+        self.transmit(&msg).await?;
+
+        let descriptor = match expectation.wait(self.response_timeout.for_node(self.db, &node.address)).await {
+            Some(IOBMessage { iob: IOB { ie: IE::TI233(descriptor), .. }, .. }) => descriptor,
+            _ => {
+                // tried again on next scan
+                warn!("Descriptor of {} didn't arrive!", node.mac());
+                return Ok(());
+            }
+        };
+
+        Ok(self.db.nodes.modify(&node.address, |opt_rec| {
+            let mut rec = opt_rec?;
+            rec.device_descriptor = Some(descriptor);
+            rec.descriptor_fw_version = Some(fw_version);
+            Some(rec)
+        })?)
+    }
+
+    /// Count consecutive unanswered scans, written only if something changed
+    fn record_scan(&self, address: &NodeAddress, answered: bool) -> Result<(), DbError> {
+        self.db.nodes.modify(address, |opt_rec| {
+            let mut rec = opt_rec?;
+            let missed_scans = match answered {
+                true => 0,
+                false => rec.missed_scans.saturating_add(1)
+            };
+
+            match rec.missed_scans == missed_scans {
+                true => None,
+                false => {
+                    rec.missed_scans = missed_scans;
+                    Some(rec)
                 }
             }
+        })
+    }
+
+    async fn transmit(&self, msg: &Message) -> Result<(), SendError> {
+        debug!("Transmit request");
+        self.retrier.send_message(msg).await
+    }
+
+    // CA is part of response key already
+    fn match_rsp_ti232(rsp: &IOBMessage) -> bool {
+        let IOBMessage { iob, .. } = rsp;
+        if iob.asdh == ASDH::with(iob.asdh.ca, COT::REQ, false) && iob.ioa == 1 {
+            if let IE::TI232(_) = iob.ie {
+                return true;
+            }
         }
 
         false
     }
-}
\ No newline at end of file
+
+    fn match_rsp_ti233(rsp: &IOBMessage) -> bool {
+        let IOBMessage { iob, .. } = rsp;
+        iob.asdh == ASDH::with(iob.asdh.ca, COT::REQ, false) && iob.ioa == 2 && matches!(iob.ie, IE::TI233(_))
+    }
+}
@@ -0,0 +1,181 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{info, warn};
+use ptnet::{IE, PtNetPacket, ASDHConstruct, COT, DUIConstruct};
+use serde::{Serialize, Deserialize};
+use tokio::{sync::{broadcast, Mutex}, time::interval, select};
+
+use crate::{database::{Database, NodeAddress, unix_now, NodeAddr, health_table::Health, parameter_table::{Parameter, ParameterState}}, client_connection::{ClientConnection, IOBMessage}, error::{PtnetMgrError, DbError}};
+
+use super::{PtNetProcess, CommandEngine, CommandMode, Retrier, RetryPolicy, SetupPoint, ResponseMatcher, setting_ie, response_key};
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct ParameterConfig {
+    /// pause between rounds writing and verifying parameters (seconds)
+    pub period: u64,
+    /// write intended value again when device drifted from it
+    pub enforce: bool,
+    /// wait for read back response (milliseconds)
+    pub read_timeout: u64,
+    /// retrying of undelivered read backs
+    pub retry: RetryPolicy
+}
+
+impl Default for ParameterConfig {
+    fn default() -> Self {
+        Self {
+            period: 300,
+            enforce: true,
+            read_timeout: 5000,
+            retry: Default::default()
+        }
+    }
+}
+
+/// Writes intended device parameters through command engine, reads them back and flags drift
+pub struct ParameterProcess<'a> {
+    conf: ParameterConfig,
+    db: &'a Database<'a>,
+    commands: &'a CommandEngine<'a>,
+    retrier: Retrier<'a>,
+    iob_rcvr: Mutex<broadcast::Receiver<IOBMessage>>,
+    /// read backs awaiting response
+    responses: ResponseMatcher
+}
+
+impl<'a> ParameterProcess<'a> {
+    pub fn new(conf: ParameterConfig, db: &'a Database, conn: &'a ClientConnection, commands: &'a CommandEngine<'a>, retrier: Retrier<'a>) -> Self {
+        ParameterProcess {
+            conf: conf,
+            db: db,
+            commands: commands,
+            retrier: retrier,
+            iob_rcvr: Mutex::new(conn.subscribe_iob()),
+            responses: ResponseMatcher::new()
+        }
+    }
+
+    async fn enforce_all(&self) -> Result<(), PtnetMgrError> {
+        let mut ticker = interval(Duration::from_secs(self.conf.period));
+
+        loop {
+            ticker.tick().await;
+
+            for (address, rec) in self.db.parameters.list()? {
+                // offline node would only fail every parameter
+                if self.db.health.get(&address)?.map(|h| h.health) == Some(Health::Offline) {
+                    continue;
+                }
+
+                for (ioa, param) in rec.params {
+                    self.enforce(&address, ioa, &param).await?;
+                }
+            }
+        }
+    }
+
+    async fn enforce(&self, address: &NodeAddress, ioa: u32, param: &Parameter) -> Result<(), PtnetMgrError> {
+        let point = SetupPoint { ioa: ioa, ti: param.ti };
+        let expected = setting_ie(&point, param.intended)?;
+        let node = NodeAddr(*address).to_string();
+        let now = unix_now();
+
+        let write = match param.state {
+            ParameterState::Pending | ParameterState::Failed => true,
+            ParameterState::Drifted => self.conf.enforce,
+            ParameterState::Written | ParameterState::InSync => false
+        };
+
+        let mut written_at = param.written_at;
+        if write {
+            if let Err(err) = self.commands.send_command(address, ioa, expected.clone(), CommandMode::Direct).await {
+                warn!(node = node.as_str(), ioa = ioa; "Writing parameter IOA {} of node {} failed! ({})", ioa, node, err);
+                return Ok(self.store(address, ioa, param, |p| p.state = ParameterState::Failed)?);
+            }
+            written_at = Some(now);
+        }
+
+        let actual = self.read_back(address, ioa).await;
+        let state = match &actual {
+            Some(ie) if *ie == expected => ParameterState::InSync,
+            Some(_) => ParameterState::Drifted,
+            None if write => ParameterState::Written,
+            None => param.state
+        };
+
+        if state == ParameterState::Drifted && param.state != ParameterState::Drifted {
+            warn!(node = node.as_str(), ioa = ioa; "Parameter IOA {} of node {} drifted from intended value {}!", ioa, node, param.intended);
+        } else if state == ParameterState::InSync && param.state != ParameterState::InSync {
+            info!(node = node.as_str(), ioa = ioa; "Parameter IOA {} of node {} is in sync", ioa, node);
+        }
+
+        Ok(self.store(address, ioa, param, |p| {
+            p.state = state;
+            p.written_at = written_at;
+            if actual.is_some() {
+                p.actual = actual;
+                p.checked_at = Some(now);
+            }
+        })?)
+    }
+
+    /// Update parameter unless its intended value was changed meanwhile
+    fn store<T>(&self, address: &NodeAddress, ioa: u32, param: &Parameter, cb: T) -> Result<(), DbError>
+    where
+        T: FnOnce(&mut Parameter)
+    {
+        self.db.parameters.modify(address, |mut rec| {
+            let p = rec.params.get_mut(&ioa).filter(|p| p.ti == param.ti && p.intended == param.intended)?;
+            cb(p);
+            Some(rec)
+        })
+    }
+
+    /// Read point of node, None if node didn't answer
+    async fn read_back(&self, address: &NodeAddress, ioa: u32) -> Option<IE> {
+        let ca = self.db.common_addresses(address).control;
+        let mut buf = packet::buffer::Dynamic::new();
+        PtNetPacket::with_asdh(&ptnet::ASDH::with(ca, COT::REQ, false), &mut buf).ok()?
+            .begin_asdu(&ptnet::DUI::with_direct(ptnet::TC_C_RD, 1, false)).ok()?
+            .add_ioa(ioa).ok()?
+            .end_asdu().ok()?;
+
+        let _node_lock = self.retrier.conn().lock_node(address).await;
+
+        // register before transmitting, response may arrive before request result
+        let expectation = self.responses.expect((*address, ca, ioa), |rsp| rsp.iob.asdh.cot == COT::REQ);
+
+        let rsp = match self.retrier.send_prm(ptnet::FC::PrmSendNoreply, address, &buf).await {
+            Ok(_) => expectation.wait(Duration::from_millis(self.conf.read_timeout)).await,
+            Err(_) => None
+        };
+
+        rsp.map(|rsp| rsp.iob.ie)
+    }
+
+    /// Hand responses over to read backs awaiting them
+    async fn route_responses(&self) -> Result<(), PtnetMgrError> {
+        let mut iob_rcvr = self.iob_rcvr.lock().await;
+
+        loop {
+            let rsp = iob_rcvr.recv().await?;
+            self.responses.dispatch(&response_key(&rsp), rsp);
+        }
+    }
+}
+
+#[async_trait]
+impl<'a> PtNetProcess for ParameterProcess<'a> {
+    fn name(&self) -> &str {
+        "parameter"
+    }
+
+    async fn run(&mut self) -> Result<(), PtnetMgrError> {
+        select! {
+            result = self.enforce_all() => result,
+            result = self.route_responses() => result
+        }
+    }
+}
@@ -1,24 +1,135 @@
-use tokio::sync::broadcast;
-use async_trait::async_trait;
+use log::warn;
 use ptnet::{IE};
+use serde::{Serialize, Deserialize};
 
-use crate::{database::{Database}, client_connection::{ClientConnection, IOBMessage}};
+use crate::{database::{Database, NodeAddress, unix_now, NodeAddr, point_table::Sample}, client_connection::IOBMessage, error::{PtnetMgrError, DbError}};
 
-use super::PtNetProcess;
+use super::IobSink;
 
-pub struct PersistProcess<'a> {
-    db: &'a Database<'a>,
-    iob_rcvr: broadcast::Receiver<IOBMessage>
+/// Where value of a point is stored
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub enum Target {
+    /// node record device status, TI232 only
+    DeviceStatus,
+    /// node record device descriptor, TI233 only
+    DeviceDescriptor,
+    /// named measurement series
+    Series(String)
 }
 
-impl<'a> PersistProcess<'a> {
-    pub fn new(db: &'a Database, conn: &'a ClientConnection) -> Self {
-        PersistProcess {
-            db: db,
-            iob_rcvr: conn.subscribe_iob()
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub struct PointMapping {
+    /// system information CA of node if not set
+    #[serde(default)]
+    pub ca: Option<u8>,
+    pub ioa: u32,
+    pub ti: u8,
+    pub target: Target
+}
+
+impl PointMapping {
+    /// `system` resolves system information CA of node, only when mapping needs it
+    fn matches(&self, ca: u8, ioa: u32, ti: u8, system: &mut impl FnMut() -> u8) -> bool {
+        self.ioa == ioa && self.ti == ti && match self.ca {
+            Some(mapped) => mapped == ca,
+            None => system() == ca
+        }
+    }
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct PersistConfig {
+    /// points to persist, replaces built-in device status/descriptor mapping when set
+    pub mappings: Vec<PointMapping>,
+    /// store unmapped points as series named "CA/IOA/TI"
+    pub store_unknown: bool,
+    /// samples kept per series
+    pub max_samples: usize
+}
+
+impl Default for PersistConfig {
+    fn default() -> Self {
+        Self {
+            mappings: vec![
+                PointMapping { ca: None, ioa: 1, ti: 232, target: Target::DeviceStatus },
+                PointMapping { ca: None, ioa: 2, ti: 233, target: Target::DeviceDescriptor }
+            ],
+            store_unknown: false,
+            max_samples: 16
+        }
+    }
+}
+
+/// Stores mapped points in node records and measurement series
+pub struct PersistSink<'a> {
+    conf: PersistConfig,
+    db: &'a Database<'a>
+}
+
+impl<'a> PersistSink<'a> {
+    pub fn new(conf: PersistConfig, db: &'a Database) -> Self {
+        PersistSink {
+            conf: conf,
+            db: db
         }
     }
 
+    /// Where IOB is stored, None if it isn't
+    fn target(&self, iob_msg: &IOBMessage) -> Option<Target> {
+        let IOBMessage { iob, message: msg, .. } = iob_msg;
+        let ti = iob.ie.type_id();
+
+        // looked up once per point, most points have no mapping
+        let mut system_ca = None;
+        let mut system = || *system_ca.get_or_insert_with(|| self.db.common_addresses(&msg.header.address).system);
+
+        match self.conf.mappings.iter().find(|m| m.matches(iob.asdh.ca, iob.ioa, ti, &mut system)) {
+            Some(mapping) => Some(mapping.target.clone()),
+            None if self.conf.store_unknown => Some(Target::Series(format!("{:02X}/{}/{}", iob.asdh.ca, iob.ioa, ti))),
+            None => None
+        }
+    }
+
+    /// Store IOB received at unix time `at` in the past into its series, returns false if it has none.
+    /// Node records aren't touched, they hold newer state already.
+    pub fn backfill(&self, iob_msg: &IOBMessage, at: u64) -> Result<bool, DbError> {
+        match self.target(iob_msg) {
+            Some(Target::Series(series)) => {
+                self.db.points.record(&iob_msg.message.header.address, &series, Sample { at: at, value: iob_msg.iob.ie.clone() }, self.conf.max_samples)?;
+                Ok(true)
+            },
+            _ => Ok(false)
+        }
+    }
+
+    fn persist(&self, address: &NodeAddress, port: i32, target: &Target, ie: IE) -> Result<(), DbError> {
+        match (target, ie) {
+            (Target::DeviceStatus, IE::TI232(ti232)) => {
+                self.db.nodes.modify(address, |opt_rec| {
+                    let mut rec = opt_rec.unwrap_or_else(|| self.db.discovered_node(address));
+                    rec.device_status = Some(ti232);
+                    rec.port = Some(port);
+                    Some(rec)
+                })?;
+            },
+            (Target::DeviceDescriptor, IE::TI233(ti233)) => {
+                self.db.nodes.modify(address, |opt_rec| {
+                    let mut rec = opt_rec.unwrap_or_else(|| self.db.discovered_node(address));
+                    rec.device_descriptor = Some(ti233);
+                    rec.port = Some(port);
+                    Some(rec)
+                })?;
+            },
+            (Target::Series(series), ie) => {
+                self.db.points.record(address, series, Sample { at: unix_now(), value: ie }, self.conf.max_samples)?;
+            },
+            (target, _) => warn!("Can't store point from {} to {:?}, wrong type", NodeAddr(*address), target)
+        };
+
+        Ok(())
+    }
+
 /*
     fn persist_prm(&self, msg: &Message) -> Result<(), E> {
         let scanner = Scanner::new(&msg.payload[..]);
@@ -27,31 +138,17 @@ impl<'a> PersistProcess<'a> {
 */
 }
 
-#[async_trait]
-impl<'a> PtNetProcess for PersistProcess<'a> {
-    async fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        loop {
-            let IOBMessage { iob, message: msg } = self.iob_rcvr.recv().await?;
-
-            if iob.asdh.ca == 0x3E {
-                match iob.ioa {
-                    1 => if let IE::TI232(ti232) = iob.ie {
-                            self.db.nodes.modify(&msg.header.address, |opt_rec| {
-                                let mut rec = opt_rec.unwrap_or_default();
-                                rec.device_status = Some(ti232);
-                                Some(rec)
-                            })?;
-                        },
-                    2 => if let IE::TI233(ti233) = iob.ie {
-                            self.db.nodes.modify(&msg.header.address, |opt_rec| {
-                                let mut rec = opt_rec.unwrap_or_default();
-                                rec.device_descriptor = Some(ti233);
-                                Some(rec)
-                            })?;
-                        },
-                    _ => ()
-                }
-            }
+impl<'a> IobSink for PersistSink<'a> {
+    fn name(&self) -> &str {
+        "persist"
+    }
+
+    fn accept(&mut self, iob_msg: &IOBMessage) -> Result<(), PtnetMgrError> {
+        if let Some(target) = self.target(iob_msg) {
+            let msg = &iob_msg.message;
+            self.persist(&msg.header.address, msg.port, &target, iob_msg.iob.ie.clone())?;
         }
+
+        Ok(())
     }
 }
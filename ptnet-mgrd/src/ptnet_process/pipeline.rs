@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+
+use async_trait::async_trait;
+use log::{error, warn};
+use tokio::{sync::broadcast, select};
+
+use crate::{client_connection::{ClientConnection, IOBMessage, IOBClass}, error::PtnetMgrError};
+
+use super::PtNetProcess;
+
+/// Consumer of IOBs decoded by dispatcher, e.g. persistence or exporter
+///
+/// Sinks are called one after another for each IOB, in order IOBs were received,
+/// so sink shouldn't block for long.
+pub trait IobSink: Send {
+    /// sink name used in logs, same as its configuration section
+    fn name(&self) -> &str;
+    fn accept(&mut self, iob: &IOBMessage) -> Result<(), PtnetMgrError>;
+}
+
+/// Hands IOBs of connection to all sinks, sinks share its subscription
+pub struct PipelineProcess<'a> {
+    sinks: Vec<Box<dyn IobSink + 'a>>,
+    iob_rcvr: broadcast::Receiver<IOBMessage>,
+    /// spontaneous IOBs, passed ahead of bulk traffic
+    spontaneous_rcvr: broadcast::Receiver<IOBMessage>,
+    /// sequence numbers of IOBs passed from spontaneous channel, their bulk copies are skipped
+    passed: VecDeque<u64>
+}
+
+impl<'a> PipelineProcess<'a> {
+    pub fn new(sinks: Vec<Box<dyn IobSink + 'a>>, conn: &'a ClientConnection) -> Self {
+        PipelineProcess {
+            sinks: sinks,
+            iob_rcvr: conn.subscribe_iob(),
+            spontaneous_rcvr: conn.subscribe_spontaneous(),
+            passed: VecDeque::new()
+        }
+    }
+}
+
+#[async_trait]
+impl<'a> PtNetProcess for PipelineProcess<'a> {
+    fn name(&self) -> &str {
+        "pipeline"
+    }
+
+    async fn run(&mut self) -> Result<(), PtnetMgrError> {
+        loop {
+            let iob_msg = select! {
+                biased;
+                result = self.spontaneous_rcvr.recv() => match result {
+                    Ok(iob_msg) => {
+                        self.passed.push_back(iob_msg.seq);
+                        iob_msg
+                    },
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        // missed IOBs are passed from bulk channel
+                        warn!("Pipeline missed {} spontaneous IOBs, taking them from bulk traffic", n);
+                        continue;
+                    },
+                    Err(err) => return Err(err.into())
+                },
+                result = self.iob_rcvr.recv() => match result? {
+                    iob_msg if iob_msg.class == IOBClass::Spontaneous => {
+                        // copies arrive in order, older passed ones were lost on bulk channel
+                        while self.passed.front().map_or(false, |seq| *seq < iob_msg.seq) {
+                            self.passed.pop_front();
+                        }
+                        if self.passed.front() == Some(&iob_msg.seq) {
+                            self.passed.pop_front();
+                            continue;
+                        }
+                        iob_msg
+                    },
+                    iob_msg => iob_msg
+                }
+            };
+
+            for sink in self.sinks.iter_mut() {
+                if let Err(err) = sink.accept(&iob_msg) {
+                    error!("Sink {} failed! ({})", sink.name(), err);
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
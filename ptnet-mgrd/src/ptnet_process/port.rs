@@ -0,0 +1,170 @@
+use std::{collections::HashMap, time::Duration};
+
+use async_trait::async_trait;
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+use tokio::{sync::{broadcast, Mutex}, time::interval, select};
+
+use crate::{database::{Database, NodeAddress, unix_now, port_table::{PortRecord, PortStatus}}, client_connection::{ClientConnection, ClientConnectionSender, Message}, error::{PtnetMgrError, DbError}};
+
+use super::{PtNetProcess, Router};
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct PortConfig {
+    /// pause between writes of port and node records (seconds)
+    pub period: u64,
+    /// port silent for longer than this is reported silent (seconds)
+    pub silence: u64
+}
+
+impl Default for PortConfig {
+    fn default() -> Self {
+        Self {
+            period: 30,
+            silence: 300
+        }
+    }
+}
+
+/// Discovers ptlink ports from traffic, tracks their status and port each node is heard on
+///
+/// ptlink protocol has no request listing ports, port appears once something is heard on it.
+pub struct PortProcess<'a> {
+    conf: PortConfig,
+    db: &'a Database<'a>,
+    /// learns ports nodes are heard on
+    router: &'a Router<'a>,
+    /// knows group addresses, which aren't learned as node ports
+    sender: &'a ClientConnectionSender<'a>,
+    message_rcvr: Mutex<broadcast::Receiver<Message>>,
+    /// unix time of last frame received, by port
+    last_heard: std::sync::Mutex<HashMap<i32, u64>>,
+    /// port node was last heard on
+    node_ports: std::sync::Mutex<HashMap<NodeAddress, i32>>
+}
+
+impl<'a> PortProcess<'a> {
+    pub fn new(conf: PortConfig, db: &'a Database, conn: &'a ClientConnection, sender: &'a ClientConnectionSender<'a>, router: &'a Router<'a>) -> Self {
+        PortProcess {
+            conf: conf,
+            db: db,
+            router: router,
+            sender: sender,
+            message_rcvr: Mutex::new(conn.subscribe()),
+            last_heard: std::sync::Mutex::new(HashMap::new()),
+            node_ports: std::sync::Mutex::new(HashMap::new())
+        }
+    }
+
+    async fn track_traffic(&self) -> Result<(), PtnetMgrError> {
+        let mut message_rcvr = self.message_rcvr.lock().await;
+
+        loop {
+            let msg = message_rcvr.recv().await?;
+
+            self.last_heard.lock().unwrap().insert(msg.port, unix_now());
+            if !self.sender.is_group_address(&msg.header.address) {
+                self.node_ports.lock().unwrap().insert(msg.header.address, msg.port);
+            }
+        }
+    }
+
+    /// Traffic is accounted in memory, records are written once per period
+    async fn store_all(&self) -> Result<(), PtnetMgrError> {
+        let mut ticker = interval(Duration::from_secs(self.conf.period));
+
+        loop {
+            ticker.tick().await;
+
+            let node_ports = self.node_ports.lock().unwrap().clone();
+            for (address, port) in &node_ports {
+                self.store_node_port(address, *port)?;
+                self.router.heard(address, *port);
+            }
+
+            let mut ports: Vec<i32> = self.db.ports.list()?.iter().map(|rec| rec.port).collect();
+            ports.extend(self.last_heard.lock().unwrap().keys());
+            ports.sort();
+            ports.dedup();
+
+            for port in ports {
+                let nodes = node_ports.values().filter(|p| **p == port).count();
+                self.store_port(port, nodes)?;
+            }
+        }
+    }
+
+    /// Record port node was heard on, written only if it changed
+    fn store_node_port(&self, address: &NodeAddress, port: i32) -> Result<(), DbError> {
+        self.db.nodes.modify(address, |opt_rec| {
+            // traffic of nodes not in database isn't recorded
+            let mut rec = opt_rec?;
+
+            match rec.port == Some(port) {
+                true => None,
+                false => {
+                    rec.port = Some(port);
+                    Some(rec)
+                }
+            }
+        })
+    }
+
+    /// Store status of port, written only if something changed
+    fn store_port(&self, port: i32, nodes: usize) -> Result<(), DbError> {
+        let now = unix_now();
+        let heard = self.last_heard.lock().unwrap().get(&port).copied();
+
+        self.db.ports.modify(port, |opt_rec| {
+            let org_rec = opt_rec.clone();
+            let mut rec = opt_rec.unwrap_or(PortRecord {
+                port: port,
+                status: PortStatus::Up,
+                first_seen: now,
+                last_heard: now,
+                nodes: 0
+            });
+
+            if let Some(heard) = heard {
+                rec.last_heard = rec.last_heard.max(heard);
+            }
+            // nodes moved over to other port meanwhile are not counted
+            rec.nodes = nodes;
+
+            let status = match now.saturating_sub(rec.last_heard) > self.conf.silence {
+                true => PortStatus::Silent,
+                false => PortStatus::Up
+            };
+
+            if org_rec.is_none() {
+                info!("Discovered port {}", port);
+            } else if rec.status != status {
+                match status {
+                    PortStatus::Up => info!("Port {} is up", port),
+                    PortStatus::Silent => warn!("Port {} went silent!", port)
+                };
+            }
+            rec.status = status;
+
+            match Some(&rec) == org_rec.as_ref() {
+                true => None,
+                false => Some(rec)
+            }
+        })
+    }
+}
+
+#[async_trait]
+impl<'a> PtNetProcess for PortProcess<'a> {
+    fn name(&self) -> &str {
+        "port"
+    }
+
+    async fn run(&mut self) -> Result<(), PtnetMgrError> {
+        select! {
+            result = self.track_traffic() => result,
+            result = self.store_all() => result
+        }
+    }
+}
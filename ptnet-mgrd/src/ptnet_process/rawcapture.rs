@@ -0,0 +1,134 @@
+use async_trait::async_trait;
+use log::{debug, warn};
+use ptnet::{FC, HeaderBits, Scanner};
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::{database::{Database, NodeAddress, unix_now, NodeAddr, raw_frame_table::RawFrame}, client_connection::{ClientConnection, Message}, error::PtnetMgrError};
+
+use super::PtNetProcess;
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct RawCaptureConfig {
+    /// nodes frames are captured from
+    pub nodes: Vec<NodeAddress>,
+    /// capture from all nodes, e.g. while commissioning new device types
+    pub all: bool,
+    /// frames kept per node
+    pub max_frames: usize
+}
+
+impl Default for RawCaptureConfig {
+    fn default() -> Self {
+        Self {
+            nodes: Vec::new(),
+            all: false,
+            max_frames: 16
+        }
+    }
+}
+
+impl RawCaptureConfig {
+    pub fn captures(&self, address: &NodeAddress) -> bool {
+        self.all || self.nodes.contains(address)
+    }
+}
+
+/// Why payload of PRM message doesn't decode, None if it does or carries no IOBs
+pub fn undecodable(msg: &Message) -> Option<String> {
+    if !msg.header.prm() || !matches!(msg.header.fc(), Some(FC::PrmSendConfirm) | Some(FC::PrmSendNoreply)) {
+        return None;
+    }
+
+    Scanner::new(&msg.payload[..]).into_iob_iter()
+        .find_map(|item| item.err())
+        .map(|err| format!("{:?}", err))
+}
+
+/// Stores frames decoder doesn't understand, so support can extend it for new device types
+pub struct RawCaptureProcess<'a> {
+    conf: RawCaptureConfig,
+    db: &'a Database<'a>,
+    msg_rcvr: broadcast::Receiver<Message>
+}
+
+impl<'a> RawCaptureProcess<'a> {
+    pub fn new(conf: RawCaptureConfig, db: &'a Database, conn: &'a ClientConnection) -> Self {
+        RawCaptureProcess {
+            conf: conf,
+            db: db,
+            msg_rcvr: conn.subscribe()
+        }
+    }
+}
+
+#[async_trait]
+impl<'a> PtNetProcess for RawCaptureProcess<'a> {
+    fn name(&self) -> &str {
+        "rawcapture"
+    }
+
+    async fn run(&mut self) -> Result<(), PtnetMgrError> {
+        loop {
+            let msg = match self.msg_rcvr.recv().await {
+                Ok(msg) => msg,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("Raw capture skipped {} messages", n);
+                    continue;
+                },
+                Err(err) => return Err(err.into())
+            };
+
+            if !self.conf.captures(&msg.header.address) {
+                continue;
+            }
+
+            if let Some(reason) = undecodable(&msg) {
+                let node = NodeAddr(msg.header.address).to_string();
+                debug!(node = node.as_str(); "Capturing undecodable frame of {} ({})", node, reason);
+
+                let frame = RawFrame {
+                    at: unix_now(),
+                    port: msg.port,
+                    control: msg.header.C,
+                    payload: msg.payload,
+                    reason: reason
+                };
+                self.db.raw_frames.record(&msg.header.address, frame, self.conf.max_frames)?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ptnet::{PtNetPacket, ASDHConstruct, COT, DUIConstruct, BIT_PRM, FC_PRM_SEND_NOREPLY, PORT_AUTO};
+
+    use super::*;
+
+    fn message(payload: Vec<u8>) -> Message {
+        Message {
+            port: PORT_AUTO,
+            header: ptnet::Header { C: (BIT_PRM | FC_PRM_SEND_NOREPLY) as u8, address: [0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0xEF] },
+            payload: payload
+        }
+    }
+
+    #[test]
+    fn detects_undecodable() {
+        let mut buf = packet::buffer::Dynamic::new();
+        PtNetPacket::with_asdh(&ptnet::ASDH::with(0x3E, COT::REQ, false), &mut buf).unwrap()
+            .begin_asdu(&ptnet::DUI::with_direct(ptnet::TC_C_RD, 1, false)).unwrap()
+            .add_ioa(0).unwrap()
+            .end_asdu().unwrap();
+        let valid: Vec<u8> = buf.into();
+
+        assert_eq!(None, undecodable(&message(valid.clone())));
+        assert_eq!(None, undecodable(&message(Vec::new())));
+
+        let mut truncated = valid.clone();
+        truncated.truncate(valid.len() - 1);
+        assert!(undecodable(&message(truncated)).is_some());
+    }
+}
@@ -0,0 +1,453 @@
+use std::{collections::HashMap, io, time::Duration};
+
+use tokio::sync::{mpsc, Mutex};
+
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value};
+
+use crate::{config, database::Database, client_connection::{ClientConnection, ClientConnectionSender}, fw_index::FirmwareIndex, fw_policy::FirmwarePolicy, time_window::UpdateWindows, site::LabelFilter};
+
+use super::{PtNetProcess, UpdateLimiter, CommandEngine, Retrier, Router, NodeScanProcess, NodeScanConfig, PersistSink, PersistConfig, IobSink, PipelineProcess, CommandProcess, LinkTestProcess, LinkTestConfig, FWUProcess, FWUConfig, CampaignProcess, CampaignConfig, HealthProcess, HealthConfig, CommissioningProcess, CommissioningConfig, GroupControl, GroupProcess, GroupConfig, EnergyProcess, EnergyConfig, PortProcess, PortConfig, AlarmProcess, AlarmConfig, DerivedProcess, DerivedConfig, ParameterProcess, ParameterConfig, ApiProcess, ApiConfig, ApiRequest, SchedulerProcess, SchedulerConfig, BindingProcess, BindingConfig, EmTestProcess, EmTestConfig, RawCaptureProcess, RawCaptureConfig};
+
+/// Per-process configuration section
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub struct ProcessSection {
+    #[serde(default = "enabled_default")]
+    pub enabled: bool,
+    /// process specific settings
+    #[serde(flatten)]
+    pub params: Map<String, Value>
+}
+
+fn enabled_default() -> bool {
+    true
+}
+
+impl Default for ProcessSection {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            params: Map::new()
+        }
+    }
+}
+
+/// Per-connection state processes are built from
+pub struct ProcessContext<'a> {
+    pub db: &'a Database<'a>,
+    pub conn: &'a ClientConnection,
+    pub sender: &'a ClientConnectionSender<'a>,
+    pub limiter: &'a UpdateLimiter,
+    pub router: &'a Router<'a>,
+    pub commands: &'a CommandEngine<'a>,
+    pub groups: &'a GroupControl<'a>,
+    /// firmware updates are disabled if not set
+    pub fw_index: Option<&'a FirmwareIndex>,
+    pub windows: &'a UpdateWindows,
+    pub fw_policy: &'a FirmwarePolicy,
+    /// requests of HTTP API, None if API is disabled
+    pub api_requests: Option<&'a Mutex<mpsc::Receiver<ApiRequest>>>
+}
+
+/// Builds process from its config section, returns None if process can't run in given context
+pub type ProcessFactory = for<'a> fn(&'a ProcessContext<'a>, Value) -> Result<Option<Box<dyn PtNetProcess + 'a>>, Box<dyn std::error::Error>>;
+
+/// Builds IOB sink from its config section, returns None if sink can't run in given context
+pub type SinkFactory = for<'a> fn(&'a ProcessContext<'a>, Value) -> Result<Option<Box<dyn IobSink + 'a>>, Box<dyn std::error::Error>>;
+
+/// Process feeding all sinks
+const PIPELINE: &str = "pipeline";
+
+/// Named processes and IOB sinks which can be enabled and tuned from configuration
+pub struct ProcessRegistry {
+    factories: Vec<(&'static str, ProcessFactory)>,
+    /// run by pipeline process in registration order
+    sinks: Vec<(&'static str, SinkFactory)>
+}
+
+impl ProcessRegistry {
+    pub fn new() -> Self {
+        Self {
+            factories: Vec::new(),
+            sinks: Vec::new()
+        }
+    }
+
+    /// Registry with all processes of ptnet-mgrd
+    pub fn builtin() -> Self {
+        let mut registry = Self::new();
+
+        registry.register("nodescan", build_nodescan);
+        registry.register("command", build_command);
+        registry.register("linktest", build_linktest);
+        registry.register("fwu", build_fwu);
+        registry.register("campaign", build_campaign);
+        registry.register("health", build_health);
+        registry.register("commissioning", build_commissioning);
+        registry.register("group", build_group);
+        registry.register("energy", build_energy);
+        registry.register("port", build_port);
+        registry.register("alarm", build_alarm);
+        registry.register("derived", build_derived);
+        registry.register("parameter", build_parameter);
+        registry.register("api", build_api);
+        registry.register("scheduler", build_scheduler);
+        registry.register("binding", build_binding);
+        registry.register("emtest", build_emtest);
+        registry.register("rawcapture", build_rawcapture);
+
+        // sinks of plugins compiled in by feature are registered here too, after built-in ones
+        registry.register_sink("persist", build_persist);
+
+        registry
+    }
+
+    pub fn register(&mut self, name: &'static str, factory: ProcessFactory) {
+        self.factories.retain(|(n, _)| *n != name);
+        self.factories.push((name, factory));
+    }
+
+    pub fn register_sink(&mut self, name: &'static str, factory: SinkFactory) {
+        self.sinks.retain(|(n, _)| *n != name);
+        self.sinks.push((name, factory));
+    }
+
+    /// Process or sink of this name can be configured
+    pub fn knows(&self, name: &str) -> bool {
+        self.sections().any(|n| n == name)
+    }
+
+    /// Names of configuration sections of processes and sinks
+    pub fn sections(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.factories.iter().map(|(name, _)| *name).chain(self.sinks.iter().map(|(name, _)| *name))
+    }
+
+    /// Names of processes run, sinks run inside pipeline process
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        let pipeline = match self.sinks.is_empty() {
+            true => None,
+            false => Some(PIPELINE)
+        };
+
+        self.factories.iter().map(|(name, _)| *name).chain(pipeline)
+    }
+
+    /// Build all enabled processes, processes and sinks without config section run with defaults.
+    /// Enabled sinks run in single pipeline process.
+    pub fn build<'a>(&self, ctx: &'a ProcessContext<'a>, sections: &HashMap<String, ProcessSection>) -> Result<Vec<Box<dyn PtNetProcess + 'a>>, Box<dyn std::error::Error>> {
+        // typo in config would silently run process with defaults
+        if let Some(name) = sections.keys().find(|name| !self.knows(name)) {
+            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown process '{}' in configuration", name))));
+        }
+
+        let mut processes = Vec::new();
+
+        for (name, factory) in &self.factories {
+            let section = sections.get(*name).cloned().unwrap_or_default();
+
+            if !section.enabled {
+                info!("Process {} disabled", name);
+                continue;
+            }
+
+            match factory(ctx, Value::Object(section.params))? {
+                Some(process) => processes.push(process),
+                None => warn!("Process {} can't run in this configuration", name)
+            }
+        }
+
+        let mut sinks = Vec::new();
+
+        for (name, factory) in &self.sinks {
+            let section = sections.get(*name).cloned().unwrap_or_default();
+
+            if !section.enabled {
+                info!("Sink {} disabled", name);
+                continue;
+            }
+
+            match factory(ctx, Value::Object(section.params))? {
+                Some(sink) => sinks.push(sink),
+                None => warn!("Sink {} can't run in this configuration", name)
+            }
+        }
+
+        if !sinks.is_empty() {
+            processes.push(Box::new(PipelineProcess::new(sinks, ctx.conn)));
+        }
+
+        Ok(processes)
+    }
+}
+
+fn build_nodescan<'a>(ctx: &'a ProcessContext<'a>, params: Value) -> Result<Option<Box<dyn PtNetProcess + 'a>>, Box<dyn std::error::Error>> {
+    let conf: NodeScanConfig = config::params(params)?;
+
+    Ok(Some(Box::new(NodeScanProcess::new(
+        Duration::from_secs(conf.period),
+        conf.window,
+        conf.response_timeout,
+        ctx.db,
+        ctx.conn,
+        Retrier::new(ctx.sender, ctx.router, conf.retry).metered(&ctx.db.bandwidth, "nodescan"),
+        ctx.limiter
+    ))))
+}
+
+fn build_persist<'a>(ctx: &'a ProcessContext<'a>, params: Value) -> Result<Option<Box<dyn IobSink + 'a>>, Box<dyn std::error::Error>> {
+    let conf: PersistConfig = config::params(params)?;
+
+    Ok(Some(Box::new(PersistSink::new(
+        conf,
+        ctx.db
+    ))))
+}
+
+fn build_command<'a>(ctx: &'a ProcessContext<'a>, _params: Value) -> Result<Option<Box<dyn PtNetProcess + 'a>>, Box<dyn std::error::Error>> {
+    Ok(Some(Box::new(CommandProcess::new(
+        ctx.commands,
+        ctx.conn
+    ))))
+}
+
+fn build_linktest<'a>(ctx: &'a ProcessContext<'a>, params: Value) -> Result<Option<Box<dyn PtNetProcess + 'a>>, Box<dyn std::error::Error>> {
+    let conf: LinkTestConfig = config::params(params)?;
+
+    Ok(Some(Box::new(LinkTestProcess::new(
+        conf,
+        ctx.db,
+        ctx.conn,
+        ctx.sender
+    ))))
+}
+
+fn build_fwu<'a>(ctx: &'a ProcessContext<'a>, params: Value) -> Result<Option<Box<dyn PtNetProcess + 'a>>, Box<dyn std::error::Error>> {
+    let conf: FWUConfig = config::params(params)?;
+
+    Ok(ctx.fw_index.map(|fw_index| -> Box<dyn PtNetProcess + 'a> {
+        Box::new(FWUProcess::new(
+            ctx.db,
+            ctx.conn,
+            Retrier::new(ctx.sender, ctx.router, conf.retry).metered(&ctx.db.bandwidth, "fwu"),
+            fw_index,
+            ctx.windows,
+            ctx.fw_policy,
+            ctx.limiter,
+            conf.rollback
+        ))
+    }))
+}
+
+fn build_campaign<'a>(ctx: &'a ProcessContext<'a>, params: Value) -> Result<Option<Box<dyn PtNetProcess + 'a>>, Box<dyn std::error::Error>> {
+    let conf: CampaignConfig = config::params(params)?;
+
+    // campaigns only assign goals, FWU process executes them
+    if ctx.fw_index.is_none() {
+        return Ok(None);
+    }
+
+    Ok(Some(Box::new(CampaignProcess::new(
+        Duration::from_secs(conf.period),
+        ctx.db,
+        ctx.windows
+    ))))
+}
+
+fn build_health<'a>(ctx: &'a ProcessContext<'a>, params: Value) -> Result<Option<Box<dyn PtNetProcess + 'a>>, Box<dyn std::error::Error>> {
+    let conf: HealthConfig = config::params(params)?;
+
+    Ok(Some(Box::new(HealthProcess::new(
+        conf,
+        ctx.db,
+        ctx.conn
+    ))))
+}
+
+fn build_commissioning<'a>(ctx: &'a ProcessContext<'a>, params: Value) -> Result<Option<Box<dyn PtNetProcess + 'a>>, Box<dyn std::error::Error>> {
+    let conf: CommissioningConfig = config::params(params)?;
+    let retry = conf.retry.clone();
+
+    // without model there is nothing to commission nodes with
+    Ok(conf.model_root.clone().map(|model_root| -> Box<dyn PtNetProcess + 'a> {
+        Box::new(CommissioningProcess::new(
+            conf,
+            model_root,
+            ctx.db,
+            ctx.conn,
+            ctx.commands,
+            Retrier::new(ctx.sender, ctx.router, retry).metered(&ctx.db.bandwidth, "commissioning")
+        ))
+    }))
+}
+
+fn build_group<'a>(ctx: &'a ProcessContext<'a>, params: Value) -> Result<Option<Box<dyn PtNetProcess + 'a>>, Box<dyn std::error::Error>> {
+    let conf: GroupConfig = config::params(params)?;
+    let retry = conf.retry.clone();
+
+    Ok(Some(Box::new(GroupProcess::new(
+        conf,
+        ctx.db,
+        ctx.conn,
+        Retrier::new(ctx.sender, ctx.router, retry).metered(&ctx.db.bandwidth, "group")
+    ))))
+}
+
+fn build_energy<'a>(ctx: &'a ProcessContext<'a>, params: Value) -> Result<Option<Box<dyn PtNetProcess + 'a>>, Box<dyn std::error::Error>> {
+    let conf: EnergyConfig = config::params(params)?;
+    let retry = conf.retry.clone();
+
+    Ok(Some(Box::new(EnergyProcess::new(
+        conf,
+        ctx.db,
+        ctx.conn,
+        Retrier::new(ctx.sender, ctx.router, retry).metered(&ctx.db.bandwidth, "energy")
+    ))))
+}
+
+fn build_port<'a>(ctx: &'a ProcessContext<'a>, params: Value) -> Result<Option<Box<dyn PtNetProcess + 'a>>, Box<dyn std::error::Error>> {
+    let conf: PortConfig = config::params(params)?;
+
+    Ok(Some(Box::new(PortProcess::new(
+        conf,
+        ctx.db,
+        ctx.conn,
+        ctx.sender,
+        ctx.router
+    ))))
+}
+
+fn build_alarm<'a>(ctx: &'a ProcessContext<'a>, params: Value) -> Result<Option<Box<dyn PtNetProcess + 'a>>, Box<dyn std::error::Error>> {
+    let conf: AlarmConfig = config::params(params)?;
+
+    // nothing to evaluate without rules
+    if conf.rules.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(Box::new(AlarmProcess::new(
+        conf,
+        ctx.db
+    ))))
+}
+
+fn build_derived<'a>(ctx: &'a ProcessContext<'a>, params: Value) -> Result<Option<Box<dyn PtNetProcess + 'a>>, Box<dyn std::error::Error>> {
+    let conf: DerivedConfig = config::params(params)?;
+
+    if conf.points.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(Box::new(DerivedProcess::new(
+        conf,
+        ctx.db
+    ))))
+}
+
+fn build_parameter<'a>(ctx: &'a ProcessContext<'a>, params: Value) -> Result<Option<Box<dyn PtNetProcess + 'a>>, Box<dyn std::error::Error>> {
+    let conf: ParameterConfig = config::params(params)?;
+    let retry = conf.retry.clone();
+
+    Ok(Some(Box::new(ParameterProcess::new(
+        conf,
+        ctx.db,
+        ctx.conn,
+        ctx.commands,
+        Retrier::new(ctx.sender, ctx.router, retry).metered(&ctx.db.bandwidth, "parameter")
+    ))))
+}
+
+fn build_api<'a>(ctx: &'a ProcessContext<'a>, params: Value) -> Result<Option<Box<dyn PtNetProcess + 'a>>, Box<dyn std::error::Error>> {
+    let conf: ApiConfig = config::params(params)?;
+
+    Ok(ctx.api_requests.map(|requests| -> Box<dyn PtNetProcess + 'a> {
+        Box::new(ApiProcess::new(
+            conf,
+            requests,
+            ctx.db,
+            ctx.commands,
+            Retrier::new(ctx.sender, ctx.router, Default::default()).metered(&ctx.db.bandwidth, "api")
+        ))
+    }))
+}
+
+fn build_scheduler<'a>(ctx: &'a ProcessContext<'a>, params: Value) -> Result<Option<Box<dyn PtNetProcess + 'a>>, Box<dyn std::error::Error>> {
+    let conf: SchedulerConfig = config::params(params)?;
+
+    Ok(Some(Box::new(SchedulerProcess::new(
+        conf,
+        ctx.db,
+        ctx.commands
+    ))))
+}
+
+fn build_binding<'a>(ctx: &'a ProcessContext<'a>, params: Value) -> Result<Option<Box<dyn PtNetProcess + 'a>>, Box<dyn std::error::Error>> {
+    let conf: BindingConfig = config::params(params)?;
+
+    if conf.bindings.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(Box::new(BindingProcess::new(
+        conf,
+        ctx.db,
+        ctx.groups
+    ))))
+}
+
+fn build_emtest<'a>(ctx: &'a ProcessContext<'a>, params: Value) -> Result<Option<Box<dyn PtNetProcess + 'a>>, Box<dyn std::error::Error>> {
+    let conf: EmTestConfig = config::params(params)?;
+    let nodes = conf.nodes.parse::<LabelFilter>()?;
+
+    Ok(Some(Box::new(EmTestProcess::new(
+        conf,
+        nodes,
+        ctx.db,
+        ctx.conn,
+        ctx.commands
+    ))))
+}
+
+fn build_rawcapture<'a>(ctx: &'a ProcessContext<'a>, params: Value) -> Result<Option<Box<dyn PtNetProcess + 'a>>, Box<dyn std::error::Error>> {
+    let conf: RawCaptureConfig = config::params(params)?;
+
+    // opt-in, captures nothing unless told which nodes
+    if !conf.all && conf.nodes.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(Box::new(RawCaptureProcess::new(
+        conf,
+        ctx.db,
+        ctx.conn
+    ))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn section_parse() {
+        let sections: HashMap<String, ProcessSection> = serde_json::from_str(
+            r#"{ "nodescan": { "window": 8 }, "linktest": { "enabled": false } }"#
+        ).unwrap();
+
+        let nodescan = &sections["nodescan"];
+        assert!(nodescan.enabled);
+        let conf: NodeScanConfig = serde_json::from_value(Value::Object(nodescan.params.clone())).unwrap();
+        assert_eq!(conf, NodeScanConfig { window: 8, ..Default::default() });
+
+        assert!(!sections["linktest"].enabled);
+    }
+
+    #[test]
+    fn sinks_run_in_pipeline() {
+        let registry = ProcessRegistry::builtin();
+
+        assert!(registry.knows("persist"));
+        assert!(!registry.knows(PIPELINE));
+        assert!(registry.names().any(|name| name == PIPELINE));
+        assert!(!registry.names().any(|name| name == "persist"), "Sink isn't process of its own");
+    }
+}
@@ -0,0 +1,181 @@
+use std::{collections::HashMap, hash::Hash, sync::{Mutex, atomic::{AtomicU64, Ordering}}, time::Duration};
+
+use serde::{Serialize, Deserialize};
+use tokio::{sync::oneshot, time::timeout};
+
+use crate::{database::{Database, NodeAddress, link_quality_table::LinkQualityRecord}, client_connection::IOBMessage};
+
+/// Response from node is expected at address, CA and IOA
+pub type ResponseKey = (NodeAddress, u8, u32);
+
+/// How long to wait for response of node, derived from its measured response times
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct ResponseTimeout {
+    /// percentile of recent response times timeout is derived from
+    pub percentile: u8,
+    /// timeout is percentile multiplied by factor
+    pub factor: f64,
+    /// bounds of derived timeout (milliseconds)
+    pub min: u64,
+    pub max: u64,
+    /// timeout of node with fewer response times measured (milliseconds)
+    pub default: u64,
+    /// response times needed to derive timeout
+    pub min_samples: usize
+}
+
+impl Default for ResponseTimeout {
+    fn default() -> Self {
+        Self {
+            percentile: 95,
+            factor: 2.0,
+            min: 500,
+            max: 10000,
+            default: 5000,
+            min_samples: 5
+        }
+    }
+}
+
+impl ResponseTimeout {
+    /// Timeout for node with given scan statistics
+    pub fn of(&self, link_quality: Option<&LinkQualityRecord>) -> Duration {
+        let derived = link_quality
+            .filter(|rec| rec.response_times.len() >= self.min_samples)
+            .and_then(|rec| rec.percentile(self.percentile))
+            .map(|pct| ((pct as f64 * self.factor) as u64).clamp(self.min, self.max.max(self.min)));
+
+        Duration::from_millis(derived.unwrap_or(self.default))
+    }
+
+    /// Timeout for node, default one if its statistics can't be read
+    pub fn for_node(&self, db: &Database, address: &NodeAddress) -> Duration {
+        self.of(db.link_quality.get(address).ok().flatten().as_ref())
+    }
+}
+
+/// Key response message answers
+pub fn response_key(rsp: &IOBMessage) -> ResponseKey {
+    (rsp.message.header.address, rsp.iob.asdh.ca, rsp.iob.ioa)
+}
+
+struct Waiter<M> {
+    /// tells requests of the same key apart
+    seq: u64,
+    /// filters messages of right key, e.g. by information element type
+    accepts: fn(&M) -> bool,
+    sender: oneshot::Sender<M>
+}
+
+/// Routes responses to requests awaiting them, unrelated messages are left alone
+pub struct ResponseMatcher<K = ResponseKey, M = IOBMessage> {
+    pending: Mutex<HashMap<K, Waiter<M>>>,
+    next_seq: AtomicU64
+}
+
+/// Outstanding request, forgotten when dropped
+pub struct Expectation<'a, K: Eq + Hash, M> {
+    matcher: &'a ResponseMatcher<K, M>,
+    key: K,
+    seq: u64,
+    rcvr: oneshot::Receiver<M>
+}
+
+impl<K: Eq + Hash + Clone, M> ResponseMatcher<K, M> {
+    pub fn new() -> Self {
+        Self {
+            pending: Mutex::new(HashMap::new()),
+            next_seq: AtomicU64::new(0)
+        }
+    }
+
+    /// Register before transmitting, response may arrive before request result
+    pub fn expect(&self, key: K, accepts: fn(&M) -> bool) -> Expectation<'_, K, M> {
+        let (sender, rcvr) = oneshot::channel();
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        self.pending.lock().unwrap().insert(key.clone(), Waiter { seq: seq, accepts: accepts, sender: sender });
+
+        Expectation { matcher: self, key: key, seq: seq, rcvr: rcvr }
+    }
+
+    /// Hand message over to request awaiting it, false if there is none
+    pub fn dispatch(&self, key: &K, msg: M) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+
+        match pending.get(key) {
+            Some(waiter) if (waiter.accepts)(&msg) => {
+                let waiter = pending.remove(key).unwrap();
+                waiter.sender.send(msg).is_ok()
+            },
+            _ => false
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, M> Default for ResponseMatcher<K, M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, K: Eq + Hash, M> Expectation<'a, K, M> {
+    /// Wait for response, None if it didn't arrive in time
+    pub async fn wait(mut self, within: Duration) -> Option<M> {
+        timeout(within, &mut self.rcvr).await.ok().and_then(|r| r.ok())
+    }
+}
+
+impl<'a, K: Eq + Hash, M> Drop for Expectation<'a, K, M> {
+    fn drop(&mut self) {
+        let mut pending = self.matcher.pending.lock().unwrap();
+
+        // entry of the same key may already belong to newer request
+        if pending.get(&self.key).map_or(false, |waiter| waiter.seq == self.seq) {
+            pending.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_timeout() {
+        let timeout = ResponseTimeout::default();
+        let rec = |times: Vec<u32>| LinkQualityRecord { response_times: times, ..Default::default() };
+
+        assert_eq!(Duration::from_millis(5000), timeout.of(None));
+        // too few samples
+        assert_eq!(Duration::from_millis(5000), timeout.of(Some(&rec(vec![100; 4]))));
+        assert_eq!(Duration::from_millis(800), timeout.of(Some(&rec(vec![100, 200, 300, 400, 400]))));
+        assert_eq!(Duration::from_millis(500), timeout.of(Some(&rec(vec![20; 10]))));
+        assert_eq!(Duration::from_millis(10000), timeout.of(Some(&rec(vec![9000; 10]))));
+    }
+
+    #[tokio::test]
+    async fn interleaved() {
+        let matcher: ResponseMatcher<u32, (u32, bool)> = ResponseMatcher::new();
+
+        let first = matcher.expect(1, |msg| msg.1);
+        let second = matcher.expect(2, |msg| msg.1);
+
+        assert!(!matcher.dispatch(&3, (3, true)), "nobody awaits key");
+        assert!(!matcher.dispatch(&2, (2, false)), "filtered out");
+        assert!(matcher.dispatch(&2, (2, true)));
+        assert!(matcher.dispatch(&1, (1, true)));
+        assert!(!matcher.dispatch(&1, (1, true)), "already answered");
+
+        assert_eq!(Some((1, true)), first.wait(Duration::from_millis(10)).await);
+        assert_eq!(Some((2, true)), second.wait(Duration::from_millis(10)).await);
+    }
+
+    #[tokio::test]
+    async fn forgotten_when_dropped() {
+        let matcher: ResponseMatcher<u32, u32> = ResponseMatcher::new();
+
+        assert_eq!(None, matcher.expect(1, |_| true).wait(Duration::from_millis(10)).await);
+        assert!(matcher.pending.lock().unwrap().is_empty());
+    }
+}
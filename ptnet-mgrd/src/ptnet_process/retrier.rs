@@ -0,0 +1,211 @@
+use std::{time::{Duration, Instant}, fmt};
+
+use log::{debug, warn};
+use ptnet::{FC, BIT_PRM, PORT_AUTO};
+use serde::{Serialize, Deserialize};
+use tokio::time::{sleep, timeout};
+
+use crate::{database::{NodeAddress, NodeAddr}, client_connection::{ClientConnection, ClientConnectionSender, Message, SendOutcome}};
+
+use super::{Router, BandwidthMeter, frame_size};
+
+/// What to do with result of a send
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum RetryAction {
+    Done,
+    /// transient failure, send again
+    Retry,
+    /// permanent failure, retrying won't help
+    Fail
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    Transmit(String),
+    /// permanent failure reported by ptlink
+    Failed(SendOutcome),
+    /// still failing after all attempts, carries last result if any
+    Exhausted(Option<SendOutcome>)
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Transmit(err) => write!(f, "Transmit failed ({})", err),
+            SendError::Failed(code) => write!(f, "Send failed ({:?})", code),
+            SendError::Exhausted(Some(code)) => write!(f, "Send failed after retries ({:?})", code),
+            SendError::Exhausted(None) => write!(f, "Send failed after retries (no result)")
+        }
+    }
+}
+
+impl std::error::Error for SendError {
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// sends including the first one
+    pub max_attempts: u32,
+    /// wait before first retry (milliseconds), doubles with each retry
+    pub backoff: u64,
+    /// max. wait before retry (milliseconds)
+    pub max_backoff: u64,
+    /// wait for send result from ptlink (milliseconds)
+    pub result_timeout: u64
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff: 500,
+            max_backoff: 5000,
+            result_timeout: 10000
+        }
+    }
+}
+
+/// Sends messages, retrying transient failures
+pub struct Retrier<'a> {
+    sender: &'a ClientConnectionSender<'a>,
+    /// picks port of messages not sent to particular port
+    router: &'a Router<'a>,
+    policy: RetryPolicy,
+    /// accounts sends to process of given name, throttled by its budget
+    meter: Option<(&'a BandwidthMeter, &'static str)>
+}
+
+impl<'a> Retrier<'a> {
+    pub fn new(sender: &'a ClientConnectionSender<'a>, router: &'a Router<'a>, policy: RetryPolicy) -> Self {
+        Self {
+            sender: sender,
+            router: router,
+            policy: policy,
+            meter: None
+        }
+    }
+
+    /// Account sends to budget of process
+    pub fn metered(self, meter: &'a BandwidthMeter, process: &'static str) -> Self {
+        Self {
+            meter: Some((meter, process)),
+            ..self
+        }
+    }
+
+    pub fn conn(&self) -> &'a ClientConnection {
+        self.sender.conn()
+    }
+
+    /// Longest payload connection accepts
+    pub fn max_payload(&self) -> usize {
+        self.sender.max_payload()
+    }
+
+    /// Sends of message including the first one
+    pub fn max_attempts(&self) -> u32 {
+        self.policy.max_attempts.max(1)
+    }
+
+    /// Classify send result, missing result (timed out waiting for it) is retried
+    pub fn classify(outcome: Option<SendOutcome>) -> RetryAction {
+        match outcome {
+            Some(SendOutcome::Delivered) => RetryAction::Done,
+            Some(SendOutcome::NotDelivered) | Some(SendOutcome::TimedOut) | None => RetryAction::Retry,
+            // no point in hammering port which is down
+            Some(SendOutcome::LinkDown) => RetryAction::Fail,
+            Some(SendOutcome::PortInvalid) | Some(SendOutcome::Unknown(_)) => RetryAction::Fail
+        }
+    }
+
+    pub async fn send_message(&self, msg: &Message) -> Result<(), SendError> {
+        self.send_counted(msg).await.map(|_| ())
+    }
+
+    /// Send message, returns number of attempts it took
+    pub async fn send_counted(&self, msg: &Message) -> Result<u32, SendError> {
+        let mut backoff = Duration::from_millis(self.policy.backoff);
+        let mut last: Option<SendOutcome> = None;
+
+        for attempt in 1..=self.max_attempts() {
+            if attempt > 1 {
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_millis(self.policy.max_backoff));
+            }
+
+            // route is re-evaluated each attempt, failures may have moved node to other port
+            let address = msg.header.address;
+            let port = match msg.port {
+                PORT_AUTO => self.router.port_for(&address),
+                port => port
+            };
+
+            if let Some((meter, process)) = self.meter {
+                meter.throttle(process).await;
+            }
+
+            let rcvr = self.sender.send_message(&Message { port: port, ..msg.clone() }).await
+                .map_err(|err| SendError::Transmit(err.to_string()))?;
+
+            // retries take airtime too
+            if let Some((meter, process)) = self.meter {
+                meter.record(process, frame_size(&msg.payload), Instant::now());
+            }
+
+            let (trace, outcome) = match timeout(Duration::from_millis(self.policy.result_timeout), rcvr).await {
+                Ok(Ok(result)) => (Some(result.trace), Some(result.outcome)),
+                _ => (None, None)
+            };
+            last = outcome;
+
+            let action = Retrier::classify(last);
+            match action {
+                RetryAction::Done => self.router.delivered(&address, port),
+                RetryAction::Retry | RetryAction::Fail => self.router.failed(&address, port)
+            };
+
+            match action {
+                RetryAction::Done => return Ok(attempt),
+                RetryAction::Retry => {
+                    let node = NodeAddr(msg.header.address).to_string();
+                    debug!(node = node.as_str(), attempt = attempt, trace = trace; "Send to {} attempt {} failed ({:?})", node, attempt, last)
+                },
+                RetryAction::Fail => return Err(SendError::Failed(last.unwrap()))
+            };
+        }
+
+        let node = NodeAddr(msg.header.address).to_string();
+        warn!(node = node.as_str(); "Send to {} failed after {} attempts!", node, self.max_attempts());
+        Err(SendError::Exhausted(last))
+    }
+
+    pub async fn send_prm(&self, fc: FC, address: &NodeAddress, buf: &[u8]) -> Result<(), SendError> {
+        let msg = Message {
+            port: PORT_AUTO,
+            header: ptnet::Header {
+                C: (BIT_PRM as u8) | (fc as u8),
+                address: *address,
+            },
+            payload: buf.into(),
+        };
+
+        self.send_message(&msg).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify() {
+        assert_eq!(RetryAction::Done, Retrier::classify(Some(SendOutcome::Delivered)));
+        assert_eq!(RetryAction::Retry, Retrier::classify(Some(SendOutcome::NotDelivered)));
+        assert_eq!(RetryAction::Retry, Retrier::classify(Some(SendOutcome::TimedOut)));
+        assert_eq!(RetryAction::Retry, Retrier::classify(None));
+        assert_eq!(RetryAction::Fail, Retrier::classify(Some(SendOutcome::LinkDown)));
+        assert_eq!(RetryAction::Fail, Retrier::classify(Some(SendOutcome::PortInvalid)));
+        assert_eq!(RetryAction::Fail, Retrier::classify(Some(SendOutcome::Unknown(0xFFFF))));
+    }
+}
@@ -0,0 +1,196 @@
+use log::{info, warn, error};
+use ptnet::PORT_AUTO;
+use serde::{Serialize, Deserialize};
+
+use crate::database::{Database, NodeAddress, unix_now, NodeAddr, port_table::PortStatus, route_table::RouteRecord};
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct RoutingConfig {
+    /// send to port node is pinned to instead of letting ptlink server choose
+    pub enabled: bool,
+    /// fail over to other port after this many consecutive failed sends
+    pub fail_after: u32,
+    /// try port node failed over from again after this long (seconds)
+    pub failback: u64
+}
+
+impl Default for RoutingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            fail_after: 2,
+            failback: 3600
+        }
+    }
+}
+
+/// Pins nodes to ports they are reachable on, fails over to other ports and back
+pub struct Router<'a> {
+    conf: RoutingConfig,
+    db: &'a Database<'a>
+}
+
+impl<'a> Router<'a> {
+    pub fn new(conf: RoutingConfig, db: &'a Database) -> Self {
+        Self {
+            conf: conf,
+            db: db
+        }
+    }
+
+    /// Port to send message to node to, PORT_AUTO if node isn't pinned
+    pub fn port_for(&self, address: &NodeAddress) -> i32 {
+        if !self.conf.enabled {
+            return PORT_AUTO;
+        }
+
+        match self.db.routes.get(address) {
+            Ok(Some(rec)) => match (rec.home, rec.failed_over_at) {
+                (Some(home), Some(at)) if unix_now().saturating_sub(at) >= self.conf.failback => home,
+                _ => rec.port
+            },
+            Ok(None) => PORT_AUTO,
+            Err(err) => {
+                error!("Error reading route of {}! ({})", NodeAddr(*address), err);
+                PORT_AUTO
+            }
+        }
+    }
+
+    /// Node was heard on port, pin it there unless it's pinned already
+    pub fn heard(&self, address: &NodeAddress, port: i32) {
+        if !self.conf.enabled || port == PORT_AUTO {
+            return;
+        }
+
+        self.update(address, |opt_rec| Some(opt_rec.unwrap_or(RouteRecord::pinned(port))));
+    }
+
+    /// Message was delivered through port, it's known good now
+    pub fn delivered(&self, address: &NodeAddress, port: i32) {
+        if !self.conf.enabled || port == PORT_AUTO {
+            return;
+        }
+
+        self.update(address, |opt_rec| {
+            if let Some(home) = opt_rec.as_ref().and_then(|rec| rec.home).filter(|home| *home == port) {
+                info!("Node {} failed back to port {}", NodeAddr(*address), home);
+            }
+
+            Some(match opt_rec {
+                Some(rec) if rec.port == port => RouteRecord { failures: 0, ..rec },
+                _ => RouteRecord::pinned(port)
+            })
+        });
+    }
+
+    /// Send through port failed, fail over to next port when it keeps failing
+    pub fn failed(&self, address: &NodeAddress, port: i32) {
+        if !self.conf.enabled || port == PORT_AUTO {
+            return;
+        }
+
+        let ports: Vec<i32> = match self.db.ports.list() {
+            Ok(recs) => recs.iter().filter(|rec| rec.status == PortStatus::Up).map(|rec| rec.port).collect(),
+            Err(err) => {
+                error!("Error listing ports! ({})", err);
+                Vec::new()
+            }
+        };
+
+        let mut unpin = false;
+
+        self.update(address, |opt_rec| {
+            let mut rec = opt_rec?;
+
+            // failback attempt failed, stay on current port for another period
+            if rec.home == Some(port) && rec.port != port {
+                rec.failed_over_at = Some(unix_now());
+                return Some(rec);
+            }
+
+            rec.failures += 1;
+            if rec.failures < self.conf.fail_after.max(1) {
+                return Some(rec);
+            }
+
+            // next port in order, wrapping around
+            let next = ports.iter().find(|p| **p > port).or(ports.first()).filter(|p| **p != port);
+            let node = NodeAddr(*address).to_string();
+
+            match next {
+                Some(next) => {
+                    warn!(node = node.as_str(); "Node {} unreachable on port {}, failing over to port {}", node, port, next);
+                    Some(RouteRecord {
+                        port: *next,
+                        failures: 0,
+                        home: rec.home.or(Some(port)),
+                        failed_over_at: Some(unix_now())
+                    })
+                },
+                None => {
+                    // no other port, ptlink server chooses until node is heard again
+                    warn!(node = node.as_str(); "Node {} unreachable on port {}, unpinned", node, port);
+                    unpin = true;
+                    None
+                }
+            }
+        });
+
+        if unpin {
+            if let Err(err) = self.db.routes.remove(address) {
+                error!("Error removing route of {}! ({})", NodeAddr(*address), err);
+            }
+        }
+    }
+
+    fn update<T>(&self, address: &NodeAddress, cb: T)
+    where
+        T: FnOnce(Option<RouteRecord>) -> Option<RouteRecord>
+    {
+        if let Err(err) = self.db.routes.modify(address, cb) {
+            error!("Error storing route of {}! ({})", NodeAddr(*address), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::{testing::{make_redb, make_db}, port_table::PortRecord};
+
+    use super::*;
+
+    #[test]
+    fn failover() {
+        let rdb = make_redb("router-db.redb");
+        let db = make_db(&rdb);
+        let router = Router::new(RoutingConfig { fail_after: 2, failback: 0, ..Default::default() }, &db);
+        let a: NodeAddress = [0xFE, 0xED, 0xDE, 0xAF, 0xBE, 0xEF];
+
+        for port in [1, 2] {
+            db.ports.modify(port, |_| Some(PortRecord { port: port, status: PortStatus::Up, first_seen: 0, last_heard: 0, nodes: 0 })).unwrap();
+        }
+
+        assert_eq!(PORT_AUTO, router.port_for(&a));
+        router.heard(&a, 1);
+        assert_eq!(1, router.port_for(&a));
+
+        router.failed(&a, 1);
+        assert_eq!(1, db.routes.get(&a).unwrap().unwrap().port, "single failure is tolerated");
+        router.failed(&a, 1);
+        assert_eq!(2, db.routes.get(&a).unwrap().unwrap().port);
+
+        // failback period elapsed, home port is tried again
+        assert_eq!(1, router.port_for(&a));
+        router.delivered(&a, 1);
+        assert_eq!(RouteRecord::pinned(1), db.routes.get(&a).unwrap().unwrap());
+
+        // no other port to fail over to
+        db.ports.modify(2, |rec| Some(PortRecord { status: PortStatus::Silent, ..rec.unwrap() })).unwrap();
+        router.failed(&a, 1);
+        router.failed(&a, 1);
+        assert_eq!(None, db.routes.get(&a).unwrap(), "unpinned");
+        assert_eq!(PORT_AUTO, router.port_for(&a));
+    }
+}
@@ -0,0 +1,162 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Local, TimeZone};
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+use tokio::{sync::broadcast, time::sleep, select};
+
+use crate::{database::{Database, NodeAddress, unix_now, schedule_table::{ScheduleRecord, ScheduleRun, ScheduleStep}}, site::LabelFilter, error::{PtnetMgrError, DbError}};
+
+use super::{PtNetProcess, CommandEngine, bulk_command};
+
+/// Runtime state key of unix time schedules were last evaluated up to
+const CHECKED: &str = "scheduler.checked";
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct SchedulerConfig {
+    /// max. nodes commanded at once by schedule step
+    pub concurrency: usize,
+    /// longest sleep between evaluations of schedules (seconds), bounds delay caused by clock or site location changes
+    pub recheck: u64,
+    /// runs missed while daemon was down at most this long are caught up after restart (seconds), none if 0
+    pub catch_up: u64
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 8,
+            recheck: 600,
+            catch_up: 0
+        }
+    }
+}
+
+/// Runs schedules at their times, runs missed while process was down are caught up only after short outage
+pub struct SchedulerProcess<'a> {
+    conf: SchedulerConfig,
+    db: &'a Database<'a>,
+    commands: &'a CommandEngine<'a>
+}
+
+impl<'a> SchedulerProcess<'a> {
+    pub fn new(conf: SchedulerConfig, db: &'a Database, commands: &'a CommandEngine<'a>) -> Self {
+        SchedulerProcess {
+            conf: conf,
+            db: db,
+            commands: commands
+        }
+    }
+
+    fn step_nodes(&self, step: &ScheduleStep) -> Result<Vec<NodeAddress>, PtnetMgrError> {
+        match &step.label {
+            None => Ok(step.addresses.clone()),
+            Some(label) => Ok(self.db.query_nodes(&label.parse::<LabelFilter>()?)?
+                .into_iter()
+                .map(|info| info.node.address)
+                .collect())
+        }
+    }
+
+    /// Execute steps of schedule in order, failing step doesn't stop the following ones
+    async fn execute(&self, schedule: &ScheduleRecord) -> ScheduleRun {
+        let mut run = ScheduleRun { at: unix_now(), succeeded: 0, failed: 0 };
+
+        for (idx, step) in schedule.steps.iter().enumerate() {
+            let addresses = match self.step_nodes(step) {
+                Ok(addresses) => addresses,
+                Err(err) => {
+                    warn!("Step {} of schedule {} has no nodes! ({})", idx + 1, schedule.id, err);
+                    continue;
+                }
+            };
+
+            let summary = bulk_command(self.commands, self.conf.concurrency, &addresses, step.ioa, step.ti, step.value, step.select).await;
+            run.succeeded += summary.succeeded;
+            run.failed += summary.failed;
+        }
+
+        run
+    }
+
+    /// Run schedules due in (since, now]
+    async fn run_due(&self, since: &DateTime<Local>, now: &DateTime<Local>) -> Result<(), DbError> {
+        let location = self.db.site().location;
+
+        for schedule in self.db.schedules.list()?.iter().filter(|schedule| schedule.enabled) {
+            if !schedule.next_run(since, location.as_ref()).map_or(false, |run| run <= *now) {
+                continue;
+            }
+
+            info!("Running schedule {} ({})", schedule.id, schedule.name);
+            let run = self.execute(schedule).await;
+            if run.failed > 0 {
+                warn!("Schedule {} ({}) failed to command {} nodes", schedule.id, schedule.name, run.failed);
+            }
+
+            // schedule may have been removed meanwhile
+            if let Err(err) = self.db.schedules.modify(schedule.id, |mut rec| { rec.last_run = Some(run); Some(rec) }) {
+                warn!("Run of schedule {} not recorded! ({})", schedule.id, err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Where evaluation of schedules continues after restart, now unless missed runs are caught up
+    fn resume_from(&self, now: &DateTime<Local>) -> Result<DateTime<Local>, DbError> {
+        let checked: Option<i64> = self.db.runtime_state.get(CHECKED)?;
+        let catch_up = chrono::Duration::seconds(self.conf.catch_up as i64);
+
+        Ok(match checked.and_then(|ts| Local.timestamp_opt(ts, 0).single()) {
+            Some(checked) if checked < *now && *now - checked <= catch_up => {
+                info!("Catching up schedules missed since {}", checked);
+                checked
+            },
+            _ => *now
+        })
+    }
+
+    fn next_wake(&self, now: &DateTime<Local>) -> Result<DateTime<Local>, DbError> {
+        let location = self.db.site().location;
+        let recheck = *now + chrono::Duration::seconds(self.conf.recheck.max(1) as i64);
+
+        Ok(self.db.schedules.list()?.iter()
+            .filter(|schedule| schedule.enabled)
+            .filter_map(|schedule| schedule.next_run(now, location.as_ref()))
+            .fold(recheck, |wake, run| wake.min(run)))
+    }
+}
+
+#[async_trait]
+impl<'a> PtNetProcess for SchedulerProcess<'a> {
+    fn name(&self) -> &str {
+        "scheduler"
+    }
+
+    async fn run(&mut self) -> Result<(), PtnetMgrError> {
+        let mut evt_rcvr = self.db.schedules.events.subscribe();
+        let mut checked = self.resume_from(&Local::now())?;
+
+        loop {
+            let now = Local::now();
+            self.run_due(&checked, &now).await?;
+            checked = now;
+            if let Err(err) = self.db.runtime_state.set(CHECKED, &now.timestamp()) {
+                warn!("Schedule evaluation time not stored! ({})", err);
+            }
+
+            let wait = (self.next_wake(&now)? - Local::now()).to_std().unwrap_or(Duration::ZERO);
+
+            // changed schedules are evaluated right away
+            select! {
+                _ = sleep(wait) => (),
+                evt = evt_rcvr.recv() => if let Err(broadcast::error::RecvError::Closed) = evt {
+                    return Err(broadcast::error::RecvError::Closed.into());
+                }
+            }
+        }
+    }
+}
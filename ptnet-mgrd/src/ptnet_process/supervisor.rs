@@ -0,0 +1,118 @@
+use std::{panic::AssertUnwindSafe, time::{Duration, Instant}};
+
+use futures::{future::{self, join_all}, FutureExt};
+use log::{error, warn, info};
+use serde::{Serialize, Deserialize};
+use tokio::{sync::broadcast, time::sleep, select};
+
+use crate::database::{Database, process_table};
+
+use super::PtNetProcess;
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct RestartPolicy {
+    /// wait before first restart (milliseconds)
+    pub initial_backoff: u64,
+    /// max. wait before restart (milliseconds), backoff doubles with each consecutive failure
+    pub max_backoff: u64,
+    /// process running at least this long (seconds) is considered stable, backoff resets
+    pub stable_after: u64
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: 1000,
+            max_backoff: 60000,
+            stable_after: 300
+        }
+    }
+}
+
+/// Runs processes side by side, restarting the ones which fail and holding the paused ones
+pub struct Supervisor<'a> {
+    policy: RestartPolicy,
+    db: &'a Database<'a>
+}
+
+impl<'a> Supervisor<'a> {
+    pub fn new(policy: RestartPolicy, db: &'a Database<'a>) -> Self {
+        Self {
+            policy: policy,
+            db: db
+        }
+    }
+
+    fn is_paused(&self, name: &str) -> bool {
+        match self.db.processes.get(name) {
+            Ok(rec) => rec.is_some(),
+            Err(err) => {
+                // better run paused process than stop running one
+                error!("Error reading pause state of process {}! ({})", name, err);
+                false
+            }
+        }
+    }
+
+    /// Wait until process gets paused, or resumed if `paused` is false
+    async fn until(&self, events: &mut broadcast::Receiver<process_table::Event>, name: &str, paused: bool) {
+        loop {
+            match events.recv().await {
+                Ok(process_table::Event::Paused(n)) if paused && n == name => return,
+                Ok(process_table::Event::Resumed(n)) if !paused && n == name => return,
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(_)) => if self.is_paused(name) == paused {
+                    return;
+                },
+                Err(broadcast::error::RecvError::Closed) => future::pending::<()>().await
+            }
+        }
+    }
+
+    /// Supervise processes, never returns
+    pub async fn run(&self, processes: &mut [Box<dyn PtNetProcess + '_>]) {
+        join_all(processes.iter_mut().map(|process| self.supervise(process.as_mut()))).await;
+    }
+
+    async fn supervise(&self, process: &mut (dyn PtNetProcess + '_)) {
+        let initial_backoff = Duration::from_millis(self.policy.initial_backoff);
+        let mut backoff = initial_backoff;
+
+        loop {
+            let name = process.name().to_string();
+            // subscribed before checking, pause state changing in between isn't missed
+            let mut events = self.db.processes.events.subscribe();
+
+            if self.is_paused(&name) {
+                info!("Process {} paused", name);
+                self.until(&mut events, &name, false).await;
+                info!("Process {} resumed", name);
+                continue;
+            }
+
+            let started = Instant::now();
+
+            let result = select! {
+                result = AssertUnwindSafe(process.run()).catch_unwind() => result,
+                // process is dropped at whatever it was waiting for, like on reconnect
+                _ = self.until(&mut events, &name, true) => continue
+            };
+
+            match result {
+                Ok(Ok(())) => warn!("Process {} terminated without error", name),
+                Ok(Err(err)) => error!("Process {} failed! ({})", name, err),
+                Err(_) => error!("Process {} crashed!", name)
+            };
+
+            if started.elapsed() >= Duration::from_secs(self.policy.stable_after) {
+                backoff = initial_backoff;
+            }
+
+            info!("Restart process {} in {:?}", name, backoff);
+            sleep(backoff).await;
+
+            backoff = (backoff * 2).min(Duration::from_millis(self.policy.max_backoff));
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use std::{collections::HashMap, sync::{Mutex, RwLock}, time::{Duration, Instant}};
+
+use serde::{Serialize, Deserialize};
+
+use crate::database::NodeAddress;
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct UpdateLimits {
+    /// max. firmware downloads running at once
+    pub max_concurrent: usize,
+    /// max. firmware downloads running at once on single ptlink port
+    pub max_concurrent_per_port: usize,
+    /// scan period is multiplied by this while any download runs
+    pub scan_slowdown: u32,
+    /// release slot of node which didn't start download in time (seconds)
+    pub start_timeout: u64,
+    /// release slot of node which stopped reporting running download (seconds)
+    pub stall_timeout: u64
+}
+
+impl Default for UpdateLimits {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 4,
+            max_concurrent_per_port: 2,
+            scan_slowdown: 3,
+            start_timeout: 300,
+            stall_timeout: 900
+        }
+    }
+}
+
+struct Slot {
+    port: i32,
+    /// slot acquisition or last report of running download
+    refreshed_at: Instant,
+    /// node confirmed download by reporting its state
+    started: bool
+}
+
+/// Bookkeeping of running firmware downloads shared by FWU and scan processes
+pub struct UpdateLimiter {
+    limits: RwLock<UpdateLimits>,
+    slots: Mutex<HashMap<NodeAddress, Slot>>
+}
+
+impl UpdateLimiter {
+    pub fn new(limits: UpdateLimits) -> Self {
+        Self {
+            limits: RwLock::new(limits),
+            slots: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Apply changed limits, running downloads keep their slots
+    pub fn set_limits(&self, limits: UpdateLimits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    /// Take download slot for node on port, false if limits are reached
+    pub fn try_acquire(&self, address: &NodeAddress, port: i32) -> bool {
+        let mut slots = self.slots.lock().unwrap();
+        let limits = self.limits.read().unwrap();
+        let start_timeout = Duration::from_secs(limits.start_timeout);
+        let stall_timeout = Duration::from_secs(limits.stall_timeout);
+
+        slots.retain(|_, slot| slot.refreshed_at.elapsed() < match slot.started {
+            true => stall_timeout,
+            false => start_timeout
+        });
+
+        if slots.contains_key(address) {
+            return true;
+        }
+
+        if slots.len() >= limits.max_concurrent
+            || slots.values().filter(|slot| slot.port == port).count() >= limits.max_concurrent_per_port {
+            return false;
+        }
+
+        slots.insert(*address, Slot { port: port, refreshed_at: Instant::now(), started: false });
+        true
+    }
+
+    /// Node reports download in progress, account it even if it exceeds limits
+    pub fn mark_started(&self, address: &NodeAddress, port: i32) {
+        let mut slots = self.slots.lock().unwrap();
+
+        let slot = slots.entry(*address)
+            .or_insert(Slot { port: port, refreshed_at: Instant::now(), started: true });
+        slot.refreshed_at = Instant::now();
+        slot.started = true;
+    }
+
+    pub fn release(&self, address: &NodeAddress) {
+        self.slots.lock().unwrap().remove(address);
+    }
+
+    /// Number of downloads running or about to start
+    pub fn active(&self) -> usize {
+        self.slots.lock().unwrap().len()
+    }
+
+    /// Scan period adjusted to running downloads
+    pub fn scan_period(&self, period: Duration) -> Duration {
+        match self.active() {
+            0 => period,
+            _ => period * self.limits.read().unwrap().scan_slowdown.max(1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits() {
+        let limiter = UpdateLimiter::new(UpdateLimits {
+            max_concurrent: 3,
+            max_concurrent_per_port: 2,
+            ..Default::default()
+        });
+
+        assert!(limiter.try_acquire(&[0, 0, 0, 0, 0, 1], 1));
+        assert!(limiter.try_acquire(&[0, 0, 0, 0, 0, 2], 1));
+        assert!(!limiter.try_acquire(&[0, 0, 0, 0, 0, 3], 1), "per-port limit");
+        assert!(limiter.try_acquire(&[0, 0, 0, 0, 0, 3], 2));
+        assert!(!limiter.try_acquire(&[0, 0, 0, 0, 0, 4], 3), "global limit");
+
+        limiter.release(&[0, 0, 0, 0, 0, 1]);
+        assert!(limiter.try_acquire(&[0, 0, 0, 0, 0, 4], 3));
+
+        // already running downloads are accounted regardless of limits
+        limiter.mark_started(&[0, 0, 0, 0, 0, 5], 1);
+        assert_eq!(4, limiter.active());
+    }
+
+    #[test]
+    fn stalled_download_expires() {
+        let limiter = UpdateLimiter::new(UpdateLimits {
+            max_concurrent: 1,
+            stall_timeout: 0,
+            ..Default::default()
+        });
+
+        limiter.mark_started(&[0, 0, 0, 0, 0, 1], 1);
+        assert!(limiter.try_acquire(&[0, 0, 0, 0, 0, 2], 1), "silent download no longer holds slot");
+        assert_eq!(1, limiter.active());
+    }
+}
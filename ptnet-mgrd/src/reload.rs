@@ -0,0 +1,148 @@
+use std::{fs, path::PathBuf, sync::Arc, time::{Duration, SystemTime}};
+
+use log::{error, info, warn};
+use tokio::{signal::unix::{signal, SignalKind}, sync::watch, time::interval, select};
+
+use crate::{Configuration, logging, config::{self, Overrides}, database::set_address_format};
+
+/// How often configuration file is checked for modification
+const CHECK_PERIOD: Duration = Duration::from_secs(5);
+
+/// Parts of configuration applied by rebuilding processes of live connection
+pub fn process_parts_differ(old: &Configuration, new: &Configuration) -> bool {
+    old.processes != new.processes || old.fwu_windows != new.fwu_windows || old.fw_policy != new.fw_policy || old.restart != new.restart
+}
+
+/// Names of changed parts which apply only after reconnect to ptlink server
+fn reconnect_parts(old: &Configuration, new: &Configuration) -> Vec<&'static str> {
+    let mut parts = Vec::new();
+
+    if old.server_address != new.server_address { parts.push("server_address"); }
+    if old.t_reconnect != new.t_reconnect { parts.push("t_reconnect"); }
+    if old.command_timeouts != new.command_timeouts { parts.push("command_timeouts"); }
+    if old.group_addressing != new.group_addressing { parts.push("group_addressing"); }
+    if old.broadcast != new.broadcast { parts.push("broadcast"); }
+    if old.read_only != new.read_only { parts.push("read_only"); }
+    if old.max_payload != new.max_payload { parts.push("max_payload"); }
+    if old.routing != new.routing { parts.push("routing"); }
+    if old.dedup != new.dedup { parts.push("dedup"); }
+    if old.watchdog != new.watchdog { parts.push("watchdog"); }
+
+    parts
+}
+
+/// Names of changed parts which apply only after restart of daemon
+fn restart_parts(old: &Configuration, new: &Configuration) -> Vec<&'static str> {
+    let mut parts = Vec::new();
+
+    if old.node_model_source != new.node_model_source { parts.push("node_model_source"); }
+    if old.storage != new.storage { parts.push("storage"); }
+    if old.maintenance != new.maintenance { parts.push("maintenance"); }
+    if old.orphan_retention != new.orphan_retention { parts.push("orphan_retention"); }
+    if old.orphan_limit != new.orphan_limit { parts.push("orphan_limit"); }
+    if old.firmware_path != new.firmware_path { parts.push("firmware_path"); }
+    if old.firmware_repository != new.firmware_repository { parts.push("firmware_repository"); }
+    if old.http != new.http { parts.push("http"); }
+    if old.mqtt != new.mqtt { parts.push("mqtt"); }
+    if old.control != new.control { parts.push("control"); }
+    if old.webhooks != new.webhooks { parts.push("webhooks"); }
+    if old.sol_state != new.sol_state { parts.push("sol_state"); }
+    if old.site != new.site { parts.push("site"); }
+    if old.common_addresses != new.common_addresses { parts.push("common_addresses"); }
+    if old.log.format != new.log.format { parts.push("log.format"); }
+    if old.log.file != new.log.file { parts.push("log.file"); }
+
+    parts
+}
+
+/// Reloads configuration file when it's modified or on SIGHUP
+pub struct ConfigReloader {
+    path: PathBuf,
+    /// environment and command line overrides given at start, applied over reloaded file
+    overrides: Overrides,
+    current: watch::Sender<Arc<Configuration>>
+}
+
+impl ConfigReloader {
+    pub fn new(path: PathBuf, overrides: Overrides, current: watch::Sender<Arc<Configuration>>) -> Self {
+        ConfigReloader {
+            path: path,
+            overrides: overrides,
+            current: current
+        }
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path).and_then(|meta| meta.modified()).ok()
+    }
+
+    fn load(&self) -> Result<Configuration, Box<dyn std::error::Error>> {
+        let loaded = config::load(Some(self.path.as_path()), &self.overrides)?;
+
+        for key in &loaded.unknown_keys {
+            warn!("Unknown configuration key {} ignored", key);
+        }
+
+        config::validate(&loaded.conf)?;
+        Ok(loaded.conf)
+    }
+
+    fn apply(&self, new: Configuration) {
+        let old = self.current.borrow().clone();
+
+        if *old == new {
+            info!("Configuration unchanged");
+            return;
+        }
+
+        if old.log != new.log {
+            if let Err(err) = logging::reconfigure(&new.log) {
+                error!("Log levels not changed! ({})", err);
+            }
+        }
+
+        if old.address_format != new.address_format {
+            set_address_format(new.address_format);
+            info!("Node addresses are now written in {:?} format", new.address_format);
+        }
+
+        if old.fwu_limits != new.fwu_limits || old.fw_policy != new.fw_policy || old.bandwidth != new.bandwidth || process_parts_differ(&old, &new) {
+            info!("Applying changed processes, firmware update windows, limits, policy and bandwidth budgets");
+        }
+
+        for part in reconnect_parts(&old, &new) {
+            warn!("Change of {} applies after reconnect to ptlink server", part);
+        }
+
+        for part in restart_parts(&old, &new) {
+            warn!("Change of {} applies after restart of daemon", part);
+        }
+
+        self.current.send_replace(Arc::new(new));
+    }
+
+    pub async fn run(self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let mut ticker = interval(CHECK_PERIOD);
+        let mut modified = self.modified();
+
+        loop {
+            select! {
+                _ = hangup.recv() => info!("SIGHUP received, reloading configuration"),
+                _ = ticker.tick() => {
+                    if self.modified() == modified {
+                        continue;
+                    }
+                    info!("Configuration file modified, reloading");
+                }
+            }
+
+            modified = self.modified();
+
+            match self.load() {
+                Ok(new) => self.apply(new),
+                Err(err) => error!("Configuration {} not reloaded, keeping current! ({})", self.path.display(), err)
+            }
+        }
+    }
+}
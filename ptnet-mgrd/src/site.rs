@@ -0,0 +1,128 @@
+use std::{collections::{BTreeMap, BTreeSet}, fmt, str::FromStr};
+
+use serde::{Serialize, Deserialize};
+
+use crate::sun::Location;
+
+pub type Labels = BTreeMap<String, String>;
+/// Free-form names of nodes, e.g. `corridor` or `floor-3`
+pub type Tags = BTreeSet<String>;
+
+/// Tag may contain letters, digits, `-`, `_` and `.`, so it can be used in filters and topics
+pub fn check_tag(tag: &str) -> Result<(), String> {
+    match !tag.is_empty() && tag.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) {
+        true => Ok(()),
+        false => Err(format!("Invalid tag '{}', use letters, digits, '-', '_' and '.'", tag))
+    }
+}
+
+/// Identity of gateway in fleet, attached to everything it publishes
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct SiteConfig {
+    /// site ID, unique among gateways feeding the same backend
+    pub id: String,
+    /// free-form labels of site, e.g. region, labels of node override them
+    pub labels: Labels,
+    /// coordinates of site, needed by schedules relative to sun events
+    pub location: Option<Location>
+}
+
+impl Default for SiteConfig {
+    fn default() -> Self {
+        Self {
+            id: "default".to_string(),
+            labels: BTreeMap::new(),
+            location: None
+        }
+    }
+}
+
+impl SiteConfig {
+    /// Labels of site merged with labels of node
+    pub fn labels_of(&self, node_labels: &Labels) -> Labels {
+        let mut labels = self.labels.clone();
+        labels.extend(node_labels.iter().map(|(key, value)| (key.clone(), value.clone())));
+        labels
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+enum Condition {
+    /// label and its value, any value if None
+    Label(String, Option<String>),
+    Tag(String)
+}
+
+/// Comma separated `key=value` conditions, bare `key` matches any value, `tag:<tag>` matches
+/// nodes with the tag, all have to match
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct LabelFilter(Vec<Condition>);
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct LabelFilterError(String);
+
+impl fmt::Display for LabelFilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid label filter '{}', use key=value, key or tag:<tag> separated by commas", self.0)
+    }
+}
+
+impl std::error::Error for LabelFilterError {}
+
+impl FromStr for LabelFilter {
+    type Err = LabelFilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|cond| match (cond.strip_prefix("tag:"), cond.split_once('=')) {
+                (Some(tag), _) if check_tag(tag).is_ok() => Ok(Condition::Tag(tag.to_string())),
+                (None, Some((key, value))) if !key.is_empty() => Ok(Condition::Label(key.to_string(), Some(value.to_string()))),
+                (None, None) if !cond.is_empty() => Ok(Condition::Label(cond.to_string(), None)),
+                _ => Err(LabelFilterError(s.to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(LabelFilter)
+    }
+}
+
+impl LabelFilter {
+    pub fn matches(&self, labels: &Labels, tags: &Tags) -> bool {
+        self.0.iter().all(|cond| match cond {
+            Condition::Label(key, value) => match (labels.get(key), value) {
+                (Some(_), None) => true,
+                (Some(label), Some(value)) => label == value,
+                (None, _) => false
+            },
+            Condition::Tag(tag) => tags.contains(tag)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_filter() {
+        let site = SiteConfig {
+            id: "plant-1".to_string(),
+            labels: BTreeMap::from([("region".to_string(), "eu".to_string()), ("line".to_string(), "a".to_string())]),
+            location: None
+        };
+        let labels = site.labels_of(&BTreeMap::from([("line".to_string(), "b".to_string())]));
+        let tags = Tags::from(["corridor".to_string(), "floor-3".to_string()]);
+
+        assert_eq!(Some(&"b".to_string()), labels.get("line"));
+        assert!(LabelFilter::from_str("region=eu,line=b").unwrap().matches(&labels, &tags));
+        assert!(LabelFilter::from_str("region").unwrap().matches(&labels, &tags));
+        assert!(!LabelFilter::from_str("line=a").unwrap().matches(&labels, &tags));
+        assert!(!LabelFilter::from_str("rack").unwrap().matches(&labels, &tags));
+        assert!(LabelFilter::from_str("tag:corridor,tag:floor-3").unwrap().matches(&labels, &tags));
+        assert!(!LabelFilter::from_str("region=eu,tag:stairs").unwrap().matches(&labels, &tags));
+        assert!(LabelFilter::from_str("=eu").is_err());
+        assert!(LabelFilter::from_str("region=eu,").is_err());
+        assert!(LabelFilter::from_str("tag:").is_err());
+        assert!(LabelFilter::from_str("tag:floor 3").is_err());
+    }
+}
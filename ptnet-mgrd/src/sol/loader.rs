@@ -1,39 +1,70 @@
-use std::{path::PathBuf, fs};
+use std::{path::PathBuf, fs, collections::HashMap};
 
 use log::info;
 
-use crate::{database::node_table::NodeRecord, sol::schema};
+use crate::{database::{NodeAddress, NodeAddr, node_table::{NodeRecord, ModelInfo}, commissioning_table::NodeSetup}, sol::schema};
 
-fn parse_user_address(node_address: &str) -> Option<[u8; 6]> {
-    let mut uid: Vec<u8> = node_address.split(":").map(|x| u8::from_str_radix(x, 16).unwrap()).collect();
-    uid.insert(0, 0);
-    uid.insert(0, 0);
-    uid.try_into().ok()
+/// User model file in model root
+pub fn model_path(model_root: &str) -> PathBuf {
+    PathBuf::from(model_root).join("sol.user.json")
 }
 
-pub fn load(model_root: &str) -> Result<Vec<NodeRecord>, std::io::Error> {
-    let mut sol_user_path = PathBuf::from(model_root);
-    sol_user_path.push("sol.user.json");
+/// Address of node in model, model with invalid address isn't loaded at all
+fn model_address(name: &str, address: &str) -> Result<NodeAddress, std::io::Error> {
+    address.parse::<NodeAddr>()
+        .map(|addr| addr.0)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("SOL node '{}': {}", name, err)))
+}
+
+fn load_model(model_root: &str) -> Result<schema::UserModel, std::io::Error> {
+    let sol_user_path = model_path(model_root);
     info!("Loading SOL user model from {}", sol_user_path.as_os_str().to_str().unwrap());
     let soluser: schema::UserModel = serde_json::from_reader(fs::File::open(sol_user_path)?)?;
     info!("Model loaded");
 
+    Ok(soluser)
+}
+
+pub fn load(model_root: &str) -> Result<Vec<NodeRecord>, std::io::Error> {
+    let soluser = load_model(model_root)?;
+
     if let Some(network) = soluser.network.as_ref() {
         let mut nodes: Vec<NodeRecord> =
             network.ballasts.iter()
-                .map(|ballast| parse_user_address(ballast.address.as_str()).unwrap())
-                .map(|address| NodeRecord { address: address, ..Default::default() })
-                .collect();
+                .map(|ballast| Ok(NodeRecord {
+                    address: model_address(&ballast.name, &ballast.address)?,
+                    model: Some(ModelInfo { name: ballast.name.clone(), type_id: ballast.type_id.clone(), groups: ballast.groups.clone() }),
+                    ..Default::default()
+                }))
+                .collect::<Result<_, std::io::Error>>()?;
 
-        nodes.extend(
-            network.sensors.iter()
-                .filter(|e| e.part_of.is_none())
-                .map(|sensor| parse_user_address(sensor.address.as_str()).unwrap())
-                .map(|address| NodeRecord { address: address, ..Default::default() })
-        );
+        for sensor in network.sensors.iter().filter(|e| e.part_of.is_none()) {
+            nodes.push(NodeRecord {
+                address: model_address(&sensor.name, &sensor.address)?,
+                model: Some(ModelInfo { name: sensor.name.clone(), type_id: sensor.type_id.clone(), groups: Vec::new() }),
+                ..Default::default()
+            });
+        }
 
         Ok(nodes)
     } else {
         Ok(Vec::<NodeRecord>::new())
     }
 }
+
+/// Configuration of ballasts to be applied at commissioning, by node address
+pub fn load_setups(model_root: &str) -> Result<HashMap<NodeAddress, NodeSetup>, std::io::Error> {
+    let soluser = load_model(model_root)?;
+
+    soluser.network.iter()
+        .flat_map(|network| network.ballasts.iter())
+        .map(|ballast| Ok((
+            model_address(&ballast.name, &ballast.address)?,
+            NodeSetup {
+                ca: ballast.ca,
+                report_interval: ballast.report_interval,
+                groups: ballast.groups.clone()
+            }
+        )))
+        .collect()
+}
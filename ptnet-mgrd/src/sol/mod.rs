@@ -1,2 +1,4 @@
 pub mod loader;
+pub mod sync;
+pub mod state_writer;
 mod schema;
@@ -17,6 +17,15 @@ pub struct Ballast {
     #[serde(rename="type")]
     pub type_id: String,
     pub name: String,
+    /// common address assigned at commissioning
+    #[serde(default)]
+    pub ca: Option<u8>,
+    /// spontaneous reporting interval assigned at commissioning (seconds)
+    #[serde(default)]
+    pub report_interval: Option<u32>,
+    /// lighting groups ballast is member of
+    #[serde(default)]
+    pub groups: Vec<u8>
 }
 
 #[derive(Clone,Debug,Deserialize)]
@@ -0,0 +1,121 @@
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+
+use log::{debug, error};
+use ptnet::image_header::{FWVersion, HWVersion};
+use serde::{Serialize, Deserialize};
+use tokio::{sync::broadcast, time::interval, select};
+
+use crate::{database::{Database, NodeAddr, AddressFormat, node_table::{self, NodeRecord}}, fw_repository::write_atomic};
+
+
+/// Sidecar file in model root, user model itself is owned by commissioning tools
+const STATE_FILE: &str = "sol.state.json";
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct StateWriterConfig {
+    /// state file, `sol.state.json` in SOL model root if not set
+    pub file: Option<String>,
+    /// minimal pause between writes (seconds)
+    pub min_interval: u64
+}
+
+impl Default for StateWriterConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            min_interval: 10
+        }
+    }
+}
+
+/// Device-derived state of node, keyed by user model address
+#[derive(Debug,Clone,Serialize,PartialEq)]
+struct NodeState {
+    name: Option<String>,
+    hw_version: Option<HWVersion>,
+    fw_version: Option<FWVersion>,
+    device_descriptor: Option<ptnet::M_DEV_DC>,
+    online: Option<bool>,
+    last_seen: Option<u64>
+}
+
+impl From<&NodeRecord> for NodeState {
+    fn from(rec: &NodeRecord) -> Self {
+        NodeState {
+            name: rec.model.as_ref().map(|model| model.name.clone()),
+            hw_version: rec.device_status.map(|st| st.hw_version.into()),
+            fw_version: rec.device_status.map(|st| st.fw_version.into()),
+            device_descriptor: rec.device_descriptor.clone(),
+            online: rec.online,
+            last_seen: rec.last_seen
+        }
+    }
+}
+
+#[derive(Debug,Serialize)]
+struct State {
+    nodes: BTreeMap<String, NodeState>
+}
+
+/// Writes state of nodes for commissioning UI, at most once per `min_interval`
+pub struct StateWriter {
+    conf: StateWriterConfig,
+    path: PathBuf,
+    db: &'static Database<'static>,
+    node_events: broadcast::Receiver<node_table::Event>
+}
+
+impl StateWriter {
+    pub fn new(conf: StateWriterConfig, model_root: Option<&str>, db: &'static Database<'static>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = match (&conf.file, model_root) {
+            (Some(file), _) => PathBuf::from(file),
+            (None, Some(model_root)) => PathBuf::from(model_root).join(STATE_FILE),
+            (None, None) => return Err("SOL state file not set and there is no SOL model root".into())
+        };
+
+        Ok(Self {
+            conf: conf,
+            path: path,
+            db: db,
+            node_events: db.nodes.events.subscribe()
+        })
+    }
+
+    fn write(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let nodes = self.db.nodes.load_many(self.db.nodes.list()?.iter())?;
+        let state = State {
+            nodes: nodes.iter().map(|rec| (NodeAddr(rec.address).to_string_as(AddressFormat::Short), NodeState::from(rec))).collect()
+        };
+
+        write_atomic(&self.path, &serde_json::to_vec_pretty(&state)?)?;
+        debug!("SOL state of {} nodes written to {}", state.nodes.len(), self.path.display());
+
+        Ok(())
+    }
+
+    pub async fn run(mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut ticker = interval(Duration::from_secs(self.conf.min_interval));
+        // state left by previous run may be stale
+        let mut dirty = true;
+
+        loop {
+            select! {
+                evt = self.node_events.recv() => match evt {
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => dirty = true,
+                    Err(broadcast::error::RecvError::Closed) => return Ok(())
+                },
+                _ = ticker.tick() => {
+                    if !dirty {
+                        continue;
+                    }
+
+                    match self.write() {
+                        Ok(()) => dirty = false,
+                        Err(err) => error!("SOL state not written to {}! ({})", self.path.display(), err)
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use std::{fs, path::PathBuf, time::{Duration, Instant, SystemTime}};
+
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::time::interval;
+
+use crate::database::{self, Database, unix_now, node_table::{NodeRecord, ModelInfo}};
+
+use super::loader;
+
+/// How often model file is checked for modification
+const CHECK_PERIOD: Duration = Duration::from_secs(5);
+
+/// How often orphaned nodes are checked for expired retention when model doesn't change
+const PURGE_PERIOD: Duration = Duration::from_secs(3600);
+
+/// Model nodes are synced with and how long orphaned nodes are kept
+#[derive(Debug,Clone,PartialEq)]
+pub struct SyncSettings {
+    pub model_root: String,
+    /// seconds, orphaned nodes are never purged if 0
+    pub orphan_retention: u64,
+    /// percent of nodes in model, reconciliation orphaning more of them is refused
+    pub orphan_limit: f64
+}
+
+/// Changes reconciliation would make, by node address
+#[derive(Debug,Clone,Serialize,PartialEq,Default)]
+pub struct SyncReport {
+    pub added: Vec<NodeChange>,
+    pub renamed: Vec<NodeChange>,
+    /// orphaned nodes back in model
+    pub restored: Vec<NodeChange>,
+    /// nodes model no longer has, kept with their history until retention ends
+    pub orphaned: Vec<NodeChange>,
+    /// orphaned nodes whose retention ended, removed with their history
+    pub purged: Vec<NodeChange>
+}
+
+#[derive(Debug,Clone,Serialize,PartialEq)]
+pub struct NodeChange {
+    pub address: String,
+    /// identity in node table
+    pub from: Option<ModelInfo>,
+    /// identity in model
+    pub to: Option<ModelInfo>
+}
+
+impl NodeChange {
+    fn new(node: &NodeRecord, to: Option<ModelInfo>) -> Self {
+        NodeChange { address: node.mac(), from: node.model.clone(), to: to }
+    }
+}
+
+/// Nodes to add, re-identify, orphan and purge to match model
+struct Plan {
+    add: Vec<NodeRecord>,
+    /// known nodes with identity from model, orphaned ones among them are restored
+    update: Vec<(NodeRecord, Option<ModelInfo>)>,
+    orphan: Vec<NodeRecord>,
+    purge: Vec<NodeRecord>,
+    /// nodes not orphaned before planning
+    live: usize
+}
+
+fn plan(db: &Database, settings: &SyncSettings, now: u64) -> Result<Plan, Box<dyn std::error::Error>> {
+    let model_nodes = loader::load(&settings.model_root)?;
+    let nodes = db.nodes.load_many(db.nodes.list()?.iter())?;
+    let live = nodes.iter().filter(|node| node.orphaned_at.is_none()).count();
+    let (known, missing): (Vec<NodeRecord>, Vec<NodeRecord>) = nodes.into_iter()
+        .partition(|node| model_nodes.iter().any(|model_node| model_node.address == node.address));
+
+    Ok(Plan {
+        add: model_nodes.iter()
+            .filter(|model_node| !known.iter().any(|node| node.address == model_node.address))
+            .cloned()
+            .collect(),
+        update: known.iter()
+            .filter_map(|node| model_nodes.iter().find(|model_node| model_node.address == node.address).map(|model_node| (node, model_node)))
+            .filter(|(node, model_node)| node.model != model_node.model || node.orphaned_at.is_some())
+            .map(|(node, model_node)| (node.clone(), model_node.model.clone()))
+            .collect(),
+        orphan: missing.iter()
+            .filter(|node| node.orphaned_at.is_none())
+            .cloned()
+            .collect(),
+        purge: missing.iter()
+            .filter(|node| settings.orphan_retention > 0
+                && node.orphaned_at.map_or(false, |since| now.saturating_sub(since) >= settings.orphan_retention))
+            .cloned()
+            .collect(),
+        live: live
+    })
+}
+
+/// What reconciliation would change, nothing is modified
+pub fn report(db: &Database, settings: &SyncSettings) -> Result<SyncReport, Box<dyn std::error::Error>> {
+    let plan = plan(db, settings, unix_now())?;
+
+    Ok(SyncReport {
+        added: plan.add.iter().map(|node| NodeChange { address: node.mac(), from: None, to: node.model.clone() }).collect(),
+        renamed: plan.update.iter()
+            .filter(|(node, to)| node.model != *to)
+            .map(|(node, to)| NodeChange::new(node, to.clone()))
+            .collect(),
+        restored: plan.update.iter()
+            .filter(|(node, _)| node.orphaned_at.is_some())
+            .map(|(node, to)| NodeChange::new(node, to.clone()))
+            .collect(),
+        orphaned: plan.orphan.iter().map(|node| NodeChange::new(node, None)).collect(),
+        purged: plan.purge.iter().map(|node| NodeChange::new(node, None)).collect()
+    })
+}
+
+/// Add nodes new in model, refresh model identity of known ones, orphan nodes model no longer has
+/// and purge nodes orphaned longer than retention, returns counts of added and orphaned.
+/// Nothing is changed if more nodes than `orphan_limit` allows would be orphaned, model is likely
+/// truncated or half-written.
+pub fn reconcile(db: &Database, settings: &SyncSettings) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+    let now = unix_now();
+    let plan = plan(db, settings, now)?;
+
+    if plan.orphan.len() as f64 > plan.live as f64 * settings.orphan_limit / 100.0 {
+        return Err(format!(
+            "model would orphan {} of {} nodes, over orphan_limit of {} %",
+            plan.orphan.len(), plan.live, settings.orphan_limit
+        ).into());
+    }
+
+    info!("Add {} new nodes", plan.add.len());
+    let added: Vec<NodeRecord> = plan.add.iter()
+        .map(|node| NodeRecord { model: node.model.clone(), ..db.discovered_node(&node.address) })
+        .collect();
+    db.nodes.update_many(added.iter(), database::UpdateMode::MustCreate)?;
+
+    for (node, model) in &plan.update {
+        if node.orphaned_at.is_some() {
+            info!("Node '{}' back in model, no longer orphaned", node.mac());
+        }
+        // model change only, rest of record may have changed since planning
+        db.nodes.modify(&node.address, |opt_rec| opt_rec.map(|rec| NodeRecord { model: model.clone(), orphaned_at: None, ..rec }))?;
+    }
+    info!("Update model identity of {} nodes", plan.update.len());
+
+    for node in &plan.orphan {
+        warn!("Node '{}' ({}) not in model, orphaned", node.mac(), node.model.as_ref().map_or("unnamed", |model| model.name.as_str()));
+        db.nodes.modify(&node.address, |opt_rec| opt_rec.map(|rec| NodeRecord { orphaned_at: Some(now), ..rec }))?;
+    }
+
+    for node in &plan.purge {
+        warn!("Node '{}' orphaned for over {}s, purging with its history", node.mac(), settings.orphan_retention);
+        db.purge_node(&node.address)?;
+    }
+    info!("Orphan {} and purge {} nodes missing in model", plan.orphan.len(), plan.purge.len());
+
+    Ok((plan.add.len(), plan.orphan.len()))
+}
+
+/// Re-syncs nodes with SOL model whenever commissioning tools modify it
+pub struct ModelWatcher {
+    db: &'static Database<'static>,
+    settings: SyncSettings
+}
+
+impl ModelWatcher {
+    pub fn new(db: &'static Database<'static>, settings: SyncSettings) -> Self {
+        ModelWatcher {
+            db: db,
+            settings: settings
+        }
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        fs::metadata(self.path()).and_then(|meta| meta.modified()).ok()
+    }
+
+    fn path(&self) -> PathBuf {
+        loader::model_path(&self.settings.model_root)
+    }
+
+    pub async fn run(self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut ticker = interval(CHECK_PERIOD);
+        let mut modified = self.modified();
+        // modification time seen on previous check, model is read once it stops changing
+        let mut seen = modified;
+        let mut synced = Instant::now();
+
+        loop {
+            ticker.tick().await;
+
+            let current = self.modified();
+            if current != modified {
+                if current != seen {
+                    seen = current;
+                    continue;
+                }
+                info!("SOL model {} modified, re-syncing nodes", self.path().display());
+                modified = current;
+            } else if synced.elapsed() < PURGE_PERIOD {
+                continue;
+            }
+
+            synced = Instant::now();
+
+            // model may still be caught half-written, next modification retries
+            if let Err(err) = reconcile(self.db, &self.settings) {
+                error!("Nodes not re-synced with SOL model! ({})", err);
+            }
+        }
+    }
+}
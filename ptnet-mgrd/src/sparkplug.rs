@@ -0,0 +1,437 @@
+use std::{collections::BTreeMap, fmt};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{database::NodeAddress, site::SiteConfig};
+
+/// Metric of edge node requesting births to be published again
+pub const REBIRTH_METRIC: &str = "Node Control/Rebirth";
+const BDSEQ_METRIC: &str = "bdSeq";
+
+/// Sparkplug B data types used by gateway
+const DATATYPE_UINT64: u32 = 8;
+const DATATYPE_DOUBLE: u32 = 10;
+const DATATYPE_BOOLEAN: u32 = 11;
+const DATATYPE_STRING: u32 = 12;
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct SparkplugConfig {
+    pub group_id: String,
+    pub edge_node_id: String
+}
+
+impl Default for SparkplugConfig {
+    fn default() -> Self {
+        Self {
+            group_id: "ptnet".to_string(),
+            edge_node_id: "ptnet-mgrd".to_string()
+        }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum MetricValue {
+    UInt64(u64),
+    Double(f64),
+    Boolean(bool),
+    String(String)
+}
+
+impl MetricValue {
+    fn datatype(&self) -> u32 {
+        match self {
+            MetricValue::UInt64(_) => DATATYPE_UINT64,
+            MetricValue::Double(_) => DATATYPE_DOUBLE,
+            MetricValue::Boolean(_) => DATATYPE_BOOLEAN,
+            MetricValue::String(_) => DATATYPE_STRING
+        }
+    }
+
+    /// First number found in JSON form of value, device values don't have common numeric accessor
+    pub fn from_json(value: &serde_json::Value) -> Option<MetricValue> {
+        match value {
+            serde_json::Value::Number(n) => n.as_u64().map(MetricValue::UInt64).or(n.as_f64().map(MetricValue::Double)),
+            serde_json::Value::Bool(b) => Some(MetricValue::Boolean(*b)),
+            serde_json::Value::Object(fields) => fields.values().find_map(MetricValue::from_json),
+            serde_json::Value::Array(items) => items.iter().find_map(MetricValue::from_json),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Metric {
+    /// sent only in births, data messages use alias
+    pub name: Option<String>,
+    pub alias: Option<u64>,
+    /// ms since unix epoch
+    pub timestamp: u64,
+    pub value: MetricValue
+}
+
+/// Protobuf wire encoding of Sparkplug B payload
+#[derive(Debug,Clone,PartialEq)]
+pub struct Payload {
+    pub timestamp: u64,
+    pub metrics: Vec<Metric>,
+    pub seq: Option<u64>
+}
+
+#[derive(Debug)]
+pub struct DecodeError;
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Malformed Sparkplug payload")
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn put_key(buf: &mut Vec<u8>, field: u32, wire_type: u8) {
+    put_varint(buf, (u64::from(field) << 3) | u64::from(wire_type));
+}
+
+fn put_bytes(buf: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    put_key(buf, field, 2);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn get_varint(buf: &[u8], pos: &mut usize) -> Result<u64, DecodeError> {
+    let mut v = 0u64;
+
+    for shift in (0..64).step_by(7) {
+        let b = *buf.get(*pos).ok_or(DecodeError)?;
+        *pos += 1;
+        v |= u64::from(b & 0x7F) << shift;
+        if b & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+
+    Err(DecodeError)
+}
+
+/// Field number and raw value of next field, length-delimited values are returned as slices
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed
+}
+
+fn get_field<'a>(buf: &'a [u8], pos: &mut usize) -> Result<(u32, Field<'a>), DecodeError> {
+    let key = get_varint(buf, pos)?;
+    let field = (key >> 3) as u32;
+
+    let value = match key & 0x07 {
+        0 => Field::Varint(get_varint(buf, pos)?),
+        1 => { *pos += 8; Field::Fixed },
+        2 => {
+            let len = get_varint(buf, pos)? as usize;
+            let bytes = buf.get(*pos..pos.checked_add(len).ok_or(DecodeError)?).ok_or(DecodeError)?;
+            *pos += len;
+            Field::Bytes(bytes)
+        },
+        5 => { *pos += 4; Field::Fixed },
+        _ => return Err(DecodeError)
+    };
+
+    match *pos <= buf.len() {
+        true => Ok((field, value)),
+        false => Err(DecodeError)
+    }
+}
+
+impl Metric {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        if let Some(name) = &self.name {
+            put_bytes(&mut buf, 1, name.as_bytes());
+        }
+        if let Some(alias) = self.alias {
+            put_key(&mut buf, 2, 0);
+            put_varint(&mut buf, alias);
+        }
+        put_key(&mut buf, 3, 0);
+        put_varint(&mut buf, self.timestamp);
+        put_key(&mut buf, 4, 0);
+        put_varint(&mut buf, u64::from(self.value.datatype()));
+
+        match &self.value {
+            MetricValue::UInt64(v) => { put_key(&mut buf, 11, 0); put_varint(&mut buf, *v); },
+            MetricValue::Double(v) => { put_key(&mut buf, 13, 1); buf.extend_from_slice(&v.to_le_bytes()); },
+            MetricValue::Boolean(v) => { put_key(&mut buf, 14, 0); put_varint(&mut buf, u64::from(*v)); },
+            MetricValue::String(v) => put_bytes(&mut buf, 15, v.as_bytes())
+        }
+
+        buf
+    }
+}
+
+impl Payload {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        put_key(&mut buf, 1, 0);
+        put_varint(&mut buf, self.timestamp);
+        for metric in &self.metrics {
+            put_bytes(&mut buf, 2, &metric.encode());
+        }
+        if let Some(seq) = self.seq {
+            put_key(&mut buf, 3, 0);
+            put_varint(&mut buf, seq);
+        }
+
+        buf
+    }
+
+    /// Names and boolean values of metrics, enough to recognize node control commands
+    pub fn decode_booleans(buf: &[u8]) -> Result<Vec<(String, bool)>, DecodeError> {
+        let mut metrics = Vec::new();
+        let mut pos = 0;
+
+        while pos < buf.len() {
+            if let (2, Field::Bytes(metric)) = get_field(buf, &mut pos)? {
+                let (mut name, mut value) = (None, None);
+                let mut mpos = 0;
+
+                while mpos < metric.len() {
+                    match get_field(metric, &mut mpos)? {
+                        (1, Field::Bytes(bytes)) => name = Some(String::from_utf8_lossy(bytes).into_owned()),
+                        (14, Field::Varint(v)) => value = Some(v != 0),
+                        _ => {}
+                    }
+                }
+
+                if let (Some(name), Some(value)) = (name, value) {
+                    metrics.push((name, value));
+                }
+            }
+        }
+
+        Ok(metrics)
+    }
+}
+
+/// Device metric, births declare all of them
+struct DeviceMetric {
+    alias: u64,
+    timestamp: u64,
+    value: MetricValue
+}
+
+/// Sparkplug state of gateway as edge node, nodes are its devices
+pub struct EdgeNode {
+    conf: SparkplugConfig,
+    /// site ID and labels, declared by NBIRTH
+    site: Vec<(String, String)>,
+    /// birth/death sequence, increases with every MQTT session
+    bd_seq: u64,
+    /// message sequence, 0 is NBIRTH
+    seq: u64,
+    next_alias: u64,
+    devices: BTreeMap<String, BTreeMap<String, DeviceMetric>>
+}
+
+impl EdgeNode {
+    pub fn new(conf: SparkplugConfig, site: &SiteConfig) -> Self {
+        let mut site_metrics = vec![("Properties/Site".to_string(), site.id.clone())];
+        site_metrics.extend(site.labels.iter().map(|(key, value)| (format!("Properties/Labels/{}", key), value.clone())));
+
+        EdgeNode {
+            conf: conf,
+            site: site_metrics,
+            bd_seq: 0,
+            seq: 0,
+            next_alias: 1,
+            devices: BTreeMap::new()
+        }
+    }
+
+    fn topic(&self, kind: &str, device: Option<&str>) -> String {
+        match device {
+            None => format!("spBv1.0/{}/{}/{}", self.conf.group_id, kind, self.conf.edge_node_id),
+            Some(device) => format!("spBv1.0/{}/{}/{}/{}", self.conf.group_id, kind, self.conf.edge_node_id, device)
+        }
+    }
+
+    pub fn command_topic(&self) -> String {
+        self.topic("NCMD", None)
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        let seq = self.seq;
+        self.seq = (self.seq + 1) % 256;
+        seq
+    }
+
+    /// Last will of current session
+    pub fn death(&self) -> (String, Vec<u8>) {
+        let payload = Payload {
+            timestamp: now_ms(),
+            metrics: vec![Metric { name: Some(BDSEQ_METRIC.to_string()), alias: None, timestamp: now_ms(), value: MetricValue::UInt64(self.bd_seq) }],
+            seq: None
+        };
+
+        (self.topic("NDEATH", None), payload.encode())
+    }
+
+    /// Previous session ended, last will of next one differs
+    pub fn next_session(&mut self) {
+        self.bd_seq = (self.bd_seq + 1) % 256;
+    }
+
+    fn device_birth(&mut self, device: &str) -> (String, Vec<u8>) {
+        let metrics = self.devices.get(device).map(|metrics| metrics.iter().map(|(name, metric)| Metric {
+            name: Some(name.clone()),
+            alias: Some(metric.alias),
+            timestamp: metric.timestamp,
+            value: metric.value.clone()
+        }).collect()).unwrap_or_default();
+
+        let payload = Payload { timestamp: now_ms(), metrics: metrics, seq: Some(self.next_seq()) };
+        (self.topic("DBIRTH", Some(device)), payload.encode())
+    }
+
+    /// NBIRTH and DBIRTH of every known device, sequence starts over
+    pub fn births(&mut self) -> Vec<(String, Vec<u8>)> {
+        self.seq = 0;
+
+        let now = now_ms();
+        let mut metrics = vec![
+            Metric { name: Some(BDSEQ_METRIC.to_string()), alias: None, timestamp: now, value: MetricValue::UInt64(self.bd_seq) },
+            Metric { name: Some(REBIRTH_METRIC.to_string()), alias: None, timestamp: now, value: MetricValue::Boolean(false) }
+        ];
+        metrics.extend(self.site.iter().map(|(name, value)| Metric { name: Some(name.clone()), alias: None, timestamp: now, value: MetricValue::String(value.clone()) }));
+
+        let node_birth = Payload {
+            timestamp: now,
+            metrics: metrics,
+            seq: Some(self.next_seq())
+        };
+
+        let mut messages = vec![(self.topic("NBIRTH", None), node_birth.encode())];
+        let devices: Vec<String> = self.devices.keys().cloned().collect();
+        for device in devices {
+            messages.push(self.device_birth(&device));
+        }
+
+        messages
+    }
+
+    /// Make node known as device, returns its DBIRTH if it's new
+    pub fn add_device(&mut self, address: &NodeAddress) -> Option<(String, Vec<u8>)> {
+        let device = device_id(address);
+
+        match self.devices.contains_key(&device) {
+            true => None,
+            false => {
+                self.devices.insert(device.clone(), BTreeMap::new());
+                Some(self.device_birth(&device))
+            }
+        }
+    }
+
+    /// Forget device, returns its DDEATH if it was known
+    pub fn remove_device(&mut self, address: &NodeAddress) -> Option<(String, Vec<u8>)> {
+        let device = device_id(address);
+        self.devices.remove(&device)?;
+
+        let payload = Payload { timestamp: now_ms(), metrics: Vec::new(), seq: Some(self.next_seq()) };
+        Some((self.topic("DDEATH", Some(&device)), payload.encode()))
+    }
+
+    /// Update metric of device, new metrics need DBIRTH, known ones are sent as DDATA by alias
+    pub fn update(&mut self, address: &NodeAddress, name: &str, value: MetricValue, timestamp: u64) -> (String, Vec<u8>) {
+        let device = device_id(address);
+        let next_alias = &mut self.next_alias;
+        let metrics = self.devices.entry(device.clone()).or_default();
+
+        let alias = match metrics.get_mut(name) {
+            Some(metric) => {
+                // type of metric can't change without birth
+                let same_type = metric.value.datatype() == value.datatype();
+                metric.timestamp = timestamp;
+                metric.value = value.clone();
+                Some(metric.alias).filter(|_| same_type)
+            },
+            None => {
+                metrics.insert(name.to_string(), DeviceMetric { alias: *next_alias, timestamp: timestamp, value: value.clone() });
+                *next_alias += 1;
+                None
+            }
+        };
+
+        let alias = match alias {
+            Some(alias) => alias,
+            None => return self.device_birth(&device)
+        };
+
+        let payload = Payload {
+            timestamp: now_ms(),
+            metrics: vec![Metric { name: None, alias: Some(alias), timestamp: timestamp, value: value }],
+            seq: Some(self.next_seq())
+        };
+
+        (self.topic("DDATA", Some(&device)), payload.encode())
+    }
+}
+
+/// Device id of node, hex digits without separators
+fn device_id(address: &NodeAddress) -> String {
+    address.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aliases_and_sequence() {
+        let mut edge = EdgeNode::new(Default::default(), &Default::default());
+        let address = [1, 2, 3, 4, 5, 6];
+
+        let births = edge.births();
+        assert_eq!("spBv1.0/ptnet/NBIRTH/ptnet-mgrd", births[0].0);
+
+        // new metric is declared by birth, then sent by alias
+        assert_eq!("spBv1.0/ptnet/DBIRTH/ptnet-mgrd/010203040506", edge.update(&address, "energy", MetricValue::UInt64(1), 10).0);
+        assert_eq!("spBv1.0/ptnet/DDATA/ptnet-mgrd/010203040506", edge.update(&address, "energy", MetricValue::UInt64(2), 20).0);
+        assert_eq!(None, edge.add_device(&address));
+        assert_eq!(3, edge.seq);
+
+        let metric = &edge.devices["010203040506"]["energy"];
+        assert_eq!((1, 20), (metric.alias, metric.timestamp));
+    }
+
+    #[test]
+    fn rebirth_command() {
+        let payload = Payload {
+            timestamp: 1,
+            metrics: vec![Metric { name: Some(REBIRTH_METRIC.to_string()), alias: None, timestamp: 1, value: MetricValue::Boolean(true) }],
+            seq: None
+        };
+
+        let metrics = Payload::decode_booleans(&payload.encode()).unwrap();
+        assert_eq!(vec![(REBIRTH_METRIC.to_string(), true)], metrics);
+        assert!(Payload::decode_booleans(&[0x12, 0x05, 0x0A]).is_err());
+    }
+}
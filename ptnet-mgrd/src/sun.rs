@@ -0,0 +1,116 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Utc};
+use serde::{Serialize, Deserialize};
+
+/// Geographic position of site, sun events are computed for it
+#[derive(Debug,Clone,Copy,Serialize,Deserialize,PartialEq)]
+pub struct Location {
+    /// degrees, north positive
+    pub latitude: f64,
+    /// degrees, east positive
+    pub longitude: f64
+}
+
+#[derive(Debug,Clone,Copy,Serialize,Deserialize,PartialEq,Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SunEvent {
+    /// civil dawn, sun 6° below horizon
+    Dawn,
+    Sunrise,
+    Sunset,
+    /// civil dusk, sun 6° below horizon
+    Dusk
+}
+
+impl SunEvent {
+    /// zenith angle of sun at event (degrees), refraction included for sunrise and sunset
+    fn zenith(&self) -> f64 {
+        match self {
+            SunEvent::Dawn | SunEvent::Dusk => 96.0,
+            SunEvent::Sunrise | SunEvent::Sunset => 90.833
+        }
+    }
+
+    fn rising(&self) -> bool {
+        matches!(self, SunEvent::Dawn | SunEvent::Sunrise)
+    }
+}
+
+fn normalize(value: f64, range: f64) -> f64 {
+    value.rem_euclid(range)
+}
+
+/// Hour of day (UTC) of event, None if it doesn't happen that day (polar day or night)
+fn event_hour(day_of_year: u32, location: &Location, event: SunEvent) -> Option<f64> {
+    let lng_hour = location.longitude / 15.0;
+    let t = day_of_year as f64 + (if event.rising() { 6.0 } else { 18.0 } - lng_hour) / 24.0;
+
+    // mean anomaly and true longitude of sun
+    let m = 0.9856 * t - 3.289;
+    let l = normalize(m + 1.916 * m.to_radians().sin() + 0.020 * (2.0 * m).to_radians().sin() + 282.634, 360.0);
+
+    // right ascension in the same quadrant as true longitude
+    let ra = normalize((0.91764 * l.to_radians().tan()).atan().to_degrees(), 360.0);
+    let ra = (ra + (l / 90.0).floor() * 90.0 - (ra / 90.0).floor() * 90.0) / 15.0;
+
+    let sin_dec = 0.39782 * l.to_radians().sin();
+    let cos_dec = sin_dec.asin().cos();
+
+    let cos_h = (event.zenith().to_radians().cos() - sin_dec * location.latitude.to_radians().sin())
+        / (cos_dec * location.latitude.to_radians().cos());
+    if !(-1.0..=1.0).contains(&cos_h) {
+        return None;
+    }
+
+    let h = match event.rising() {
+        true => 360.0 - cos_h.acos().to_degrees(),
+        false => cos_h.acos().to_degrees()
+    } / 15.0;
+
+    Some(normalize(h + ra - 0.06571 * t - 6.622 - lng_hour, 24.0))
+}
+
+/// Time of sun event on given local date of site, None if it doesn't happen that day
+pub fn event_time(date: NaiveDate, location: &Location, event: SunEvent) -> Option<DateTime<Utc>> {
+    let hour = event_hour(date.ordinal(), location, event)?;
+    let time = Duration::milliseconds((hour * 3_600_000.0) as i64);
+
+    // hour is UTC, event belongs to the date whose solar noon is nearest
+    let noon = Utc.from_utc_datetime(&date.and_hms_opt(12, 0, 0).unwrap()) - Duration::milliseconds((location.longitude / 15.0 * 3_600_000.0) as i64);
+    let midnight = Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap());
+
+    [-1, 0, 1].iter()
+        .map(|days| midnight + Duration::days(*days) + time)
+        .min_by_key(|candidate| (*candidate - noon).num_seconds().abs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(expected: DateTime<Utc>, actual: Option<DateTime<Utc>>) {
+        let actual = actual.unwrap();
+        assert!((actual - expected).num_minutes().abs() <= 2, "expected {}, got {}", expected, actual);
+    }
+
+    fn utc(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap()
+    }
+
+    #[test]
+    fn sun_events() {
+        let prague = Location { latitude: 50.08, longitude: 14.42 };
+        let midsummer = NaiveDate::from_ymd_opt(2023, 6, 21).unwrap();
+
+        assert_near(utc(2023, 6, 21, 2, 8), event_time(midsummer, &prague, SunEvent::Dawn));
+        assert_near(utc(2023, 6, 21, 2, 52), event_time(midsummer, &prague, SunEvent::Sunrise));
+        assert_near(utc(2023, 6, 21, 19, 16), event_time(midsummer, &prague, SunEvent::Sunset));
+        assert_near(utc(2023, 6, 21, 20, 0), event_time(midsummer, &prague, SunEvent::Dusk));
+
+        // local morning is previous day in UTC
+        let sydney = Location { latitude: -33.87, longitude: 151.21 };
+        assert_near(utc(2023, 12, 20, 18, 41), event_time(NaiveDate::from_ymd_opt(2023, 12, 21).unwrap(), &sydney, SunEvent::Sunrise));
+
+        let svalbard = Location { latitude: 78.2, longitude: 15.6 };
+        assert_eq!(None, event_time(midsummer, &svalbard, SunEvent::Sunset));
+    }
+}
@@ -0,0 +1,87 @@
+use std::{sync::Arc, time::Duration};
+
+use log::{info, warn};
+use sd_notify::NotifyState;
+use tokio::{sync::broadcast, time::interval, select};
+
+use crate::{client_connection::ConnectionEvent, database::Database, watchdog::Heartbeat};
+
+/// How often status is refreshed when systemd watchdog is disabled
+const STATUS_PERIOD: Duration = Duration::from_secs(10);
+
+/// Reports readiness and status to systemd, pets its watchdog while daemon is healthy
+///
+/// Connection task beats every `watchdog.check` seconds, `WatchdogSec` of unit must be longer.
+pub struct SystemdNotifier {
+    db: &'static Database<'static>,
+    heartbeat: Arc<Heartbeat>,
+    conn_events: broadcast::Receiver<ConnectionEvent>
+}
+
+impl SystemdNotifier {
+    pub fn new(db: &'static Database<'static>, heartbeat: Arc<Heartbeat>, conn_events: broadcast::Receiver<ConnectionEvent>) -> Self {
+        Self {
+            db: db,
+            heartbeat: heartbeat,
+            conn_events: conn_events
+        }
+    }
+
+    /// Status line shown by `systemctl status`, fails if database can't be read
+    fn status(&self, connected: bool) -> Result<String, Box<dyn std::error::Error>> {
+        let nodes = self.db.nodes.len()?;
+        let updating = self.db.fwu_state.list()?.iter().filter(|(_, rec)| rec.attempt.is_some()).count();
+
+        Ok(format!("{}, {} nodes, {} updates in progress", if connected { "connected" } else { "disconnected" }, nodes, updating))
+    }
+
+    pub async fn run(mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut usec = 0;
+        let watchdog = sd_notify::watchdog_enabled(false, &mut usec).then(|| Duration::from_micros(usec));
+
+        let mut ticker = interval(watchdog.map_or(STATUS_PERIOD, |timeout| (timeout / 2).min(STATUS_PERIOD)));
+        let mut connected = false;
+
+        sd_notify::notify(false, &[NotifyState::Ready, NotifyState::Status("connecting")])?;
+        info!("Ready notification sent to systemd, watchdog {:?}", watchdog);
+
+        loop {
+            select! {
+                evt = self.conn_events.recv() => match evt {
+                    Ok(ConnectionEvent::Connected(_)) => {
+                        // time spent reconnecting doesn't count
+                        self.heartbeat.beat();
+                        connected = true;
+                    },
+                    Ok(ConnectionEvent::Disconnected(_)) => connected = false,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return Ok(())
+                },
+                _ = ticker.tick() => {}
+            }
+
+            // reconnecting daemon isn't wedged, server may be down for long
+            let alive = !connected || watchdog.map_or(true, |timeout| self.heartbeat.elapsed() < timeout);
+
+            let status = match self.status(connected) {
+                Ok(status) => status,
+                Err(err) => {
+                    warn!("Database unhealthy, not petting systemd watchdog ({})", err);
+                    sd_notify::notify(false, &[NotifyState::Status(&format!("database error: {}", err))])?;
+                    continue;
+                }
+            };
+
+            if !alive {
+                warn!("Connection task silent for {}s, not petting systemd watchdog", self.heartbeat.elapsed().as_secs());
+                sd_notify::notify(false, &[NotifyState::Status(&format!("wedged, {}", status))])?;
+                continue;
+            }
+
+            match watchdog {
+                Some(_) => sd_notify::notify(false, &[NotifyState::Watchdog, NotifyState::Status(&status)])?,
+                None => sd_notify::notify(false, &[NotifyState::Status(&status)])?
+            }
+        }
+    }
+}
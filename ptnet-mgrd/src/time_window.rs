@@ -0,0 +1,127 @@
+use chrono::{NaiveDateTime, NaiveTime, Weekday, Datelike, Duration, Local};
+use serde::{Serialize, Deserialize};
+
+/// Recurring daily time window in local time
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub struct TimeWindow {
+    /// window start
+    pub from: NaiveTime,
+    /// window end, window spans midnight if it's before `from`
+    pub to: NaiveTime,
+    /// days on which window starts, every day if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>
+}
+
+impl TimeWindow {
+    fn starts_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    pub fn contains(&self, t: &NaiveDateTime) -> bool {
+        let time = t.time();
+
+        if self.from <= self.to {
+            self.starts_on(t.weekday()) && time >= self.from && time < self.to
+        } else {
+            (time >= self.from && self.starts_on(t.weekday()))
+                || (time < self.to && self.starts_on((*t - Duration::days(1)).weekday()))
+        }
+    }
+}
+
+/// When firmware updates may be started
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq,Default)]
+#[serde(default)]
+pub struct UpdateWindows {
+    /// updates may start only inside these windows, anytime if empty
+    pub allowed: Vec<TimeWindow>,
+    /// updates never start inside these windows
+    pub blackout: Vec<TimeWindow>
+}
+
+impl UpdateWindows {
+    pub fn permits(&self, t: &NaiveDateTime) -> bool {
+        (self.allowed.is_empty() || self.allowed.iter().any(|w| w.contains(t)))
+            && !self.blackout.iter().any(|w| w.contains(t))
+    }
+
+    pub fn permits_now(&self) -> bool {
+        self.permits(&Local::now().naive_local())
+    }
+
+    /// First minute from `t` on updates may start, None if windows don't open within a week
+    pub fn next_permitted(&self, t: &NaiveDateTime) -> Option<NaiveDateTime> {
+        (0..=7 * 24 * 60)
+            .map(|minutes| *t + Duration::minutes(minutes))
+            .find(|t| self.permits(t))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn at(day: u32, h: u32, m: u32) -> NaiveDateTime {
+        // 2023-05-01 is monday
+        NaiveDate::from_ymd_opt(2023, 5, day).unwrap().and_hms_opt(h, m, 0).unwrap()
+    }
+
+    fn window(from: (u32, u32), to: (u32, u32), days: Vec<Weekday>) -> TimeWindow {
+        TimeWindow {
+            from: NaiveTime::from_hms_opt(from.0, from.1, 0).unwrap(),
+            to: NaiveTime::from_hms_opt(to.0, to.1, 0).unwrap(),
+            days: days
+        }
+    }
+
+    #[test]
+    fn window_contains() {
+        let night = window((1, 0), (5, 0), vec![Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri]);
+
+        assert!(night.contains(&at(1, 1, 0)));
+        assert!(night.contains(&at(1, 4, 59)));
+        assert!(!night.contains(&at(1, 5, 0)));
+        assert!(!night.contains(&at(1, 12, 0)));
+        // saturday
+        assert!(!night.contains(&at(6, 2, 0)));
+    }
+
+    #[test]
+    fn window_over_midnight() {
+        let friday_night = window((22, 0), (2, 0), vec![Weekday::Fri]);
+
+        assert!(friday_night.contains(&at(5, 23, 0)));
+        // saturday morning still belongs to friday's window
+        assert!(friday_night.contains(&at(6, 1, 0)));
+        assert!(!friday_night.contains(&at(6, 23, 0)));
+        assert!(!friday_night.contains(&at(5, 1, 0)));
+    }
+
+    #[test]
+    fn blackout_wins() {
+        let windows = UpdateWindows {
+            allowed: vec![window((0, 0), (6, 0), vec![])],
+            blackout: vec![window((3, 0), (4, 0), vec![Weekday::Wed])]
+        };
+
+        assert!(windows.permits(&at(2, 3, 30)));
+        assert!(!windows.permits(&at(3, 3, 30)));
+        assert!(!windows.permits(&at(3, 12, 0)));
+        assert!(UpdateWindows::default().permits(&at(3, 12, 0)));
+    }
+
+    #[test]
+    fn next_permitted() {
+        let windows = UpdateWindows {
+            allowed: vec![window((1, 0), (5, 0), vec![Weekday::Sat])],
+            blackout: Vec::new()
+        };
+
+        assert_eq!(Some(at(6, 1, 0)), windows.next_permitted(&at(3, 12, 0)));
+        assert_eq!(Some(at(6, 2, 0)), windows.next_permitted(&at(6, 2, 0)));
+        assert_eq!(None, UpdateWindows { allowed: vec![window((1, 0), (1, 0), vec![])], blackout: Vec::new() }.next_permitted(&at(3, 12, 0)));
+    }
+}
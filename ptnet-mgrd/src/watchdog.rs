@@ -0,0 +1,120 @@
+use std::{io, sync::Mutex, time::{Duration, Instant}};
+
+use log::{debug, warn};
+use ptnet::{BIT_PRM, FC_PRM_LINK_TEST, PORT_AUTO};
+use serde::{Serialize, Deserialize};
+use tokio::time::{interval, timeout};
+
+use crate::client_connection::{ClientConnection, ClientConnectionSender, Message};
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct WatchdogConfig {
+    /// pause between liveness checks (seconds)
+    pub check: u64,
+    /// probe server after being silent this long (seconds)
+    pub probe_after: u64,
+    /// reconnect after being silent this long (seconds)
+    pub dead_after: u64
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            check: 10,
+            probe_after: 60,
+            dead_after: 180
+        }
+    }
+}
+
+/// Beats while connection is served, stale beat means connection task is wedged
+pub struct Heartbeat {
+    last: Mutex<Instant>
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Self {
+            last: Mutex::new(Instant::now())
+        }
+    }
+
+    pub fn beat(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.last.lock().unwrap().elapsed()
+    }
+}
+
+/// Detects silently dead connection to ptlink server
+pub struct Watchdog<'a> {
+    conf: WatchdogConfig,
+    conn: &'a ClientConnection,
+    sender: &'a ClientConnectionSender<'a>,
+    heartbeat: &'a Heartbeat
+}
+
+impl<'a> Watchdog<'a> {
+    pub fn new(conf: WatchdogConfig, conn: &'a ClientConnection, sender: &'a ClientConnectionSender<'a>, heartbeat: &'a Heartbeat) -> Self {
+        Self {
+            conf: conf,
+            conn: conn,
+            sender: sender,
+            heartbeat: heartbeat
+        }
+    }
+
+    /// Returns error when connection is considered dead
+    pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut ticker = interval(Duration::from_secs(self.conf.check));
+
+        loop {
+            ticker.tick().await;
+
+            let silence = self.conn.last_activity().elapsed();
+
+            if silence > Duration::from_secs(self.conf.dead_after) {
+                return Err(Box::new(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Nothing received from ptlink server for {}s", silence.as_secs())
+                )));
+            }
+
+            if silence > Duration::from_secs(self.conf.probe_after) {
+                warn!("Connection silent for {}s, probing", silence.as_secs());
+                self.probe().await?;
+            }
+
+            // watchdog shares task with dispatcher, ticking proves it's polled
+            self.heartbeat.beat();
+        }
+    }
+
+    /// Server answers every message with result, even if it can't be delivered
+    async fn probe(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let msg = Message {
+            port: PORT_AUTO,
+            header: ptnet::Header {
+                C: (BIT_PRM | FC_PRM_LINK_TEST) as u8,
+                address: [0; 6],
+            },
+            payload: Vec::new()
+        };
+
+        debug!("Probe ptlink server");
+        let result = self.sender.send_message(&msg).await?;
+
+        // outcome itself doesn't matter, receiving it refreshes activity; unanswered probe is left to
+        // `dead_after`
+        match timeout(Duration::from_secs(self.conf.check), result).await {
+            Ok(Ok(result)) => debug!("Probe answered with {:?}", result.outcome),
+            Ok(Err(_)) => warn!("Probe dropped, connection is closing"),
+            Err(_) => warn!("Probe not answered in {}s", self.conf.check)
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,264 @@
+use std::{collections::HashMap, time::Duration};
+
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use log::{debug, error, info, warn};
+use reqwest::header::CONTENT_TYPE;
+use serde::{Serialize, Deserialize};
+use sha2::Sha256;
+use tokio::time::sleep;
+
+use crate::{database::{Database, NodeAddress, NodeAddr, journal_table::{JournalEvent, JournalEntry}}, journal};
+
+/// Longest pause between delivery attempts (seconds)
+const MAX_BACKOFF: u64 = 3600;
+
+/// Events webhook may be notified of
+#[derive(Debug,Clone,Copy,Serialize,Deserialize,PartialEq,Eq,Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// node stopped responding
+    NodeOffline,
+    /// node responds again after being offline
+    NodeOnline,
+    /// firmware update attempt of node failed
+    FwuFailed,
+    AlarmRaised,
+    AlarmCleared
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+#[serde(default)]
+pub struct WebhookConfig {
+    /// identifies webhook in logs, journal position of webhook is kept under it
+    pub name: String,
+    /// URL notifications are POSTed to
+    pub url: String,
+    /// events notified, others are skipped
+    pub events: Vec<WebhookEvent>,
+    /// key of HMAC-SHA256 signature of body in `X-Ptnet-Signature` header, unsigned if not set
+    pub secret: Option<String>,
+    /// delivery attempts after the first one, notification is dropped when all fail
+    pub retries: u32,
+    /// pause before first retry, doubled with each next one (seconds)
+    pub backoff: u64,
+    /// timeout of single HTTP request (seconds)
+    pub timeout: u64
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            name: "webhook".to_string(),
+            url: String::new(),
+            events: vec![WebhookEvent::NodeOffline, WebhookEvent::FwuFailed, WebhookEvent::AlarmRaised],
+            secret: None,
+            retries: 5,
+            backoff: 10,
+            timeout: 30
+        }
+    }
+}
+
+/// Body of webhook request
+#[derive(Debug,Clone,Serialize,PartialEq)]
+pub struct Notification {
+    pub event: WebhookEvent,
+    pub site: String,
+    pub node: String,
+    /// unix time event happened
+    pub at: u64,
+    /// journal offset of event, same for redelivered notification
+    pub offset: u64,
+    /// node, firmware update state or alarm record the event comes from
+    pub details: serde_json::Value
+}
+
+/// Hex HMAC-SHA256 of body
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts key of any length");
+    mac.update(body);
+    mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Last known state of nodes, journal carries records after change only
+#[derive(Default)]
+pub struct Transitions {
+    online: HashMap<NodeAddress, Option<bool>>,
+    failures: HashMap<NodeAddress, u32>
+}
+
+impl Transitions {
+    /// Event journal entry stands for, None if it's no notifiable change.
+    /// State before restart isn't known, so first offline node or failed update seen may be notified again.
+    pub fn event(&mut self, event: &JournalEvent) -> Result<Option<(WebhookEvent, NodeAddress, serde_json::Value)>, serde_json::Error> {
+        Ok(match event {
+            JournalEvent::NodeAdded(rec) | JournalEvent::NodeModified(rec) => {
+                let previous = self.online.insert(rec.address, rec.online);
+                match (previous, rec.online) {
+                    (Some(Some(false)), Some(false)) => None,
+                    (_, Some(false)) => Some((WebhookEvent::NodeOffline, rec.address, serde_json::to_value(rec)?)),
+                    (Some(Some(false)), Some(true)) => Some((WebhookEvent::NodeOnline, rec.address, serde_json::to_value(rec)?)),
+                    _ => None
+                }
+            },
+            JournalEvent::NodeRemoved(address) => {
+                self.online.remove(address);
+                self.failures.remove(address);
+                None
+            },
+            JournalEvent::FWUState(address, rec) => {
+                let previous = self.failures.insert(*address, rec.failures).unwrap_or_default();
+                match rec.failures > previous {
+                    true => Some((WebhookEvent::FwuFailed, *address, serde_json::to_value(rec)?)),
+                    false => None
+                }
+            },
+            JournalEvent::AlarmChanged(address, rec) => {
+                let event = match rec.active {
+                    true => WebhookEvent::AlarmRaised,
+                    false => WebhookEvent::AlarmCleared
+                };
+                Some((event, *address, serde_json::to_value(rec)?))
+            }
+        })
+    }
+}
+
+/// Delivers notifications of journaled events to webhook, resumes where it stopped before restart
+pub struct WebhookNotifier {
+    conf: WebhookConfig,
+    db: &'static Database<'static>,
+    client: reqwest::Client
+}
+
+impl WebhookNotifier {
+    pub fn new(conf: WebhookConfig, db: &'static Database<'static>) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            client: reqwest::Client::builder().timeout(Duration::from_secs(conf.timeout)).build()?,
+            conf: conf,
+            db: db
+        })
+    }
+
+    /// Journal consumer name, acknowledged offset survives restarts
+    fn journal_consumer(&self) -> String {
+        format!("webhook:{}", self.conf.name)
+    }
+
+    async fn post(&self, notification: &Notification, body: &[u8]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut request = self.client.post(&self.conf.url)
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Ptnet-Event", serde_json::to_value(notification.event)?.as_str().unwrap_or_default())
+            .header("X-Ptnet-Delivery", notification.offset.to_string());
+        if let Some(secret) = &self.conf.secret {
+            request = request.header("X-Ptnet-Signature", format!("sha256={}", signature(secret, body)));
+        }
+
+        request.body(body.to_vec()).send().await?.error_for_status()?;
+
+        Ok(())
+    }
+
+    /// POST notification, retried with growing pauses; false if all attempts failed
+    async fn deliver(&self, notification: &Notification) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let body = serde_json::to_vec(notification)?;
+        let mut backoff = self.conf.backoff.max(1);
+
+        for attempt in 0..=self.conf.retries {
+            match self.post(notification, &body).await {
+                Ok(()) => return Ok(true),
+                Err(err) if attempt < self.conf.retries => {
+                    warn!("Webhook {} failed, retrying in {} s! ({})", self.conf.name, backoff, err);
+                    sleep(Duration::from_secs(backoff)).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                },
+                Err(err) => warn!("Webhook {} failed, giving up! ({})", self.conf.name, err)
+            }
+        }
+
+        Ok(false)
+    }
+
+    async fn on_journal(&self, transitions: &mut Transitions, entry: JournalEntry) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if let Some((event, address, details)) = transitions.event(&entry.event)? {
+            if self.conf.events.contains(&event) {
+                let notification = Notification {
+                    event: event,
+                    site: self.db.site().id,
+                    node: NodeAddr(address).to_string(),
+                    at: entry.at,
+                    offset: entry.offset,
+                    details: details
+                };
+
+                match self.deliver(&notification).await? {
+                    true => debug!("Webhook {} notified of {:?} of node {}", self.conf.name, event, notification.node),
+                    false => error!("Webhook {} missed {:?} of node {}!", self.conf.name, event, notification.node)
+                }
+            }
+        }
+
+        self.db.journal.ack(&self.journal_consumer(), entry.offset).map_err(|err| err.to_string())?;
+
+        Ok(())
+    }
+
+    pub async fn run(self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let acked = self.db.journal.acked(&self.journal_consumer()).map_err(|err| err.to_string())?;
+        let mut journal = Box::pin(journal::follow(self.db, acked));
+        let mut transitions = Transitions::default();
+
+        info!("Notifying webhook {} of {:?}", self.conf.name, self.conf.events);
+
+        while let Some(entry) = journal.next().await {
+            self.on_journal(&mut transitions, entry).await?;
+        }
+
+        Err("Event journal closed".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::{node_table::NodeRecord, fwu_state_table::FWUStateRecord, alarm_table::AlarmRecord};
+
+    use super::*;
+
+    #[test]
+    fn hmac_signature() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            signature("Jefe", b"what do ya want for nothing?")
+        );
+    }
+
+    #[test]
+    fn transitions() {
+        let address: NodeAddress = [0, 0, 0xDE, 0xAD, 0xBE, 0xEF];
+        let node = |online| JournalEvent::NodeModified(NodeRecord { address: address, online: online, ..Default::default() });
+        let fwu = |failures| JournalEvent::FWUState(address, FWUStateRecord { failures: failures, ..Default::default() });
+        let alarm = |active| JournalEvent::AlarmChanged(address, AlarmRecord { rule: "temp".to_string(), series: "temp".to_string(), active: active, value: 80.0, raised_at: 0, cleared_at: None });
+        let mut transitions = Transitions::default();
+        let mut event = |evt: JournalEvent| transitions.event(&evt).unwrap().map(|(event, _, _)| event);
+
+        assert_eq!(None, event(node(Some(true))));
+        assert_eq!(Some(WebhookEvent::NodeOffline), event(node(Some(false))));
+        assert_eq!(None, event(node(Some(false))), "still offline");
+        assert_eq!(Some(WebhookEvent::NodeOnline), event(node(Some(true))));
+        assert_eq!(None, event(node(Some(true))));
+
+        assert_eq!(None, event(fwu(0)));
+        assert_eq!(Some(WebhookEvent::FwuFailed), event(fwu(1)));
+        assert_eq!(None, event(fwu(1)), "retry scheduled");
+        assert_eq!(Some(WebhookEvent::FwuFailed), event(fwu(2)));
+        assert_eq!(None, event(fwu(0)), "update succeeded");
+
+        assert_eq!(Some(WebhookEvent::AlarmRaised), event(alarm(true)));
+        assert_eq!(Some(WebhookEvent::AlarmCleared), event(alarm(false)));
+
+        assert_eq!(None, event(JournalEvent::NodeRemoved(address)));
+        assert_eq!(Some(WebhookEvent::NodeOffline), event(node(Some(false))), "state of removed node is forgotten");
+    }
+}
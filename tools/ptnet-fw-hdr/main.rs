@@ -1,9 +1,10 @@
-use clap::{Parser, Subcommand, Args};
+use clap::{Parser, Subcommand, Args, ValueEnum};
 use ptnet::image_header::{self};
 use ptnet::helpers::{any_as_u8_slice_mut, any_as_u8_slice};
 use std::io::{Seek, BufWriter, Write, SeekFrom};
 use std::str::FromStr;
-use std::{path::{PathBuf}, fs::File, mem::size_of, io::{BufReader, Read}};
+use std::ffi::OsString;
+use std::{path::{Path, PathBuf}, fs::{self, File}, mem::size_of, io::{BufReader, Read}};
 
 #[derive(Parser,Debug)]
 #[command(author, version, about, long_about = None)]
@@ -16,7 +17,13 @@ struct Cli {
 #[derive(Subcommand,Debug)]
 enum Commands {
     Add(AddHeader),
-    Print(PrintHeader)
+    Print(PrintHeader),
+    /// check magics, sizes and CRCs of image, fails with reason
+    Verify(VerifyImage),
+    /// verify every image in directory
+    Scan(ScanDir),
+    /// re-stamp versions of existing image
+    Edit(EditHeader)
 }
 
 #[derive(Args,Debug)]
@@ -35,11 +42,55 @@ struct AddHeader {
     fw: String
 }
 
+#[derive(ValueEnum,Clone,Copy,Debug,PartialEq)]
+enum Format {
+    Text,
+    Json
+}
+
 #[derive(Args,Debug)]
 struct PrintHeader {
    /// input file
    #[arg(short,long="in")]
-   infile: PathBuf
+   infile: PathBuf,
+   /// output format
+   #[arg(long,value_enum,default_value_t=Format::Text)]
+   format: Format
+}
+
+#[derive(Args,Debug)]
+struct VerifyImage {
+   /// input file
+   #[arg(short,long="in")]
+   infile: PathBuf,
+   /// output format
+   #[arg(long,value_enum,default_value_t=Format::Text)]
+   format: Format
+}
+
+#[derive(Args,Debug)]
+struct EditHeader {
+    /// input file
+    #[arg(short,long="in")]
+    infile: PathBuf,
+    /// output file, input is rewritten if not set
+    #[arg(short,long="out")]
+    outfile: Option<PathBuf>,
+    /// new hardware version vid:pid:rev
+    #[arg(long)]
+    hw: Option<String>,
+    /// new firmware version major.minor.patch
+    #[arg(long)]
+    fw: Option<String>
+}
+
+#[derive(Args,Debug)]
+struct ScanDir {
+   /// firmware directory
+   dir: PathBuf,
+   /// output format
+   #[arg(long,value_enum,default_value_t=Format::Text)]
+   format: Format
 }
 
 
@@ -81,13 +132,165 @@ impl From<image_header::LoadError> for Error {
     fn from(value: image_header::LoadError) -> Self { Error::LoadError(value) }
 }
 
+fn read_image(path: &PathBuf) -> Result<Vec<u8>, Error> {
+    let mut image: Vec<u8> = Vec::new();
+    BufReader::new(File::open(path)?).read_to_end(&mut image)?;
+    Ok(image)
+}
+
+/// All header fields with CRCs and offsets in file
+fn header_json(image: &[u8], cont: &image_header::Container, payload_range: &std::ops::Range<usize>) -> serde_json::Value {
+    let fields = unsafe { cont.header.fields };
+    serde_json::json!({
+        "file_size": image.len(),
+        "header_offset": image.len().saturating_sub(size_of::<image_header::Container>()),
+        "header_size": size_of::<image_header::Container>(),
+        "header_version": fields.version,
+        "header_crc": cont.header_crc,
+        "hw_version": fields.v0.hw_version,
+        "fw_version": fields.v0.fw_version,
+        "payload_offset": payload_range.start,
+        "payload_size": fields.v0.payload_size,
+        "payload_crc": fields.v0.payload_crc
+    })
+}
+
 fn print_header(params: &PrintHeader) -> Result<(), Error> {
-    let fin = File::open(&params.infile)?;
-    let (hdr, _payload) = image_header::Container::load_from(fin)?;
-    println!("Header: {:?}", hdr);
+    if params.format == Format::Text {
+        let fin = File::open(&params.infile)?;
+        let (hdr, _payload) = image_header::Container::load_from(fin)?;
+        println!("Header: {:?}", hdr);
+        return Ok(());
+    }
+
+    let image = read_image(&params.infile)?;
+    let (cont, payload_range) = image_header::Container::parse_from(&image[..])?;
+    println!("{}", header_json(&image, &cont, &payload_range));
+    Ok(())
+}
+
+/// Parse and check CRCs of image
+fn check_image(image: &[u8]) -> Result<(image_header::Container, std::ops::Range<usize>), Error> {
+    let (cont, payload_range) = image_header::Container::parse_from(image)?;
+    cont.verify(&image[payload_range.clone()])?;
+    Ok((cont, payload_range))
+}
+
+fn verify_image(params: &VerifyImage) -> Result<(), Error> {
+    let image = read_image(&params.infile)?;
+
+    let result = check_image(&image[..]);
+
+    match (params.format, &result) {
+        (Format::Text, Ok((cont, _))) => {
+            let fields = unsafe { cont.header.fields };
+            println!("{}: OK, hw {:?} fw {}, payload {} bytes", params.infile.display(), fields.v0.hw_version, fields.v0.fw_version, fields.v0.payload_size);
+        },
+        (Format::Json, Ok((cont, payload_range))) => {
+            let mut out = header_json(&image, cont, payload_range);
+            out["file"] = serde_json::json!(params.infile);
+            out["ok"] = serde_json::json!(true);
+            println!("{}", out);
+        },
+        (Format::Json, Err(error)) => {
+            println!("{}", serde_json::json!({ "file": params.infile, "ok": false, "error": error.to_string() }));
+        },
+        (Format::Text, Err(_)) => {}
+    }
+
+    result.map(|_| ())
+}
+
+fn scan_dir(params: &ScanDir) -> Result<(), Error> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(&params.dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        // firmware index skips dot-files too
+        .filter(|path| path.is_file() && !path.file_name().and_then(|name| name.to_str()).map_or(true, |name| name.starts_with('.')))
+        .collect();
+    paths.sort();
+
+    let mut corrupt = 0;
+    let mut rows: Vec<serde_json::Value> = Vec::new();
+
+    if params.format == Format::Text {
+        println!("{:<40} {:<24} {:<12} {:>10}  {}", "FILE", "HW", "FW", "SIZE", "STATUS");
+    }
+
+    for path in paths {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let result = read_image(&path).and_then(|image| check_image(&image[..]).map(|(cont, rng)| header_json(&image, &cont, &rng)));
+
+        if result.is_err() {
+            corrupt += 1;
+        }
+
+        match (params.format, result) {
+            (Format::Text, Ok(hdr)) => println!("{:<40} {:<24} {:<12} {:>10}  OK", name, hdr["hw_version"].to_string(), hdr["fw_version"].to_string(), hdr["payload_size"]),
+            (Format::Text, Err(error)) => println!("{:<40} {:<24} {:<12} {:>10}  CORRUPT ({})", name, "-", "-", "-", error),
+            (Format::Json, Ok(mut hdr)) => {
+                hdr["file"] = serde_json::json!(name);
+                hdr["ok"] = serde_json::json!(true);
+                rows.push(hdr);
+            },
+            (Format::Json, Err(error)) => rows.push(serde_json::json!({ "file": name, "ok": false, "error": error.to_string() }))
+        }
+    }
+
+    if params.format == Format::Json {
+        println!("{}", serde_json::Value::Array(rows));
+    }
+
+    if corrupt > 0 {
+        return Err(Error::IOError(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} corrupt image(s) in {}", corrupt, params.dir.display()))));
+    }
+
     Ok(())
 }
 
+fn edit_header(params: &EditHeader) -> Result<(), Error> {
+    let image = read_image(&params.infile)?;
+    // never re-stamp corrupt image, new CRC would hide damage
+    let (mut cont, payload_range) = check_image(&image[..])?;
+
+    let fields = unsafe { &mut cont.header.fields };
+    if let Some(hw) = &params.hw {
+        fields.v0.hw_version = FromStr::from_str(hw)?;
+    }
+    if let Some(fw) = &params.fw {
+        fields.v0.fw_version = FromStr::from_str(fw)?;
+    }
+    cont.header_crc = image_header::crc(unsafe { &cont.header.raw });
+
+    write_atomically(params.outfile.as_ref().unwrap_or(&params.infile), |writer| {
+        writer.write_all(&image[payload_range])?;
+        writer.write_all(unsafe { any_as_u8_slice(&cont) })
+    })
+}
+
+/// Write `path` through temporary file in the same directory, so that
+/// failed write never leaves truncated image behind.
+fn write_atomically<F>(path: &Path, write: F) -> Result<(), Error>
+    where F: FnOnce(&mut BufWriter<File>) -> std::io::Result<()>
+{
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(path.file_name().unwrap_or_default());
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let result = File::create(&tmp_path).and_then(|fout| {
+        let mut writer = BufWriter::new(fout);
+        write(&mut writer)?;
+        writer.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        fs::rename(&tmp_path, path)
+    });
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result.map_err(Error::from)
+}
+
 fn add_header(params: &AddHeader) -> Result<(), Error> {
     let fin = File::open(&params.infile)?;
     let mut pay: Vec<u8> = Vec::new();
@@ -115,7 +318,10 @@ fn main() -> Result<(), String> {
 
     let result = match &args.command {
         Commands::Add(params) => add_header(params),
-        Commands::Print(params) => print_header(params)
+        Commands::Print(params) => print_header(params),
+        Commands::Verify(params) => verify_image(params),
+        Commands::Scan(params) => scan_dir(params),
+        Commands::Edit(params) => edit_header(params)
     };
 
     match result {
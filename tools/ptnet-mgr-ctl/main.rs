@@ -0,0 +1,438 @@
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use clap::{Parser, Subcommand};
+use ptnet::image_header::{FWVersion, HWVersion};
+use serde_json::{json, Map, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+#[derive(Parser,Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// control socket of ptnet-mgrd, preferred when it exists
+    #[arg(long, default_value = "/run/ptnet-mgrd.sock")]
+    socket: PathBuf,
+    /// HTTP API address host:port, used when control socket doesn't exist
+    #[arg(long)]
+    http: Option<String>,
+    /// bearer token for HTTP API
+    #[arg(long, env = "PTNET_MGR_TOKEN")]
+    token: Option<String>,
+    #[command(subcommand)]
+    command: Commands
+}
+
+#[derive(Subcommand,Debug)]
+enum Commands {
+    /// list all nodes
+    Nodes {
+        /// only nodes with these labels, key=value[,key=value...], tag:<tag> for tagged nodes
+        #[arg(long)]
+        label: Option<String>
+    },
+    /// replace labels of node, key=value each
+    Labels { address: String, labels: Vec<String> },
+    /// replace tags of node
+    Tags { address: String, tags: Vec<String> },
+    /// list tags with their nodes
+    ListTags,
+    /// tag nodes
+    Tag {
+        tag: String,
+        /// node to tag, may be repeated
+        #[arg(long)]
+        address: Vec<String>,
+        /// nodes with these labels, key=value[,key=value...], tag:<tag> for tagged nodes
+        #[arg(long, conflicts_with = "address")]
+        label: Option<String>
+    },
+    /// remove tag from all nodes
+    Untag { tag: String },
+    /// list groups with their members
+    Groups,
+    /// add node to group
+    Join { group: u8, address: String },
+    /// remove node from group
+    Leave { group: u8, address: String },
+    /// show one node
+    Node { address: String },
+    /// show firmware update state and history of node
+    Fwu { address: String },
+    /// list firmware updates waiting for approval
+    Approvals,
+    /// approve firmware update, version major.minor.patch
+    Approve { address: String, version: String },
+    /// reject firmware update
+    Reject { address: String },
+    /// request device status of node now
+    Scan { address: String },
+    /// read points of node now and show returned values, device status if no IOA given
+    Read {
+        address: String,
+        /// IOA to read, may be repeated
+        #[arg(long)]
+        ioa: Vec<u32>,
+        /// common address of points
+        #[arg(long)]
+        ca: Option<u8>
+    },
+    /// show intended device parameters of node and their state on device
+    Parameters { address: String },
+    /// set intended value of device parameter, written to device and kept there
+    SetParameter { address: String, ioa: u32, ti: u8, value: u32 },
+    /// stop managing device parameter
+    RemoveParameter { address: String, ioa: u32 },
+    /// send command to IOA of node
+    Command {
+        address: String,
+        ioa: u32,
+        ti: u8,
+        value: u32,
+        /// select with this value before executing
+        #[arg(long)]
+        select: Option<u32>
+    },
+    /// send the same command to many nodes, shows result of each
+    BulkCommand {
+        ioa: u32,
+        ti: u8,
+        value: u32,
+        /// node to command, may be repeated
+        #[arg(long)]
+        address: Vec<String>,
+        /// nodes with these labels, key=value[,key=value...], tag:<tag> for tagged nodes
+        #[arg(long, conflicts_with = "address")]
+        label: Option<String>,
+        /// select with this value before executing
+        #[arg(long)]
+        select: Option<u32>
+    },
+    /// list firmware versions available for each hardware version
+    Firmware,
+    /// upload firmware image to firmware directory
+    FirmwareAdd { image: PathBuf },
+    /// delete firmware image, version major.minor.patch
+    FirmwareDelete { hw: String, version: String },
+    /// show which nodes firmware updates would update, in what order and window, without updating
+    FwuPlan,
+    /// show firmware status of all nodes against firmware index and policy
+    FwCompliance {
+        /// print CSV instead of JSON, e.g. for customer reports
+        #[arg(long)]
+        csv: bool
+    },
+    /// show nodes syncing with SOL model would add, rename, orphan and purge
+    SyncReport,
+    /// list discovered nodes waiting for adoption
+    Pending,
+    /// let daemon scan, test and update pending node
+    Adopt { address: String },
+    /// remove orphaned node with its history
+    Purge { address: String },
+    /// let new node take over history and configuration of failed one
+    Replace {
+        address: String,
+        /// address of replacement
+        new: String,
+        /// write stored parameters to replacement instead of only reading them back
+        #[arg(long)]
+        push_parameters: bool
+    },
+    /// show number of nodes in each health state
+    Health,
+    /// list ptlink ports with their status
+    Ports,
+    /// list active alarms
+    Alarms,
+    /// list processes and whether they are paused
+    Processes,
+    /// pause process until resumed, also across restarts
+    Pause {
+        name: String,
+        #[arg(long)]
+        reason: Option<String>
+    },
+    /// resume paused process
+    Resume { name: String },
+    /// show cursors processes resume from after restart
+    RuntimeState,
+    /// forget cursor, e.g. nodescan.cursor to scan from first node after restart
+    ResetRuntimeState { key: String },
+    /// show size and free space of database file
+    Database,
+    /// show emergency lighting tests of node
+    EmTests { address: String },
+    /// show frames of node decoder didn't understand
+    RawFrames { address: String },
+    /// forget captured frames of node
+    RawFramesClear { address: String },
+    /// show compliance of emergency lighting with required test periods
+    EmReport {
+        /// emergency ballasts, key=value[,key=value...], never tested ones included, all tested nodes if not given
+        #[arg(long)]
+        label: Option<String>,
+        /// longest age of passed function test
+        #[arg(long)]
+        function_days: Option<u64>,
+        /// longest age of passed duration test
+        #[arg(long)]
+        duration_days: Option<u64>
+    },
+    /// list schedules with their last runs
+    Schedules,
+    /// show one schedule
+    Schedule { id: u64 },
+    /// create schedule from JSON file with name, trigger, days, steps and enabled
+    ScheduleAdd { file: PathBuf },
+    /// replace schedule with one from JSON file, last run is kept
+    ScheduleUpdate { id: u64, file: PathBuf },
+    /// remove schedule
+    ScheduleRemove { id: u64 },
+    /// show log levels, or set level of module (of all modules if not given), control socket only
+    LogLevel {
+        level: Option<String>,
+        #[arg(long)]
+        module: Option<String>
+    }
+}
+
+/// Call as JSON-RPC method name and params, and as HTTP method, path and body (empty method if not in HTTP API)
+struct Call {
+    method: &'static str,
+    params: Value,
+    http_method: &'static str,
+    path: String,
+    /// sent as HTTP body instead of params, base64 param of socket call
+    upload: Option<Vec<u8>>
+}
+
+impl Call {
+    fn new(command: &Commands) -> Result<Self, String> {
+        let call = |method, params, http_method, path: String| Call { method: method, params: params, http_method: http_method, path: path, upload: None };
+
+        Ok(match command {
+            Commands::Nodes { label: None } => call("list_nodes", Value::Null, "GET", "/nodes".to_string()),
+            Commands::Nodes { label: Some(label) } =>
+                call("list_nodes", json!({ "label": label }), "GET", format!("/nodes?label={}", label)),
+            Commands::Labels { address, labels } => {
+                let mut map = serde_json::Map::new();
+                for label in labels {
+                    match label.split_once('=') {
+                        Some((key, value)) if !key.is_empty() => map.insert(key.to_string(), json!(value)),
+                        _ => return Err(format!("Invalid label '{}', use key=value", label))
+                    };
+                }
+                call("set_labels", json!({ "address": address, "labels": map }), "PUT", format!("/nodes/{}/labels", address))
+            },
+            Commands::Tags { address, tags } => call("set_tags", json!({ "address": address, "tags": tags }), "PUT", format!("/nodes/{}/tags", address)),
+            Commands::ListTags => call("list_tags", Value::Null, "GET", "/tags".to_string()),
+            Commands::Tag { tag, address, label } => {
+                let addresses = match address.is_empty() {
+                    true => Value::Null,
+                    false => json!(address)
+                };
+                call("add_tag", json!({ "tag": tag, "addresses": addresses, "label": label }), "POST", format!("/tags/{}", tag))
+            },
+            Commands::Untag { tag } => call("remove_tag", json!({ "tag": tag }), "DELETE", format!("/tags/{}", tag)),
+            Commands::Groups => call("list_groups", Value::Null, "GET", "/groups".to_string()),
+            Commands::Join { group, address } => call("add_to_group", json!({ "group": group, "address": address }), "PUT", format!("/groups/{}/members/{}", group, address)),
+            Commands::Leave { group, address } => call("remove_from_group", json!({ "group": group, "address": address }), "DELETE", format!("/groups/{}/members/{}", group, address)),
+            Commands::Node { address } => call("get_node", json!({ "address": address }), "GET", format!("/nodes/{}", address)),
+            Commands::Fwu { address } => call("get_fwu", json!({ "address": address }), "GET", format!("/nodes/{}/fwu", address)),
+            Commands::Approvals => call("list_approvals", Value::Null, "GET", "/approvals".to_string()),
+            Commands::Approve { address, version } => {
+                let version = FWVersion::from_str(version).map_err(|err| format!("{}", err))?;
+                let version = serde_json::to_value(version).map_err(|err| err.to_string())?;
+                call("approve", json!({ "address": address, "version": version }), "POST", format!("/nodes/{}/approve", address))
+            },
+            Commands::Reject { address } => call("reject", json!({ "address": address }), "POST", format!("/nodes/{}/reject", address)),
+            Commands::Scan { address } => call("scan", json!({ "address": address }), "POST", format!("/nodes/{}/scan", address)),
+            Commands::Read { address, ioa, ca } => {
+                let mut params = json!({ "address": address });
+                if !ioa.is_empty() {
+                    params["ioas"] = json!(ioa);
+                }
+                if let Some(ca) = ca {
+                    params["ca"] = json!(ca);
+                }
+                call("read", params, "POST", format!("/nodes/{}/read", address))
+            },
+            Commands::Parameters { address } => call("get_parameters", json!({ "address": address }), "GET", format!("/nodes/{}/parameters", address)),
+            Commands::SetParameter { address, ioa, ti, value } =>
+                call("set_parameter", json!({ "address": address, "ioa": ioa, "ti": ti, "value": value }), "PUT", format!("/nodes/{}/parameters/{}", address, ioa)),
+            Commands::RemoveParameter { address, ioa } =>
+                call("remove_parameter", json!({ "address": address, "ioa": ioa }), "DELETE", format!("/nodes/{}/parameters/{}", address, ioa)),
+            Commands::Command { address, ioa, ti, value, select } => call(
+                "command",
+                json!({ "address": address, "ioa": ioa, "ti": ti, "value": value, "select": select }),
+                "POST",
+                format!("/nodes/{}/command", address)
+            ),
+            Commands::BulkCommand { ioa, ti, value, address, label, select } => {
+                let addresses = match address.is_empty() {
+                    true => Value::Null,
+                    false => json!(address)
+                };
+                call("bulk_command", json!({ "addresses": addresses, "label": label, "ioa": ioa, "ti": ti, "value": value, "select": select }), "POST", "/commands/bulk".to_string())
+            },
+            Commands::Pending => call("list_pending", Value::Null, "GET", "/adoptions".to_string()),
+            Commands::Adopt { address } => call("adopt_node", json!({ "address": address }), "POST", format!("/nodes/{}/adopt", address)),
+            Commands::Purge { address } => call("purge_node", json!({ "address": address }), "DELETE", format!("/nodes/{}", address)),
+            Commands::Replace { address, new, push_parameters } => call("replace_node", json!({ "address": address, "new": new, "push_parameters": push_parameters }), "POST", format!("/nodes/{}/replace", address)),
+            Commands::SyncReport => call("sync_report", Value::Null, "GET", "/sync-report".to_string()),
+            Commands::FwCompliance { csv: false } => call("fw_compliance", Value::Null, "GET", "/firmware/compliance".to_string()),
+            Commands::FwCompliance { csv: true } => call("fw_compliance", json!({ "format": "csv" }), "GET", "/firmware/compliance?format=csv".to_string()),
+            Commands::FwuPlan => call("fwu_plan", Value::Null, "GET", "/firmware/plan".to_string()),
+            Commands::Firmware => call("list_firmware", Value::Null, "GET", "/firmware".to_string()),
+            Commands::FirmwareAdd { image } => {
+                let image = std::fs::read(image).map_err(|err| format!("{}: {}", image.display(), err))?;
+                Call { upload: Some(image.clone()), ..call("add_firmware", json!({ "image": BASE64.encode(&image) }), "POST", "/firmware".to_string()) }
+            },
+            Commands::FirmwareDelete { hw, version } => {
+                let hw = HWVersion::from_str(hw).map_err(|err| format!("{}", err))?;
+                let version = FWVersion::from_str(version).map_err(|err| format!("{}", err))?;
+                let params = json!({
+                    "hw_version": serde_json::to_value(hw).map_err(|err| err.to_string())?,
+                    "fw_version": serde_json::to_value(version).map_err(|err| err.to_string())?
+                });
+                call("delete_firmware", params, "DELETE", "/firmware".to_string())
+            },
+            Commands::Health => call("health", Value::Null, "GET", "/health".to_string()),
+            Commands::Ports => call("list_ports", Value::Null, "GET", "/ports".to_string()),
+            Commands::Alarms => call("list_alarms", Value::Null, "GET", "/alarms".to_string()),
+            Commands::Processes => call("list_processes", Value::Null, "GET", "/processes".to_string()),
+            Commands::Pause { name, reason } =>
+                call("pause_process", json!({ "name": name, "reason": reason }), "POST", format!("/processes/{}/pause", name)),
+            Commands::Resume { name } => call("resume_process", json!({ "name": name }), "POST", format!("/processes/{}/resume", name)),
+            Commands::RuntimeState => call("runtime_state", Value::Null, "GET", "/runtime-state".to_string()),
+            Commands::ResetRuntimeState { key } => call("reset_runtime_state", json!({ "key": key }), "DELETE", format!("/runtime-state/{}", key)),
+            Commands::Database => call("storage_stats", Value::Null, "GET", "/database".to_string()),
+            Commands::EmTests { address } => call("get_em_tests", json!({ "address": address }), "GET", format!("/nodes/{}/em-tests", address)),
+            Commands::RawFrames { address } => call("get_raw_frames", json!({ "address": address }), "GET", format!("/nodes/{}/raw-frames", address)),
+            Commands::RawFramesClear { address } => call("clear_raw_frames", json!({ "address": address }), "DELETE", format!("/nodes/{}/raw-frames", address)),
+            Commands::EmReport { label, function_days, duration_days } => {
+                let query: Vec<String> = [("label", label.clone()), ("function_days", function_days.map(|d| d.to_string())), ("duration_days", duration_days.map(|d| d.to_string()))]
+                    .into_iter()
+                    .filter_map(|(key, value)| value.map(|value| format!("{}={}", key, value)))
+                    .collect();
+                let path = match query.is_empty() {
+                    true => "/em-report".to_string(),
+                    false => format!("/em-report?{}", query.join("&"))
+                };
+                call("em_report", json!({ "label": label, "function_days": function_days, "duration_days": duration_days }), "GET", path)
+            },
+            Commands::Schedules => call("list_schedules", Value::Null, "GET", "/schedules".to_string()),
+            Commands::Schedule { id } => call("get_schedule", json!({ "id": id }), "GET", format!("/schedules/{}", id)),
+            Commands::ScheduleAdd { file } => call("create_schedule", Value::Object(read_json(file)?), "POST", "/schedules".to_string()),
+            Commands::ScheduleUpdate { id, file } => {
+                let mut params = read_json(file)?;
+                params.insert("id".to_string(), json!(id));
+                call("update_schedule", Value::Object(params), "PUT", format!("/schedules/{}", id))
+            },
+            Commands::ScheduleRemove { id } => call("remove_schedule", json!({ "id": id }), "DELETE", format!("/schedules/{}", id)),
+            Commands::LogLevel { level: None, .. } => call("get_log_levels", Value::Null, "", String::new()),
+            Commands::LogLevel { level: Some(level), module } =>
+                call("set_log_level", json!({ "module": module, "level": level }), "", String::new())
+        })
+    }
+
+    /// Params without address, which is part of HTTP path
+    fn http_body(&self) -> Option<Value> {
+        match (self.http_method, &self.params) {
+            ("POST", Value::Object(params)) | ("PUT", Value::Object(params)) | ("DELETE", Value::Object(params)) => {
+                let mut body = params.clone();
+                body.remove("address");
+                Some(Value::Object(body))
+            },
+            _ => None
+        }
+    }
+}
+
+/// JSON object from file
+fn read_json(file: &PathBuf) -> Result<Map<String, Value>, String> {
+    let text = std::fs::read_to_string(file).map_err(|err| format!("{}: {}", file.display(), err))?;
+    serde_json::from_str(&text).map_err(|err| format!("{}: {}", file.display(), err))
+}
+
+fn call_socket(socket: &PathBuf, call: &Call) -> Result<Value, String> {
+    let mut stream = UnixStream::connect(socket).map_err(|err| format!("{}: {}", socket.display(), err))?;
+
+    let mut request = serde_json::to_vec(&json!({ "jsonrpc": "2.0", "id": 1, "method": call.method, "params": call.params }))
+        .map_err(|err| err.to_string())?;
+    request.push(b'\n');
+    stream.write_all(&request).map_err(|err| err.to_string())?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).map_err(|err| err.to_string())?;
+    let response: Value = serde_json::from_str(&line).map_err(|err| err.to_string())?;
+
+    match response.get("error") {
+        Some(error) => Err(error.get("message").and_then(Value::as_str).unwrap_or("Unknown error").to_string()),
+        None => Ok(response.get("result").cloned().unwrap_or(Value::Null))
+    }
+}
+
+fn call_http(addr: &str, token: Option<&str>, call: &Call) -> Result<Value, String> {
+    if call.http_method.is_empty() {
+        return Err(format!("{} is available only on control socket", call.method));
+    }
+
+    let mut stream = TcpStream::connect(addr).map_err(|err| format!("{}: {}", addr, err))?;
+    let (content_type, body) = match (&call.upload, call.http_body()) {
+        (Some(upload), _) => ("application/octet-stream", upload.clone()),
+        (None, Some(body)) => ("application/json", body.to_string().into_bytes()),
+        (None, None) => ("application/json", Vec::new())
+    };
+
+    let authorization = match token {
+        Some(token) => format!("Authorization: Bearer {}\r\n", token),
+        None => String::new()
+    };
+
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n{}Content-Type: {}\r\nContent-Length: {}\r\n\r\n",
+        call.http_method, call.path, addr, authorization, content_type, body.len()
+    ).map_err(|err| err.to_string())?;
+    stream.write_all(&body).map_err(|err| err.to_string())?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).map_err(|err| err.to_string())?;
+
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(|| "Malformed HTTP response".to_string())?;
+    let status: u16 = head.split_whitespace().nth(1).and_then(|s| s.parse().ok())
+        .ok_or_else(|| "Malformed HTTP response".to_string())?;
+    let body: Value = match (body.trim(), head.to_ascii_lowercase().contains("content-type: text/")) {
+        ("", _) => Value::Null,
+        // reports exported as text, e.g. CSV
+        (_, true) => Value::String(body.to_string()),
+        (body, false) => serde_json::from_str(body).map_err(|err| err.to_string())?
+    };
+
+    match status {
+        200..=299 => Ok(body),
+        _ => Err(body.get("error").and_then(Value::as_str).map(str::to_string).unwrap_or(format!("HTTP status {}", status)))
+    }
+}
+
+fn main() -> Result<(), String> {
+    let args = Cli::parse();
+    let call = Call::new(&args.command)?;
+
+    let result = match (args.socket.exists(), &args.http) {
+        (true, _) => call_socket(&args.socket, &call)?,
+        (false, Some(addr)) => call_http(addr, args.token.as_deref(), &call)?,
+        (false, None) => return Err(format!("Control socket {} doesn't exist and --http not given", args.socket.display()))
+    };
+
+    match result {
+        Value::Null => {},
+        Value::String(text) => print!("{}", text),
+        result => println!("{}", serde_json::to_string_pretty(&result).map_err(|err| err.to_string())?)
+    }
+
+    Ok(())
+}
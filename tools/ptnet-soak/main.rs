@@ -0,0 +1,218 @@
+use clap::Parser;
+use ptnet::{PtNetPacket, ASDHConstruct, DUIConstruct, COT, IE, FC, MessageResultCode, MAGIC_MESSAGE, MAGIC_RESULT, MAGIC_SERVER_MESSAGE};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Fake ptlink server flooding ptnet-mgrd with synthetic traffic
+#[derive(Parser,Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// address ptnet-mgrd connects to, its `server_address`
+    #[arg(long, default_value = "127.0.0.1:9885")]
+    listen: String,
+    /// IOBs sent per second
+    #[arg(long, default_value_t = 10000)]
+    rate: u32,
+    /// IOBs packed into one message
+    #[arg(long, default_value_t = 4)]
+    iobs_per_message: u32,
+    /// number of simulated nodes
+    #[arg(long, default_value_t = 1000)]
+    nodes: u32,
+    /// common address, IOA and type of generated points
+    #[arg(long, default_value_t = 0x3E)]
+    ca: u8,
+    #[arg(long, default_value_t = 0x100)]
+    ioa: u32,
+    #[arg(long, default_value_t = 48)]
+    ti: u8,
+    /// stop after this many seconds, run until killed if not given
+    #[arg(long)]
+    duration: Option<u64>,
+    /// report throughput every N seconds
+    #[arg(long, default_value_t = 1)]
+    report: u64
+}
+
+#[derive(Default)]
+struct Counters {
+    iobs_sent: AtomicU64,
+    messages_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    /// messages sent by ptnet-mgrd, all of them are confirmed as delivered
+    requests: AtomicU64
+}
+
+// Function that converts to byte array. (found on stackoverflow)
+unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
+    ::std::slice::from_raw_parts((p as *const T) as *const u8, ::std::mem::size_of::<T>())
+}
+
+unsafe fn any_as_u8_slice_mut<T: Sized>(p: &mut T) -> &mut [u8] {
+    ::std::slice::from_raw_parts_mut((p as *mut T) as *mut u8, ::std::mem::size_of::<T>())
+}
+
+fn node_address(n: u32) -> [u8; 6] {
+    let b = n.to_be_bytes();
+    [0x02, 0x50, b[0], b[1], b[2], b[3]]
+}
+
+/// Spontaneous message of node carrying `count` points with changing values
+fn build_payload(cli: &Cli, seq: u64, count: u32) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut buf = packet::buffer::Dynamic::new();
+    let mut builder = PtNetPacket::with_asdh(&ptnet::ASDH::with(cli.ca, COT::REQ, false), &mut buf)?
+        .begin_asdu(&ptnet::DUI::with_direct(cli.ti, count as u8, false))?;
+
+    for i in 0..count {
+        let value = (seq as u32).wrapping_add(i);
+        builder = builder
+            .add_ioa(cli.ioa + i)?
+            .add_ie(&IE::from_bytes(cli.ti, &value.to_le_bytes())?)?;
+    }
+    builder.end_asdu()?;
+
+    Ok(buf.into())
+}
+
+/// Write IOBs at requested rate, paced in 10ms slices
+fn generate(cli: &Cli, mut stream: TcpStream, counters: &Counters) -> Result<(), Box<dyn std::error::Error>> {
+    let slice = Duration::from_millis(10);
+    let per_message = cli.iobs_per_message.clamp(1, 32);
+    let started = Instant::now();
+    let mut seq: u64 = 0;
+
+    loop {
+        let due = (started.elapsed().as_secs_f64() * f64::from(cli.rate)) as u64;
+
+        while counters.iobs_sent.load(Ordering::Relaxed) < due {
+            let payload = build_payload(cli, seq, per_message)?;
+            let raw_msg = ptnet::ServerMessage {
+                iPort: 0,
+                header: ptnet::Header {
+                    C: (ptnet::BIT_PRM as u8) | (FC::PrmSendNoreply as u8),
+                    address: node_address((seq % u64::from(cli.nodes.max(1))) as u32)
+                },
+                payloadLength: payload.len() as u8
+            };
+
+            unsafe {
+                stream.write_all(any_as_u8_slice(&MAGIC_SERVER_MESSAGE))?;
+                stream.write_all(any_as_u8_slice(&raw_msg))?;
+            }
+            stream.write_all(&payload)?;
+
+            seq += 1;
+            counters.iobs_sent.fetch_add(u64::from(per_message), Ordering::Relaxed);
+            counters.messages_sent.fetch_add(1, Ordering::Relaxed);
+            counters.bytes_sent.fetch_add(payload.len() as u64, Ordering::Relaxed);
+        }
+
+        thread::sleep(slice);
+    }
+}
+
+/// Confirm every message of ptnet-mgrd as delivered, so its retries stay out of the numbers
+fn confirm(mut stream: TcpStream, counters: &Counters) -> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        let mut magic: ptnet::magic_t = 0;
+        let mut raw_msg = ptnet::Message {
+            id: 0,
+            iPort: 0,
+            header: ptnet::Header { C: 0, address: [0; 6] },
+            payloadLength: 0
+        };
+
+        unsafe {
+            stream.read_exact(any_as_u8_slice_mut(&mut magic))?;
+        }
+        if magic != MAGIC_MESSAGE {
+            return Err(format!("Unexpected magic {:#x}", magic as u32).into());
+        }
+        unsafe {
+            stream.read_exact(any_as_u8_slice_mut(&mut raw_msg))?;
+        }
+
+        let mut payload = vec![0; usize::from(raw_msg.payloadLength)];
+        stream.read_exact(&mut payload)?;
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+
+        let result = ptnet::MessageResult { msgId: raw_msg.id, result: MessageResultCode::Delivered as u16 };
+        unsafe {
+            stream.write_all(any_as_u8_slice(&MAGIC_RESULT))?;
+            stream.write_all(any_as_u8_slice(&result))?;
+        }
+    }
+}
+
+fn report(cli: &Cli, counters: &Counters) {
+    let started = Instant::now();
+    let mut last = (0, 0, 0, 0);
+    let mut last_at = started;
+
+    loop {
+        thread::sleep(Duration::from_secs(cli.report.max(1)));
+
+        let now = (
+            counters.iobs_sent.load(Ordering::Relaxed),
+            counters.messages_sent.load(Ordering::Relaxed),
+            counters.bytes_sent.load(Ordering::Relaxed),
+            counters.requests.load(Ordering::Relaxed)
+        );
+        let secs = last_at.elapsed().as_secs_f64();
+        last_at = Instant::now();
+
+        println!("{:>6}s  iob/s {:>8.0}  msg/s {:>7.0}  kB/s {:>7.1}  requests/s {:>6.0}  total iobs {}",
+            started.elapsed().as_secs(),
+            (now.0 - last.0) as f64 / secs,
+            (now.1 - last.1) as f64 / secs,
+            (now.2 - last.2) as f64 / secs / 1000.0,
+            (now.3 - last.3) as f64 / secs,
+            now.0);
+
+        last = now;
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Arc::new(Cli::parse());
+    let counters = Arc::new(Counters::default());
+
+    let listener = TcpListener::bind(&cli.listen)?;
+    eprintln!("Waiting for ptnet-mgrd on {}", cli.listen);
+    let (stream, peer) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    eprintln!("ptnet-mgrd connected from {}, sending {} IOBs/s from {} nodes", peer, cli.rate, cli.nodes);
+
+    {
+        let stream = stream.try_clone()?;
+        let counters = counters.clone();
+        thread::spawn(move || {
+            if let Err(err) = confirm(stream, &counters) {
+                eprintln!("Reading from ptnet-mgrd failed! ({})", err);
+                std::process::exit(1);
+            }
+        });
+    }
+
+    {
+        let cli = cli.clone();
+        let counters = counters.clone();
+        thread::spawn(move || report(&cli, &counters));
+    }
+
+    if let Some(duration) = cli.duration {
+        let counters = counters.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_secs(duration));
+            let iobs = counters.iobs_sent.load(Ordering::Relaxed);
+            println!("done, {} IOBs in {}s ({:.0}/s)", iobs, duration, iobs as f64 / duration.max(1) as f64);
+            std::process::exit(0);
+        });
+    }
+
+    generate(&cli, stream, &counters)
+}